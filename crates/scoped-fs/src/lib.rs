@@ -1,9 +1,9 @@
 mod error;
 pub use error::Error;
-use serde::Deserialize;
-use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 // Contains no trailing slash
 pub struct ScopedPath(String);
 
@@ -11,6 +11,44 @@ impl ScopedPath {
     pub fn new(path: String) -> Self {
         Self(path.trim_end_matches('/').to_owned())
     }
+    /// Builds a scoped path from a path relative to some base directory.
+    /// Returns None if the path contains anything but normal UTF-8 segments.
+    pub fn from_relative(path: &Path) -> Option<Self> {
+        let mut segments = vec![];
+        for component in path.components() {
+            match component {
+                Component::Normal(segment) => segments.push(segment.to_str()?),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        Some(Self(segments.join("/")))
+    }
+
+    /// Parses a path sent by a client, surrounding slashes are ignored.
+    /// Returns None if it contains .. or anything else that could leave the base.
+    pub fn parse(path: &str) -> Option<Self> {
+        Self::from_relative(Path::new(path.trim_matches('/')))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether this path equals `ancestor` or lies below it
+    pub fn starts_with(&self, ancestor: &ScopedPath) -> bool {
+        ancestor.0.is_empty()
+            || self.0 == ancestor.0
+            || self
+                .0
+                .strip_prefix(&ancestor.0)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
     pub fn with_base(&self, base: &Path) -> PathBuf {
         // TODO: Ensure that we don't break out of base
         base.join(&self.0)
@@ -38,17 +76,34 @@ impl ScopedPath {
         }
     }
 
+    pub fn parent(&self) -> Self {
+        match self.0.rsplit_once('/') {
+            Some((parent, _filename)) => Self(parent.to_owned()),
+            None => Self::default(),
+        }
+    }
+
     pub fn file_extension(&self) -> Option<&str> {
         let filename = self.file_name();
         filename.rsplit_once('.').map(|(_prefix, ext)| ext)
     }
 }
 
+impl Serialize for ScopedPath {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
 impl<'de> Deserialize<'de> for ScopedPath {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        Ok(Self::new(String::deserialize(deserializer)?))
+        let path = String::deserialize(deserializer)?;
+        Self::parse(&path).ok_or_else(|| serde::de::Error::custom("invalid path"))
     }
 }
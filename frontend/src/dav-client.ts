@@ -1,3 +1,6 @@
 import { createClient } from "webdav";
 
-export const davClient = createClient("/dav");
+/** Public path of the server root, the page's base element points to its /frontend/ */
+export const basePath = new URL(document.baseURI).pathname.replace(/\/frontend\/?$/, "");
+
+export const davClient = createClient(`${basePath}/dav`);
@@ -1,6 +1,6 @@
 import { html, LitElement, PropertyValues } from "lit";
 import { customElement, property } from "lit/decorators.js";
-import { davClient } from "./dav-client.ts";
+import { basePath, davClient } from "./dav-client.ts";
 import { FileStat } from "webdav";
 
 
@@ -28,7 +28,7 @@ export class FileRouter extends LitElement {
   }
 
   updateRoute = () => {
-    this.path = globalThis.location.pathname.replace(/^\/frontend/, '')
+    this.path = globalThis.location.pathname.slice(`${basePath}/frontend`.length)
   }
 
   _onNavigate = (event: NavigateEvent) => {
@@ -77,7 +77,7 @@ export class FileItem extends LitElement {
 
   override render() {
     const size = this.stat.type == 'file' ? formatFilesize(this.stat.size) : ''
-    const href = this.stat.type == 'file' ? `dav${this.stat.filename}` : `/frontend${this.stat.filename}`
+    const href = this.stat.type == 'file' ? `${basePath}/dav${this.stat.filename}` : `${basePath}/frontend${this.stat.filename}`
 
     return html`
       <td><input type="checkbox" .checked=${this.selected} @input=${(e: InputEvent) => this.selected = (e.target! as HTMLInputElement).checked} /></td>
@@ -105,13 +105,32 @@ export class FileBrowser extends LitElement {
   @property()
   entries: Array<FileStat> = []
 
+  events: EventSource | null = null
+
+  disconnectedCallback(): void {
+    super.disconnectedCallback()
+    this.events?.close()
+    this.events = null
+  }
+
   protected shouldUpdate(changedProperties: PropertyValues): boolean {
     if (changedProperties.has('path')) {
       this.fetchContents()
+      this.subscribe()
     }
     return true
   }
 
+  subscribe() {
+    this.events?.close()
+    this.events = null
+    if (this.path === null) return
+    const path = this.path.replace(/^\/mount/, '').replace(/\/$/, '')
+    this.events = new EventSource(`${basePath}/notifications${path}`)
+    this.events.addEventListener('change', () => this.fetchContents())
+    this.events.addEventListener('lagged', () => this.fetchContents())
+  }
+
   async fetchContents() {
     if (this.path === null) return
     const entries = await davClient.getDirectoryContents(this.path, { details: false }) as FileStat[]
@@ -123,6 +142,7 @@ export class FileBrowser extends LitElement {
   }
 
   override render() {
+    const parent = this.path.replace(/\/$/, '').replace(/[^/]*$/, '')
     return html`
       <h1>File Browser</h1>
       <pre>${this.path}</pre>
@@ -137,7 +157,7 @@ export class FileBrowser extends LitElement {
         <tr>
           <td></td>
           <td>
-            <a href="./">..</a>
+            <a href=${`${basePath}/frontend${parent}`}>..</a>
           </td>
           <td></td>
           <td></td>
@@ -2,7 +2,8 @@ import { defineConfig } from "vite";
 import { resolve } from "node:path";
 
 export default defineConfig({
-  base: "/frontend",
+  // Resolved against the base element the server adds to the page
+  base: "./",
   build: {
     minify: false,
     modulePreload: {
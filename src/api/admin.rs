@@ -0,0 +1,34 @@
+use super::{Error, ErrorBody};
+use crate::{
+    auth::Authenticator,
+    dav::User,
+    reload::{ReloadReport, Reloader},
+};
+use axum::{Extension, Json};
+
+/// Only users listed in auth.admins may use the admin API
+pub(crate) fn require_admin(authenticator: &Authenticator, user: &User) -> Result<(), Error> {
+    if !authenticator.is_admin(user) {
+        return Err(crate::dav::Error::Forbidden.into());
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    responses(
+        (status = 200, body = ReloadReport),
+        (status = 403, body = ErrorBody),
+        (status = 422, body = ErrorBody),
+    ),
+    tag = "admin",
+)]
+pub async fn route_reload(
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(reloader): Extension<Reloader>,
+    user: User,
+) -> Result<Json<ReloadReport>, Error> {
+    require_admin(&authenticator, &user)?;
+    Ok(Json(reloader.reload().await?))
+}
@@ -0,0 +1,68 @@
+use axum::{
+    Json,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Dav(#[from] crate::dav::Error),
+
+    #[error("Bad Request: {0}")]
+    BadRequest(&'static str),
+
+    #[error("Not Found")]
+    NotFound,
+
+    #[error(transparent)]
+    Reload(#[from] crate::reload::Error),
+}
+
+impl From<crate::filesystem::Error> for Error {
+    fn from(value: crate::filesystem::Error) -> Self {
+        Self::Dav(value.into())
+    }
+}
+
+impl From<crate::db::Error> for Error {
+    fn from(value: crate::db::Error) -> Self {
+        Self::Dav(value.into())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Dav(value.into())
+    }
+}
+
+impl Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Dav(err) => err.status_code(),
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Reload(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (
+            self.status_code(),
+            Json(ErrorBody {
+                error: self.to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
@@ -0,0 +1,361 @@
+use super::{Error, ErrorBody, MountPath};
+use crate::{
+    dav::{
+        User,
+        fs::{FSResource, FSResourceService, FSResourceServicePath},
+    },
+    filesystem::{DavMetadata, FilesystemProvider},
+};
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use rustical_dav::{
+    privileges::UserPrivilege,
+    resource::{Resource, ResourceService},
+};
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Entry {
+    pub name: String,
+    /// Path inside the mount
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+}
+
+impl<FSP: FilesystemProvider> From<&FSResource<FSP>> for Entry {
+    fn from(resource: &FSResource<FSP>) -> Self {
+        Self {
+            name: resource.path.file_name().to_owned(),
+            path: resource.path.as_str().to_owned(),
+            is_dir: resource.metadata.is_dir(),
+            size: resource.metadata.len(),
+            modified: resource.metadata.modified().into(),
+            created: resource.metadata.created().into(),
+            content_type: resource.get_content_type().map(str::to_owned),
+            etag: resource.get_etag(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TransferRequest {
+    /// Source path inside the mount
+    pub from: String,
+    /// Destination path inside the mount
+    pub to: String,
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RenameRequest {
+    pub path: String,
+    /// New file name, must not contain slashes
+    pub name: String,
+}
+
+/// Rejects the mount root before checking the privileges on the parent
+async fn authorize_parent<FSP: FilesystemProvider>(
+    resource_service: &FSResourceService<FSP>,
+    path: &FSResourceServicePath,
+    user: &User,
+) -> Result<(), Error> {
+    if path.path.is_root() {
+        return Err(Error::BadRequest("the mount root already exists"));
+    }
+    Ok(resource_service.authorize_create(path, user).await?)
+}
+
+fn mount_path(mount: &str, path: &str) -> Result<FSResourceServicePath, Error> {
+    Ok(FSResourceServicePath {
+        mount: mount.to_owned(),
+        path: ScopedPath::parse(path).ok_or(Error::BadRequest("invalid path"))?,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/mounts/{mount}/stat/{path}",
+    params(
+        ("mount" = String, Path),
+        ("path" = String, Path, description = "Path inside the mount, may contain slashes"),
+    ),
+    responses(
+        (status = 200, body = Entry),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_stat<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+) -> Result<Json<Entry>, Error> {
+    let resource = resource_service
+        .authorize(&path, &user, UserPrivilege::Read)
+        .await?;
+    Ok(Json(Entry::from(&resource)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/mounts/{mount}/list/{path}",
+    params(
+        ("mount" = String, Path),
+        ("path" = String, Path, description = "Path inside the mount, may contain slashes"),
+    ),
+    responses(
+        (status = 200, body = Vec<Entry>),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_list<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+) -> Result<Json<Vec<Entry>>, Error> {
+    resource_service
+        .authorize(&path, &user, UserPrivilege::Read)
+        .await?;
+    let members = resource_service.get_members(&path).await?;
+    Ok(Json(members.iter().map(Entry::from).collect()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/mounts/{mount}/mkdir/{path}",
+    params(
+        ("mount" = String, Path),
+        ("path" = String, Path, description = "Path inside the mount, may contain slashes"),
+    ),
+    responses(
+        (status = 201, body = Entry),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_mkdir<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+) -> Result<Response, Error> {
+    authorize_parent(&resource_service, &path, &user).await?;
+    resource_service.create_collection(&path).await?;
+    let resource = resource_service.get_resource(&path, false).await?;
+    Ok((StatusCode::CREATED, Json(Entry::from(&resource))).into_response())
+}
+
+#[utoipa::path(
+    put,
+    path = "/mounts/{mount}/upload/{path}",
+    params(
+        ("mount" = String, Path),
+        ("path" = String, Path, description = "Path inside the mount, may contain slashes"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, body = Entry, description = "An existing file was replaced"),
+        (status = 201, body = Entry, description = "The file was created"),
+        (status = 403, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_upload<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+    body: Body,
+) -> Result<Response, Error> {
+    if path.path.is_root() {
+        return Err(Error::BadRequest("the mount root already exists"));
+    }
+    // Like DAV PUT, replacing needs write access to the file, creating it write access to the parent
+    match resource_service
+        .authorize(&path, &user, UserPrivilege::Write)
+        .await
+    {
+        Ok(_) => {}
+        Err(crate::dav::Error::FS(crate::filesystem::Error::NotFound)) => {
+            authorize_parent(&resource_service, &path, &user).await?
+        }
+        Err(err) => return Err(err.into()),
+    }
+    let existed = resource_service.write_file(&path, body).await?;
+    let resource = resource_service.get_resource(&path, false).await?;
+    let status = if existed {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    Ok((status, Json(Entry::from(&resource))).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/mounts/{mount}/files/{path}",
+    params(
+        ("mount" = String, Path),
+        ("path" = String, Path, description = "Path inside the mount, may contain slashes"),
+    ),
+    responses(
+        (status = 204),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_delete<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+) -> Result<StatusCode, Error> {
+    if path.path.is_root() {
+        return Err(Error::BadRequest("the mount root cannot be deleted"));
+    }
+    resource_service
+        .authorize(&path, &user, UserPrivilege::Write)
+        .await?;
+    resource_service.delete_resource(&path, false).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn transfer<FSP: FilesystemProvider>(
+    resource_service: &FSResourceService<FSP>,
+    mount: &str,
+    user: &User,
+    request: &TransferRequest,
+    is_move: bool,
+) -> Result<Response, Error> {
+    let from = mount_path(mount, &request.from)?;
+    let to = mount_path(mount, &request.to)?;
+    if from.path.is_root() || to.path.is_root() {
+        return Err(Error::BadRequest(
+            "the mount root cannot be moved or copied",
+        ));
+    }
+    let privilege = if is_move {
+        UserPrivilege::Write
+    } else {
+        UserPrivilege::Read
+    };
+    resource_service.authorize(&from, user, privilege).await?;
+    authorize_parent(resource_service, &to, user).await?;
+
+    let overwritten = if is_move {
+        resource_service
+            .move_resource(&from, &to, user, request.overwrite)
+            .await?
+    } else {
+        resource_service
+            .copy_resource(&from, &to, user, request.overwrite)
+            .await?
+    };
+    let resource = resource_service.get_resource(&to, false).await?;
+    let status = if overwritten {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    Ok((status, Json(Entry::from(&resource))).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/mounts/{mount}/move",
+    params(("mount" = String, Path)),
+    request_body = TransferRequest,
+    responses(
+        (status = 200, body = Entry, description = "An existing resource was replaced"),
+        (status = 201, body = Entry),
+        (status = 400, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_move<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(MountPath { mount }): Path<MountPath>,
+    user: User,
+    Json(request): Json<TransferRequest>,
+) -> Result<Response, Error> {
+    transfer(&resource_service, &mount, &user, &request, true).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/mounts/{mount}/copy",
+    params(("mount" = String, Path)),
+    request_body = TransferRequest,
+    responses(
+        (status = 200, body = Entry, description = "An existing resource was replaced"),
+        (status = 201, body = Entry),
+        (status = 400, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_copy<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(MountPath { mount }): Path<MountPath>,
+    user: User,
+    Json(request): Json<TransferRequest>,
+) -> Result<Response, Error> {
+    transfer(&resource_service, &mount, &user, &request, false).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/mounts/{mount}/rename",
+    params(("mount" = String, Path)),
+    request_body = RenameRequest,
+    responses(
+        (status = 201, body = Entry),
+        (status = 400, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    ),
+    tag = "files",
+)]
+pub async fn route_rename<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(MountPath { mount }): Path<MountPath>,
+    user: User,
+    Json(request): Json<RenameRequest>,
+) -> Result<Response, Error> {
+    if request.name.is_empty()
+        || request.name.contains('/')
+        || matches!(request.name.as_str(), "." | "..")
+    {
+        return Err(Error::BadRequest("invalid file name"));
+    }
+    let from = ScopedPath::parse(&request.path).ok_or(Error::BadRequest("invalid path"))?;
+    let transfer_request = TransferRequest {
+        to: from
+            .parent()
+            .join_segment(&request.name)
+            .as_str()
+            .to_owned(),
+        from: request.path,
+        overwrite: false,
+    };
+    transfer(&resource_service, &mount, &user, &transfer_request, true).await
+}
@@ -0,0 +1,203 @@
+use super::{Error, ErrorBody, MountPath};
+use crate::{
+    dav::{
+        User,
+        fs::{FSResourceService, FSResourceServicePath},
+    },
+    filesystem::{DavMetadata, FilesystemProvider},
+    shares::{Grant, GrantPrivilege, GrantStore, Grantee},
+};
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use rustical_dav::{Principal, privileges::UserPrivilege};
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GrantEntry {
+    pub id: String,
+    pub owner: String,
+    pub mount: String,
+    /// Shared path inside the owner's mount
+    pub path: String,
+    pub grantee: Grantee,
+    pub privilege: GrantPrivilege,
+    /// Name of the collection in the recipient's mount
+    pub name: String,
+    pub created: DateTime<Utc>,
+}
+
+impl From<&Grant> for GrantEntry {
+    fn from(grant: &Grant) -> Self {
+        Self {
+            id: grant.id.to_owned(),
+            owner: grant.owner.to_owned(),
+            mount: grant.mount.to_owned(),
+            path: grant.path.as_str().to_owned(),
+            grantee: grant.grantee.to_owned(),
+            privilege: grant.privilege,
+            name: grant.name.to_owned(),
+            created: grant.created,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateGrantRequest {
+    /// Folder inside the mount
+    pub path: String,
+    pub grantee: Grantee,
+    pub privilege: GrantPrivilege,
+    /// Defaults to the folder's name
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrantPath {
+    id: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/grants",
+    responses((status = 200, body = Vec<GrantEntry>, description = "Folders the user shared")),
+    tag = "grants",
+)]
+pub async fn route_list_grants(
+    Extension(store): Extension<GrantStore>,
+    user: User,
+) -> Result<Json<Vec<GrantEntry>>, Error> {
+    Ok(Json(
+        store
+            .list_by_owner(user.get_id())?
+            .iter()
+            .map(GrantEntry::from)
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/grants/received",
+    responses((status = 200, body = Vec<GrantEntry>, description = "Folders shared with the user")),
+    tag = "grants",
+)]
+pub async fn route_list_received_grants(
+    Extension(store): Extension<GrantStore>,
+    user: User,
+) -> Result<Json<Vec<GrantEntry>>, Error> {
+    Ok(Json(
+        store
+            .received_by(user.get_id())?
+            .iter()
+            .map(GrantEntry::from)
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/mounts/{mount}/grants",
+    params(("mount" = String, Path)),
+    request_body = CreateGrantRequest,
+    responses(
+        (status = 201, body = GrantEntry),
+        (status = 400, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    tag = "grants",
+)]
+pub async fn route_create_grant<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Extension(store): Extension<GrantStore>,
+    Path(MountPath { mount }): Path<MountPath>,
+    user: User,
+    Json(request): Json<CreateGrantRequest>,
+) -> Result<Response, Error> {
+    // Only the owner of a mount shares from it
+    if mount != user.get_id() {
+        return Err(crate::dav::Error::Forbidden.into());
+    }
+    if request.grantee == Grantee::User(mount.to_owned()) {
+        return Err(Error::BadRequest("a folder can't be shared with its owner"));
+    }
+    let path = FSResourceServicePath {
+        mount,
+        path: ScopedPath::parse(&request.path).ok_or(Error::BadRequest("invalid path"))?,
+    };
+    let (first_segment, _) = path
+        .path
+        .as_str()
+        .split_once('/')
+        .unwrap_or((path.path.as_str(), ""));
+    if store
+        .received_by(user.get_id())?
+        .iter()
+        .any(|grant| grant.name == first_segment)
+    {
+        return Err(Error::BadRequest(
+            "folders shared with you can't be shared again",
+        ));
+    }
+    // Sharing can't grant more than the owner has
+    let privilege = match request.privilege {
+        GrantPrivilege::Read => UserPrivilege::Read,
+        GrantPrivilege::Write => UserPrivilege::Write,
+    };
+    let resource = resource_service.authorize(&path, &user, privilege).await?;
+    if !resource.metadata.is_dir() {
+        return Err(Error::BadRequest("only folders can be shared with users"));
+    }
+    let name = request
+        .name
+        .unwrap_or_else(|| path.path.file_name().to_owned());
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(Error::BadRequest("invalid name"));
+    }
+
+    let grant = Grant {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        owner: user.get_id().to_owned(),
+        mount: path.mount,
+        path: path.path,
+        grantee: request.grantee,
+        privilege: request.privilege,
+        name,
+        created: Utc::now(),
+    };
+    let entry = GrantEntry::from(&grant);
+    store.insert(grant)?;
+    Ok((StatusCode::CREATED, Json(entry)).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/grants/{id}",
+    params(("id" = String, Path)),
+    responses(
+        (status = 204),
+        (status = 404, body = ErrorBody),
+    ),
+    tag = "grants",
+)]
+pub async fn route_revoke_grant(
+    Extension(store): Extension<GrantStore>,
+    Path(GrantPath { id }): Path<GrantPath>,
+    user: User,
+) -> Result<StatusCode, Error> {
+    if store
+        .get(&id)?
+        .is_none_or(|grant| grant.owner != user.get_id())
+    {
+        return Err(Error::NotFound);
+    }
+    store.remove(&id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
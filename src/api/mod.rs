@@ -0,0 +1,99 @@
+use crate::{
+    dav::fs::FSResourceService,
+    filesystem::FilesystemProvider,
+    reload::ReloadReport,
+    shares::{GrantPrivilege, Grantee, ShareKind},
+};
+use axum::{
+    Json, Router,
+    routing::{delete, get, post, put},
+};
+use serde::Deserialize;
+use utoipa::OpenApi;
+
+mod admin;
+mod error;
+mod files;
+mod grants;
+mod shares;
+pub(crate) use admin::require_admin;
+use admin::*;
+pub use error::{Error, ErrorBody};
+use files::*;
+use grants::*;
+use shares::*;
+
+#[derive(Debug, Deserialize)]
+pub struct MountPath {
+    mount: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Wolke API"),
+    servers((url = "/api/v1")),
+    paths(
+        route_stat,
+        route_list,
+        route_mkdir,
+        route_upload,
+        route_delete,
+        route_move,
+        route_copy,
+        route_rename,
+        route_list_shares,
+        route_create_share,
+        route_revoke_share,
+        route_list_grants,
+        route_list_received_grants,
+        route_create_grant,
+        route_revoke_grant,
+        route_reload,
+    ),
+    components(schemas(
+        Entry,
+        TransferRequest,
+        RenameRequest,
+        ShareEntry,
+        CreateShareRequest,
+        ShareKind,
+        GrantEntry,
+        CreateGrantRequest,
+        Grantee,
+        GrantPrivilege,
+        ReloadReport,
+        ErrorBody
+    )),
+    tags(
+        (name = "files", description = "File operations inside a mount"),
+        (name = "shares", description = "Public share links"),
+        (name = "grants", description = "Folders shared with other users and groups"),
+        (name = "admin", description = "Server administration, limited to auth.admins"),
+    ),
+)]
+pub struct ApiDoc;
+
+/// JSON API for the web file manager, nested at /api/v1
+pub fn api_router<FSP: FilesystemProvider>(resource_service: FSResourceService<FSP>) -> Router {
+    Router::new()
+        .route("/openapi.json", get(|| async { Json(ApiDoc::openapi()) }))
+        .route("/mounts/{mount}/stat", get(route_stat::<FSP>))
+        .route("/mounts/{mount}/stat/{*path}", get(route_stat::<FSP>))
+        .route("/mounts/{mount}/list", get(route_list::<FSP>))
+        .route("/mounts/{mount}/list/{*path}", get(route_list::<FSP>))
+        .route("/mounts/{mount}/mkdir/{*path}", post(route_mkdir::<FSP>))
+        .route("/mounts/{mount}/upload/{*path}", put(route_upload::<FSP>))
+        .route("/mounts/{mount}/files/{*path}", delete(route_delete::<FSP>))
+        .route("/mounts/{mount}/move", post(route_move::<FSP>))
+        .route("/mounts/{mount}/copy", post(route_copy::<FSP>))
+        .route("/mounts/{mount}/rename", post(route_rename::<FSP>))
+        .route("/mounts/{mount}/shares", post(route_create_share::<FSP>))
+        .route("/shares", get(route_list_shares))
+        .route("/shares/{token}", delete(route_revoke_share))
+        .route("/mounts/{mount}/grants", post(route_create_grant::<FSP>))
+        .route("/grants", get(route_list_grants))
+        .route("/grants/received", get(route_list_received_grants))
+        .route("/grants/{id}", delete(route_revoke_grant))
+        .route("/admin/reload", post(route_reload))
+        .with_state(resource_service)
+}
@@ -0,0 +1,181 @@
+use super::{Error, ErrorBody, MountPath};
+use crate::{
+    base_url::BaseUrl,
+    dav::{
+        User,
+        fs::{FSResourceService, FSResourceServicePath},
+    },
+    filesystem::{DavMetadata, FilesystemProvider},
+    shares::{Share, ShareKind, ShareStore, hash_password},
+};
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use rustical_dav::{Principal, privileges::UserPrivilege};
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ShareEntry {
+    pub token: String,
+    pub mount: String,
+    pub path: String,
+    pub kind: ShareKind,
+    /// Web page for visitors
+    pub url: String,
+    /// WebDAV endpoint for visitors
+    pub dav_url: String,
+    pub has_password: bool,
+    pub expires: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u64>,
+    pub downloads: u64,
+    /// False once the share expired or reached its download limit
+    pub active: bool,
+    pub created: DateTime<Utc>,
+}
+
+impl ShareEntry {
+    /// Links are absolute so they can be handed out as they are
+    fn new(share: &Share, base_url: &BaseUrl) -> Self {
+        Self {
+            token: share.token.to_owned(),
+            mount: share.mount.to_owned(),
+            path: share.path.as_str().to_owned(),
+            kind: share.kind,
+            url: base_url.url(&format!("/s/{}", share.token)),
+            dav_url: base_url.url(&format!("/dav/share/{}/", share.token)),
+            has_password: share.password_hash.is_some(),
+            expires: share.expires,
+            max_downloads: share.max_downloads,
+            downloads: share.downloads,
+            active: share.is_active(),
+            created: share.created,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateShareRequest {
+    /// Path inside the mount
+    pub path: String,
+    pub kind: ShareKind,
+    pub password: Option<String>,
+    pub expires: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenPath {
+    token: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/shares",
+    responses((status = 200, body = Vec<ShareEntry>)),
+    tag = "shares",
+)]
+pub async fn route_list_shares(
+    Extension(store): Extension<ShareStore>,
+    Extension(base_url): Extension<BaseUrl>,
+    user: User,
+) -> Result<Json<Vec<ShareEntry>>, Error> {
+    Ok(Json(
+        store
+            .list_by_owner(user.get_id())?
+            .iter()
+            .map(|share| ShareEntry::new(share, &base_url))
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/mounts/{mount}/shares",
+    params(("mount" = String, Path)),
+    request_body = CreateShareRequest,
+    responses(
+        (status = 201, body = ShareEntry),
+        (status = 400, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    tag = "shares",
+)]
+pub async fn route_create_share<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Extension(store): Extension<ShareStore>,
+    Extension(base_url): Extension<BaseUrl>,
+    Path(MountPath { mount }): Path<MountPath>,
+    user: User,
+    Json(request): Json<CreateShareRequest>,
+) -> Result<Response, Error> {
+    let path = FSResourceServicePath {
+        mount,
+        path: ScopedPath::parse(&request.path).ok_or(Error::BadRequest("invalid path"))?,
+    };
+    // Sharing can't grant more than the owner has
+    let privilege = match request.kind {
+        ShareKind::Read => UserPrivilege::Read,
+        ShareKind::FileDrop => UserPrivilege::Write,
+    };
+    let resource = resource_service.authorize(&path, &user, privilege).await?;
+    let is_dir = resource.metadata.is_dir();
+    if request.kind == ShareKind::FileDrop && !is_dir {
+        return Err(Error::BadRequest("file drops must share a folder"));
+    }
+    if request.expires.is_some_and(|expires| expires < Utc::now()) {
+        return Err(Error::BadRequest("expiry date lies in the past"));
+    }
+    if request.password.as_deref() == Some("") {
+        return Err(Error::BadRequest("password must not be empty"));
+    }
+
+    let share = Share {
+        token: Share::new_token(),
+        owner: user.get_id().to_owned(),
+        mount: path.mount,
+        path: path.path,
+        is_dir,
+        kind: request.kind,
+        password_hash: request.password.as_deref().map(hash_password),
+        expires: request.expires,
+        max_downloads: request.max_downloads,
+        downloads: 0,
+        created: Utc::now(),
+    };
+    let entry = ShareEntry::new(&share, &base_url);
+    store.insert(share)?;
+    Ok((StatusCode::CREATED, Json(entry)).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/shares/{token}",
+    params(("token" = String, Path)),
+    responses(
+        (status = 204),
+        (status = 404, body = ErrorBody),
+    ),
+    tag = "shares",
+)]
+pub async fn route_revoke_share(
+    Extension(store): Extension<ShareStore>,
+    Path(TokenPath { token }): Path<TokenPath>,
+    user: User,
+) -> Result<StatusCode, Error> {
+    // Shares of other users are reported as missing to not leak tokens
+    if store
+        .get(&token)?
+        .is_none_or(|share| share.owner != user.get_id())
+    {
+        return Err(Error::NotFound);
+    }
+    store.remove(&token)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,102 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Appends to a file and rotates it to path.1, path.2, … once it exceeds max_size
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    /// Number of rotated files to keep
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_size: u64, keep: usize) -> io::Result<Self> {
+        let (file, size) = open(&path)?;
+        Ok(Self {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                match std::fs::rename(rotated(&self.path, index), rotated(&self.path, index + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        (self.file, self.size) = open(&self.path)?;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Writes a complete line, lines are never split across files
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_whole_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        file.sync().unwrap();
+
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "fourth\n");
+        assert_eq!(read(&rotated(&path, 1)), "third\n");
+        assert_eq!(read(&rotated(&path, 2)), "second\n");
+        // Older rotations are dropped
+        assert!(!rotated(&path, 3).exists());
+
+        // Appends to what's there after a restart
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        file.write_line(b"5\n").unwrap();
+        assert_eq!(read(&path), "fourth\n5\n");
+    }
+}
@@ -0,0 +1,261 @@
+//! Audit log of file operations, written as JSON lines independently of tracing
+use crate::config::AuditConfig;
+use axum::{extract::Request, middleware::Next, response::Response};
+use chrono::{DateTime, Utc};
+use headers::{HeaderMapExt, UserAgent};
+use scoped_fs::ScopedPath;
+use serde::Serialize;
+use std::{
+    io::Write,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+mod file;
+use file::RotatingFile;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Upload,
+    Download,
+    Mkcol,
+    Delete,
+    Copy,
+    Move,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub time: DateTime<Utc>,
+    pub action: AuditAction,
+    /// User id or share:{token} for public share links, None outside of requests
+    pub principal: Option<String>,
+    pub mount: String,
+    pub path: ScopedPath,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<ScopedPath>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Whether an existing resource was replaced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overwritten: Option<bool>,
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, mount: &str, path: &ScopedPath) -> Self {
+        Self {
+            time: Utc::now(),
+            action,
+            principal: None,
+            mount: mount.to_owned(),
+            path: path.to_owned(),
+            destination: None,
+            size: None,
+            overwritten: None,
+            client_ip: None,
+            user_agent: None,
+        }
+    }
+
+    pub fn destination(mut self, destination: &ScopedPath) -> Self {
+        self.destination = Some(destination.to_owned());
+        self
+    }
+
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn overwritten(mut self, overwritten: bool) -> Self {
+        self.overwritten = Some(overwritten);
+        self
+    }
+}
+
+/// Who made the current request, filled in as the request is handled
+#[derive(Debug, Clone, Default)]
+struct RequestContext {
+    principal: Arc<Mutex<Option<String>>>,
+    client_ip: Option<IpAddr>,
+    user_agent: Option<String>,
+}
+
+tokio::task_local! {
+    // The resource service doesn't see the request, so its context travels with the task
+    static REQUEST: RequestContext;
+}
+
+/// Attributes audit events of the current request to a principal
+pub fn set_principal(principal: impl Into<String>) {
+    let _ = REQUEST.try_with(|request| {
+        *request.principal.lock().unwrap() = Some(principal.into());
+    });
+}
+
+/// Middleware that makes the client of each request known to the audit log
+pub async fn request_context(request: Request, next: Next) -> Response {
+    let context = RequestContext {
+        principal: Default::default(),
+        client_ip: crate::server::peer_ip(request.extensions()),
+        user_agent: request
+            .headers()
+            .typed_get::<UserAgent>()
+            .map(|ua| ua.to_string()),
+    };
+    REQUEST.scope(context, next.run(request)).await
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+struct Inner {
+    sink: Mutex<Sink>,
+    downloads: bool,
+}
+
+/// Disabled unless configured, records are dropped then
+#[derive(Clone, Default)]
+pub struct AuditLog(Option<Arc<Inner>>);
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AuditLog").field(&self.0.is_some()).finish()
+    }
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Result<Self, std::io::Error> {
+        if !config.enabled {
+            return Ok(Self(None));
+        }
+        let sink = match &config.path {
+            Some(path) => Sink::File(RotatingFile::open(
+                path.to_owned(),
+                config.max_size,
+                config.keep,
+            )?),
+            None => Sink::Stdout,
+        };
+        Ok(Self(Some(Arc::new(Inner {
+            sink: Mutex::new(sink),
+            downloads: config.downloads,
+        }))))
+    }
+
+    pub fn record(&self, mut event: AuditEvent) {
+        let Some(inner) = &self.0 else {
+            return;
+        };
+        if event.action == AuditAction::Download && !inner.downloads {
+            return;
+        }
+        let _ = REQUEST.try_with(|request| {
+            event.principal = request.principal.lock().unwrap().clone();
+            event.client_ip = request.client_ip;
+            event.user_agent = request.user_agent.clone();
+        });
+        let mut line = serde_json::to_vec(&event).expect("AuditEvent can always be serialized");
+        line.push(b'\n');
+        let result = match &mut *inner.sink.lock().unwrap() {
+            Sink::Stdout => std::io::stdout().lock().write_all(&line),
+            Sink::File(file) => file.write_line(&line),
+        };
+        if let Err(err) = result {
+            tracing::error!("Failed to write audit log: {err}");
+        }
+    }
+
+    /// Makes sure everything recorded so far is on disk
+    pub fn flush(&self) {
+        let Some(inner) = &self.0 else {
+            return;
+        };
+        let result = match &mut *inner.sink.lock().unwrap() {
+            Sink::Stdout => std::io::stdout().flush(),
+            Sink::File(file) => file.sync(),
+        };
+        if let Err(err) = result {
+            tracing::error!("Failed to flush audit log: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(path: &std::path::Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn events_carry_the_request_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit/audit.jsonl");
+        let log = AuditLog::new(&AuditConfig {
+            enabled: true,
+            path: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let file = ScopedPath::new("notes.txt".to_owned());
+        let context = RequestContext {
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            user_agent: Some("curl/8".to_owned()),
+            ..Default::default()
+        };
+        REQUEST
+            .scope(context, async {
+                set_principal("alice");
+                log.record(AuditEvent::new(AuditAction::Upload, "alice", &file).size(5));
+                // Downloads aren't recorded unless configured
+                log.record(AuditEvent::new(AuditAction::Download, "alice", &file));
+            })
+            .await;
+        log.record(
+            AuditEvent::new(AuditAction::Move, "alice", &file)
+                .destination(&ScopedPath::new("old.txt".to_owned())),
+        );
+        log.flush();
+
+        let lines = lines(&path);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["action"], "upload");
+        assert_eq!(lines[0]["principal"], "alice");
+        assert_eq!(lines[0]["client_ip"], "192.0.2.1");
+        assert_eq!(lines[0]["user_agent"], "curl/8");
+        assert_eq!(lines[0]["size"], 5);
+        assert_eq!(lines[1]["action"], "move");
+        assert_eq!(lines[1]["destination"], "old.txt");
+        assert!(lines[1]["principal"].is_null());
+        assert!(lines[1].get("size").is_none());
+    }
+
+    #[test]
+    fn disabled_log_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::new(&AuditConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        log.record(AuditEvent::new(
+            AuditAction::Delete,
+            "alice",
+            &ScopedPath::new("notes.txt".to_owned()),
+        ));
+        log.flush();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,59 @@
+use axum::response::{IntoResponse, Response};
+use http::{HeaderValue, StatusCode, header};
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    /// The authentication backend couldn't be reached or misbehaved
+    #[error("Authentication backend error: {0}")]
+    Backend(String),
+
+    /// Too many failed logins or requests, contains the time until the next try
+    #[error("Too Many Requests")]
+    TooManyRequests(Duration),
+}
+
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Backend(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl From<ldap3::LdapError> for Error {
+    fn from(value: ldap3::LdapError) -> Self {
+        Self::Backend(value.to_string())
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        if let Self::Backend(err) = &self {
+            tracing::error!("{err}");
+        }
+        let mut response = (self.status_code(), self.to_string()).into_response();
+        match self {
+            Self::Unauthorized => {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Basic realm="Wolke", charset="UTF-8""#),
+                );
+            }
+            Self::TooManyRequests(retry_after) => {
+                // Round up so clients don't retry too early
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            }
+            Self::Backend(_) => {}
+        }
+        response
+    }
+}
@@ -0,0 +1,580 @@
+use super::{AuthProvider, Authenticated, Error};
+use crate::config::LdapConfig;
+use async_trait::async_trait;
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, ldap_escape};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Looks up the user's DN with a search, then binds as that DN to check the password
+pub struct LdapProvider {
+    config: LdapConfig,
+    /// Keeps clients sending Basic auth with every request from opening two connections each time
+    cache: Mutex<HashMap<[u8; 32], (Authenticated, Instant)>>,
+    /// Salts the credential hashes used as cache keys
+    cache_salt: String,
+}
+
+impl LdapProvider {
+    pub fn new(config: LdapConfig) -> Self {
+        Self {
+            config,
+            cache: Default::default(),
+            cache_salt: super::random_token(),
+        }
+    }
+
+    fn cache_key(&self, username: &str, password: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(&self.cache_salt)
+            .chain_update((username.len() as u64).to_le_bytes())
+            .chain_update(username)
+            .chain_update(password)
+            .finalize()
+            .into()
+    }
+
+    fn cached(&self, key: &[u8; 32]) -> Option<Authenticated> {
+        let cache = self.cache.lock().unwrap();
+        let (authenticated, expires) = cache.get(key)?;
+        (*expires > Instant::now()).then(|| authenticated.clone())
+    }
+
+    fn remember(&self, key: [u8; 32], authenticated: &Authenticated) {
+        if self.config.cache_lifetime == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, expires)| *expires > now);
+        cache.insert(
+            key,
+            (
+                authenticated.clone(),
+                now + Duration::from_secs(self.config.cache_lifetime),
+            ),
+        );
+    }
+
+    async fn connect(&self) -> Result<Ldap, Error> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(self.config.timeout))
+            .set_starttls(self.config.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+
+    /// Binds with the service account or anonymously
+    async fn connect_search(&self) -> Result<Ldap, Error> {
+        let mut ldap = self.connect().await?;
+        if let Some(bind_dn) = &self.config.bind_dn {
+            ldap.simple_bind(bind_dn, &self.config.bind_password)
+                .await?
+                .success()?;
+        }
+        Ok(ldap)
+    }
+
+    async fn groups(
+        &self,
+        ldap: &mut Ldap,
+        dn: &str,
+        username: &str,
+    ) -> Result<Vec<String>, Error> {
+        let Some(group_base_dn) = &self.config.group_base_dn else {
+            return Ok(vec![]);
+        };
+        let filter = self
+            .config
+            .group_filter
+            .replace("{dn}", &ldap_escape(dn))
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(
+                group_base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![self.config.group_id_attribute.as_str()],
+            )
+            .await?
+            .success()?;
+        let mut groups: Vec<String> = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let entry = SearchEntry::construct(entry);
+                let id = first_attr(&entry, &self.config.group_id_attribute)?;
+                // Only mapped groups are known to Wolke, a directory group must not
+                // grant access to a mount just by being named like it
+                self.config.group_mapping.get(&id).cloned()
+            })
+            .collect();
+        groups.sort();
+        groups.dedup();
+        Ok(groups)
+    }
+}
+
+fn first_attr(entry: &SearchEntry, attribute: &str) -> Option<String> {
+    entry.attrs.get(attribute)?.first().cloned()
+}
+
+#[async_trait]
+impl AuthProvider for LdapProvider {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Authenticated>, Error> {
+        // An empty password would result in an unauthenticated bind that always succeeds
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+        let cache_key = self.cache_key(username, password);
+        if let Some(authenticated) = self.cached(&cache_key) {
+            return Ok(Some(authenticated));
+        }
+        let mut ldap = self.connect_search().await?;
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(
+                &self.config.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![
+                    self.config.id_attribute.as_str(),
+                    self.config.displayname_attribute.as_str(),
+                ],
+            )
+            .await?
+            .success()?;
+        let mut entries = entries.into_iter();
+        let (Some(entry), None) = (entries.next(), entries.next()) else {
+            // Unknown user or a filter matching multiple entries
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        };
+        let entry = SearchEntry::construct(entry);
+
+        let mut user_ldap = self.connect().await?;
+        let bind = user_ldap.simple_bind(&entry.dn, password).await?;
+        let _ = user_ldap.unbind().await;
+        if bind.rc != 0 {
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        }
+
+        let id =
+            first_attr(&entry, &self.config.id_attribute).unwrap_or_else(|| username.to_owned());
+        let groups = self.groups(&mut ldap, &entry.dn, username).await?;
+        let _ = ldap.unbind().await;
+        let authenticated = Authenticated {
+            id,
+            displayname: first_attr(&entry, &self.config.displayname_attribute),
+            groups: Some(groups),
+        };
+        self.remember(cache_key, &authenticated);
+        Ok(Some(authenticated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{Authenticator, HomeMounts},
+        filesystem,
+        groups::GroupStore,
+    };
+    use futures::{SinkExt, StreamExt};
+    use ldap3_proto::{
+        LdapCodec,
+        proto::{LdapFilter, LdapMsg, LdapPartialAttribute, LdapSearchResultEntry},
+        simple::ServerOps,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    const SERVICE_DN: &str = "cn=wolke,dc=example,dc=org";
+    const ALICE_DN: &str = "uid=alice,ou=people,dc=example,dc=org";
+
+    struct Entry {
+        dn: String,
+        password: Option<String>,
+        attrs: Vec<(String, String)>,
+    }
+
+    impl Entry {
+        fn new(dn: &str, password: Option<&str>, attrs: &[(&str, &str)]) -> Self {
+            Self {
+                dn: dn.to_owned(),
+                password: password.map(str::to_owned),
+                attrs: attrs
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            }
+        }
+
+        fn values<'a>(&'a self, attribute: &'a str) -> impl Iterator<Item = &'a str> {
+            self.attrs
+                .iter()
+                .filter(move |(name, _)| name.eq_ignore_ascii_case(attribute))
+                .map(|(_, value)| value.as_str())
+        }
+
+        fn matches(&self, filter: &LdapFilter) -> bool {
+            match filter {
+                LdapFilter::And(filters) => filters.iter().all(|filter| self.matches(filter)),
+                LdapFilter::Or(filters) => filters.iter().any(|filter| self.matches(filter)),
+                LdapFilter::Not(filter) => !self.matches(filter),
+                LdapFilter::Equality(attribute, expected) => self
+                    .values(attribute)
+                    .any(|value| value.eq_ignore_ascii_case(expected)),
+                LdapFilter::Present(attribute) => self.values(attribute).next().is_some(),
+                _ => false,
+            }
+        }
+
+        fn to_result(&self) -> LdapSearchResultEntry {
+            LdapSearchResultEntry {
+                dn: self.dn.to_owned(),
+                attributes: self
+                    .attrs
+                    .iter()
+                    .map(|(name, value)| LdapPartialAttribute {
+                        atype: name.to_owned(),
+                        vals: vec![value.as_bytes().to_vec()],
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    /// In-process directory answering binds and searches, records the DNs of successful binds
+    #[derive(Clone)]
+    struct Directory {
+        entries: Arc<Vec<Entry>>,
+        binds: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Directory {
+        async fn start() -> (Self, String) {
+            let directory = Self {
+                entries: Arc::new(vec![
+                    Entry::new(SERVICE_DN, Some("service-secret"), &[]),
+                    Entry::new(
+                        ALICE_DN,
+                        Some("wonderland"),
+                        &[
+                            ("objectClass", "person"),
+                            ("uid", "alice"),
+                            ("cn", "Alice Liddell"),
+                        ],
+                    ),
+                    // Not a person, the user filter must not find it
+                    Entry::new(
+                        "uid=printer,ou=devices,dc=example,dc=org",
+                        Some("wonderland"),
+                        &[("objectClass", "device"), ("uid", "printer")],
+                    ),
+                    Entry::new(
+                        "cn=staff,ou=groups,dc=example,dc=org",
+                        None,
+                        &[
+                            ("objectClass", "groupOfNames"),
+                            ("cn", "staff"),
+                            ("member", ALICE_DN),
+                        ],
+                    ),
+                    Entry::new(
+                        "cn=readers,ou=groups,dc=example,dc=org",
+                        None,
+                        &[
+                            ("objectClass", "groupOfNames"),
+                            ("cn", "readers"),
+                            ("member", ALICE_DN),
+                        ],
+                    ),
+                ]),
+                binds: Default::default(),
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ldap://{}", listener.local_addr().unwrap());
+            let server = directory.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(server.clone().serve(stream));
+                }
+            });
+            (directory, url)
+        }
+
+        async fn serve(self, stream: TcpStream) {
+            let (reader, writer) = stream.into_split();
+            let mut requests = FramedRead::new(reader, LdapCodec::default());
+            let mut responses = FramedWrite::new(writer, LdapCodec::default());
+            while let Some(Ok(request)) = requests.next().await {
+                let messages: Vec<LdapMsg> = match ServerOps::try_from(request) {
+                    Ok(ServerOps::SimpleBind(bind)) => {
+                        let valid = self.entries.iter().any(|entry| {
+                            entry.dn == bind.dn && entry.password.as_deref() == Some(&bind.pw)
+                        });
+                        if valid {
+                            self.binds.lock().unwrap().push(bind.dn.to_owned());
+                            vec![bind.gen_success()]
+                        } else {
+                            vec![bind.gen_invalid_cred()]
+                        }
+                    }
+                    Ok(ServerOps::Search(search)) => self
+                        .entries
+                        .iter()
+                        .filter(|entry| {
+                            entry.dn.ends_with(&search.base) && entry.matches(&search.filter)
+                        })
+                        .map(|entry| search.gen_result_entry(entry.to_result()))
+                        .chain(std::iter::once(search.gen_success()))
+                        .collect(),
+                    // Unbind or anything the provider doesn't use
+                    _ => return,
+                };
+                for message in messages {
+                    if responses.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+
+        fn binds(&self) -> Vec<String> {
+            self.binds.lock().unwrap().clone()
+        }
+    }
+
+    fn config(url: String) -> LdapConfig {
+        LdapConfig {
+            url,
+            bind_dn: Some(SERVICE_DN.to_owned()),
+            bind_password: "service-secret".to_owned(),
+            base_dn: "ou=people,dc=example,dc=org".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_as_the_user() {
+        let (directory, url) = Directory::start().await;
+        let provider = LdapProvider::new(config(url));
+
+        let authenticated = provider
+            .authenticate("alice", "wonderland")
+            .await
+            .unwrap()
+            .expect("valid credentials");
+        assert_eq!(authenticated.id, "alice");
+        assert_eq!(authenticated.displayname.as_deref(), Some("Alice Liddell"));
+        // The service account searches, the password is checked by binding as the user
+        assert_eq!(directory.binds(), [SERVICE_DN, ALICE_DN]);
+
+        let wrong = provider.authenticate("alice", "queen").await.unwrap();
+        assert!(wrong.is_none());
+        assert!(provider.authenticate("alice", "").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn caches_successful_binds() {
+        let (directory, url) = Directory::start().await;
+        let provider = LdapProvider::new(config(url.clone()));
+
+        for _ in 0..3 {
+            let authenticated = provider
+                .authenticate("alice", "wonderland")
+                .await
+                .unwrap()
+                .expect("valid credentials");
+            assert_eq!(authenticated.id, "alice");
+        }
+        assert_eq!(directory.binds(), [SERVICE_DN, ALICE_DN]);
+
+        // Other passwords still go to the directory
+        assert!(
+            provider
+                .authenticate("alice", "queen")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(directory.binds(), [SERVICE_DN, ALICE_DN, SERVICE_DN]);
+
+        let provider = LdapProvider::new(LdapConfig {
+            cache_lifetime: 0,
+            ..config(url)
+        });
+        for _ in 0..2 {
+            provider
+                .authenticate("alice", "wonderland")
+                .await
+                .unwrap()
+                .expect("valid credentials");
+        }
+        assert_eq!(directory.binds().len(), 7);
+    }
+
+    #[tokio::test]
+    async fn search_filter_selects_a_single_person() {
+        let (directory, url) = Directory::start().await;
+        let provider = LdapProvider::new(config(url.clone()));
+
+        // Only entries matching the whole user filter log in
+        assert!(
+            provider
+                .authenticate("printer", "wonderland")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        // The login name is escaped, a wildcard doesn't match alice
+        assert!(
+            provider
+                .authenticate("*", "wonderland")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            provider
+                .authenticate("al*", "wonderland")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(directory.binds(), [SERVICE_DN; 3]);
+
+        let provider = LdapProvider::new(LdapConfig {
+            user_filter: "(&(objectClass=person)(cn={username}))".to_owned(),
+            ..config(url)
+        });
+        let authenticated = provider
+            .authenticate("Alice Liddell", "wonderland")
+            .await
+            .unwrap()
+            .expect("found by cn");
+        assert_eq!(authenticated.id, "alice");
+    }
+
+    #[tokio::test]
+    async fn maps_groups() {
+        let (_directory, url) = Directory::start().await;
+        let group_config = |group_mapping| LdapConfig {
+            group_base_dn: Some("ou=groups,dc=example,dc=org".to_owned()),
+            group_mapping,
+            ..config(url.clone())
+        };
+
+        // Nothing is mapped by default
+        let provider = LdapProvider::new(group_config(HashMap::new()));
+        let authenticated = provider
+            .authenticate("alice", "wonderland")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(authenticated.groups, Some(vec![]));
+
+        let provider = LdapProvider::new(group_config(HashMap::from([
+            ("readers".to_owned(), "readers".to_owned()),
+            ("staff".to_owned(), "staff".to_owned()),
+        ])));
+        let authenticated = provider
+            .authenticate("alice", "wonderland")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            authenticated.groups,
+            Some(vec!["readers".to_owned(), "staff".to_owned()])
+        );
+
+        // Groups that aren't mapped are left out
+        let provider = LdapProvider::new(group_config(HashMap::from([(
+            "staff".to_owned(),
+            "team".to_owned(),
+        )])));
+        let authenticated = provider
+            .authenticate("alice", "wonderland")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(authenticated.groups, Some(vec!["team".to_owned()]));
+
+        // Without a group base DN memberships aren't looked up
+        let provider = LdapProvider::new(config(url));
+        let authenticated = provider
+            .authenticate("alice", "wonderland")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(authenticated.groups, Some(vec![]));
+    }
+
+    #[derive(Default)]
+    struct RecordingHomes(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl HomeMounts for RecordingHomes {
+        async fn create_home(&self, id: &str) -> Result<(), filesystem::Error> {
+            self.0.lock().unwrap().push(id.to_owned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn login_creates_the_home_mount() {
+        let (_directory, url) = Directory::start().await;
+        let provider = LdapProvider::new(LdapConfig {
+            group_base_dn: Some("ou=groups,dc=example,dc=org".to_owned()),
+            group_mapping: HashMap::from([
+                ("readers".to_owned(), "readers".to_owned()),
+                ("staff".to_owned(), "staff".to_owned()),
+            ]),
+            ..config(url)
+        });
+        let homes = Arc::new(RecordingHomes::default());
+        let authenticator = Authenticator::new(
+            GroupStore::from_config(&HashMap::new()),
+            Duration::from_secs(60),
+        )
+        .with_providers(vec![Arc::new(provider)])
+        .with_home_mounts(homes.clone());
+
+        let user = authenticator
+            .login("alice", "wonderland", None)
+            .await
+            .unwrap();
+        assert_eq!(user.id, "alice");
+        assert_eq!(user.memberships, ["readers", "staff"]);
+        assert_eq!(*homes.0.lock().unwrap(), ["alice"]);
+
+        // The mount is only created once
+        authenticator
+            .login("alice", "wonderland", None)
+            .await
+            .unwrap();
+        assert_eq!(*homes.0.lock().unwrap(), ["alice"]);
+
+        assert!(matches!(
+            authenticator.login("alice", "queen", None).await,
+            Err(Error::Unauthorized)
+        ));
+    }
+}
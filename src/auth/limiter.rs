@@ -0,0 +1,190 @@
+use crate::config::RateLimitConfig;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Account(String),
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Throttles failed logins per IP address and per account
+#[derive(Clone)]
+pub struct LoginThrottle {
+    config: Arc<RateLimitConfig>,
+    failures: Arc<Mutex<HashMap<Key, Failures>>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: Arc<RateLimitConfig>) -> Self {
+        Self {
+            config,
+            failures: Default::default(),
+        }
+    }
+
+    /// How long a key has to wait after its nth failure
+    fn delay(&self, count: u32) -> Duration {
+        if count >= self.config.lockout_threshold {
+            return Duration::from_secs(self.config.lockout_duration);
+        }
+        let Some(exponent) = count.checked_sub(self.config.free_attempts) else {
+            return Duration::ZERO;
+        };
+        let delay = self
+            .config
+            .base_delay
+            .saturating_mul(1u64.checked_shl(exponent).unwrap_or(u64::MAX));
+        Duration::from_secs(delay.min(self.config.max_delay))
+    }
+
+    fn keys(ip: Option<IpAddr>, account: &str) -> impl Iterator<Item = Key> {
+        ip.map(|ip| Key::Ip(ip.to_canonical()))
+            .into_iter()
+            .chain(std::iter::once(Key::Account(account.to_owned())))
+    }
+
+    /// Returns the time to wait if the IP address or account is throttled
+    pub fn check(&self, ip: Option<IpAddr>, account: &str) -> Result<(), Duration> {
+        let failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        let reset_after = Duration::from_secs(self.config.reset_after);
+        let retry_after = Self::keys(ip, account)
+            .filter_map(|key| failures.get(&key))
+            .filter(|failures| now.duration_since(failures.last) < reset_after)
+            .map(|failures| {
+                (failures.last + self.delay(failures.count)).saturating_duration_since(now)
+            })
+            .max()
+            .unwrap_or_default();
+        if retry_after.is_zero() {
+            Ok(())
+        } else {
+            Err(retry_after)
+        }
+    }
+
+    pub fn record_failure(&self, ip: Option<IpAddr>, account: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        let reset_after = Duration::from_secs(self.config.reset_after);
+        failures.retain(|_, failures| now.duration_since(failures.last) < reset_after);
+        for key in Self::keys(ip, account) {
+            let entry = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            entry.count += 1;
+            entry.last = now;
+            if entry.count == self.config.lockout_threshold {
+                tracing::warn!(
+                    key = ?key,
+                    duration = self.config.lockout_duration,
+                    "login locked out after repeated failures"
+                );
+            }
+        }
+    }
+
+    /// Failures from the same IP address still count towards its limit
+    pub fn record_success(&self, account: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&Key::Account(account.to_owned()));
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket limiting the request rate of each principal
+#[derive(Clone)]
+pub struct RequestLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RequestLimiter {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            rate: requests_per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Returns the time until the next request is allowed if the principal is over its limit
+    pub fn check(&self, principal: &str) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        // Full buckets carry no state
+        let (rate, burst) = (self.rate, self.burst);
+        buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+        });
+        let bucket = buckets.entry(principal.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1. - bucket.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_exhausted() {
+        let limiter = RequestLimiter::new(1., 3);
+        for _ in 0..3 {
+            assert!(limiter.check("alice").is_ok());
+        }
+        let retry_after = limiter.check("alice").unwrap_err();
+        assert!(!retry_after.is_zero() && retry_after <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let limiter = RequestLimiter::new(50., 1);
+        assert!(limiter.check("alice").is_ok());
+        let retry_after = limiter.check("alice").unwrap_err();
+        std::thread::sleep(retry_after + Duration::from_millis(5));
+        assert!(limiter.check("alice").is_ok());
+        assert!(limiter.check("alice").is_err());
+    }
+
+    #[test]
+    fn principals_have_their_own_buckets() {
+        let limiter = RequestLimiter::new(1., 1);
+        assert!(limiter.check("alice").is_ok());
+        assert!(limiter.check("alice").is_err());
+        assert!(limiter.check("bob").is_ok());
+        // Dropping full buckets doesn't reset exhausted ones
+        assert!(limiter.check("carol").is_ok());
+        assert!(limiter.check("alice").is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use super::{AuthProvider, Authenticated, Error};
+use crate::{
+    db::{self, Database, json_column, to_json},
+    shares::{hash_password, verify_password},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{Params, params};
+use serde::{Deserialize, Serialize};
+
+/// App token, presented as `{id}.{secret}` instead of the password
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppToken {
+    pub id: String,
+    pub name: String,
+    /// Argon2 PHC string of the secret
+    pub secret_hash: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalUser {
+    pub id: String,
+    pub displayname: Option<String>,
+    /// Argon2 PHC string
+    pub password_hash: String,
+    #[serde(default)]
+    pub tokens: Vec<AppToken>,
+    pub created: DateTime<Utc>,
+}
+
+impl LocalUser {
+    pub fn new(id: String, displayname: Option<String>, password: &str) -> Self {
+        Self {
+            id,
+            displayname,
+            password_hash: hash_password(password),
+            tokens: vec![],
+            created: Utc::now(),
+        }
+    }
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password_hash = hash_password(password);
+    }
+
+    /// Returns the token as presented by clients, its secret isn't stored
+    pub fn create_token(&mut self, name: String) -> (AppToken, String) {
+        let id = uuid::Uuid::new_v4().simple().to_string()[..12].to_owned();
+        let secret = super::random_token();
+        let token = AppToken {
+            id: id.to_owned(),
+            name,
+            secret_hash: hash_password(&secret),
+            created: Utc::now(),
+        };
+        self.tokens.push(token.clone());
+        (token, format!("{id}.{secret}"))
+    }
+
+    /// Accepts the password or an app token
+    pub fn verify(&self, password: &str) -> bool {
+        if let Some((id, secret)) = password.split_once('.')
+            && let Some(token) = self.tokens.iter().find(|token| token.id == id)
+            && verify_password(&token.secret_hash, secret)
+        {
+            return true;
+        }
+        verify_password(&self.password_hash, password)
+    }
+}
+
+/// Users managed with `wolke user`, persisted in the database
+#[derive(Clone)]
+pub struct LocalUserStore {
+    db: Database,
+}
+
+impl LocalUserStore {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn query(&self, sql: &str, params: impl Params) -> Result<Vec<LocalUser>, db::Error> {
+        self.db.with(|conn| {
+            let mut stmt = conn.prepare(sql)?;
+            let users = stmt
+                .query_map(params, |row| json_column::<LocalUser>(row, 0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(users)
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<LocalUser>, db::Error> {
+        Ok(self
+            .query("SELECT data FROM users WHERE id = ?1", [id])?
+            .pop())
+    }
+
+    pub fn list(&self) -> Result<Vec<LocalUser>, db::Error> {
+        self.query("SELECT data FROM users ORDER BY id", [])
+    }
+
+    /// Inserts or replaces a user
+    pub fn insert(&self, user: LocalUser) -> Result<(), db::Error> {
+        let data = to_json(&user)?;
+        self.db.with(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO users (id, data) VALUES (?1, ?2)",
+                params![user.id, data],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<LocalUser>, db::Error> {
+        let user = self.get(id)?;
+        if user.is_some() {
+            self.db
+                .with(|conn| Ok(conn.execute("DELETE FROM users WHERE id = ?1", [id])?))?;
+        }
+        Ok(user)
+    }
+}
+
+#[async_trait]
+impl AuthProvider for LocalUserStore {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Authenticated>, Error> {
+        let Some(user) = self
+            .get(username)
+            .map_err(|err| Error::Backend(err.to_string()))?
+        else {
+            return Ok(None);
+        };
+        if !user.verify(password) {
+            return Ok(None);
+        }
+        Ok(Some(Authenticated {
+            id: user.id,
+            displayname: user.displayname,
+            groups: None,
+        }))
+    }
+}
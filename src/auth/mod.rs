@@ -0,0 +1,337 @@
+//! Authentication providers behind the User extractor
+use crate::{
+    config::AuthConfig,
+    dav::User,
+    filesystem::{self, FilesystemProvider},
+    groups::{GroupStore, mount_group},
+    metrics::Metrics,
+    server::ConnectionInfo,
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+mod error;
+mod ldap;
+mod limiter;
+mod local;
+mod oidc;
+mod proxy;
+mod session;
+pub use error::Error;
+pub use ldap::LdapProvider;
+pub use limiter::{LoginThrottle, RequestLimiter};
+pub use local::{LocalUser, LocalUserStore};
+pub use oidc::{Oidc, oidc_router};
+pub use proxy::ProxyAuth;
+pub use session::{SESSION_COOKIE, SessionId, SessionStore};
+
+/// 256 bit of randomness, URL safe
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// A successfully authenticated user as reported by a provider
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+    pub id: String,
+    pub displayname: Option<String>,
+    /// Group ids, None if the provider doesn't manage group memberships
+    pub groups: Option<Vec<String>>,
+}
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Returns None if the credentials are wrong
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Authenticated>, Error>;
+}
+
+/// Creates the mount named like a user on first login
+#[async_trait]
+pub trait HomeMounts: Send + Sync {
+    async fn create_home(&self, id: &str) -> Result<(), filesystem::Error>;
+}
+
+#[async_trait]
+impl<FSP: FilesystemProvider> HomeMounts for FSP {
+    async fn create_home(&self, id: &str) -> Result<(), filesystem::Error> {
+        self.create_mount(id).await
+    }
+}
+
+pub type Providers = Vec<Arc<dyn AuthProvider>>;
+
+/// The password providers enabled in the configuration, in the order they're tried
+pub fn providers_from_config(config: &AuthConfig, local_users: &LocalUserStore) -> Providers {
+    let mut providers: Providers = vec![];
+    if config.local_users {
+        providers.push(Arc::new(local_users.clone()));
+    }
+    if let Some(ldap) = &config.ldap {
+        providers.push(Arc::new(LdapProvider::new(ldap.clone())));
+    }
+    providers
+}
+
+#[derive(Clone)]
+pub struct Authenticator {
+    /// Replaced when the configuration is reloaded
+    providers: Arc<RwLock<Providers>>,
+    /// Users allowed to use the admin API
+    admins: Arc<RwLock<HashSet<String>>>,
+    groups: GroupStore,
+    home_mounts: Option<Arc<dyn HomeMounts>>,
+    /// Users whose home mount was already created
+    homes: Arc<Mutex<HashSet<String>>>,
+    sessions: SessionStore,
+    /// Whether users can log in interactively and get a session
+    session_login: bool,
+    /// Whether verified TLS client certificates log users in
+    client_cert_login: bool,
+    /// Replaces all other login methods if set
+    proxy: Option<Arc<ProxyAuth>>,
+    throttle: Option<LoginThrottle>,
+    limiter: Option<RequestLimiter>,
+    metrics: Option<Metrics>,
+}
+
+impl Authenticator {
+    pub fn new(groups: GroupStore, session_lifetime: Duration) -> Self {
+        Self {
+            providers: Default::default(),
+            admins: Default::default(),
+            groups,
+            home_mounts: None,
+            homes: Default::default(),
+            sessions: SessionStore::new(session_lifetime),
+            session_login: false,
+            client_cert_login: false,
+            proxy: None,
+            throttle: None,
+            limiter: None,
+            metrics: None,
+        }
+    }
+
+    pub fn with_providers(self, providers: Providers) -> Self {
+        self.set_providers(providers);
+        self
+    }
+
+    pub fn set_providers(&self, providers: Providers) {
+        *self.providers.write().unwrap() = providers;
+    }
+
+    pub fn has_providers(&self) -> bool {
+        !self.providers.read().unwrap().is_empty()
+    }
+
+    pub fn with_admins(self, admins: &[String]) -> Self {
+        self.set_admins(admins);
+        self
+    }
+
+    pub fn set_admins(&self, admins: &[String]) {
+        *self.admins.write().unwrap() = admins.iter().cloned().collect();
+    }
+
+    pub fn is_admin(&self, user: &User) -> bool {
+        self.admins.read().unwrap().contains(&user.id)
+    }
+
+    pub fn with_home_mounts(mut self, home_mounts: Arc<dyn HomeMounts>) -> Self {
+        self.home_mounts = Some(home_mounts);
+        self
+    }
+
+    pub fn with_session_login(mut self) -> Self {
+        self.session_login = true;
+        self
+    }
+
+    pub fn with_client_cert_login(mut self) -> Self {
+        self.client_cert_login = true;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: ProxyAuth) -> Self {
+        self.proxy = Some(Arc::new(proxy));
+        self
+    }
+
+    pub fn with_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub fn with_request_limiter(mut self, limiter: RequestLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn record_failure(&self, reason: &'static str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.auth_failure(reason);
+        }
+    }
+
+    /// Counts a request towards the principal's rate limit
+    pub fn check_rate(&self, user: &User) -> Result<(), Error> {
+        let Some(limiter) = &self.limiter else {
+            return Ok(());
+        };
+        limiter.check(&user.id).map_err(|retry_after| {
+            tracing::info!(user = %user.id, ?retry_after, "request rate limited");
+            Error::TooManyRequests(retry_after)
+        })
+    }
+
+    /// Without any provider every request is made by the same local user
+    pub fn is_enabled(&self) -> bool {
+        self.has_providers() || self.session_login || self.client_cert_login || self.proxy.is_some()
+    }
+
+    pub fn has_proxy(&self) -> bool {
+        self.proxy.is_some()
+    }
+
+    /// Trusts the user header only from the configured proxies
+    pub async fn proxy_login(
+        &self,
+        connection: Option<&ConnectionInfo>,
+        headers: &http::HeaderMap,
+    ) -> Result<User, Error> {
+        let Some(proxy) = &self.proxy else {
+            return Err(Error::Unauthorized);
+        };
+        let authenticated = proxy.authenticate(connection, headers).inspect_err(|_| {
+            self.record_failure("proxy");
+        })?;
+        Ok(self.provision(authenticated).await)
+    }
+
+    pub fn has_client_cert_login(&self) -> bool {
+        self.client_cert_login
+    }
+
+    /// The certificate was already verified during the TLS handshake
+    pub async fn client_cert_login(&self, id: String) -> User {
+        let authenticated = Authenticated {
+            id,
+            displayname: None,
+            groups: None,
+        };
+        self.provision(authenticated).await
+    }
+
+    pub fn has_session_login(&self) -> bool {
+        self.session_login
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    /// The user a session belongs to
+    pub fn session_user(&self, session: &str) -> Option<User> {
+        let authenticated = self.sessions.get(session)?;
+        Some(User {
+            memberships: self.groups.memberships(&authenticated.id),
+            displayname: authenticated.displayname,
+            id: authenticated.id,
+            unrestricted: false,
+        })
+    }
+
+    /// Also used for other secrets that can be guessed, like share passwords
+    pub fn throttle(&self) -> Option<&LoginThrottle> {
+        self.throttle.as_ref()
+    }
+
+    /// Tries the providers in order, failed attempts are throttled per client address and account
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        client: Option<IpAddr>,
+    ) -> Result<User, Error> {
+        if let Some(throttle) = &self.throttle
+            && let Err(retry_after) = throttle.check(client, username)
+        {
+            tracing::info!(username, ?client, ?retry_after, "login throttled");
+            self.record_failure("throttled");
+            return Err(Error::TooManyRequests(retry_after));
+        }
+        // Not holding the lock across the awaits, a reload may swap the providers meanwhile
+        let providers = self.providers.read().unwrap().clone();
+        for provider in &providers {
+            let authenticated = provider
+                .authenticate(username, password)
+                .await
+                .inspect_err(|_| self.record_failure("backend"))?;
+            if let Some(authenticated) = authenticated {
+                if let Some(throttle) = &self.throttle {
+                    throttle.record_success(username);
+                }
+                return Ok(self.provision(authenticated).await);
+            }
+        }
+        tracing::warn!(username, ?client, "login failed");
+        self.record_failure("invalid_credentials");
+        if let Some(throttle) = &self.throttle {
+            throttle.record_failure(client, username);
+        }
+        Err(Error::Unauthorized)
+    }
+
+    /// Applies group memberships and creates the home mount
+    pub async fn provision(&self, authenticated: Authenticated) -> User {
+        if let Some(groups) = &authenticated.groups {
+            self.groups
+                .sync_external_memberships(&authenticated.id, groups);
+        }
+        // Mounts in the group namespace belong to groups even if a user is called like that
+        if let Some(home_mounts) = &self.home_mounts
+            && mount_group(&authenticated.id).is_none()
+            && !self.homes.lock().unwrap().contains(&authenticated.id)
+        {
+            match home_mounts.create_home(&authenticated.id).await {
+                Ok(()) => {
+                    self.homes
+                        .lock()
+                        .unwrap()
+                        .insert(authenticated.id.to_owned());
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "Failed to create home mount for {}: {err}",
+                        authenticated.id
+                    )
+                }
+            }
+        }
+        User {
+            memberships: self.groups.memberships(&authenticated.id),
+            displayname: authenticated.displayname,
+            id: authenticated.id,
+            unrestricted: false,
+        }
+    }
+}
@@ -0,0 +1,670 @@
+//! OpenID Connect authorization code flow with PKCE for the web frontend
+use super::{Authenticated, Authenticator, Error, SESSION_COOKIE, SessionId, random_token};
+use crate::{base_url::BaseUrl, config::OidcConfig};
+use axum::{
+    Extension, Json, Router,
+    extract::{Query, State},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::{HeaderValue, header};
+use jsonwebtoken::{DecodingKey, Header, Validation, jwk::JwkSet};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+/// Logins have to be completed within this time
+const PENDING_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    return_to: String,
+    created: Instant,
+}
+
+#[derive(Clone)]
+pub struct Oidc {
+    config: Arc<OidcConfig>,
+    http: reqwest::Client,
+    metadata: Arc<OnceCell<ProviderMetadata>>,
+    /// Signing keys of the provider, fetched again when a token uses an unknown key
+    keys: Arc<RwLock<Option<Arc<JwkSet>>>>,
+    /// By state parameter
+    pending: Arc<Mutex<HashMap<String, PendingLogin>>>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            http: reqwest::Client::new(),
+            metadata: Default::default(),
+            keys: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, Error> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                Ok::<_, Error>(
+                    self.http
+                        .get(url)
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await?,
+                )
+            })
+            .await
+    }
+
+    async fn fetch_keys(&self, metadata: &ProviderMetadata) -> Result<Arc<JwkSet>, Error> {
+        let keys: Arc<JwkSet> = Arc::new(
+            self.http
+                .get(&metadata.jwks_uri)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?,
+        );
+        *self.keys.write().unwrap() = Some(keys.clone());
+        Ok(keys)
+    }
+
+    /// The key a token was signed with, keys are fetched again once if it's unknown after a rotation
+    async fn signing_key(
+        &self,
+        metadata: &ProviderMetadata,
+        header: &Header,
+    ) -> Result<DecodingKey, Error> {
+        let find = |keys: &JwkSet| match &header.kid {
+            Some(kid) => keys.find(kid).cloned(),
+            // Without a key id the provider must only have a single key
+            None => match keys.keys.as_slice() {
+                [key] => Some(key.clone()),
+                _ => None,
+            },
+        };
+        let cached = self.keys.read().unwrap().clone();
+        let jwk = match cached.as_deref().and_then(find) {
+            Some(jwk) => jwk,
+            None => find(self.fetch_keys(metadata).await?.as_ref())
+                .ok_or_else(|| Error::Backend("ID token signed with an unknown key".to_owned()))?,
+        };
+        DecodingKey::from_jwk(&jwk).map_err(|err| Error::Backend(format!("invalid JWK: {err}")))
+    }
+
+    /// Verifies the ID token's signature with the provider's keys and validates its claims
+    async fn id_token_claims(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Map<String, Value>, Error> {
+        let invalid =
+            |err: jsonwebtoken::errors::Error| Error::Backend(format!("invalid ID token: {err}"));
+        let header = jsonwebtoken::decode_header(id_token).map_err(invalid)?;
+        let key = self.signing_key(metadata, &header).await?;
+        // Only the algorithm of the token, decoding fails if it doesn't fit the key
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let claims = jsonwebtoken::decode::<Map<String, Value>>(id_token, &key, &validation)
+            .map_err(invalid)?
+            .claims;
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(Error::Backend(
+                "invalid ID token: nonce doesn't match".to_owned(),
+            ));
+        }
+        Ok(claims)
+    }
+
+    fn map_claims(&self, claims: &Map<String, Value>) -> Result<Authenticated, Error> {
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str);
+        let id = claim(&self.config.id_claim)
+            .ok_or_else(|| Error::Backend(format!("claim {} is missing", self.config.id_claim)))?
+            .to_owned();
+        let groups = self.config.groups_claim.as_ref().map(|groups_claim| {
+            let mut groups: Vec<String> = claims
+                .get(groups_claim)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                // Only mapped groups are known to Wolke
+                .filter_map(|group| self.config.group_mapping.get(group).cloned())
+                .collect();
+            groups.sort();
+            groups.dedup();
+            groups
+        });
+        Ok(Authenticated {
+            id,
+            displayname: claim(&self.config.displayname_claim).map(str::to_owned),
+            groups,
+        })
+    }
+
+    /// The session is valid for every route below the public base path
+    fn session_cookie(&self, base_url: &BaseUrl, value: &str, max_age: Duration) -> HeaderValue {
+        let secure = if self.config.redirect_url.starts_with("https://") {
+            "; Secure"
+        } else {
+            ""
+        };
+        HeaderValue::try_from(format!(
+            "{SESSION_COOKIE}={value}; Path={}/; Max-Age={}; HttpOnly; SameSite=Lax{secure}",
+            base_url.path(""),
+            max_age.as_secs()
+        ))
+        .unwrap()
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Backend(value.to_string())
+    }
+}
+
+/// Login endpoints, nested at /auth
+pub fn oidc_router(oidc: Oidc) -> Router {
+    Router::new()
+        .route("/oidc/login", get(route_login))
+        .route("/oidc/callback", get(route_callback))
+        .route("/logout", post(route_logout))
+        .route("/me", get(route_me))
+        .with_state(oidc)
+}
+
+/// Only allows redirects to local paths
+fn sanitize_return_to(base_url: &BaseUrl, return_to: Option<String>) -> String {
+    return_to
+        .filter(|path| path.starts_with('/') && !path.starts_with("//") && !path.contains('\\'))
+        .unwrap_or_else(|| base_url.path("/frontend"))
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    return_to: Option<String>,
+}
+
+async fn route_login(
+    State(oidc): State<Oidc>,
+    Extension(base_url): Extension<BaseUrl>,
+    Query(LoginQuery { return_to }): Query<LoginQuery>,
+) -> Result<Response, Error> {
+    let metadata = oidc.metadata().await?;
+    let state = random_token();
+    let nonce = random_token();
+    let verifier = random_token();
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+    let mut url = reqwest::Url::parse(&metadata.authorization_endpoint)
+        .map_err(|err| Error::Backend(err.to_string()))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &oidc.config.client_id)
+        .append_pair("redirect_uri", &oidc.config.redirect_url)
+        .append_pair("scope", &oidc.config.scopes.join(" "))
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    let now = Instant::now();
+    let mut pending = oidc.pending.lock().unwrap();
+    pending.retain(|_, login| now.duration_since(login.created) < PENDING_LIFETIME);
+    pending.insert(
+        state,
+        PendingLogin {
+            verifier,
+            nonce,
+            return_to: sanitize_return_to(&base_url, return_to),
+            created: now,
+        },
+    );
+    Ok(Redirect::to(url.as_str()).into_response())
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+}
+
+async fn route_callback(
+    State(oidc): State<Oidc>,
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(base_url): Extension<BaseUrl>,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, Error> {
+    let pending = oidc
+        .pending
+        .lock()
+        .unwrap()
+        .remove(&query.state)
+        .filter(|login| login.created.elapsed() < PENDING_LIFETIME)
+        .ok_or(Error::Unauthorized)?;
+    if let Some(error) = query.error {
+        tracing::info!("OIDC login failed: {error}");
+        return Err(Error::Unauthorized);
+    }
+    let code = query.code.ok_or(Error::Unauthorized)?;
+
+    let metadata = oidc.metadata().await?;
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", oidc.config.redirect_url.as_str()),
+        ("client_id", oidc.config.client_id.as_str()),
+        ("code_verifier", pending.verifier.as_str()),
+    ];
+    if let Some(client_secret) = &oidc.config.client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+    let tokens: TokenResponse = oidc
+        .http
+        .post(&metadata.token_endpoint)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut claims = oidc
+        .id_token_claims(metadata, &tokens.id_token, &pending.nonce)
+        .await?;
+    if let Some(userinfo_endpoint) = &metadata.userinfo_endpoint {
+        let userinfo: Map<String, Value> = oidc
+            .http
+            .get(userinfo_endpoint)
+            .bearer_auth(&tokens.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // Userinfo must be about the same subject, it might contain claims missing in the ID token
+        if userinfo.get("sub") == claims.get("sub") {
+            for (name, value) in userinfo {
+                claims.entry(name).or_insert(value);
+            }
+        }
+    }
+
+    let authenticated = oidc.map_claims(&claims)?;
+    authenticator.provision(authenticated.clone()).await;
+    let sessions = authenticator.sessions();
+    let session = sessions.create(authenticated);
+    let mut response = Redirect::to(&pending.return_to).into_response();
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        oidc.session_cookie(&base_url, &session, sessions.lifetime()),
+    );
+    Ok(response)
+}
+
+async fn route_logout(
+    State(oidc): State<Oidc>,
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(base_url): Extension<BaseUrl>,
+    SessionId(session): SessionId,
+) -> Response {
+    if let Some(session) = session {
+        authenticator.sessions().remove(&session);
+    }
+    let mut response = Redirect::to(&base_url.path("/frontend")).into_response();
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        oidc.session_cookie(&base_url, "", Duration::ZERO),
+    );
+    response
+}
+
+#[derive(Serialize)]
+struct Me {
+    id: String,
+    displayname: Option<String>,
+    groups: Vec<String>,
+}
+
+async fn route_me(user: crate::dav::User) -> Json<Me> {
+    Json(Me {
+        id: user.id,
+        displayname: user.displayname,
+        groups: user.memberships,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::GroupStore;
+    use axum::Form;
+    use jsonwebtoken::{Algorithm, EncodingKey};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use serde_json::json;
+    use std::time::SystemTime;
+    use tokio::net::TcpListener;
+
+    const KEY_ID: &str = "idp-key";
+
+    fn generate_key() -> (EncodingKey, Value) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwk = json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "alg": "EdDSA",
+            "use": "sig",
+            "kid": KEY_ID,
+            "x": URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+        });
+        (EncodingKey::from_ed_der(pkcs8.as_ref()), jwk)
+    }
+
+    fn sign(key: &EncodingKey, claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(KEY_ID.to_owned());
+        jsonwebtoken::encode(&header, claims, key).unwrap()
+    }
+
+    fn expires() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 300
+    }
+
+    struct Authorization {
+        challenge: String,
+        nonce: String,
+    }
+
+    /// In-process identity provider issuing ID tokens for alice
+    struct Idp {
+        issuer: String,
+        key: EncodingKey,
+        jwk: Value,
+        /// By authorization code
+        codes: Mutex<HashMap<String, Authorization>>,
+    }
+
+    impl Idp {
+        async fn start() -> Arc<Self> {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (key, jwk) = generate_key();
+            let idp = Arc::new(Self {
+                issuer: format!("http://{}", listener.local_addr().unwrap()),
+                key,
+                jwk,
+                codes: Default::default(),
+            });
+            let router = Router::new()
+                .route("/.well-known/openid-configuration", get(idp_metadata))
+                .route("/jwks", get(idp_jwks))
+                .route("/token", post(idp_token))
+                .with_state(idp.clone());
+            tokio::spawn(async move { axum::serve(listener, router).await });
+            idp
+        }
+
+        /// What the provider does once the user logged in and consented
+        fn authorize(&self, challenge: &str, nonce: &str) -> String {
+            let code = random_token();
+            self.codes.lock().unwrap().insert(
+                code.to_owned(),
+                Authorization {
+                    challenge: challenge.to_owned(),
+                    nonce: nonce.to_owned(),
+                },
+            );
+            code
+        }
+
+        fn claims(&self, nonce: &str) -> Value {
+            json!({
+                "iss": self.issuer,
+                "aud": "wolke",
+                "sub": "f81d4fae",
+                "exp": expires(),
+                "nonce": nonce,
+                "preferred_username": "alice",
+                "name": "Alice Liddell",
+                "groups": ["wolke-staff", "unrelated"],
+            })
+        }
+    }
+
+    async fn idp_metadata(State(idp): State<Arc<Idp>>) -> Json<Value> {
+        Json(json!({
+            "issuer": idp.issuer,
+            "authorization_endpoint": format!("{}/authorize", idp.issuer),
+            "token_endpoint": format!("{}/token", idp.issuer),
+            "jwks_uri": format!("{}/jwks", idp.issuer),
+        }))
+    }
+
+    async fn idp_jwks(State(idp): State<Arc<Idp>>) -> Json<Value> {
+        Json(json!({ "keys": [idp.jwk] }))
+    }
+
+    async fn idp_token(
+        State(idp): State<Arc<Idp>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, http::StatusCode> {
+        let field = |name: &str| form.get(name).map(String::as_str);
+        let authorization = idp
+            .codes
+            .lock()
+            .unwrap()
+            .remove(field("code").unwrap_or_default())
+            .ok_or(http::StatusCode::BAD_REQUEST)?;
+        let verifier = field("code_verifier").ok_or(http::StatusCode::BAD_REQUEST)?;
+        if field("grant_type") != Some("authorization_code")
+            || field("client_id") != Some("wolke")
+            || URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+                != authorization.challenge
+        {
+            return Err(http::StatusCode::BAD_REQUEST);
+        }
+        Ok(Json(json!({
+            "access_token": random_token(),
+            "token_type": "Bearer",
+            "id_token": sign(&idp.key, &idp.claims(&authorization.nonce)),
+        })))
+    }
+
+    fn oidc(idp: &Idp) -> Oidc {
+        Oidc::new(OidcConfig {
+            issuer: idp.issuer.to_owned(),
+            client_id: "wolke".to_owned(),
+            groups_claim: Some("groups".to_owned()),
+            group_mapping: HashMap::from([("wolke-staff".to_owned(), "staff".to_owned())]),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn code_flow_with_pkce() {
+        let idp = Idp::start().await;
+        let oidc = oidc(&idp);
+        let authenticator = Authenticator::new(
+            GroupStore::from_config(&HashMap::new()),
+            Duration::from_secs(60),
+        );
+
+        let login = route_login(
+            State(oidc.clone()),
+            Extension(BaseUrl::root()),
+            Query(LoginQuery {
+                return_to: Some("/frontend/files".to_owned()),
+            }),
+        )
+        .await
+        .unwrap();
+        let location = login.headers()[header::LOCATION].to_str().unwrap();
+        let url = reqwest::Url::parse(location).unwrap();
+        assert!(location.starts_with(&format!("{}/authorize?", idp.issuer)));
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["client_id"], "wolke");
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let code = idp.authorize(&params["code_challenge"], &params["nonce"]);
+        let callback = route_callback(
+            State(oidc.clone()),
+            Extension(authenticator.clone()),
+            Extension(BaseUrl::root()),
+            Query(CallbackQuery {
+                code: Some(code.to_owned()),
+                state: params["state"].to_owned(),
+                error: None,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(callback.headers()[header::LOCATION], "/frontend/files");
+        let cookie = callback.headers()[header::SET_COOKIE].to_str().unwrap();
+        let session = cookie
+            .strip_prefix(&format!("{SESSION_COOKIE}="))
+            .and_then(|cookie| cookie.split(';').next())
+            .unwrap();
+
+        let user = authenticator.session_user(session).unwrap();
+        assert_eq!(user.id, "f81d4fae");
+        assert_eq!(user.displayname.as_deref(), Some("Alice Liddell"));
+        // Only mapped groups are used
+        assert_eq!(user.memberships, ["staff"]);
+
+        // The state can't be used twice
+        let replay = route_callback(
+            State(oidc),
+            Extension(authenticator),
+            Extension(BaseUrl::root()),
+            Query(CallbackQuery {
+                code: Some(code),
+                state: params["state"].to_owned(),
+                error: None,
+            }),
+        )
+        .await;
+        assert!(matches!(replay, Err(Error::Unauthorized)));
+    }
+
+    #[test]
+    fn maps_the_configured_id_claim() {
+        let claims = json!({ "sub": "f81d4fae", "preferred_username": "alice" });
+        let claims = claims.as_object().unwrap();
+        let map = |id_claim: &str| {
+            Oidc::new(OidcConfig {
+                id_claim: id_claim.to_owned(),
+                ..Default::default()
+            })
+            .map_claims(claims)
+        };
+        // The subject never changes, unlike names users may be able to edit
+        assert_eq!(
+            Oidc::new(OidcConfig::default())
+                .map_claims(claims)
+                .unwrap()
+                .id,
+            "f81d4fae"
+        );
+        assert_eq!(map("preferred_username").unwrap().id, "alice");
+        assert!(map("email").is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_id_tokens() {
+        let idp = Idp::start().await;
+        let oidc = oidc(&idp);
+        let metadata = oidc.metadata().await.unwrap();
+        let claims = idp.claims("nonce");
+
+        let valid = sign(&idp.key, &claims);
+        assert!(
+            oidc.id_token_claims(metadata, &valid, "nonce")
+                .await
+                .is_ok()
+        );
+        assert!(
+            oidc.id_token_claims(metadata, &valid, "other nonce")
+                .await
+                .is_err()
+        );
+
+        // Same key id, but not the provider's key
+        let (forged_key, _) = generate_key();
+        let forged = sign(&forged_key, &claims);
+        assert!(
+            oidc.id_token_claims(metadata, &forged, "nonce")
+                .await
+                .is_err()
+        );
+
+        // Unsigned
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let unsigned = format!("{header}.{payload}.");
+        assert!(
+            oidc.id_token_claims(metadata, &unsigned, "nonce")
+                .await
+                .is_err()
+        );
+
+        for (claim, value) in [
+            ("iss", json!("https://attacker.example")),
+            ("aud", json!("other-client")),
+            ("exp", json!(1_000_000)),
+        ] {
+            let mut modified = claims.clone();
+            modified[claim] = value;
+            let token = sign(&idp.key, &modified);
+            assert!(
+                oidc.id_token_claims(metadata, &token, "nonce")
+                    .await
+                    .is_err(),
+                "{claim}"
+            );
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use super::{Authenticated, Error};
+use crate::{config::ProxyAuthConfig, server::ConnectionInfo};
+use http::HeaderMap;
+use std::net::IpAddr;
+
+/// Takes the user from headers set by an authenticating reverse proxy
+pub struct ProxyAuth {
+    config: ProxyAuthConfig,
+}
+
+impl ProxyAuth {
+    pub fn new(config: ProxyAuthConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn is_trusted(&self, peer: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as mapped IPv6 addresses
+        let peer = peer.to_canonical();
+        self.config
+            .trusted_proxies
+            .iter()
+            .any(|net| net.contains(&peer))
+    }
+
+    /// Requests from untrusted peers are rejected, as are those without a peer address
+    /// unless they came in on a trusted Unix socket
+    pub fn authenticate(
+        &self,
+        connection: Option<&ConnectionInfo>,
+        headers: &HeaderMap,
+    ) -> Result<Authenticated, Error> {
+        let peer = connection.and_then(|info| info.peer).map(|peer| peer.ip());
+        let trusted = connection.is_some_and(|info| info.trusted_socket)
+            || peer.is_some_and(|peer| self.is_trusted(peer));
+        if !trusted {
+            tracing::warn!(?peer, "Rejected request from untrusted proxy");
+            return Err(Error::Unauthorized);
+        }
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        let Some(id) = header(&self.config.user_header) else {
+            return Err(Error::Unauthorized);
+        };
+        let displayname = self
+            .config
+            .displayname_header
+            .as_deref()
+            .and_then(header)
+            .map(str::to_owned);
+        let groups = self
+            .config
+            .groups_header
+            .as_deref()
+            .map(|name| header(name).unwrap_or_default())
+            .map(|groups| {
+                groups
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|group| !group.is_empty())
+                    // Only mapped groups are known to Wolke
+                    .filter_map(|group| self.config.group_mapping.get(group).cloned())
+                    .collect()
+            });
+        Ok(Authenticated {
+            id: id.to_owned(),
+            displayname,
+            groups,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(connection: Option<&ConnectionInfo>) -> Option<String> {
+        let proxy = ProxyAuth::new(ProxyAuthConfig::default());
+        let mut headers = HeaderMap::new();
+        headers.insert("Remote-User", "alice".parse().unwrap());
+        proxy
+            .authenticate(connection, &headers)
+            .ok()
+            .map(|authenticated| authenticated.id)
+    }
+
+    #[test]
+    fn trusts_only_configured_unix_sockets() {
+        assert_eq!(
+            login(Some(&ConnectionInfo::unix(true))).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(login(Some(&ConnectionInfo::unix(false))), None);
+        assert_eq!(login(None), None);
+    }
+}
@@ -0,0 +1,68 @@
+use super::Authenticated;
+use axum::extract::FromRequestParts;
+use headers::{Cookie, HeaderMapExt};
+use http::request::Parts;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub const SESSION_COOKIE: &str = "wolke_session";
+
+/// Browser sessions created by an interactive login
+#[derive(Clone)]
+pub struct SessionStore {
+    lifetime: Duration,
+    sessions: Arc<Mutex<HashMap<String, (Authenticated, Instant)>>>,
+}
+
+impl SessionStore {
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            lifetime,
+            sessions: Default::default(),
+        }
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Returns the new session id
+    pub fn create(&self, authenticated: Authenticated) -> String {
+        let id = super::random_token();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.insert(id.to_owned(), (authenticated, now + self.lifetime));
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<Authenticated> {
+        let sessions = self.sessions.lock().unwrap();
+        let (authenticated, expires) = sessions.get(id)?;
+        (*expires > Instant::now()).then(|| authenticated.clone())
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Authenticated> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(id)
+            .map(|(authenticated, _)| authenticated)
+    }
+}
+
+/// Session id from the cookie, if any
+pub struct SessionId(pub Option<String>);
+
+impl<S: Send + Sync> FromRequestParts<S> for SessionId {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(parts.headers.typed_get::<Cookie>().and_then(
+            |cookie| cookie.get(SESSION_COOKIE).map(str::to_owned),
+        )))
+    }
+}
@@ -0,0 +1,287 @@
+//! Where clients reach us, which differs from what we see when running behind a proxy
+use crate::{config::HttpConfig, dav::fs::FSPrincipalUri, server::ConnectionInfo};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use http::{Extensions, HeaderMap, HeaderValue, Uri, header};
+use ipnet::IpNet;
+use std::{net::IpAddr, sync::Arc};
+
+/// Trims a configured or forwarded prefix to the form /a/b, empty for the root
+fn normalize_prefix(prefix: &str) -> Option<String> {
+    let prefix = prefix.trim_matches('/');
+    if prefix
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '?' | '#' | '"' | '<' | '>'))
+    {
+        return None;
+    }
+    Some(if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{prefix}")
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct Forwarding {
+    base_path: Arc<str>,
+    trusted_proxies: Arc<[IpNet]>,
+}
+
+impl Forwarding {
+    pub fn new(config: &HttpConfig) -> anyhow::Result<Self> {
+        let base_path = normalize_prefix(&config.base_path)
+            .ok_or_else(|| anyhow::anyhow!("Invalid base path {:?}", config.base_path))?;
+        Ok(Self {
+            base_path: base_path.into(),
+            trusted_proxies: config.trusted_proxies.clone().into(),
+        })
+    }
+
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Walks X-Forwarded-For from the right while the hop that added an entry is a
+    /// trusted proxy. Entries left of the first untrusted hop are client supplied.
+    fn client_ip(
+        &self,
+        peer: Option<IpAddr>,
+        trusted: bool,
+        headers: &HeaderMap,
+    ) -> Option<IpAddr> {
+        let entries = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .rev()
+            .flat_map(|value| value.to_str().unwrap_or("invalid").rsplit(','));
+        let (mut client, mut trusted) = (peer, trusted);
+        for entry in entries {
+            if !trusted {
+                break;
+            }
+            match entry.trim().parse() {
+                Ok(ip) => {
+                    client = Some(ip);
+                    trusted = self.is_trusted(ip);
+                }
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+/// Address of the client as reported by trusted proxies, available as request extension
+#[derive(Debug, Clone, Copy)]
+struct ClientIp(IpAddr);
+
+/// Address of the client, which is the peer unless the request came through trusted proxies
+pub fn client_ip(extensions: &Extensions) -> Option<IpAddr> {
+    extensions
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| *ip)
+        .or_else(|| crate::server::peer_ip(extensions))
+}
+
+/// Public URL of the application root, available as request extension
+#[derive(Debug, Clone)]
+pub struct BaseUrl {
+    scheme: &'static str,
+    host: Option<String>,
+    /// Prefix stripped by a proxy before the request reached us
+    forwarded_prefix: String,
+    base_path: Arc<str>,
+}
+
+impl BaseUrl {
+    /// Absolute path of a route for use in hrefs and redirects
+    pub fn path(&self, path: &str) -> String {
+        format!("{}{}{path}", self.forwarded_prefix, self.base_path)
+    }
+
+    /// Absolute URL of a route if the host is known, the absolute path otherwise
+    pub fn url(&self, path: &str) -> String {
+        match &self.host {
+            Some(host) => format!("{}://{host}{}", self.scheme, self.path(path)),
+            None => self.path(path),
+        }
+    }
+
+    /// Path of a route as the request reaches our router, without the forwarded prefix
+    pub fn local_path(&self, path: &str) -> String {
+        format!("{}{path}", self.base_path)
+    }
+
+    /// Public path of a path that reached our router, which already includes the base path
+    pub fn public_path(&self, local_path: &str) -> String {
+        format!("{}{local_path}", self.forwarded_prefix)
+    }
+
+    fn local_destination(&self, destination: &HeaderValue) -> Option<HeaderValue> {
+        let destination = destination.to_str().ok()?.parse::<Uri>().ok()?;
+        let path = destination.path();
+        let rest = path.strip_prefix(&self.forwarded_prefix)?;
+        if !rest.starts_with('/') {
+            return None;
+        }
+        HeaderValue::try_from(rest).ok()
+    }
+}
+
+#[cfg(test)]
+impl BaseUrl {
+    /// Served at the root of the host without a proxy
+    pub fn root() -> Self {
+        Self {
+            scheme: "http",
+            host: None,
+            forwarded_prefix: String::new(),
+            base_path: "".into(),
+        }
+    }
+}
+
+/// First value of an X-Forwarded-* header, proxies append to existing lists
+fn forwarded<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+fn valid_host(host: &str) -> bool {
+    host.parse::<http::uri::Authority>()
+        .is_ok_and(|authority| authority.as_str() == host && !host.contains('@'))
+}
+
+/// Determines the BaseUrl of a request, honouring X-Forwarded-Proto/Host/Prefix
+/// from trusted proxies. Destination headers are made relative to our router.
+pub async fn resolve_base_url(
+    State(forwarding): State<Forwarding>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let connection = ConnectionInfo::from_extensions(request.extensions());
+    let secure = connection.is_some_and(|info| info.secure);
+    let peer = connection.and_then(|info| info.peer).map(|peer| peer.ip());
+    let trusted = connection.is_some_and(|info| info.trusted_socket)
+        || peer.is_some_and(|peer| forwarding.is_trusted(peer));
+    let headers = request.headers();
+    let from_proxy = |name| trusted.then(|| forwarded(headers, name)).flatten();
+
+    let scheme = match from_proxy("x-forwarded-proto") {
+        Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+        Some(proto) if proto.eq_ignore_ascii_case("http") => "http",
+        _ if secure => "https",
+        _ => "http",
+    };
+    let host = from_proxy("x-forwarded-host")
+        .or_else(|| {
+            headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+        })
+        .filter(|host| valid_host(host))
+        .map(str::to_owned);
+    let forwarded_prefix = from_proxy("x-forwarded-prefix")
+        .and_then(normalize_prefix)
+        .unwrap_or_default();
+    let base_url = BaseUrl {
+        scheme,
+        host,
+        forwarded_prefix,
+        base_path: forwarding.base_path.clone(),
+    };
+
+    if !base_url.forwarded_prefix.is_empty()
+        && let Some(destination) = request
+            .headers()
+            .get("Destination")
+            .and_then(|destination| base_url.local_destination(destination))
+    {
+        request.headers_mut().insert("Destination", destination);
+    }
+    if let Some(client) = forwarding.client_ip(peer, trusted, request.headers()) {
+        request.extensions_mut().insert(ClientIp(client));
+    }
+    request
+        .extensions_mut()
+        .insert(FSPrincipalUri::new(base_url.path("")));
+    request.extensions_mut().insert(base_url);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarding(trusted_proxies: &[&str]) -> Forwarding {
+        Forwarding::new(&HttpConfig {
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|net| net.parse().unwrap())
+                .collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxies() {
+        let forwarding = forwarding(&["10.0.0.0/8"]);
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let client = |peer, values: &[&str]| {
+            let peer = ip(peer);
+            let trusted = forwarding.is_trusted(peer);
+            forwarding
+                .client_ip(Some(peer), trusted, &forwarded_for(values))
+                .unwrap()
+        };
+
+        assert_eq!(client("10.0.0.1", &["192.0.2.1"]), ip("192.0.2.1"));
+        assert_eq!(client("::ffff:10.0.0.1", &["192.0.2.1"]), ip("192.0.2.1"));
+        // Spoofed entries in front of the real client are ignored
+        assert_eq!(
+            client("10.0.0.1", &["198.51.100.7, 192.0.2.1, 10.0.0.2"]),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            client("10.0.0.1", &["198.51.100.7", "192.0.2.1"]),
+            ip("192.0.2.1")
+        );
+        // Untrusted peers can't claim other addresses
+        assert_eq!(client("192.0.2.9", &["10.0.0.1"]), ip("192.0.2.9"));
+        assert_eq!(client("10.0.0.1", &[]), ip("10.0.0.1"));
+        assert_eq!(client("10.0.0.1", &["unknown"]), ip("10.0.0.1"));
+
+        // Trusted Unix sockets have no peer address
+        let headers = forwarded_for(&["192.0.2.1"]);
+        assert_eq!(
+            forwarding.client_ip(None, true, &headers),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(forwarding.client_ip(None, false, &headers), None);
+    }
+}
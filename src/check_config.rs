@@ -0,0 +1,388 @@
+//! `wolke check-config` validates the configuration without starting the server
+use crate::{
+    base_url::Forwarding,
+    config::{Config, ListenerConfig},
+    server::load_tls_config,
+    setup_tracing::env_filter,
+};
+use anyhow::{Result, bail};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    net::TcpListener,
+    path::{Path, PathBuf},
+};
+
+/// Keys whose values never get printed
+const SECRETS: &[&str] = &["password", "secret", "token"];
+
+#[derive(Serialize)]
+struct Problem {
+    /// Dotted path like http.listeners[0].address
+    field: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Report {
+    ok: bool,
+    problems: Vec<Problem>,
+    /// The merged configuration with defaults and WOLKE_ overrides, unset if it doesn't parse
+    config: Option<Value>,
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn push(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Problem {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+}
+
+/// Every error of the config file and the environment, not just the first one
+fn load_errors(err: figment::Error) -> Vec<Problem> {
+    err.into_iter()
+        .map(|err| {
+            let source = err
+                .metadata
+                .as_ref()
+                .map(|metadata| match &metadata.source {
+                    Some(source) => format!("{} {source}", metadata.name),
+                    None => metadata.name.to_string(),
+                });
+            Problem {
+                field: err.path.join("."),
+                message: match source {
+                    Some(source) => format!("{} (in {source})", err.kind),
+                    None => err.kind.to_string(),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Tries to create a file in the directory
+fn probe_dir(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(format!(".wolke-check-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+/// The directory must exist or be creatable, like the server does with create_dir_all
+fn check_dir(problems: &mut Problems, field: &str, path: &Path) {
+    match std::fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => problems.push(field, "not a directory"),
+        Ok(_) => {
+            if let Err(err) = probe_dir(path) {
+                problems.push(field, format!("{} is not writable: {err}", path.display()));
+            }
+        }
+        Err(_) => match path.ancestors().skip(1).find(|dir| dir.is_dir()) {
+            Some(existing) => {
+                if let Err(err) = probe_dir(existing) {
+                    problems.push(
+                        field,
+                        format!(
+                            "{} doesn't exist and can't be created in {}: {err}",
+                            path.display(),
+                            existing.display()
+                        ),
+                    );
+                }
+            }
+            None => problems.push(field, format!("{} doesn't exist", path.display())),
+        },
+    }
+}
+
+/// A file the server writes, its directory is created if necessary
+fn check_file(problems: &mut Problems, field: &str, path: &Path) {
+    if path.is_dir() {
+        problems.push(field, format!("{} is a directory", path.display()));
+        return;
+    }
+    if path.exists() {
+        if let Err(err) = std::fs::OpenOptions::new().append(true).open(path) {
+            problems.push(field, format!("{} is not writable: {err}", path.display()));
+        }
+        return;
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => check_dir(problems, field, parent),
+        _ => check_dir(problems, field, Path::new(".")),
+    }
+}
+
+fn check_paths(problems: &mut Problems, config: &Config) {
+    let root_path = &config.fs.root_path;
+    if !root_path.is_dir() {
+        problems.push(
+            "fs.root_path",
+            format!("{} is not a directory", root_path.display()),
+        );
+    } else if let Err(err) = probe_dir(root_path) {
+        problems.push(
+            "fs.root_path",
+            format!("{} is not writable: {err}", root_path.display()),
+        );
+    }
+    if let Some(path) = &config.database.path {
+        check_file(problems, "database.path", path);
+    }
+    check_dir(
+        problems,
+        "uploads.staging_path",
+        &config.uploads.staging_path,
+    );
+    if let Some(path) = &config.search.index_path {
+        check_dir(problems, "search.index_path", path);
+    }
+    if config.audit.enabled
+        && let Some(path) = &config.audit.path
+    {
+        check_file(problems, "audit.path", path);
+    }
+}
+
+/// Every directory below fs.root_path is served as a mount
+fn check_mounts(problems: &mut Problems, config: &Config) {
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+    let root_path = absolute(&config.fs.root_path);
+    let state_paths: [(&str, Option<&PathBuf>); 4] = [
+        ("database.path", config.database.path.as_ref()),
+        ("uploads.staging_path", Some(&config.uploads.staging_path)),
+        ("search.index_path", config.search.index_path.as_ref()),
+        ("audit.path", config.audit.path.as_ref()),
+    ];
+    for (field, path) in state_paths {
+        let Some(path) = path.map(|path| absolute(path)) else {
+            continue;
+        };
+        if path.starts_with(&root_path) {
+            problems.push(
+                field,
+                format!(
+                    "{} is inside fs.root_path and would be served",
+                    path.display()
+                ),
+            );
+        } else if root_path.starts_with(&path) {
+            problems.push(field, format!("{} contains fs.root_path", path.display()));
+        }
+    }
+
+    for id in config.groups.keys() {
+        let field = format!("groups.{id}");
+        // Group mounts are prefixed, so only separators are a problem
+        if id.is_empty() || id.contains(['/', '\\', '\0']) {
+            problems.push(field, "not a valid mount name");
+        }
+    }
+}
+
+fn check_tls(problems: &mut Problems, field: &str, listener: &ListenerConfig) {
+    if let Some(tls) = listener.tls()
+        && let Err(err) = load_tls_config(tls)
+    {
+        problems.push(format!("{field}.tls"), err.to_string());
+    }
+}
+
+/// Binds the listeners, they stay bound until the end so duplicates are caught too
+fn check_listeners(problems: &mut Problems, config: &Config) -> Vec<TcpListener> {
+    let mut bound = vec![];
+    let mut bind =
+        |problems: &mut Problems, field: &str, address: &str| match TcpListener::bind(address) {
+            Ok(listener) => bound.push(listener),
+            Err(err) => problems.push(field, format!("Cannot listen on {address}: {err}")),
+        };
+
+    if config.http.listeners.is_empty() {
+        let listener = &config.http.listeners()[0];
+        if let ListenerConfig::Tcp { address, .. } = listener {
+            bind(problems, "http.port", address);
+        }
+        check_tls(problems, "http", listener);
+    } else {
+        if config.http.tls.is_some() {
+            problems.push("http.tls", "ignored because http.listeners is set");
+        }
+        for (i, listener) in config.http.listeners.iter().enumerate() {
+            let field = format!("http.listeners[{i}]");
+            match listener {
+                ListenerConfig::Tcp { address, .. } => {
+                    bind(problems, &format!("{field}.address"), address);
+                }
+                ListenerConfig::Unix { path, .. } => {
+                    check_file(problems, &format!("{field}.path"), path);
+                }
+                // Only known when started by systemd
+                ListenerConfig::Systemd { .. } => {}
+            }
+            check_tls(problems, &field, listener);
+        }
+    }
+    if config.metrics.enabled
+        && let Some(listen) = &config.metrics.listen
+    {
+        bind(problems, "metrics.listen", listen);
+    }
+    bound
+}
+
+fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = Problems::default();
+    check_paths(&mut problems, config);
+    check_mounts(&mut problems, config);
+    let _bound = check_listeners(&mut problems, config);
+    if let Err(err) = Forwarding::new(&config.http) {
+        problems.push("http.base_path", err.to_string());
+    }
+    if let Err(err) = env_filter(&config.tracing) {
+        problems.push("tracing.filter", err.to_string());
+    }
+    problems.0
+}
+
+/// Replaces secrets and drops unset values, which TOML can't represent
+fn redact(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| {
+                    let secret = SECRETS.iter().any(|secret| key.contains(secret));
+                    let value = match value {
+                        Value::String(value) if secret && !value.is_empty() => {
+                            Value::String("<redacted>".to_owned())
+                        }
+                        value => redact(value),
+                    };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(redact).collect()),
+        value => value,
+    }
+}
+
+pub fn run(config_file: &str, json: bool) -> Result<()> {
+    let (problems, config) = match Config::load(config_file) {
+        Ok(config) => (check(&config), Some(redact(serde_json::to_value(&config)?))),
+        Err(err) => (load_errors(*err), None),
+    };
+    let ok = problems.is_empty();
+
+    if json {
+        let report = Report {
+            ok,
+            problems,
+            config,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        if let Some(config) = &config {
+            println!("{}", toml::to_string_pretty(config)?);
+        }
+        for problem in &problems {
+            eprintln!("{}: {}", problem.field, problem.message);
+        }
+    }
+    if !ok {
+        bail!("{config_file} has errors");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(dir: &Path, toml: &str) -> Result<Config, Box<figment::Error>> {
+        let file = dir.join("wolke.toml");
+        let toml = format!(
+            "[fs]\nroot_path = {:?}\n[uploads]\nstaging_path = {:?}\n{toml}",
+            dir.join("root"),
+            dir.join("uploads"),
+        );
+        std::fs::write(&file, toml).unwrap();
+        Config::load(file.to_str().unwrap())
+    }
+
+    fn fields(problems: &[Problem]) -> Vec<&str> {
+        problems
+            .iter()
+            .map(|problem| problem.field.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("root")).unwrap();
+        let config = load(
+            dir.path(),
+            "[[http.listeners]]\ntype = \"tcp\"\naddress = \"127.0.0.1:0\"\n",
+        )
+        .unwrap();
+        assert!(fields(&check(&config)).is_empty());
+
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let toml = format!(
+            "[database]\npath = {:?}\n[groups.\"a/b\"]\nmembers = []\n\
+            [tracing]\nfilter = \"wolke=loud\"\n[[http.listeners]]\ntype = \"tcp\"\n\
+            address = \"{}\"\n[[http.listeners]]\ntype = \"unix\"\npath = {:?}\n",
+            dir.path().join("root/wolke.db"),
+            taken.local_addr().unwrap(),
+            dir.path().join("file/wolke.sock"),
+        );
+        let config = load(dir.path(), &toml).unwrap();
+        assert_eq!(
+            fields(&check(&config)),
+            [
+                "database.path",
+                "groups.a/b",
+                "http.listeners[0].address",
+                "http.listeners[1].path",
+                "tracing.filter",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_fields_that_dont_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        let Err(err) = load(dir.path(), "[http]\nport = \"high\"\n") else {
+            panic!("the port is not a number");
+        };
+        let problems = load_errors(*err);
+        assert_eq!(fields(&problems), ["http.port"]);
+        assert!(problems[0].message.contains("wolke.toml"));
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let config = serde_json::json!({
+            "auth": {
+                "ldap": { "bind_password": "hunter2", "url": "ldap://localhost" },
+                "oidc": { "client_secret": "", "issuer": null },
+            },
+        });
+        assert_eq!(
+            redact(config),
+            serde_json::json!({
+                "auth": {
+                    "ldap": { "bind_password": "<redacted>", "url": "ldap://localhost" },
+                    "oidc": { "client_secret": "" },
+                },
+            })
+        );
+    }
+}
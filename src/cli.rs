@@ -0,0 +1,658 @@
+//! Administration subcommands, they work on the state files directly and don't need a running server
+use crate::{
+    auth::{LocalUser, LocalUserStore, random_token},
+    config::Config,
+    db::Database,
+    filesystem::{ChangeNotifier, FilesystemProvider, SimpleFilesystemProvider},
+    groups::{GroupStore, mount_group},
+    shares::{Share, ShareKind, ShareStore},
+};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use clap::{Args, Subcommand};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    io::BufRead,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the server, the default without a subcommand
+    Serve,
+    /// Manage local users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage mounts
+    #[command(subcommand)]
+    Mount(MountCommand),
+    /// Manage public share links
+    #[command(subcommand)]
+    Share(ShareCommand),
+    /// Manage app tokens of local users
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Back up and restore the database
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Validate the configuration and print the effective one with secrets redacted
+    CheckConfig,
+}
+
+#[derive(Args, Debug)]
+pub struct PasswordArgs {
+    /// Read the password from the first line of stdin, a random one is generated and printed otherwise
+    #[arg(long)]
+    password_stdin: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Create a user and its home mount
+    Add {
+        id: String,
+        #[arg(long)]
+        displayname: Option<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Change the password of a user
+    Passwd {
+        id: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    List,
+    /// Remove a user, its mount and shares are kept
+    Remove {
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MountCommand {
+    /// Create an empty mount
+    Create {
+        name: String,
+    },
+    List,
+    /// Check that mounts are accessible, all mounts if none are given
+    Check {
+        names: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ShareCommand {
+    List {
+        /// Only list shares of this user
+        #[arg(long)]
+        owner: Option<String>,
+    },
+    Revoke {
+        token: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokenCommand {
+    /// Create an app token, it's only printed once
+    Create {
+        user: String,
+        #[arg(long, default_value = "cli")]
+        name: String,
+    },
+    List {
+        user: String,
+    },
+    Revoke {
+        user: String,
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Show the schema version, applies pending migrations
+    Status,
+    /// Write a consistent copy of the database, the server can keep running
+    Backup { to: PathBuf },
+    /// Replace the database with a backup, the server must be stopped
+    Restore { from: PathBuf },
+}
+
+/// Prints JSON for scripts or the given text otherwise
+fn output(json: bool, value: &impl Serialize, text: impl FnOnce()) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        text();
+    }
+    Ok(())
+}
+
+fn read_password(args: &PasswordArgs) -> Result<(String, bool)> {
+    if !args.password_stdin {
+        return Ok((random_token(), true));
+    }
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_owned();
+    if password.is_empty() {
+        bail!("Empty password");
+    }
+    Ok((password, false))
+}
+
+fn database(config: &Config) -> Result<Database> {
+    let Some(path) = &config.database.path else {
+        bail!("database.path isn't configured, the state only lives in the server's memory");
+    };
+    Ok(Database::open(Some(path.to_owned()))?)
+}
+
+fn user_store(config: &Config) -> Result<LocalUserStore> {
+    Ok(LocalUserStore::new(database(config)?))
+}
+
+fn share_store(config: &Config) -> Result<ShareStore> {
+    Ok(ShareStore::new(
+        database(config)?,
+        Duration::from_secs(config.shares.session_lifetime),
+    ))
+}
+
+#[derive(Serialize)]
+struct UserEntry {
+    id: String,
+    displayname: Option<String>,
+    tokens: usize,
+    created: DateTime<Utc>,
+}
+
+impl From<LocalUser> for UserEntry {
+    fn from(user: LocalUser) -> Self {
+        Self {
+            id: user.id,
+            displayname: user.displayname,
+            tokens: user.tokens.len(),
+            created: user.created,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PasswordOutput {
+    id: String,
+    /// Only set if it was generated
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenEntry {
+    id: String,
+    name: String,
+    created: DateTime<Utc>,
+    /// Only set when the token was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MountEntry {
+    name: String,
+    /// user, group or null if no local user or configured group owns it
+    owner: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct MountCheck {
+    name: String,
+    ok: bool,
+    problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ShareEntry {
+    token: String,
+    owner: String,
+    mount: String,
+    path: String,
+    kind: ShareKind,
+    password: bool,
+    expires: Option<DateTime<Utc>>,
+    downloads: u64,
+    max_downloads: Option<u64>,
+    active: bool,
+}
+
+impl From<Share> for ShareEntry {
+    fn from(share: Share) -> Self {
+        Self {
+            active: share.is_active(),
+            password: share.password_hash.is_some(),
+            path: share.path.as_str().to_owned(),
+            token: share.token,
+            owner: share.owner,
+            mount: share.mount,
+            kind: share.kind,
+            expires: share.expires,
+            downloads: share.downloads,
+            max_downloads: share.max_downloads,
+        }
+    }
+}
+
+pub async fn run(command: Command, config: Config, json: bool) -> Result<()> {
+    match command {
+        Command::Serve | Command::CheckConfig => unreachable!("handled by main"),
+        Command::User(command) => user(command, &config, json).await,
+        Command::Mount(command) => mount(command, &config, json).await,
+        Command::Share(command) => share(command, &config, json),
+        Command::Token(command) => token(command, &config, json),
+        Command::Db(command) => db(command, &config, json),
+    }
+}
+
+async fn user(command: UserCommand, config: &Config, json: bool) -> Result<()> {
+    let store = user_store(config)?;
+    match command {
+        UserCommand::Add {
+            id,
+            displayname,
+            password,
+        } => {
+            if store.get(&id)?.is_some() {
+                bail!("User {id} already exists");
+            }
+            let (password, generated) = read_password(&password)?;
+            let fs_provider = SimpleFilesystemProvider::new(
+                config.fs.root_path.to_owned(),
+                ChangeNotifier::default(),
+            );
+            fs_provider
+                .create_mount(&id)
+                .await
+                .with_context(|| format!("Invalid user id {id}"))?;
+            store.insert(LocalUser::new(id.to_owned(), displayname, &password))?;
+            let out = PasswordOutput {
+                id,
+                password: generated.then_some(password),
+            };
+            output(json, &out, || match &out.password {
+                Some(password) => println!("Created user {} with password {password}", out.id),
+                None => println!("Created user {}", out.id),
+            })
+        }
+        UserCommand::Passwd { id, password } => {
+            let Some(mut user) = store.get(&id)? else {
+                bail!("User {id} doesn't exist");
+            };
+            let (password, generated) = read_password(&password)?;
+            user.set_password(&password);
+            store.insert(user)?;
+            let out = PasswordOutput {
+                id,
+                password: generated.then_some(password),
+            };
+            output(json, &out, || match &out.password {
+                Some(password) => println!("New password of {}: {password}", out.id),
+                None => println!("Changed password of {}", out.id),
+            })
+        }
+        UserCommand::List => {
+            let users: Vec<UserEntry> = store.list()?.into_iter().map(Into::into).collect();
+            output(json, &users, || {
+                for user in &users {
+                    println!(
+                        "{}\t{}\t{} tokens",
+                        user.id,
+                        user.displayname.as_deref().unwrap_or("-"),
+                        user.tokens
+                    );
+                }
+            })
+        }
+        UserCommand::Remove { id } => {
+            let Some(user) = store.remove(&id)? else {
+                bail!("User {id} doesn't exist");
+            };
+            let user = UserEntry::from(user);
+            output(json, &user, || println!("Removed user {}", user.id))
+        }
+    }
+}
+
+fn list_mounts(root_path: &Path) -> Result<Vec<String>> {
+    let mut mounts = vec![];
+    for entry in std::fs::read_dir(root_path)
+        .with_context(|| format!("Cannot read {}", root_path.display()))?
+    {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && !name.starts_with('.') {
+            mounts.push(name);
+        }
+    }
+    mounts.sort();
+    Ok(mounts)
+}
+
+fn check_mount(root_path: &Path, name: &str) -> MountCheck {
+    let path = root_path.join(name);
+    let mut problems = vec![];
+    match std::fs::metadata(&path) {
+        Ok(metadata) if !metadata.is_dir() => problems.push("not a directory".to_owned()),
+        Ok(_) => {
+            if let Err(err) = std::fs::read_dir(&path) {
+                problems.push(format!("not readable: {err}"));
+            }
+            let probe = path.join(".wolke-check");
+            match std::fs::write(&probe, b"") {
+                Ok(()) => {
+                    let _ = std::fs::remove_file(&probe);
+                }
+                Err(err) => problems.push(format!("not writable: {err}")),
+            }
+        }
+        Err(err) => problems.push(format!("missing: {err}")),
+    }
+    MountCheck {
+        name: name.to_owned(),
+        ok: problems.is_empty(),
+        problems,
+    }
+}
+
+async fn mount(command: MountCommand, config: &Config, json: bool) -> Result<()> {
+    let root_path = &config.fs.root_path;
+    match command {
+        MountCommand::Create { name } => {
+            SimpleFilesystemProvider::new(root_path.to_owned(), ChangeNotifier::default())
+                .create_mount(&name)
+                .await
+                .with_context(|| format!("Cannot create mount {name}"))?;
+            let mount = MountEntry { name, owner: None };
+            output(json, &mount, || println!("Created mount {}", mount.name))
+        }
+        MountCommand::List => {
+            let groups = GroupStore::from_config(&config.groups);
+            let users: HashSet<String> = match &config.database.path {
+                Some(_) => user_store(config)?
+                    .list()?
+                    .into_iter()
+                    .map(|user| user.id)
+                    .collect(),
+                None => HashSet::new(),
+            };
+            let mounts: Vec<_> = list_mounts(root_path)?
+                .into_iter()
+                .map(|name| MountEntry {
+                    owner: if users.contains(&name) {
+                        Some("user")
+                    } else if mount_group(&name).is_some_and(|id| groups.get(id).is_some()) {
+                        Some("group")
+                    } else {
+                        None
+                    },
+                    name,
+                })
+                .collect();
+            output(json, &mounts, || {
+                for mount in &mounts {
+                    println!("{}\t{}", mount.name, mount.owner.unwrap_or("-"));
+                }
+            })
+        }
+        MountCommand::Check { names } => {
+            let names = if names.is_empty() {
+                list_mounts(root_path)?
+            } else {
+                names
+            };
+            let checks: Vec<_> = names
+                .iter()
+                .map(|name| check_mount(root_path, name))
+                .collect();
+            output(json, &checks, || {
+                for check in &checks {
+                    if check.ok {
+                        println!("{}\tok", check.name);
+                    } else {
+                        println!("{}\t{}", check.name, check.problems.join(", "));
+                    }
+                }
+            })?;
+            if checks.iter().any(|check| !check.ok) {
+                bail!("Some mounts have problems");
+            }
+            Ok(())
+        }
+    }
+}
+
+fn share(command: ShareCommand, config: &Config, json: bool) -> Result<()> {
+    let store = share_store(config)?;
+    match command {
+        ShareCommand::List { owner } => {
+            let shares = match owner {
+                Some(owner) => store.list_by_owner(&owner)?,
+                None => store.list()?,
+            };
+            let shares: Vec<ShareEntry> = shares.into_iter().map(Into::into).collect();
+            output(json, &shares, || {
+                for share in &shares {
+                    println!(
+                        "{}\t{}\t{}{}\t{}",
+                        share.token,
+                        share.owner,
+                        share.mount,
+                        share.path,
+                        if share.active { "active" } else { "inactive" }
+                    );
+                }
+            })
+        }
+        ShareCommand::Revoke { token } => {
+            let Some(share) = store.remove(&token)? else {
+                bail!("Share {token} doesn't exist");
+            };
+            let share = ShareEntry::from(share);
+            output(json, &share, || println!("Revoked share {}", share.token))
+        }
+    }
+}
+
+fn token(command: TokenCommand, config: &Config, json: bool) -> Result<()> {
+    let store = user_store(config)?;
+    let user_id = match &command {
+        TokenCommand::Create { user, .. }
+        | TokenCommand::List { user }
+        | TokenCommand::Revoke { user, .. } => user,
+    };
+    let Some(mut user) = store.get(user_id)? else {
+        bail!("User {user_id} doesn't exist");
+    };
+    match command {
+        TokenCommand::Create { name, .. } => {
+            let (token, secret) = user.create_token(name);
+            store.insert(user)?;
+            let token = TokenEntry {
+                id: token.id,
+                name: token.name,
+                created: token.created,
+                token: Some(secret),
+            };
+            output(json, &token, || {
+                println!("{}", token.token.as_deref().unwrap_or_default())
+            })
+        }
+        TokenCommand::List { .. } => {
+            let tokens: Vec<_> = user
+                .tokens
+                .into_iter()
+                .map(|token| TokenEntry {
+                    id: token.id,
+                    name: token.name,
+                    created: token.created,
+                    token: None,
+                })
+                .collect();
+            output(json, &tokens, || {
+                for token in &tokens {
+                    println!("{}\t{}\t{}", token.id, token.name, token.created);
+                }
+            })
+        }
+        TokenCommand::Revoke { id, .. } => {
+            let Some(index) = user.tokens.iter().position(|token| token.id == id) else {
+                bail!("Token {id} doesn't exist");
+            };
+            let token = user.tokens.remove(index);
+            store.insert(user)?;
+            let token = TokenEntry {
+                id: token.id,
+                name: token.name,
+                created: token.created,
+                token: None,
+            };
+            output(json, &token, || println!("Revoked token {}", token.id))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DbStatus {
+    path: PathBuf,
+    schema_version: u32,
+}
+
+fn db(command: DbCommand, config: &Config, json: bool) -> Result<()> {
+    match command {
+        DbCommand::Status => {
+            let db = database(config)?;
+            let status = DbStatus {
+                path: db.path().map(Path::to_owned).unwrap_or_default(),
+                schema_version: db.schema_version()?,
+            };
+            output(json, &status, || {
+                println!(
+                    "{}\tschema version {}",
+                    status.path.display(),
+                    status.schema_version
+                )
+            })
+        }
+        DbCommand::Backup { to } => {
+            database(config)?
+                .backup(&to)
+                .with_context(|| format!("Cannot back up to {}", to.display()))?;
+            output(json, &to, || println!("Backed up to {}", to.display()))
+        }
+        DbCommand::Restore { from } => {
+            let Some(path) = &config.database.path else {
+                bail!("database.path isn't configured");
+            };
+            Database::restore(path, &from)
+                .with_context(|| format!("Cannot restore from {}", from.display()))?;
+            output(json, &from, || println!("Restored from {}", from.display()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration with its state in dir
+    fn config(dir: &Path) -> Config {
+        let file = dir.join("wolke.toml");
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        let toml = format!(
+            "[fs]\nroot_path = {:?}\n[database]\npath = {:?}\n",
+            dir.join("root"),
+            dir.join("wolke.db")
+        );
+        std::fs::write(&file, toml).unwrap();
+        Config::load(file.to_str().unwrap()).unwrap()
+    }
+
+    async fn run_json(dir: &Path, command: Command) -> Result<()> {
+        run(command, config(dir), true).await
+    }
+
+    #[tokio::test]
+    async fn users_and_their_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let add = UserCommand::Add {
+            id: "alice".to_owned(),
+            displayname: Some("Alice".to_owned()),
+            password: PasswordArgs {
+                password_stdin: false,
+            },
+        };
+        run_json(dir.path(), Command::User(add)).await.unwrap();
+        assert!(dir.path().join("root/alice").is_dir());
+        let duplicate = UserCommand::Add {
+            id: "alice".to_owned(),
+            displayname: None,
+            password: PasswordArgs {
+                password_stdin: false,
+            },
+        };
+        assert!(
+            run_json(dir.path(), Command::User(duplicate))
+                .await
+                .is_err()
+        );
+
+        let create = TokenCommand::Create {
+            user: "alice".to_owned(),
+            name: "laptop".to_owned(),
+        };
+        run_json(dir.path(), Command::Token(create)).await.unwrap();
+        let store = user_store(&config(dir.path())).unwrap();
+        let tokens = store.get("alice").unwrap().unwrap().tokens;
+        assert_eq!(tokens.len(), 1);
+        let revoke = TokenCommand::Revoke {
+            user: "alice".to_owned(),
+            id: tokens[0].id.to_owned(),
+        };
+        run_json(dir.path(), Command::Token(revoke)).await.unwrap();
+        assert!(store.get("alice").unwrap().unwrap().tokens.is_empty());
+
+        let remove = UserCommand::Remove {
+            id: "alice".to_owned(),
+        };
+        run_json(dir.path(), Command::User(remove)).await.unwrap();
+        assert!(store.get("alice").unwrap().is_none());
+        // The mount is kept
+        assert!(dir.path().join("root/alice").is_dir());
+    }
+
+    #[tokio::test]
+    async fn mount_checks_report_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let create = MountCommand::Create {
+            name: "shared".to_owned(),
+        };
+        run_json(dir.path(), Command::Mount(create)).await.unwrap();
+        let invalid = MountCommand::Create {
+            name: "..".to_owned(),
+        };
+        assert!(run_json(dir.path(), Command::Mount(invalid)).await.is_err());
+        std::fs::write(dir.path().join("root/file"), "").unwrap();
+
+        let root = dir.path().join("root");
+        assert_eq!(list_mounts(&root).unwrap(), ["shared"]);
+        assert!(check_mount(&root, "shared").ok);
+        assert!(!check_mount(&root, "file").ok);
+        assert!(!check_mount(&root, "missing").ok);
+        let check = MountCommand::Check {
+            names: vec!["shared".to_owned(), "missing".to_owned()],
+        };
+        assert!(run_json(dir.path(), Command::Mount(check)).await.is_err());
+    }
+}
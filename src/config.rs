@@ -1,5 +1,14 @@
-use std::path::PathBuf;
+use ipnet::IpNet;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
 
+use figment::{
+    Figment,
+    providers::{Env, Format, Toml},
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -7,6 +16,73 @@ use serde::{Deserialize, Serialize};
 pub struct HttpConfig {
     pub host: String,
     pub port: u16,
+    /// Terminate TLS ourselves instead of behind a reverse proxy
+    pub tls: Option<TlsConfig>,
+    /// Replaces host, port and tls if not empty
+    pub listeners: Vec<ListenerConfig>,
+    /// Seconds to let running requests finish on SIGTERM or SIGINT
+    pub shutdown_timeout: u64,
+    /// URL prefix all routes are served below, e.g. /files
+    pub base_path: String,
+    /// Proxies whose X-Forwarded-Proto/Host/Prefix headers describe the public URL
+    /// and whose X-Forwarded-For identifies the client for login throttling
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl HttpConfig {
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        let address = match self.host.parse::<IpAddr>() {
+            // Brackets IPv6 addresses
+            Ok(ip) => SocketAddr::from((ip, self.port)).to_string(),
+            Err(_) => format!("{}:{}", self.host, self.port),
+        };
+        vec![ListenerConfig::Tcp {
+            address,
+            tls: self.tls.clone(),
+        }]
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ListenerConfig {
+    Tcp {
+        /// host:port, IPv6 addresses in brackets
+        address: String,
+        tls: Option<TlsConfig>,
+    },
+    Unix {
+        path: PathBuf,
+        /// Permissions of the socket file, e.g. 0o660
+        mode: Option<u32>,
+        /// Trust clients like http.trusted_proxies and auth.proxy.trusted_proxies,
+        /// for a proxy on the same host. Unix sockets have no peer address to check
+        #[serde(default)]
+        trusted: bool,
+        tls: Option<TlsConfig>,
+    },
+    /// Sockets passed by systemd socket activation
+    Systemd {
+        /// Only use the sockets with this FileDescriptorName=, all of them otherwise
+        name: Option<String>,
+        /// Trust clients of Unix sockets, see the unix listener
+        #[serde(default)]
+        trusted: bool,
+        tls: Option<TlsConfig>,
+    },
+}
+
+impl ListenerConfig {
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        match self {
+            Self::Tcp { tls, .. } | Self::Unix { tls, .. } | Self::Systemd { tls, .. } => {
+                tls.as_ref()
+            }
+        }
+    }
 }
 
 impl Default for HttpConfig {
@@ -14,10 +90,61 @@ impl Default for HttpConfig {
         Self {
             host: "0.0.0.0".to_owned(),
             port: 5000,
+            tls: None,
+            listeners: vec![],
+            shutdown_timeout: 30,
+            base_path: String::new(),
+            trusted_proxies: vec![],
         }
     }
 }
 
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// Which part of a client certificate names the user
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientCertUser {
+    #[default]
+    CommonName,
+    /// The first email address in the subject alternative names
+    Email,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert_path: PathBuf,
+    /// PEM private key
+    pub key_path: PathBuf,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Reload the certificate when the files change, e.g. after a renewal
+    #[serde(default = "default_true")]
+    pub reload: bool,
+    /// CA certificates client certificates are verified against,
+    /// a verified client is logged in as the user named in its certificate
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+    /// Reject connections without a client certificate
+    #[serde(default)]
+    pub client_cert_required: bool,
+    #[serde(default)]
+    pub client_cert_user: ClientCertUser,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -27,23 +154,370 @@ pub struct Config {
     pub tracing: TracingConfig,
 
     pub fs: FSConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub search: SearchConfig,
+    #[serde(default)]
+    pub uploads: UploadConfig,
+    #[serde(default)]
+    pub shares: ShareConfig,
+    /// Groups by id, every member has access to the mount `group:<id>`
+    #[serde(default)]
+    pub groups: HashMap<String, GroupConfig>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
 }
 
-#[derive(Deserialize, Serialize)]
+impl Config {
+    /// Reads the config file, WOLKE_ environment variables take precedence
+    pub fn load(path: &str) -> Result<Self, Box<figment::Error>> {
+        Ok(Figment::new()
+            .merge(Toml::file(path))
+            .merge(Env::prefixed("WOLKE_").split("__"))
+            .extract()?)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct FSConfig {
     pub root_path: PathBuf,
+    /// Watch root_path for changes made outside of Wolke
+    #[serde(default)]
+    pub watch: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct DatabaseConfig {
+    /// SQLite database for users, shares and the change log, it's only kept in memory if unset
+    pub path: Option<PathBuf>,
+    /// Days to keep change log entries
+    pub change_log_retention: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            change_log_retention: 30,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MetricsConfig {
+    /// Serve Prometheus metrics at /metrics, labels contain mount names and thereby user ids
+    pub enabled: bool,
+    /// Address like 127.0.0.1:9090 for a separate admin listener without authentication,
+    /// /metrics is served on the main listener to auth.admins only if unset
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AuditConfig {
+    /// Record uploads, moves, copies and deletions
+    pub enabled: bool,
+    /// JSON lines file, written to stdout if unset
+    pub path: Option<PathBuf>,
+    /// Bytes after which the file is rotated to path.1
+    pub max_size: u64,
+    /// Number of rotated files to keep
+    pub keep: usize,
+    /// Also record downloads
+    pub downloads: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            max_size: 100 * 1024 * 1024,
+            keep: 10,
+            downloads: false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, default)]
 pub struct TracingConfig {
     pub opentelemetry: bool,
+    /// Log filter directives like `wolke=debug,warn`, RUST_LOG is used if unset
+    pub filter: Option<String>,
 }
 
 impl Default for TracingConfig {
     fn default() -> Self {
         Self {
             opentelemetry: true,
+            filter: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SearchConfig {
+    /// Directory to persist the search index in, it's only kept in memory if unset
+    pub index_path: Option<PathBuf>,
+    /// Index the words of text documents for DAV:contains
+    pub full_text: bool,
+    /// Larger documents are not indexed for full-text search
+    pub max_text_size: u64,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            index_path: None,
+            full_text: false,
+            max_text_size: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct UploadConfig {
+    /// Directory for partial uploads, should be on the same filesystem as fs.root_path
+    pub staging_path: PathBuf,
+    /// Maximum size of a resumable upload in bytes
+    pub max_size: Option<u64>,
+    /// Seconds after the last activity until an unfinished upload is removed
+    pub expiration: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            staging_path: std::env::temp_dir().join("wolke-uploads"),
+            max_size: None,
+            expiration: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ShareConfig {
+    /// Maximum size in bytes of a file visitors add to a file drop
+    pub max_drop_size: u64,
+    /// Seconds a browser stays unlocked after entering the password of a share
+    pub session_lifetime: u64,
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        Self {
+            max_drop_size: 1024 * 1024 * 1024,
+            session_lifetime: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct GroupConfig {
+    pub displayname: Option<String>,
+    /// User ids
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AuthConfig {
+    /// Let users managed by `wolke user` log in.
+    /// Without any provider all requests are made by a single local user
+    pub local_users: bool,
+    pub ldap: Option<LdapConfig>,
+    /// Login for the web frontend
+    pub oidc: Option<OidcConfig>,
+    /// Trust the user from an authenticating reverse proxy, all other login methods are disabled
+    pub proxy: Option<ProxyAuthConfig>,
+    /// Create a mount named like the user on first login
+    pub home_mounts: bool,
+    /// Seconds a browser session stays valid
+    pub session_lifetime: u64,
+    pub rate_limit: RateLimitConfig,
+    /// Users allowed to use the admin API
+    pub admins: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            local_users: false,
+            ldap: None,
+            oidc: None,
+            proxy: None,
+            home_mounts: true,
+            session_lifetime: 7 * 24 * 60 * 60,
+            rate_limit: RateLimitConfig::default(),
+            admins: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RateLimitConfig {
+    /// Throttle failed logins per IP address and account
+    pub enabled: bool,
+    /// Failed logins before the backoff starts
+    pub free_attempts: u32,
+    /// Seconds to wait after the first throttled failure, doubled with every further failure
+    pub base_delay: u64,
+    /// Upper bound for the backoff in seconds
+    pub max_delay: u64,
+    /// Failed logins until the IP address or account is locked out
+    pub lockout_threshold: u32,
+    /// Seconds a lockout lasts
+    pub lockout_duration: u64,
+    /// Seconds without failures after which they're forgotten
+    pub reset_after: u64,
+    /// Sustained requests per second of each user, unlimited if unset
+    pub requests_per_second: Option<f64>,
+    /// Requests a user can make at once before being limited to requests_per_second
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            free_attempts: 3,
+            base_delay: 1,
+            max_delay: 60,
+            lockout_threshold: 20,
+            lockout_duration: 15 * 60,
+            reset_after: 60 * 60,
+            requests_per_second: None,
+            burst: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct OidcConfig {
+    /// Issuer URL, the provider metadata is discovered from it
+    pub issuer: String,
+    pub client_id: String,
+    /// Public clients only rely on PKCE
+    pub client_secret: Option<String>,
+    /// Must point to /auth/oidc/callback
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// Claim used as user id and name of the home mount. Only use a claim like
+    /// preferred_username if the provider doesn't let users change it.
+    pub id_claim: String,
+    pub displayname_claim: String,
+    /// Group memberships aren't taken from OIDC if unset
+    pub groups_claim: Option<String>,
+    /// Maps OIDC group names to Wolke groups, groups that aren't mapped are ignored
+    pub group_mapping: HashMap<String, String>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_url: "http://localhost:5000/auth/oidc/callback".to_owned(),
+            scopes: vec!["openid".to_owned(), "profile".to_owned()],
+            id_claim: "sub".to_owned(),
+            displayname_claim: "name".to_owned(),
+            groups_claim: None,
+            group_mapping: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ProxyAuthConfig {
+    /// Header containing the user id
+    pub user_header: String,
+    pub displayname_header: Option<String>,
+    /// Comma-separated group ids, group memberships aren't taken from the proxy if unset
+    pub groups_header: Option<String>,
+    /// Maps proxy group names to Wolke groups, groups that aren't mapped are ignored
+    pub group_mapping: HashMap<String, String>,
+    /// CIDR ranges of the proxies like 10.0.0.0/8, requests from anywhere else are rejected
+    /// unless they come in on a trusted Unix socket listener
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl Default for ProxyAuthConfig {
+    fn default() -> Self {
+        Self {
+            user_header: "Remote-User".to_owned(),
+            displayname_header: Some("Remote-Name".to_owned()),
+            groups_header: None,
+            group_mapping: HashMap::new(),
+            trusted_proxies: vec![
+                IpNet::from(IpAddr::from([127, 0, 0, 1])),
+                IpNet::from(IpAddr::from(Ipv6Addr::LOCALHOST)),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LdapConfig {
+    /// ldap:// or ldaps:// URL
+    pub url: String,
+    pub starttls: bool,
+    /// Connection timeout in seconds
+    pub timeout: u64,
+    /// Service account used for searches, binds anonymously if unset
+    pub bind_dn: Option<String>,
+    pub bind_password: String,
+    pub base_dn: String,
+    /// {username} is replaced with the escaped login name
+    pub user_filter: String,
+    /// Attribute used as user id and name of the home mount
+    pub id_attribute: String,
+    pub displayname_attribute: String,
+    /// Group memberships aren't read from LDAP if unset
+    pub group_base_dn: Option<String>,
+    /// {dn} is replaced with the user's DN, {username} with the login name
+    pub group_filter: String,
+    pub group_id_attribute: String,
+    /// Maps LDAP group ids to Wolke groups, groups that aren't mapped are ignored
+    pub group_mapping: HashMap<String, String>,
+    /// Seconds a successful login is remembered without asking the directory again, 0 disables
+    pub cache_lifetime: u64,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        Self {
+            url: "ldap://localhost:389".to_owned(),
+            starttls: false,
+            timeout: 5,
+            bind_dn: None,
+            bind_password: String::new(),
+            base_dn: String::new(),
+            user_filter: "(&(objectClass=person)(uid={username}))".to_owned(),
+            id_attribute: "uid".to_owned(),
+            displayname_attribute: "cn".to_owned(),
+            group_base_dn: None,
+            group_filter: "(&(objectClass=groupOfNames)(member={dn}))".to_owned(),
+            group_id_attribute: "cn".to_owned(),
+            group_mapping: HashMap::new(),
+            cache_lifetime: 60,
         }
     }
 }
@@ -17,12 +17,28 @@ pub enum Error {
 
     #[error(transparent)]
     Axum(#[from] axum::Error),
+
+    #[error(transparent)]
+    Search(#[from] crate::search::QueryError),
+
+    #[error(transparent)]
+    Database(#[from] crate::db::Error),
+
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error("Upload exceeds its declared length")]
+    UploadTooLarge,
 }
 
 impl Error {
-    fn status_code(&self) -> StatusCode {
+    pub fn status_code(&self) -> StatusCode {
         match self {
             Self::FS(err) => err.status_code(),
+            Self::Search(crate::search::QueryError::TooLarge) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Search(_) => StatusCode::BAD_REQUEST,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::UploadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -1,7 +1,8 @@
 use crate::{
+    audit::{AuditAction, AuditEvent},
     dav::{
-        Error,
-        fs::{FSResourceService, FSResourceServicePath},
+        Error, User,
+        fs::{FSResource, FSResourceService, FSResourceServicePath},
     },
     filesystem::{DavMetadata, FileReader, Filesystem, FilesystemProvider},
 };
@@ -12,27 +13,19 @@ use axum::{
 };
 use axum_extra::TypedHeader;
 use headers::Range;
-use http::{HeaderValue, Request, StatusCode, header};
+use http::{HeaderMap, HeaderValue, Request, StatusCode, header};
 use httpdate::HttpDate;
 use percent_encoding::{CONTROLS, percent_encode};
-use rustical_dav::resource::ResourceService;
+use rustical_dav::{privileges::UserPrivilege, resource::Resource};
 use std::ops::Bound;
 
-pub async fn route_get<FSP: FilesystemProvider>(
-    State(resource_service): State<FSResourceService<FSP>>,
-    Path(path): Path<FSResourceServicePath>,
-    http_range: Option<TypedHeader<Range>>,
-    req: Request<Body>,
-) -> Result<Response<Body>, Error> {
-    let resource = resource_service.get_resource(&path, false).await?;
+/// Headers describing a file, shared by GET and HEAD
+pub(super) fn insert_file_headers<FSP: FilesystemProvider>(
+    headers: &mut HeaderMap,
+    resource: &FSResource<FSP>,
+) {
     let filename = resource.path.file_name();
     let filename = percent_encode(filename.as_bytes(), CONTROLS).to_string();
-    let filesystem = resource_service.0.get_filesystem(&path.mount).await?;
-    let md = filesystem.metadata(&path.path).await?;
-    let file = filesystem.get_file(&path.path).await?;
-
-    let mut res = Response::builder().status(StatusCode::OK);
-    let headers = res.headers_mut().unwrap();
 
     if let Some(content_type) = mime_guess::from_path(&filename).first_raw() {
         headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
@@ -51,9 +44,38 @@ pub async fn route_get<FSP: FilesystemProvider>(
 
     headers.insert(
         header::LAST_MODIFIED,
-        HeaderValue::try_from(HttpDate::from(md.modified()).to_string()).unwrap(),
+        HeaderValue::try_from(HttpDate::from(resource.metadata.modified()).to_string()).unwrap(),
     );
 
+    if let Some(etag) = resource
+        .get_etag()
+        .and_then(|etag| HeaderValue::try_from(etag).ok())
+    {
+        headers.insert(header::ETAG, etag);
+    }
+}
+
+pub async fn route_get<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+    http_range: Option<TypedHeader<Range>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Error> {
+    let resource = resource_service
+        .authorize(&path, &user, UserPrivilege::Read)
+        .await?;
+    let filesystem = resource_service
+        .provider
+        .get_filesystem(&path.mount)
+        .await?;
+    let md = &resource.metadata;
+    let file = filesystem.get_file(&path.path).await?;
+
+    let mut res = Response::builder().status(StatusCode::OK);
+    let headers = res.headers_mut().unwrap();
+    insert_file_headers(headers, &resource);
+
     let mut length = md.len();
     let mut offset = 0;
 
@@ -104,11 +126,15 @@ pub async fn route_get<FSP: FilesystemProvider>(
         );
     }
 
+    headers.insert(header::CONTENT_LENGTH, length.into());
     if offset != 0 || length != md.len() {
         res = res.status(StatusCode::PARTIAL_CONTENT);
     }
 
     let stream = file.stream(length, offset).await?;
+    resource_service
+        .audit
+        .record(AuditEvent::new(AuditAction::Download, &path.mount, &path.path).size(length));
 
     Ok(res.body(Body::from_stream(stream)).unwrap())
 }
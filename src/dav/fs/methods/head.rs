@@ -0,0 +1,75 @@
+use super::insert_file_headers;
+use crate::{
+    dav::{
+        Error, User,
+        fs::{FSResourceService, FSResourceServicePath},
+    },
+    filesystem::{DavMetadata, FilesystemProvider},
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    response::Response,
+};
+use http::{StatusCode, header};
+use rustical_dav::privileges::UserPrivilege;
+
+/// Same headers as route_get without opening the file
+pub async fn route_head<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+) -> Result<Response<Body>, Error> {
+    let resource = resource_service
+        .authorize(&path, &user, UserPrivilege::Read)
+        .await?;
+
+    // Like GET, which only serves files
+    if resource.metadata.is_dir() {
+        return Err(crate::filesystem::Error::NotFound.into());
+    }
+
+    let mut res = Response::builder().status(StatusCode::OK);
+    let headers = res.headers_mut().unwrap();
+    insert_file_headers(headers, &resource);
+    headers.insert(header::CONTENT_LENGTH, resource.metadata.len().into());
+
+    Ok(res.body(Body::empty()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scoped_fs::ScopedPath;
+
+    #[tokio::test]
+    async fn head_matches_get() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("alice/docs")).unwrap();
+        std::fs::write(dir.path().join("alice/docs/notes.txt"), "hello").unwrap();
+        let service = FSResourceService::in_dir(dir.path());
+        let head = |path: &str| {
+            let path = FSResourceServicePath {
+                mount: "alice".to_owned(),
+                path: ScopedPath::new(path.to_owned()),
+            };
+            let user = User {
+                id: "alice".to_owned(),
+                displayname: None,
+                memberships: vec![],
+                unrestricted: false,
+            };
+            route_head(State(service.clone()), Path(path), user)
+        };
+
+        let res = head("docs/notes.txt").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "5");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain");
+        assert!(res.headers().contains_key(header::ETAG));
+        for path in ["docs", "", "missing.txt"] {
+            let err = head(path).await.unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use crate::{
     dav::{
-        Error,
+        Error, User,
         fs::{FSResourceService, FSResourceServicePath},
     },
-    filesystem::{Filesystem, FilesystemProvider},
+    filesystem::FilesystemProvider,
 };
 use axum::{
     body::Body,
@@ -15,9 +15,10 @@ use http::StatusCode;
 pub async fn route_mkcol<FSP: FilesystemProvider>(
     State(resource_service): State<FSResourceService<FSP>>,
     Path(path): Path<FSResourceServicePath>,
+    user: User,
 ) -> Result<Response<Body>, Error> {
-    let filesystem = resource_service.0.get_filesystem(&path.mount).await?;
-    filesystem.create_dir(&path.path).await?;
+    resource_service.authorize_create(&path, &user).await?;
+    resource_service.create_collection(&path).await?;
 
     Ok(StatusCode::CREATED.into_response())
 }
@@ -1,8 +1,17 @@
 mod get;
 pub use get::*;
 
+mod head;
+pub use head::*;
+
+mod options;
+pub use options::*;
+
 mod put;
 pub use put::*;
 
 mod mkcol;
 pub use mkcol::*;
+
+mod search;
+pub use search::*;
@@ -0,0 +1,105 @@
+use crate::{
+    dav::{
+        Error, User,
+        fs::{FSResourceService, FSResourceServicePath},
+    },
+    filesystem::{DavMetadata, FilesystemProvider},
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    response::Response,
+};
+use http::{HeaderValue, StatusCode, header};
+use rustical_dav::{privileges::UserPrivilege, resource::ResourceService};
+
+// GET and HEAD only serve files
+const ALLOW_COLLECTION: &str = "OPTIONS, PROPFIND, PROPPATCH, COPY, MOVE, DELETE, SEARCH";
+// The mount root itself can't be deleted or moved
+const ALLOW_MOUNT_ROOT: &str = "OPTIONS, PROPFIND, PROPPATCH, SEARCH";
+const ALLOW_FILE: &str = "OPTIONS, GET, HEAD, PUT, PROPFIND, PROPPATCH, COPY, MOVE, DELETE";
+const ALLOW_NONEXISTENT: &str = "OPTIONS, PUT, MKCOL";
+
+pub async fn route_options<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+) -> Result<Response<Body>, Error> {
+    let allow = match resource_service
+        .authorize(&path, &user, UserPrivilege::Read)
+        .await
+    {
+        Ok(resource) if !resource.metadata.is_dir() => ALLOW_FILE,
+        Ok(_) if path.path.is_root() => ALLOW_MOUNT_ROOT,
+        Ok(_) => ALLOW_COLLECTION,
+        // Whether something could be created is only revealed to those who can see the parent
+        Err(Error::FS(crate::filesystem::Error::NotFound)) if !path.path.is_root() => {
+            let parent = FSResourceServicePath {
+                mount: path.mount.to_owned(),
+                path: path.path.parent(),
+            };
+            resource_service
+                .authorize(&parent, &user, UserPrivilege::Read)
+                .await?;
+            ALLOW_NONEXISTENT
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut res = Response::builder().status(StatusCode::OK);
+    let headers = res.headers_mut().unwrap();
+    headers.insert(header::ALLOW, HeaderValue::from_static(allow));
+    headers.insert(
+        "DAV",
+        HeaderValue::from_static(<FSResourceService<FSP> as ResourceService>::DAV_HEADER),
+    );
+    if allow.contains("SEARCH") {
+        headers.insert("DASL", HeaderValue::from_static("<DAV:basicsearch>"));
+    }
+
+    Ok(res.body(Body::empty()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scoped_fs::ScopedPath;
+
+    #[tokio::test]
+    async fn allow_depends_on_the_resource() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("alice/docs")).unwrap();
+        std::fs::create_dir_all(dir.path().join("bob")).unwrap();
+        std::fs::write(dir.path().join("alice/docs/notes.txt"), "hello").unwrap();
+        let service = FSResourceService::in_dir(dir.path());
+        let options = |mount: &str, path: &str| {
+            let path = FSResourceServicePath {
+                mount: mount.to_owned(),
+                path: ScopedPath::new(path.to_owned()),
+            };
+            let user = User {
+                id: "alice".to_owned(),
+                displayname: None,
+                memberships: vec![],
+                unrestricted: false,
+            };
+            route_options(State(service.clone()), Path(path), user)
+        };
+
+        for (path, allow) in [
+            ("docs/notes.txt", ALLOW_FILE),
+            ("docs", ALLOW_COLLECTION),
+            ("", ALLOW_MOUNT_ROOT),
+            ("docs/new.txt", ALLOW_NONEXISTENT),
+        ] {
+            let res = options("alice", path).await.unwrap();
+            assert_eq!(res.headers()[header::ALLOW], allow, "{path}");
+            assert_eq!(res.headers().contains_key("DASL"), allow.contains("SEARCH"));
+        }
+
+        // Nothing is revealed about missing parents or other users' mounts
+        let err = options("alice", "missing/new.txt").await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        assert!(options("bob", "").await.is_err());
+    }
+}
@@ -1,32 +1,36 @@
 use crate::{
     dav::{
-        Error,
+        Error, User,
         fs::{FSResourceService, FSResourceServicePath},
     },
-    filesystem::{Filesystem, FilesystemProvider},
+    filesystem::FilesystemProvider,
 };
 use axum::{
     body::Body,
     extract::{Path, State},
     response::{IntoResponse, Response},
 };
-use futures::StreamExt;
 use http::{Request, StatusCode};
-use std::io::Write;
+use rustical_dav::privileges::UserPrivilege;
 
 pub async fn route_put<FSP: FilesystemProvider>(
     State(resource_service): State<FSResourceService<FSP>>,
     Path(path): Path<FSResourceServicePath>,
+    user: User,
     req: Request<Body>,
 ) -> Result<Response<Body>, Error> {
-    let mut stream = req.into_body().into_data_stream();
-
-    let filesystem = resource_service.0.get_filesystem(&path.mount).await?;
-    let mut file = filesystem.create_file(&path.path).await?;
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        file.write_all(&chunk)?;
+    // Overwriting needs write access to the file, creating it write access to the parent
+    match resource_service
+        .authorize(&path, &user, UserPrivilege::Write)
+        .await
+    {
+        Ok(_) => {}
+        Err(Error::FS(crate::filesystem::Error::NotFound)) => {
+            resource_service.authorize_create(&path, &user).await?
+        }
+        Err(err) => return Err(err),
     }
+    resource_service.write_file(&path, req.into_body()).await?;
 
     Ok(StatusCode::CREATED.into_response())
 }
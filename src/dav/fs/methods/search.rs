@@ -0,0 +1,196 @@
+use crate::{
+    dav::{
+        Error, PATH_SEGMENT, User,
+        fs::{FSPrincipalUri, FSResourceService, FSResourceServicePath},
+    },
+    filesystem::FilesystemProvider,
+    search::{
+        Depth, IndexEntry, MAX_QUERY_SIZE, Property, Query, QueryError, SearchIndex, Select,
+        UnknownProperty,
+    },
+};
+use axum::{
+    Extension,
+    body::Body,
+    extract::{Path, State},
+    response::Response,
+};
+use http::{HeaderValue, StatusCode, Uri, header};
+use httpdate::HttpDate;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use quick_xml::escape::escape;
+use rustical_dav::{privileges::UserPrivilege, resource::PrincipalUri};
+use scoped_fs::ScopedPath;
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime},
+};
+
+pub async fn route_search<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    Extension(index): Extension<SearchIndex>,
+    Extension(puri): Extension<FSPrincipalUri>,
+    user: User,
+    body: Body,
+) -> Result<Response<Body>, Error> {
+    resource_service
+        .authorize(&path, &user, UserPrivilege::Read)
+        .await?;
+
+    let body = axum::body::to_bytes(body, MAX_QUERY_SIZE)
+        .await
+        .map_err(|_| QueryError::TooLarge)?;
+    let body = std::str::from_utf8(&body).map_err(|_| QueryError::Invalid("body is not UTF-8"))?;
+    let query = Query::parse(body)?;
+    let mount_href = puri.principal_uri(&path.mount);
+    let scopes = query
+        .scopes
+        .iter()
+        .map(|scope| {
+            Ok((
+                resolve_scope(&mount_href, &path.path, &scope.href)?,
+                scope.depth,
+            ))
+        })
+        .collect::<Result<Vec<(ScopedPath, Depth)>, QueryError>>()?;
+
+    let mount_index = index
+        .mount(resource_service.provider.as_ref(), &path.mount)
+        .await?;
+    let results = query.execute(&mount_index.entries(), &scopes);
+    let revision = resource_service.changes().revision(&path.mount);
+
+    let mut multistatus =
+        String::from(r#"<?xml version="1.0" encoding="utf-8"?><multistatus xmlns="DAV:">"#);
+    for (path, entry) in &results {
+        write_response(
+            &mut multistatus,
+            &query.select,
+            &mount_href,
+            path,
+            entry,
+            revision,
+        );
+    }
+    multistatus.push_str("</multistatus>");
+
+    let mut res = Response::builder().status(StatusCode::MULTI_STATUS);
+    res.headers_mut().unwrap().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    Ok(res.body(Body::from(multistatus)).unwrap())
+}
+
+/// Resolves a DAV:scope href relative to the search arbiter and makes sure it stays below it
+fn resolve_scope(
+    mount_href: &str,
+    base: &ScopedPath,
+    href: &str,
+) -> Result<ScopedPath, QueryError> {
+    let href = match href.parse::<Uri>() {
+        Ok(uri) if uri.scheme().is_some() => uri.path().to_owned(),
+        _ => href.to_owned(),
+    };
+    let href = percent_decode_str(&href).decode_utf8_lossy();
+    let path = if href.starts_with('/') {
+        let rest = href
+            .strip_prefix(mount_href.trim_end_matches('/'))
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .ok_or(QueryError::Invalid("scope outside of the search arbiter"))?;
+        ScopedPath::new(rest.trim_start_matches('/').to_owned())
+    } else {
+        match href.trim_end_matches('/') {
+            "" | "." => base.to_owned(),
+            relative => base.join_segment(relative),
+        }
+    };
+    if !path.starts_with(base) {
+        return Err(QueryError::Invalid("scope outside of the search arbiter"));
+    }
+    Ok(path)
+}
+
+fn write_response(
+    out: &mut String,
+    select: &Select,
+    mount_href: &str,
+    path: &ScopedPath,
+    entry: &IndexEntry,
+    revision: u64,
+) {
+    let mut href = mount_href.trim_end_matches('/').to_owned();
+    for segment in path.as_str().split('/').filter(|s| !s.is_empty()) {
+        href.push('/');
+        href.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+    }
+    if entry.is_dir {
+        href.push('/');
+    }
+    write!(out, "<response><href>{}</href>", escape(&href)).unwrap();
+
+    let (properties, unknown): (&[Property], &[UnknownProperty]) = match select {
+        Select::AllProp => (Property::ALL, &[]),
+        Select::Props(properties, unknown) => (properties.as_slice(), unknown.as_slice()),
+    };
+    let mut found = String::new();
+    let mut missing = String::new();
+    for property in properties {
+        match property_value(*property, path, entry, revision) {
+            Some(value) => write!(found, "<{0}>{value}</{0}>", property.name()).unwrap(),
+            None => write!(missing, "<{}/>", property.name()).unwrap(),
+        }
+    }
+    for property in unknown {
+        match &property.namespace {
+            Some(namespace) => write!(
+                missing,
+                r#"<x:{} xmlns:x="{}"/>"#,
+                property.name,
+                escape(namespace)
+            ),
+            None => write!(missing, r#"<{} xmlns=""/>"#, property.name),
+        }
+        .unwrap();
+    }
+
+    if !found.is_empty() {
+        write!(
+            out,
+            "<propstat><prop>{found}</prop><status>HTTP/1.1 200 OK</status></propstat>"
+        )
+        .unwrap();
+    }
+    if !missing.is_empty() {
+        write!(
+            out,
+            "<propstat><prop>{missing}</prop><status>HTTP/1.1 404 Not Found</status></propstat>"
+        )
+        .unwrap();
+    }
+    out.push_str("</response>");
+}
+
+/// Escaped XML content of a property, consistent with FSResource::get_prop
+fn property_value(
+    property: Property,
+    path: &ScopedPath,
+    entry: &IndexEntry,
+    revision: u64,
+) -> Option<String> {
+    let date = |millis: u64| {
+        HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_millis(millis)).to_string()
+    };
+    Some(match property {
+        Property::Displayname => escape(path.file_name()).into_owned(),
+        Property::Resourcetype if entry.is_dir => "<collection/>".to_owned(),
+        Property::Resourcetype => String::new(),
+        Property::Getcontentlength => entry.len.to_string(),
+        Property::Getlastmodified => date(entry.modified),
+        Property::Creationdate => date(entry.created),
+        Property::Getcontenttype => escape(entry.content_type.as_deref()?).into_owned(),
+        Property::Getetag if entry.is_dir => escape(format!("\"{revision}\"")).into_owned(),
+        Property::Getetag => escape(&entry.etag()?).into_owned(),
+    })
+}
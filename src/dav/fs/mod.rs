@@ -1,53 +1,224 @@
 use super::{Error, User};
 use crate::{
+    audit::{AuditAction, AuditEvent, AuditLog},
     dav::fs::methods::{route_mkcol, route_put},
-    filesystem::{DavMetadata, Filesystem, FilesystemProvider},
+    filesystem::{ChangeKind, DavMetadata, Filesystem, FilesystemProvider},
+    groups::{GroupStore, group_mount, mount_group},
 };
 use async_trait::async_trait;
-use axum::handler::Handler;
+use axum::{body::Body, handler::Handler};
 use derive_more::{Constructor, Deref};
+use futures::StreamExt;
 use httpdate::HttpDate;
-use methods::route_get;
 use rustical_dav::{
-    privileges::UserPrivilegeSet,
+    privileges::{UserPrivilege, UserPrivilegeSet},
     resource::{
         AxumMethods, MethodFunction, PrincipalUri, Resource, ResourceName, ResourceService,
     },
-    xml::{Resourcetype, ResourcetypeInner},
+    xml::{GroupMemberSet, GroupMembership, HrefElement, Resourcetype, ResourcetypeInner},
 };
 use rustical_xml::{EnumVariants, PropName, XmlDeserialize, XmlSerialize};
 use scoped_fs::ScopedPath;
 use serde::Deserialize;
-use std::{borrow::Cow, sync::Arc, time::SystemTime};
+use std::{borrow::Cow, io::Write, path::Path, sync::Arc, time::SystemTime};
 use tower::Service;
 
-#[derive(Debug, Clone)]
-pub struct FSPrincipalUri;
+/// Hrefs of mounts, prefix is the public path of the application root
+#[derive(Debug, Clone, Constructor)]
+pub struct FSPrincipalUri {
+    prefix: String,
+}
 
 impl PrincipalUri for FSPrincipalUri {
     fn principal_collection(&self) -> String {
-        "/dav/mount/".into()
+        format!("{}/dav/mount/", self.prefix)
     }
     fn principal_uri(&self, principal: &str) -> String {
-        format!("/dav/mount/{principal}/")
+        format!("{}/dav/mount/{principal}/", self.prefix)
     }
 }
 
 mod methods;
+pub use methods::{route_get, route_head};
+mod service;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct FSResourceServicePath {
-    mount: String,
+    pub mount: String,
     #[serde(default)]
-    path: ScopedPath,
+    pub path: ScopedPath,
 }
 
 #[derive(Debug, Constructor, Deref)]
-pub struct FSResourceService<FSP: FilesystemProvider>(Arc<FSP>);
+pub struct FSResourceService<FSP: FilesystemProvider> {
+    #[deref]
+    pub provider: Arc<FSP>,
+    pub groups: GroupStore,
+    pub audit: AuditLog,
+}
 
 impl<FSP: FilesystemProvider> Clone for FSResourceService<FSP> {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            provider: self.provider.clone(),
+            groups: self.groups.clone(),
+            audit: self.audit.clone(),
+        }
+    }
+}
+
+impl<FSP: FilesystemProvider> FSResourceService<FSP> {
+    /// Loads a resource if the user has the privilege on it
+    pub async fn authorize(
+        &self,
+        path: &FSResourceServicePath,
+        user: &User,
+        privilege: UserPrivilege,
+    ) -> Result<FSResource<FSP>, Error> {
+        let resource = self.get_resource(path, false).await?;
+        if !resource.get_user_privileges(user)?.has(&privilege) {
+            return Err(Error::Forbidden);
+        }
+        Ok(resource)
+    }
+
+    /// New resources need the write privilege on their parent collection
+    pub async fn authorize_create(
+        &self,
+        path: &FSResourceServicePath,
+        user: &User,
+    ) -> Result<(), Error> {
+        if path.path.is_root() {
+            return Err(crate::filesystem::Error::Conflict.into());
+        }
+        let parent = FSResourceServicePath {
+            mount: path.mount.to_owned(),
+            path: path.path.parent(),
+        };
+        self.authorize(&parent, user, UserPrivilege::Write).await?;
+        Ok(())
+    }
+
+    pub async fn create_collection(&self, path: &FSResourceServicePath) -> Result<(), Error> {
+        let filesystem = self.get_filesystem(&path.mount).await?;
+        let change = self
+            .changes()
+            .begin(&path.mount, &path.path, ChangeKind::Created);
+        filesystem.create_dir(&path.path).await?;
+        change.finish(ChangeKind::Created);
+        self.audit
+            .record(AuditEvent::new(AuditAction::Mkcol, &path.mount, &path.path));
+        Ok(())
+    }
+
+    /// Writes body to a file, returns whether the file existed before
+    pub async fn write_file(
+        &self,
+        path: &FSResourceServicePath,
+        body: Body,
+    ) -> Result<bool, Error> {
+        self.write(path, body, true, None).await
+    }
+
+    /// Writes body to a file that must not exist yet, at most max_size bytes
+    pub async fn create_file(
+        &self,
+        path: &FSResourceServicePath,
+        body: Body,
+        max_size: u64,
+    ) -> Result<(), Error> {
+        self.write(path, body, false, Some(max_size)).await?;
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        path: &FSResourceServicePath,
+        body: Body,
+        overwrite: bool,
+        max_size: Option<u64>,
+    ) -> Result<bool, Error> {
+        let mut stream = body.into_data_stream();
+
+        let filesystem = self.get_filesystem(&path.mount).await?;
+        let existed = filesystem.metadata(&path.path).await.is_ok();
+        if existed && !overwrite {
+            return Err(crate::filesystem::Error::Conflict.into());
+        }
+        // Written files are renamed into place
+        let change = self
+            .changes()
+            .begin(&path.mount, &path.path, ChangeKind::Created);
+        let mut file = filesystem.create_file(&path.path).await?;
+        let mut size = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            if max_size.is_some_and(|max_size| size > max_size) {
+                return Err(Error::UploadTooLarge);
+            }
+            file.write_all(&chunk)?;
+        }
+        if overwrite {
+            file.commit()?;
+        } else {
+            file.commit_new().map_err(|err| match err.kind() {
+                std::io::ErrorKind::AlreadyExists => crate::filesystem::Error::Conflict,
+                _ => err.into(),
+            })?;
+        }
+
+        change.finish(if existed {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Created
+        });
+        self.audit.record(
+            AuditEvent::new(AuditAction::Upload, &path.mount, &path.path)
+                .size(size)
+                .overwritten(existed),
+        );
+        Ok(existed)
+    }
+
+    /// Moves a completely staged upload into place
+    pub async fn import_file(
+        &self,
+        path: &FSResourceServicePath,
+        source: &Path,
+    ) -> Result<bool, Error> {
+        let filesystem = self.get_filesystem(&path.mount).await?;
+        let size = std::fs::metadata(source)?.len();
+        let change = self
+            .changes()
+            .begin(&path.mount, &path.path, ChangeKind::Created);
+        let existed = filesystem.import_file(source, &path.path).await?;
+        change.finish(if existed {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Created
+        });
+        self.audit.record(
+            AuditEvent::new(AuditAction::Upload, &path.mount, &path.path)
+                .size(size)
+                .overwritten(existed),
+        );
+        Ok(existed)
+    }
+}
+
+#[cfg(test)]
+impl FSResourceService<crate::filesystem::SimpleFilesystemProvider> {
+    /// Service over the mounts below root, without groups and audit log
+    pub fn in_dir(root: &Path) -> Self {
+        Self::new(
+            Arc::new(crate::filesystem::SimpleFilesystemProvider::new(
+                root.to_owned(),
+                Default::default(),
+            )),
+            GroupStore::default(),
+            AuditLog::new(&Default::default()).unwrap(),
+        )
     }
 }
 
@@ -69,10 +240,36 @@ impl<FSP: FilesystemProvider> ResourceService for FSResourceService<FSP> {
     ) -> Result<Self::Resource, Self::Error> {
         let fs = self.get_filesystem(&path.mount).await?;
         let metadata = fs.metadata(&path.path).await?;
+        // The root of a mount is the principal owning it
+        let principal = path.path.is_root().then(|| match mount_group(&path.mount) {
+            Some(group) => {
+                let group = self.groups.get(group);
+                PrincipalGroups {
+                    membership: vec![],
+                    member_set: group
+                        .as_ref()
+                        .map(|group| group.all_members().into_iter().collect())
+                        .unwrap_or_default(),
+                    displayname: group.and_then(|group| group.displayname),
+                }
+            }
+            None => PrincipalGroups {
+                membership: self
+                    .groups
+                    .memberships(&path.mount)
+                    .iter()
+                    .map(|group| group_mount(group))
+                    .collect(),
+                member_set: vec![],
+                displayname: None,
+            },
+        });
         Ok(FSResource {
             mount: path.mount.clone(),
             path: path.path.to_owned(),
             metadata,
+            revision: self.changes().revision(&path.mount),
+            principal,
         })
     }
 
@@ -86,13 +283,22 @@ impl<FSP: FilesystemProvider> ResourceService for FSResourceService<FSP> {
             return Ok(vec![]);
         }
 
+        let revision = self.changes().revision(&path.mount);
         let mut result = vec![];
         let listdir: Vec<_> = filesystem.list_dir(&path.path).await?.into_iter().collect();
         for entry in listdir {
+            // Deleted by someone else since it was listed
+            let metadata = match filesystem.metadata(&entry).await {
+                Ok(metadata) => metadata,
+                Err(crate::filesystem::Error::NotFound) => continue,
+                Err(err) => return Err(err.into()),
+            };
             result.push(FSResource {
                 mount: path.mount.clone(),
-                metadata: filesystem.metadata(&entry).await.unwrap(),
+                metadata,
                 path: entry,
+                revision,
+                principal: None,
             });
         }
         Ok(result)
@@ -103,8 +309,17 @@ impl<FSP: FilesystemProvider> ResourceService for FSResourceService<FSP> {
         path: &Self::PathComponents,
         _use_trashbin: bool,
     ) -> Result<(), Self::Error> {
-        let filesystem = self.0.get_filesystem(&path.mount).await?;
+        let filesystem = self.provider.get_filesystem(&path.mount).await?;
+        let change = self
+            .changes()
+            .begin(&path.mount, &path.path, ChangeKind::Deleted);
         filesystem.delete_file(&path.path).await?;
+        change.finish(ChangeKind::Deleted);
+        self.audit.record(AuditEvent::new(
+            AuditAction::Delete,
+            &path.mount,
+            &path.path,
+        ));
         Ok(())
     }
 
@@ -118,10 +333,25 @@ impl<FSP: FilesystemProvider> ResourceService for FSResourceService<FSP> {
         _user: &Self::Principal,
         overwrite: bool,
     ) -> Result<bool, Self::Error> {
-        assert_eq!(mount, dest_mount);
+        if mount != dest_mount {
+            return Err(crate::filesystem::Error::CrossMount.into());
+        }
 
         let fs = self.get_filesystem(mount).await?;
-        Ok(fs.copy(path, dest_path, overwrite).await?)
+        // Copies are renamed into place
+        let change = self.changes().begin(mount, dest_path, ChangeKind::Created);
+        let overwritten = fs.copy(path, dest_path, overwrite).await?;
+        change.finish(if overwritten {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Created
+        });
+        self.audit.record(
+            AuditEvent::new(AuditAction::Copy, mount, path)
+                .destination(dest_path)
+                .overwritten(overwritten),
+        );
+        Ok(overwritten)
     }
 
     async fn move_resource(
@@ -134,10 +364,23 @@ impl<FSP: FilesystemProvider> ResourceService for FSResourceService<FSP> {
         _user: &Self::Principal,
         overwrite: bool,
     ) -> Result<bool, Self::Error> {
-        assert_eq!(mount, dest_mount);
+        if mount != dest_mount {
+            return Err(crate::filesystem::Error::CrossMount.into());
+        }
 
         let fs = self.get_filesystem(mount).await?;
-        Ok(fs.mv(path, dest_path, overwrite).await?)
+        let moved = ChangeKind::Moved {
+            to: dest_path.to_owned(),
+        };
+        let change = self.changes().begin(mount, path, moved.clone());
+        let overwritten = fs.mv(path, dest_path, overwrite).await?;
+        change.finish(moved);
+        self.audit.record(
+            AuditEvent::new(AuditAction::Move, mount, path)
+                .destination(dest_path)
+                .overwritten(overwritten),
+        );
+        Ok(overwritten)
     }
 }
 
@@ -146,6 +389,21 @@ pub struct FSResource<FSP: FilesystemProvider> {
     pub mount: String,
     pub path: ScopedPath,
     pub metadata: <FSP::FS as Filesystem>::Metadata,
+    /// Revision of the mount when this resource was loaded
+    pub revision: u64,
+    /// Only set for the root of a mount
+    pub principal: Option<PrincipalGroups>,
+}
+
+/// Group relations of the principal owning a mount, as principal ids
+#[derive(Debug, Clone, Default)]
+pub struct PrincipalGroups {
+    /// Groups a user is a member of
+    pub membership: Vec<String>,
+    /// Members of a group
+    pub member_set: Vec<String>,
+    /// Configured display name of a group
+    pub displayname: Option<String>,
 }
 
 impl<FSP: FilesystemProvider> ResourceName for FSResource<FSP> {
@@ -171,9 +429,33 @@ pub enum FSResourceProp {
     Getcontenttype(Option<String>),
     #[xml(ns = "rustical_dav::namespace::NS_DAV")]
     Getetag(Option<String>),
+
+    // WebDAV Access Control (RFC 3744)
+    #[xml(skip_deserializing)]
+    #[xml(ns = "rustical_dav::namespace::NS_DAV")]
+    GroupMembership(GroupMembership),
+    #[xml(skip_deserializing)]
+    #[xml(ns = "rustical_dav::namespace::NS_DAV")]
+    GroupMemberSet(GroupMemberSet),
 }
 
 impl<FSP: FilesystemProvider> FSResource<FSP> {
+    fn principal_hrefs(
+        &self,
+        puri: &impl PrincipalUri,
+        ids: impl Fn(&PrincipalGroups) -> &Vec<String>,
+    ) -> Vec<HrefElement> {
+        self.principal
+            .as_ref()
+            .map(|principal| {
+                ids(principal)
+                    .iter()
+                    .map(|id| HrefElement::new(puri.principal_uri(id)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     pub fn get_content_type(&self) -> Option<&'static str> {
         self.path
             .file_extension()
@@ -191,7 +473,12 @@ impl<FSP: FilesystemProvider> Resource for FSResource<FSP> {
     }
 
     fn get_resourcetype(&self) -> Resourcetype {
-        if self.metadata.is_dir() {
+        if self.principal.is_some() {
+            Resourcetype(&[
+                ResourcetypeInner(Some(rustical_dav::namespace::NS_DAV), "collection"),
+                ResourcetypeInner(Some(rustical_dav::namespace::NS_DAV), "principal"),
+            ])
+        } else if self.metadata.is_dir() {
             Resourcetype(&[ResourcetypeInner(
                 Some(rustical_dav::namespace::NS_DAV),
                 "collection",
@@ -203,7 +490,7 @@ impl<FSP: FilesystemProvider> Resource for FSResource<FSP> {
 
     fn get_prop(
         &self,
-        _puri: &impl PrincipalUri,
+        puri: &impl PrincipalUri,
         _user: &User,
         prop: &FSResourcePropName,
     ) -> Result<Self::Prop, Self::Error> {
@@ -224,22 +511,40 @@ impl<FSP: FilesystemProvider> Resource for FSResource<FSP> {
                 FSResourceProp::Getcontenttype(self.get_content_type().map(|mime| mime.to_owned()))
             }
             FSResourcePropName::Getetag => FSResourceProp::Getetag(self.get_etag()),
+            FSResourcePropName::GroupMembership => FSResourceProp::GroupMembership(
+                GroupMembership(self.principal_hrefs(puri, |principal| &principal.membership)),
+            ),
+            FSResourcePropName::GroupMemberSet => FSResourceProp::GroupMemberSet(GroupMemberSet(
+                self.principal_hrefs(puri, |principal| &principal.member_set),
+            )),
         })
     }
 
     fn get_displayname(&self) -> Option<&str> {
-        Some(self.path.file_name())
+        self.principal
+            .as_ref()
+            .and_then(|principal| principal.displayname.as_deref())
+            .or(Some(self.path.file_name()))
     }
 
     fn get_owner(&self) -> Option<&str> {
         Some(&self.mount)
     }
 
-    fn get_user_privileges(&self, _user: &User) -> Result<UserPrivilegeSet, Self::Error> {
-        Ok(UserPrivilegeSet::all())
+    fn get_user_privileges(&self, user: &User) -> Result<UserPrivilegeSet, Self::Error> {
+        let is_owner = user.can_access(&self.mount);
+        if self.metadata.is_read_only() {
+            return Ok(UserPrivilegeSet::owner_read(is_owner));
+        }
+        Ok(UserPrivilegeSet::owner_only(is_owner))
     }
 
     fn get_etag(&self) -> Option<String> {
+        if self.metadata.is_dir() {
+            // A directory's mtime doesn't change with its contents' so the mount revision
+            // is included to invalidate the ETag of every collection on every change
+            return Some(format!("\"{}\"", self.revision));
+        }
         let modified = self
             .metadata
             .modified()
@@ -273,3 +578,45 @@ impl<FSP: FilesystemProvider> AxumMethods for FSResourceService<FSP> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::FromRequestParts;
+
+    #[tokio::test]
+    async fn full_privileges_without_authentication() {
+        let dir = tempfile::tempdir().unwrap();
+        for mount in ["user", "alice"] {
+            std::fs::create_dir_all(dir.path().join(mount)).unwrap();
+            std::fs::write(dir.path().join(mount).join("notes.txt"), "").unwrap();
+        }
+        let service = FSResourceService::in_dir(dir.path());
+        let (mut parts, ()) = http::Request::new(()).into_parts();
+        let anonymous = User::from_request_parts(&mut parts, &()).await.unwrap();
+        let alice = User {
+            id: "alice".to_owned(),
+            displayname: None,
+            memberships: vec![],
+            unrestricted: false,
+        };
+
+        for mount in ["user", "alice"] {
+            for path in ["", "notes.txt"] {
+                let path = FSResourceServicePath {
+                    mount: mount.to_owned(),
+                    path: ScopedPath::new(path.to_owned()),
+                };
+                let resource = service.get_resource(&path, false).await.unwrap();
+                let privileges = resource.get_user_privileges(&anonymous).unwrap();
+                assert!(privileges.has(&UserPrivilege::Read));
+                assert!(privileges.has(&UserPrivilege::Write));
+
+                // With authentication users only get their own mounts
+                let privileges = resource.get_user_privileges(&alice).unwrap();
+                assert_eq!(privileges.has(&UserPrivilege::Read), mount == "alice");
+                assert_eq!(privileges.has(&UserPrivilege::Write), mount == "alice");
+            }
+        }
+    }
+}
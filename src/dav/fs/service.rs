@@ -0,0 +1,81 @@
+use super::{
+    FSResourceService,
+    methods::{route_head, route_options, route_search},
+};
+use crate::filesystem::FilesystemProvider;
+use axum::{
+    extract::Request,
+    handler::Handler,
+    response::{IntoResponse, Response},
+};
+use rustical_dav::resource::ResourceService;
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{Service, ServiceExt};
+
+/// Wraps the service built by rustical_dav to additionally handle
+/// methods that AxumMethods doesn't offer or doesn't handle the way we need
+#[derive(Clone)]
+pub struct FSService<FSP: FilesystemProvider, S> {
+    resource_service: FSResourceService<FSP>,
+    inner: S,
+}
+
+impl<FSP: FilesystemProvider> FSResourceService<FSP> {
+    pub fn service(
+        &self,
+    ) -> FSService<
+        FSP,
+        impl Service<Request, Response: IntoResponse, Error = Infallible, Future: Send + 'static>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    > {
+        FSService {
+            resource_service: self.clone(),
+            inner: self.clone().axum_service(),
+        }
+    }
+}
+
+impl<FSP, S> Service<Request> for FSService<FSP, S>
+where
+    FSP: FilesystemProvider,
+    S: Service<Request, Error = Infallible> + Clone + Send + 'static,
+    S::Response: IntoResponse,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let state = self.resource_service.clone();
+        match req.method().as_str() {
+            "SEARCH" => {
+                let mut service = Handler::with_state(route_search, state);
+                Box::pin(Service::call(&mut service, req))
+            }
+            "HEAD" => {
+                let mut service = Handler::with_state(route_head, state);
+                Box::pin(Service::call(&mut service, req))
+            }
+            "OPTIONS" => {
+                let mut service = Handler::with_state(route_options, state);
+                Box::pin(Service::call(&mut service, req))
+            }
+            _ => {
+                let inner = self.inner.clone();
+                Box::pin(async move { Ok(inner.oneshot(req).await?.into_response()) })
+            }
+        }
+    }
+}
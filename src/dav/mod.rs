@@ -1,16 +1,56 @@
 mod error;
 pub mod fs;
+use crate::{
+    auth::{Authenticator, SESSION_COOKIE},
+    groups::{GroupStore, mount_group},
+    server::ConnectionInfo,
+};
 use axum::extract::FromRequestParts;
 pub use error::Error;
+use headers::{Authorization, Cookie, HeaderMapExt, authorization::Basic};
+use percent_encoding::{AsciiSet, CONTROLS};
 use rustical_dav::Principal;
-use std::convert::Infallible;
 
-#[derive(Debug, derive_more::From, Clone)]
-pub struct User(pub String);
+/// Characters to percent-encode in a single segment of an href
+pub const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub id: String,
+    pub displayname: Option<String>,
+    /// Ids of the groups the user is a member of
+    pub memberships: Vec<String>,
+    /// Authentication is disabled and the user has access to every mount
+    pub unrestricted: bool,
+}
+
+impl User {
+    /// Users have access to their own mount and to the mounts of their groups
+    pub fn can_access(&self, mount: &str) -> bool {
+        if self.unrestricted {
+            return true;
+        }
+        match mount_group(mount) {
+            Some(group) => self.memberships.iter().any(|id| id == group),
+            None => self.id == mount,
+        }
+    }
+}
 
 impl Principal for User {
     fn get_id(&self) -> &str {
-        &self.0
+        &self.id
     }
 }
 
@@ -18,12 +58,97 @@ impl<S> FromRequestParts<S> for User
 where
     S: Send + Sync,
 {
-    type Rejection = Infallible;
+    type Rejection = crate::auth::Error;
 
     async fn from_request_parts(
-        _parts: &mut http::request::Parts,
+        parts: &mut http::request::Parts,
         _state: &S,
     ) -> Result<Self, Self::Rejection> {
-        Ok(User("user".to_owned()))
+        if let Some(user) = parts.extensions.get::<User>() {
+            return Ok(user.clone());
+        }
+        let Some(authenticator) = parts
+            .extensions
+            .get::<Authenticator>()
+            .filter(|authenticator| authenticator.is_enabled())
+            .cloned()
+        else {
+            let id = "user".to_owned();
+            let memberships = parts
+                .extensions
+                .get::<GroupStore>()
+                .map(|groups| groups.memberships(&id))
+                .unwrap_or_default();
+            crate::audit::set_principal(&id);
+            return Ok(User {
+                id,
+                displayname: None,
+                memberships,
+                unrestricted: true,
+            });
+        };
+        let connection = ConnectionInfo::from_extensions(&parts.extensions);
+        let client_cert_user = connection
+            .and_then(|info| info.client_cert_user.clone())
+            .filter(|_| authenticator.has_client_cert_login());
+        let session_user = parts
+            .headers
+            .typed_get::<Cookie>()
+            .and_then(|cookie| cookie.get(SESSION_COOKIE).map(str::to_owned))
+            .and_then(|session| authenticator.session_user(&session));
+        let user = if authenticator.has_proxy() {
+            authenticator
+                .proxy_login(connection, &parts.headers)
+                .await?
+        } else if let Some(id) = client_cert_user {
+            authenticator.client_cert_login(id).await
+        } else if let Some(user) = session_user {
+            user
+        } else {
+            let Some(Authorization(basic)) = parts.headers.typed_get::<Authorization<Basic>>()
+            else {
+                return Err(crate::auth::Error::Unauthorized);
+            };
+            authenticator
+                .login(
+                    basic.username(),
+                    basic.password(),
+                    crate::base_url::client_ip(&parts.extensions),
+                )
+                .await?
+        };
+        authenticator.check_rate(&user)?;
+        crate::audit::set_principal(&user.id);
+        // Other extractors in the same request reuse the result
+        parts.extensions.insert(user.clone());
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_and_users_have_separate_mounts() {
+        let user = |id: &str, memberships: &[&str]| User {
+            id: id.to_owned(),
+            displayname: None,
+            memberships: memberships.iter().map(|group| group.to_string()).collect(),
+            unrestricted: false,
+        };
+        let bob = user("bob", &["staff"]);
+        assert!(bob.can_access("bob"));
+        assert!(bob.can_access("group:staff"));
+        assert!(!bob.can_access("staff"));
+
+        // A member of a group called bob doesn't get bob's mount
+        let alice = user("alice", &["bob"]);
+        assert!(!alice.can_access("bob"));
+        assert!(alice.can_access("group:bob"));
+
+        // Nor does a user called like a group get the group's mount
+        let impostor = user("group:staff", &[]);
+        assert!(!impostor.can_access("group:staff"));
     }
 }
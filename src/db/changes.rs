@@ -0,0 +1,83 @@
+use super::{Database, Error, to_json};
+use crate::filesystem::Change;
+use chrono::{DateTime, Utc};
+use rusqlite::params;
+use std::{collections::HashMap, time::Duration};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Durable record of all changes and the latest revision of each mount
+#[derive(Debug, Clone)]
+pub struct ChangeLog {
+    db: Database,
+}
+
+impl ChangeLog {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    pub fn revisions(&self) -> Result<HashMap<String, u64>, Error> {
+        self.db.with(|conn| {
+            let mut stmt = conn.prepare("SELECT mount, revision FROM revisions")?;
+            let revisions = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+                .collect::<Result<_, _>>()?;
+            Ok(revisions)
+        })
+    }
+
+    pub fn append(&self, change: &Change) -> Result<(), Error> {
+        let kind = to_json(&change.kind)?;
+        let origin = to_json(&change.origin)?;
+        self.db.with(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO change_log (mount, path, kind, origin, revision, time)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    change.mount,
+                    change.path.as_str(),
+                    kind,
+                    origin,
+                    change.revision as i64,
+                    Utc::now().to_rfc3339(),
+                ],
+            )?;
+            tx.execute(
+                "INSERT INTO revisions (mount, revision) VALUES (?1, ?2)
+                ON CONFLICT (mount) DO UPDATE SET revision = excluded.revision",
+                params![change.mount, change.revision as i64],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Returns the number of removed entries
+    pub fn prune(&self, before: DateTime<Utc>) -> Result<usize, Error> {
+        self.db.with(|conn| {
+            Ok(conn.execute(
+                "DELETE FROM change_log WHERE time < ?1",
+                [before.to_rfc3339()],
+            )?)
+        })
+    }
+
+    /// Periodically removes entries older than retention
+    pub fn spawn_pruning(&self, retention: Duration) {
+        let log = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let before = Utc::now() - retention;
+                match log.prune(before) {
+                    Ok(0) => {}
+                    Ok(count) => tracing::debug!("Pruned {count} change log entries"),
+                    Err(err) => tracing::warn!("Failed to prune the change log: {err}"),
+                }
+            }
+        });
+    }
+}
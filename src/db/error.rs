@@ -0,0 +1,18 @@
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The database was created by a newer version of Wolke
+    #[error("Database schema version {0} is newer than supported version {1}")]
+    NewerSchema(u32, u32),
+
+    #[error("Database integrity check failed: {0}")]
+    Corrupt(String),
+}
@@ -0,0 +1,62 @@
+use super::Error;
+use rusqlite::Connection;
+
+/// Applied in order, the schema version is the number of applied migrations.
+/// Never change a released migration, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: State that used to live in JSON files and the change log
+    r#"
+    CREATE TABLE users (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE shares (
+        token TEXT PRIMARY KEY NOT NULL,
+        owner TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX shares_owner ON shares (owner);
+    CREATE TABLE grants (
+        id TEXT PRIMARY KEY NOT NULL,
+        owner TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX grants_owner ON grants (owner);
+    CREATE TABLE revisions (
+        mount TEXT PRIMARY KEY NOT NULL,
+        revision INTEGER NOT NULL
+    );
+    CREATE TABLE change_log (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        mount TEXT NOT NULL,
+        path TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        origin TEXT NOT NULL,
+        revision INTEGER NOT NULL,
+        time TEXT NOT NULL
+    );
+    CREATE INDEX change_log_mount ON change_log (mount, revision);
+    CREATE INDEX change_log_time ON change_log (time);
+    "#,
+];
+
+pub const LATEST_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn schema_version(conn: &Connection) -> Result<u32, Error> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+pub fn migrate(conn: &mut Connection) -> Result<(), Error> {
+    let version = schema_version(conn)?;
+    if version > LATEST_VERSION {
+        return Err(Error::NewerSchema(version, LATEST_VERSION));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index as u32 + 1)?;
+        tx.commit()?;
+        tracing::info!("Migrated database to schema version {}", index + 1);
+    }
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Embedded SQLite database for server-side state like users, shares and the change log
+use rusqlite::{Connection, OpenFlags, Row, types::Type};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+mod changes;
+mod error;
+mod migrations;
+pub use changes::ChangeLog;
+pub use error::Error;
+pub use migrations::LATEST_VERSION;
+
+/// Shared connection to the metadata database.
+/// Queries are short so a single connection behind a mutex is enough.
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Database")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Database {
+    /// Opens or creates the database and applies pending migrations.
+    /// Everything is lost on exit if path is None.
+    pub fn open(path: Option<PathBuf>) -> Result<Self, Error> {
+        let mut conn = match &path {
+            Some(path) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let conn = Connection::open(path)?;
+                // The CLI may write while the server is running
+                conn.pragma_update(None, "journal_mode", "WAL")?;
+                conn.busy_timeout(Duration::from_secs(5))?;
+                conn
+            }
+            None => Connection::open_in_memory()?,
+        };
+        migrations::migrate(&mut conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            path: path.map(Arc::new),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref().map(PathBuf::as_path)
+    }
+
+    pub fn with<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T, Error>) -> Result<T, Error> {
+        f(&mut self.conn.lock().unwrap())
+    }
+
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        self.with(|conn| migrations::schema_version(conn))
+    }
+
+    /// Moves the write-ahead log into the database file, done on shutdown
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        self.with(|conn| {
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+            Ok(())
+        })
+    }
+
+    /// Writes a consistent copy of the database, the server can keep running
+    pub fn backup(&self, to: &Path) -> Result<(), Error> {
+        if to.exists() {
+            return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+        }
+        self.with(|conn| {
+            conn.execute("VACUUM INTO ?1", [to.to_string_lossy()])?;
+            Ok(())
+        })
+    }
+
+    /// Replaces the database at path with a backup, the server must not be running
+    pub fn restore(path: &Path, from: &Path) -> Result<(), Error> {
+        {
+            let backup = Connection::open_with_flags(from, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            let integrity: String =
+                backup.pragma_query_value(None, "integrity_check", |row| row.get(0))?;
+            if integrity != "ok" {
+                return Err(Error::Corrupt(integrity));
+            }
+            let version = migrations::schema_version(&backup)?;
+            if version > LATEST_VERSION {
+                return Err(Error::NewerSchema(version, LATEST_VERSION));
+            }
+        }
+        let tmp_path = path.with_extension("restore.tmp");
+        std::fs::copy(from, &tmp_path)?;
+        // Stale write-ahead logs of the old database must not be applied to the restored one
+        for suffix in ["-wal", "-shm"] {
+            let mut journal = path.as_os_str().to_owned();
+            journal.push(suffix);
+            match std::fs::remove_file(&journal) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        std::fs::rename(&tmp_path, path)?;
+        // Brings older backups up to date
+        Self::open(Some(path.to_owned()))?;
+        Ok(())
+    }
+}
+
+pub fn to_json(value: &impl Serialize) -> Result<String, Error> {
+    Ok(serde_json::to_string(value)?)
+}
+
+/// Reads a column containing a JSON document
+pub fn json_column<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let json: String = row.get(index)?;
+    serde_json::from_str(&json)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{LocalUser, LocalUserStore},
+        filesystem::{ChangeKind, ChangeNotifier},
+    };
+    use scoped_fs::ScopedPath;
+
+    #[test]
+    fn revisions_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/wolke.db");
+        let db = Database::open(Some(path.clone())).unwrap();
+        assert_eq!(db.schema_version().unwrap(), LATEST_VERSION);
+        let changes = ChangeNotifier::default()
+            .with_log(ChangeLog::new(db.clone()))
+            .unwrap();
+        let file = ScopedPath::new("notes.txt".to_owned());
+        changes.notify_external("alice", &file, ChangeKind::Created);
+        changes.notify_external("alice", &file, ChangeKind::Modified);
+        let revision = changes.revision("alice");
+        db.checkpoint().unwrap();
+        drop((changes, db));
+
+        let db = Database::open(Some(path)).unwrap();
+        let changes = ChangeNotifier::default()
+            .with_log(ChangeLog::new(db))
+            .unwrap();
+        assert_eq!(changes.revision("alice"), revision);
+    }
+
+    #[test]
+    fn backups_restore_the_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wolke.db");
+        let backup = dir.path().join("backup.db");
+        let users = LocalUserStore::new(Database::open(Some(path.clone())).unwrap());
+        users
+            .insert(LocalUser::new("alice".to_owned(), None, "secret"))
+            .unwrap();
+        let db = Database::open(Some(path.clone())).unwrap();
+        db.backup(&backup).unwrap();
+        // Existing files are never overwritten
+        assert!(db.backup(&backup).is_err());
+        users.remove("alice").unwrap();
+        drop((users, db));
+
+        Database::restore(&path, &backup).unwrap();
+        let users = LocalUserStore::new(Database::open(Some(path.clone())).unwrap());
+        assert!(users.get("alice").unwrap().is_some());
+
+        std::fs::write(&backup, "not a database").unwrap();
+        assert!(Database::restore(&path, &backup).is_err());
+        assert!(users.get("alice").unwrap().is_some());
+    }
+}
@@ -0,0 +1,380 @@
+use crate::db::{self, ChangeLog};
+use scoped_fs::ScopedPath;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    mem::{Discriminant, discriminant},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::broadcast;
+
+// inotify also reports the changes we make ourselves. Their events are dropped until the
+// one of the last step arrived, or for this long after the change if it never does.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+    Moved { to: ScopedPath },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOrigin {
+    /// Made through one of our endpoints
+    Server,
+    /// Picked up by the filesystem watcher
+    External,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub mount: String,
+    pub path: ScopedPath,
+    #[serde(flatten)]
+    pub kind: ChangeKind,
+    pub origin: ChangeOrigin,
+    /// Revision of the mount after this change
+    pub revision: u64,
+}
+
+/// Where changes show up besides the mount they were made in,
+/// like folders shared into the mounts of other users
+pub trait ChangeRouting: std::fmt::Debug + Send + Sync {
+    /// Mount and path a path of a mount is stored at
+    fn resolve(&self, mount: &str, path: &ScopedPath) -> (String, ScopedPath);
+
+    /// Other mounts showing a stored path, with its path there.
+    /// Also reports the shared folders a change to one of their ancestors affects.
+    fn aliases(&self, mount: &str, path: &ScopedPath) -> Vec<(String, ScopedPath)>;
+}
+
+/// Fan-out point for all changes to mounted filesystems.
+/// Every change bumps the revision of its mount which is used for collection ETags.
+#[derive(Debug, Clone)]
+pub struct ChangeNotifier {
+    sender: broadcast::Sender<Change>,
+    // Revisions start at the server start time so they don't repeat across restarts
+    initial_revision: u64,
+    revisions: Arc<RwLock<HashMap<String, u64>>>,
+    /// Paths the server is changing or whose watcher events are still due
+    expected: Arc<Mutex<HashMap<(String, ScopedPath), Expected>>>,
+    log: Option<ChangeLog>,
+    routing: Arc<RwLock<Option<Arc<dyn ChangeRouting>>>>,
+}
+
+#[derive(Debug)]
+struct Expected {
+    /// Server changes to the path in progress
+    active: usize,
+    /// Kind of the watcher event reporting the last step, e.g. the rename into place
+    echo: Discriminant<ChangeKind>,
+    /// Whether that event arrived already
+    echoed: bool,
+    /// When the last change finished
+    finished: Option<Instant>,
+}
+
+impl Expected {
+    /// Drops echoes that never arrived, e.g. since the change failed before its last step
+    fn expire(expected: &mut HashMap<(String, ScopedPath), Expected>) {
+        let now = Instant::now();
+        expected.retain(|_, entry| {
+            entry.active > 0
+                || entry
+                    .finished
+                    .is_none_or(|finished| now.duration_since(finished) < ECHO_TIMEOUT)
+        });
+    }
+}
+
+impl Default for ChangeNotifier {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(1024);
+        let initial_revision = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            sender,
+            initial_revision,
+            revisions: Default::default(),
+            expected: Default::default(),
+            log: None,
+            routing: Default::default(),
+        }
+    }
+}
+
+impl ChangeNotifier {
+    /// Records all changes and continues from the persisted revisions
+    pub fn with_log(mut self, log: ChangeLog) -> Result<Self, db::Error> {
+        // Revisions never go backwards, even if the clock did
+        let revisions = log
+            .revisions()?
+            .into_iter()
+            .map(|(mount, revision)| (mount, revision.max(self.initial_revision)))
+            .collect();
+        self.revisions = Arc::new(RwLock::new(revisions));
+        self.log = Some(log);
+        Ok(self)
+    }
+
+    /// Changes are stored and announced where routing resolves them to,
+    /// and announced again in every mount showing them
+    pub fn set_routing(&self, routing: Arc<dyn ChangeRouting>) {
+        *self.routing.write().unwrap() = Some(routing);
+    }
+
+    fn routing(&self) -> Option<Arc<dyn ChangeRouting>> {
+        self.routing.read().unwrap().clone()
+    }
+
+    fn resolve(&self, mount: &str, path: &ScopedPath) -> (String, ScopedPath) {
+        match self.routing() {
+            Some(routing) => routing.resolve(mount, path),
+            None => (mount.to_owned(), path.to_owned()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.sender.subscribe()
+    }
+
+    pub fn revision(&self, mount: &str) -> u64 {
+        self.revisions
+            .read()
+            .unwrap()
+            .get(mount)
+            .copied()
+            .unwrap_or(self.initial_revision)
+    }
+
+    /// Marks path as being changed by the server until the returned guard is finished or
+    /// dropped. Watcher events for it are dropped until one of the kind of `echo` arrived,
+    /// which must be how the watcher sees the last step of the change.
+    pub fn begin(&self, mount: &str, path: &ScopedPath, echo: ChangeKind) -> ServerChange {
+        // The watcher reports where the change is stored
+        let key = self.resolve(mount, path);
+        let mut expected = self.expected.lock().unwrap();
+        Expected::expire(&mut expected);
+        let entry = expected.entry(key.clone()).or_insert(Expected {
+            active: 0,
+            echo: discriminant(&echo),
+            echoed: false,
+            finished: None,
+        });
+        entry.active += 1;
+        entry.echo = discriminant(&echo);
+        entry.echoed = false;
+        ServerChange {
+            changes: self.clone(),
+            key,
+            mount: mount.to_owned(),
+            finished: false,
+        }
+    }
+
+    fn end(&self, key: &(String, ScopedPath)) {
+        let mut expected = self.expected.lock().unwrap();
+        let Some(entry) = expected.get_mut(key) else {
+            return;
+        };
+        entry.active -= 1;
+        if entry.active == 0 && entry.echoed {
+            expected.remove(key);
+        } else {
+            entry.finished = Some(Instant::now());
+        }
+    }
+
+    pub fn notify_external(&self, mount: &str, path: &ScopedPath, kind: ChangeKind) {
+        {
+            let mut expected = self.expected.lock().unwrap();
+            Expected::expire(&mut expected);
+            let key = (mount.to_owned(), path.to_owned());
+            if let Some(entry) = expected.get_mut(&key) {
+                if entry.echo == discriminant(&kind) {
+                    if entry.active == 0 {
+                        // Everything after the last step was made by someone else
+                        expected.remove(&key);
+                    } else {
+                        entry.echoed = true;
+                    }
+                }
+                return;
+            }
+        }
+        self.send(mount, path, kind, ChangeOrigin::External);
+    }
+
+    /// Sends a change of a stored path along with its aliases in other mounts
+    fn send(&self, mount: &str, path: &ScopedPath, kind: ChangeKind, origin: ChangeOrigin) {
+        let aliases = self
+            .routing()
+            .map(|routing| alias_changes(routing.as_ref(), mount, path, &kind))
+            .unwrap_or_default();
+        self.send_one(mount, path, kind, origin);
+        for (mount, path, kind) in aliases {
+            self.send_one(&mount, &path, kind, origin);
+        }
+    }
+
+    fn send_one(&self, mount: &str, path: &ScopedPath, kind: ChangeKind, origin: ChangeOrigin) {
+        let revision = {
+            let mut revisions = self.revisions.write().unwrap();
+            let revision = revisions
+                .entry(mount.to_owned())
+                .or_insert(self.initial_revision);
+            *revision += 1;
+            *revision
+        };
+        tracing::debug!(mount, path = path.as_str(), ?kind, ?origin, "change");
+        let change = Change {
+            mount: mount.to_owned(),
+            path: path.to_owned(),
+            kind,
+            origin,
+            revision,
+        };
+        if let Some(log) = &self.log
+            && let Err(err) = log.append(&change)
+        {
+            tracing::error!("Failed to record change: {err}");
+        }
+        // Sending only fails if nobody is subscribed
+        let _ = self.sender.send(change);
+    }
+}
+
+/// How a change looks in the mounts showing the changed path.
+/// Moves out of a shared folder are deletions for its recipients, moves into it creations.
+fn alias_changes(
+    routing: &dyn ChangeRouting,
+    mount: &str,
+    path: &ScopedPath,
+    kind: &ChangeKind,
+) -> Vec<(String, ScopedPath, ChangeKind)> {
+    let aliases = routing.aliases(mount, path);
+    let ChangeKind::Moved { to } = kind else {
+        return aliases
+            .into_iter()
+            .map(|(mount, path)| (mount, path, kind.clone()))
+            .collect();
+    };
+    let folder = |path: &ScopedPath| {
+        path.as_str()
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let mut targets = routing.aliases(mount, to);
+    let mut changes = vec![];
+    for (alias_mount, alias_path) in aliases {
+        let target = targets.iter().position(|(target_mount, target_path)| {
+            *target_mount == alias_mount && folder(target_path) == folder(&alias_path)
+        });
+        let kind = match target {
+            Some(index) => ChangeKind::Moved {
+                to: targets.remove(index).1,
+            },
+            None => ChangeKind::Deleted,
+        };
+        changes.push((alias_mount, alias_path, kind));
+    }
+    changes.extend(
+        targets
+            .into_iter()
+            .map(|(mount, path)| (mount, path, ChangeKind::Created)),
+    );
+    changes
+}
+
+/// A change the server is making, see [ChangeNotifier::begin]
+#[must_use]
+pub struct ServerChange {
+    changes: ChangeNotifier,
+    /// Where the change is stored
+    key: (String, ScopedPath),
+    /// Mount the change was made through
+    mount: String,
+    finished: bool,
+}
+
+impl ServerChange {
+    /// Announces the completed change
+    pub fn finish(mut self, kind: ChangeKind) {
+        self.finished = true;
+        self.changes.end(&self.key);
+        let kind = match kind {
+            ChangeKind::Moved { to } => ChangeKind::Moved {
+                to: self.changes.resolve(&self.mount, &to).1,
+            },
+            kind => kind,
+        };
+        let (mount, path) = &self.key;
+        self.changes.send(mount, path, kind, ChangeOrigin::Server);
+    }
+}
+
+impl Drop for ServerChange {
+    /// The change failed, nothing is announced
+    fn drop(&mut self) {
+        if !self.finished {
+            self.changes.end(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> ScopedPath {
+        ScopedPath::from_relative(std::path::Path::new(path)).unwrap()
+    }
+
+    #[test]
+    fn server_changes_are_not_echoed() {
+        let changes = ChangeNotifier::default();
+        let mut receiver = changes.subscribe();
+        let file = path("notes.txt");
+
+        let change = changes.begin("alice", &file, ChangeKind::Created);
+        // Events of the upload in progress
+        changes.notify_external("alice", &file, ChangeKind::Modified);
+        change.finish(ChangeKind::Created);
+        // The rename into place only arrives after the request finished
+        changes.notify_external("alice", &file, ChangeKind::Created);
+        // Anything after is someone else's
+        changes.notify_external("alice", &file, ChangeKind::Modified);
+
+        assert_eq!(receiver.try_recv().unwrap().origin, ChangeOrigin::Server);
+        let external = receiver.try_recv().unwrap();
+        assert_eq!(external.origin, ChangeOrigin::External);
+        assert_eq!(external.kind, ChangeKind::Modified);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(changes.revision("alice"), changes.initial_revision + 2);
+    }
+
+    #[test]
+    fn echo_before_the_change_finished() {
+        let changes = ChangeNotifier::default();
+        let mut receiver = changes.subscribe();
+        let file = path("notes.txt");
+
+        let change = changes.begin("alice", &file, ChangeKind::Deleted);
+        changes.notify_external("alice", &file, ChangeKind::Deleted);
+        change.finish(ChangeKind::Deleted);
+        changes.notify_external("alice", &file, ChangeKind::Created);
+
+        assert_eq!(receiver.try_recv().unwrap().origin, ChangeOrigin::Server);
+        assert_eq!(receiver.try_recv().unwrap().origin, ChangeOrigin::External);
+        assert!(receiver.try_recv().is_err());
+    }
+}
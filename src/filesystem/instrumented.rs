@@ -0,0 +1,125 @@
+use super::{ChangeNotifier, Error, Filesystem, FilesystemProvider, PendingFile};
+use crate::metrics::Metrics;
+use async_trait::async_trait;
+use scoped_fs::ScopedPath;
+use std::{path::Path, time::Instant};
+
+/// Records the latency of every filesystem operation
+#[derive(Clone)]
+pub struct InstrumentedFilesystemProvider<P: FilesystemProvider> {
+    inner: P,
+    metrics: Metrics,
+}
+
+impl<P: FilesystemProvider> InstrumentedFilesystemProvider<P> {
+    pub fn new(inner: P, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl<P: FilesystemProvider> FilesystemProvider for InstrumentedFilesystemProvider<P> {
+    type FS = InstrumentedFilesystem<P::FS>;
+
+    fn changes(&self) -> &ChangeNotifier {
+        self.inner.changes()
+    }
+
+    async fn get_filesystem(&self, mount: &str) -> Result<Self::FS, Error> {
+        Ok(InstrumentedFilesystem {
+            inner: self.inner.get_filesystem(mount).await?,
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    async fn create_mount(&self, mount: &str) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.inner.create_mount(mount).await;
+        self.metrics.observe_fs("create_mount", start);
+        result
+    }
+}
+
+#[derive(Clone)]
+pub struct InstrumentedFilesystem<FS: Filesystem> {
+    inner: FS,
+    metrics: Metrics,
+}
+
+#[async_trait]
+impl<FS: Filesystem> Filesystem for InstrumentedFilesystem<FS> {
+    type FileReader = FS::FileReader;
+    type Metadata = FS::Metadata;
+
+    async fn metadata(&self, path: &ScopedPath) -> Result<Self::Metadata, Error> {
+        let start = Instant::now();
+        let result = self.inner.metadata(path).await;
+        self.metrics.observe_fs("metadata", start);
+        result
+    }
+
+    async fn get_file(&self, path: &ScopedPath) -> Result<Self::FileReader, Error> {
+        let start = Instant::now();
+        let result = self.inner.get_file(path).await;
+        self.metrics.observe_fs("get_file", start);
+        result
+    }
+
+    async fn delete_file(&self, path: &ScopedPath) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.inner.delete_file(path).await;
+        self.metrics.observe_fs("delete_file", start);
+        result
+    }
+
+    async fn list_dir(&self, path: &ScopedPath) -> Result<Vec<ScopedPath>, Error> {
+        let start = Instant::now();
+        let result = self
+            .inner
+            .list_dir(path)
+            .await
+            .map(|entries| entries.into_iter().collect());
+        self.metrics.observe_fs("list_dir", start);
+        result
+    }
+
+    async fn create_dir(&self, path: &ScopedPath) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.inner.create_dir(path).await;
+        self.metrics.observe_fs("create_dir", start);
+        result
+    }
+
+    async fn create_file(&self, path: &ScopedPath) -> Result<PendingFile, Error> {
+        let start = Instant::now();
+        let result = self.inner.create_file(path).await;
+        self.metrics.observe_fs("create_file", start);
+        result
+    }
+
+    async fn copy(
+        &self,
+        from: &ScopedPath,
+        to: &ScopedPath,
+        overwrite: bool,
+    ) -> Result<bool, Error> {
+        let start = Instant::now();
+        let result = self.inner.copy(from, to, overwrite).await;
+        self.metrics.observe_fs("copy", start);
+        result
+    }
+
+    async fn mv(&self, from: &ScopedPath, to: &ScopedPath, overwrite: bool) -> Result<bool, Error> {
+        let start = Instant::now();
+        let result = self.inner.mv(from, to, overwrite).await;
+        self.metrics.observe_fs("move", start);
+        result
+    }
+
+    async fn import_file(&self, source: &Path, to: &ScopedPath) -> Result<bool, Error> {
+        let start = Instant::now();
+        let result = self.inner.import_file(source, to).await;
+        self.metrics.observe_fs("import_file", start);
+        result
+    }
+}
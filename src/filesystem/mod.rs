@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+pub use changes::{Change, ChangeKind, ChangeNotifier, ChangeRouting};
 use futures::Stream;
 use http::StatusCode;
 use scoped_fs::ScopedPath;
@@ -6,13 +7,22 @@ use std::fs::DirEntry;
 use std::time::SystemTime;
 use std::{
     cmp,
-    fs::File,
     io::{Read, SeekFrom},
-    path::PathBuf,
+    path::{Path, PathBuf},
     pin::Pin,
     task::{Context, Poll},
 };
 
+mod changes;
+mod instrumented;
+mod pending;
+mod sharing;
+mod watcher;
+pub use instrumented::InstrumentedFilesystemProvider;
+pub use pending::PendingFile;
+pub use sharing::SharingFilesystemProvider;
+pub use watcher::watch_root;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -21,6 +31,13 @@ pub enum Error {
     NotFound,
     #[error("Conflict")]
     Conflict,
+    #[error("Forbidden")]
+    Forbidden,
+    /// Source and destination live in different filesystems
+    #[error("Cannot move or copy between mounts")]
+    CrossMount,
+    #[error(transparent)]
+    Database(#[from] crate::db::Error),
 }
 
 impl From<std::io::Error> for Error {
@@ -39,6 +56,9 @@ impl Error {
             Self::IO(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::NotFound => StatusCode::NOT_FOUND,
             Self::Conflict => StatusCode::CONFLICT,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::CrossMount => StatusCode::FORBIDDEN,
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -47,7 +67,10 @@ impl Error {
 pub trait FilesystemProvider: Clone + Send + Sync + 'static {
     type FS: Filesystem;
 
+    fn changes(&self) -> &ChangeNotifier;
     async fn get_filesystem(&self, mount: &str) -> Result<Self::FS, Error>;
+    /// Creates an empty mount if it doesn't exist yet
+    async fn create_mount(&self, mount: &str) -> Result<(), Error>;
 }
 
 #[async_trait]
@@ -108,6 +131,10 @@ pub trait DavMetadata: Clone + Send + Sync + 'static {
     fn modified(&self) -> SystemTime;
     fn created(&self) -> SystemTime;
     fn is_dir(&self) -> bool;
+    /// Shared with read access only
+    fn is_read_only(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -123,7 +150,8 @@ pub trait Filesystem: Clone + Send + Sync + 'static {
         path: &ScopedPath,
     ) -> Result<impl IntoIterator<Item = ScopedPath>, Error>;
     async fn create_dir(&self, path: &ScopedPath) -> Result<(), Error>;
-    async fn create_file(&self, path: &ScopedPath) -> Result<File, Error>;
+    /// The file only appears at path once committed
+    async fn create_file(&self, path: &ScopedPath) -> Result<PendingFile, Error>;
     async fn copy(
         &self,
         from: &ScopedPath,
@@ -131,16 +159,25 @@ pub trait Filesystem: Clone + Send + Sync + 'static {
         overwrite: bool,
     ) -> Result<bool, Error>;
     async fn mv(&self, from: &ScopedPath, to: &ScopedPath, overwrite: bool) -> Result<bool, Error>;
+    /// Atomically moves a file from outside the filesystem into it, replacing existing files.
+    /// Returns whether the target existed.
+    async fn import_file(&self, source: &Path, to: &ScopedPath) -> Result<bool, Error>;
+}
+
+/// A single visible directory below the root
+pub fn is_valid_mount_name(mount: &str) -> bool {
+    !mount.is_empty() && !mount.starts_with('.') && !mount.contains(['/', '\\'])
 }
 
 #[derive(Clone)]
 pub struct SimpleFilesystemProvider {
     root_path: PathBuf,
+    changes: ChangeNotifier,
 }
 
 impl SimpleFilesystemProvider {
-    pub fn new(root_path: PathBuf) -> Self {
-        Self { root_path }
+    pub fn new(root_path: PathBuf, changes: ChangeNotifier) -> Self {
+        Self { root_path, changes }
     }
 }
 
@@ -148,13 +185,26 @@ impl SimpleFilesystemProvider {
 impl FilesystemProvider for SimpleFilesystemProvider {
     type FS = SimpleFilesystem;
 
+    fn changes(&self) -> &ChangeNotifier {
+        &self.changes
+    }
+
     async fn get_filesystem(&self, mount: &str) -> Result<Self::FS, Error> {
-        let sub_path = self.root_path.join(mount);
-        assert!(sub_path.starts_with(&self.root_path));
+        // Mount names come from URLs, .. would leave the root
+        if !is_valid_mount_name(mount) {
+            return Err(Error::NotFound);
+        }
         Ok(SimpleFilesystem {
             root_path: self.root_path.join(mount),
         })
     }
+
+    async fn create_mount(&self, mount: &str) -> Result<(), Error> {
+        if !is_valid_mount_name(mount) {
+            return Err(Error::Forbidden);
+        }
+        Ok(std::fs::create_dir_all(self.root_path.join(mount))?)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -221,7 +271,10 @@ impl Filesystem for SimpleFilesystem {
         Ok(std::fs::read_dir(&ospath)?
             .collect::<Result<Vec<DirEntry>, _>>()?
             .into_iter()
-            .map(|entry| path.join_segment(entry.file_name().to_str().unwrap()))
+            // Uploads in progress only show up once committed
+            .filter(|entry| !pending::is_tmp_path(&entry.path()))
+            // Paths are UTF-8, other names can't be addressed anyway
+            .filter_map(|entry| Some(path.join_segment(entry.file_name().to_str()?)))
             .collect())
     }
 
@@ -230,9 +283,9 @@ impl Filesystem for SimpleFilesystem {
         Ok(std::fs::create_dir(&ospath)?)
     }
 
-    async fn create_file(&self, path: &ScopedPath) -> Result<File, Error> {
+    async fn create_file(&self, path: &ScopedPath) -> Result<PendingFile, Error> {
         let ospath = path.with_base(&self.root_path);
-        Ok(File::create(ospath)?)
+        Ok(PendingFile::create(ospath)?)
     }
 
     async fn copy(
@@ -247,7 +300,14 @@ impl Filesystem for SimpleFilesystem {
         if exists && !overwrite {
             return Err(Error::Conflict);
         }
-        std::fs::copy(&ospath_from, &ospath_to)?;
+        // Copied next to the target first so it's never seen half-written
+        let tmp_path = pending::tmp_path(&ospath_to);
+        if let Err(err) = std::fs::copy(&ospath_from, &tmp_path)
+            .and_then(|_| std::fs::rename(&tmp_path, &ospath_to))
+        {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
         Ok(exists)
     }
 
@@ -261,4 +321,25 @@ impl Filesystem for SimpleFilesystem {
         std::fs::rename(&ospath_from, &ospath_to)?;
         Ok(exists)
     }
+
+    async fn import_file(&self, source: &Path, to: &ScopedPath) -> Result<bool, Error> {
+        let ospath = to.with_base(&self.root_path);
+        let exists = ospath.exists();
+        match std::fs::rename(source, &ospath) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                // Copy next to the target first so it's never seen half-written
+                let tmp_path = pending::tmp_path(&ospath);
+                if let Err(err) = std::fs::copy(source, &tmp_path)
+                    .and_then(|_| std::fs::rename(&tmp_path, &ospath))
+                {
+                    let _ = std::fs::remove_file(&tmp_path);
+                    return Err(err.into());
+                }
+                std::fs::remove_file(source)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+        Ok(exists)
+    }
 }
@@ -0,0 +1,77 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const TMP_SUFFIX: &str = ".wolke-tmp";
+
+/// Hidden sibling of path that content is written to before it's renamed into place
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.{}{TMP_SUFFIX}", uuid::Uuid::new_v4()))
+}
+
+pub fn is_tmp_path(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(TMP_SUFFIX))
+}
+
+/// A file that only replaces its target once committed.
+/// Dropping it before, e.g. when an upload is aborted, removes what was written so far.
+pub struct PendingFile {
+    file: File,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl PendingFile {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        let tmp_path = tmp_path(&path);
+        Ok(Self {
+            file: File::create(&tmp_path)?,
+            tmp_path,
+            path,
+            committed: false,
+        })
+    }
+
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+
+    /// Like commit, but fails with AlreadyExists instead of replacing a file at the target,
+    /// also one that was created while writing
+    pub fn commit_new(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        std::fs::hard_link(&self.tmp_path, &self.path)?;
+        std::fs::remove_file(&self.tmp_path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for PendingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for PendingFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
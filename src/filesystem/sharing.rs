@@ -0,0 +1,554 @@
+//! Folders shared by other users appear as collections in the root of the recipient's mount.
+//! Operations inside them are routed to the owner's filesystem.
+use super::{
+    ChangeNotifier, ChangeRouting, DavMetadata, Error, Filesystem, FilesystemProvider, PendingFile,
+};
+use crate::shares::{Grant, GrantPrivilege, GrantStore};
+use async_trait::async_trait;
+use scoped_fs::ScopedPath;
+use std::{path::Path, sync::Arc, time::SystemTime};
+
+#[derive(Clone)]
+pub struct SharingFilesystemProvider<P: FilesystemProvider> {
+    inner: P,
+    grants: GrantStore,
+}
+
+impl<P: FilesystemProvider> SharingFilesystemProvider<P> {
+    pub fn new(inner: P, grants: GrantStore) -> Self {
+        inner
+            .changes()
+            .set_routing(Arc::new(GrantRouting(grants.clone())));
+        Self { inner, grants }
+    }
+}
+
+/// Changes inside shared folders are stored in the owner's mount
+/// and show up in the mount of every recipient
+#[derive(Debug)]
+struct GrantRouting(GrantStore);
+
+impl GrantRouting {
+    fn mounted_in(&self, mount: &str) -> Vec<Grant> {
+        self.0.mounted_in(mount).unwrap_or_else(|err| {
+            tracing::warn!("Failed to load the grants of {mount}: {err}");
+            vec![]
+        })
+    }
+}
+
+impl ChangeRouting for GrantRouting {
+    fn resolve(&self, mount: &str, path: &ScopedPath) -> (String, ScopedPath) {
+        let (first, rest) = path.as_str().split_once('/').unwrap_or((path.as_str(), ""));
+        match self
+            .mounted_in(mount)
+            .into_iter()
+            .find(|grant| !first.is_empty() && grant.name == first)
+        {
+            Some(grant) => {
+                let path = match rest {
+                    "" => grant.path,
+                    rest => grant.path.join_segment(rest),
+                };
+                (grant.mount, path)
+            }
+            None => (mount.to_owned(), path.to_owned()),
+        }
+    }
+
+    fn aliases(&self, mount: &str, path: &ScopedPath) -> Vec<(String, ScopedPath)> {
+        let grants = self.0.shared_from(mount).unwrap_or_else(|err| {
+            tracing::warn!("Failed to load the grants of {mount}: {err}");
+            vec![]
+        });
+        let mut aliases = vec![];
+        for grant in grants {
+            let Some(shared) = shared_path(&grant, path) else {
+                continue;
+            };
+            for recipient in self.0.recipients(&grant) {
+                // Only the grant mounted under its name shows the change
+                if self
+                    .mounted_in(&recipient)
+                    .iter()
+                    .any(|mounted| mounted.id == grant.id)
+                {
+                    aliases.push((recipient, shared.clone()));
+                }
+            }
+        }
+        aliases
+    }
+}
+
+/// Path of a changed path in the mount of a grant's recipients.
+/// A change to an ancestor of the shared folder affects the whole collection.
+fn shared_path(grant: &Grant, path: &ScopedPath) -> Option<ScopedPath> {
+    let name = ScopedPath::new(grant.name.to_owned());
+    if path.starts_with(&grant.path) {
+        let rest = path.as_str()[grant.path.as_str().len()..].trim_start_matches('/');
+        Some(match rest {
+            "" => name,
+            rest => name.join_segment(rest),
+        })
+    } else if grant.path.starts_with(path) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+#[async_trait]
+impl<P: FilesystemProvider> FilesystemProvider for SharingFilesystemProvider<P> {
+    type FS = SharingFilesystem<P::FS>;
+
+    fn changes(&self) -> &ChangeNotifier {
+        self.inner.changes()
+    }
+
+    async fn get_filesystem(&self, mount: &str) -> Result<Self::FS, Error> {
+        let own = self.inner.get_filesystem(mount).await?;
+        let mut mounted = vec![];
+        for grant in self.grants.mounted_in(mount)? {
+            mounted.push(Mounted {
+                fs: self.inner.get_filesystem(&grant.mount).await?,
+                name: grant.name,
+                base: grant.path,
+                writable: grant.privilege == GrantPrivilege::Write,
+            });
+        }
+        Ok(SharingFilesystem {
+            own,
+            mounted: Arc::new(mounted),
+        })
+    }
+
+    async fn create_mount(&self, mount: &str) -> Result<(), Error> {
+        self.inner.create_mount(mount).await
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mounted<FS: Filesystem> {
+    /// Name of the collection in the recipient's mount root
+    name: String,
+    fs: FS,
+    /// Shared path in the owner's filesystem
+    base: ScopedPath,
+    writable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SharingFilesystem<FS: Filesystem> {
+    own: FS,
+    mounted: Arc<Vec<Mounted<FS>>>,
+}
+
+struct Route<'a, FS: Filesystem> {
+    fs: &'a FS,
+    path: ScopedPath,
+    /// Index into mounted, None for the mount's own files
+    target: Option<usize>,
+    writable: bool,
+    /// The root of a shared folder, it can't be deleted or moved by the recipient
+    is_mount_point: bool,
+}
+
+impl<FS: Filesystem> Route<'_, FS> {
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(Error::Forbidden)
+        }
+    }
+}
+
+impl<FS: Filesystem> SharingFilesystem<FS> {
+    fn route(&self, path: &ScopedPath) -> Route<'_, FS> {
+        let (first, rest) = path.as_str().split_once('/').unwrap_or((path.as_str(), ""));
+        if !first.is_empty()
+            && let Some((index, mounted)) = self
+                .mounted
+                .iter()
+                .enumerate()
+                .find(|(_, mounted)| mounted.name == first)
+        {
+            return Route {
+                fs: &mounted.fs,
+                path: match rest {
+                    "" => mounted.base.to_owned(),
+                    rest => mounted.base.join_segment(rest),
+                },
+                target: Some(index),
+                writable: mounted.writable,
+                is_mount_point: rest.is_empty(),
+            };
+        }
+        Route {
+            fs: &self.own,
+            path: path.to_owned(),
+            target: None,
+            writable: true,
+            is_mount_point: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SharingMetadata<M: DavMetadata> {
+    inner: M,
+    read_only: bool,
+}
+
+impl<M: DavMetadata> DavMetadata for SharingMetadata<M> {
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn modified(&self) -> SystemTime {
+        self.inner.modified()
+    }
+
+    fn created(&self) -> SystemTime {
+        self.inner.created()
+    }
+
+    fn is_dir(&self) -> bool {
+        self.inner.is_dir()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem> Filesystem for SharingFilesystem<FS> {
+    type FileReader = FS::FileReader;
+    type Metadata = SharingMetadata<FS::Metadata>;
+
+    async fn metadata(&self, path: &ScopedPath) -> Result<Self::Metadata, Error> {
+        let route = self.route(path);
+        Ok(SharingMetadata {
+            inner: route.fs.metadata(&route.path).await?,
+            read_only: !route.writable,
+        })
+    }
+
+    async fn get_file(&self, path: &ScopedPath) -> Result<Self::FileReader, Error> {
+        let route = self.route(path);
+        route.fs.get_file(&route.path).await
+    }
+
+    async fn delete_file(&self, path: &ScopedPath) -> Result<(), Error> {
+        let route = self.route(path);
+        if route.is_mount_point {
+            return Err(Error::Forbidden);
+        }
+        route.check_writable()?;
+        route.fs.delete_file(&route.path).await
+    }
+
+    async fn list_dir(&self, path: &ScopedPath) -> Result<Vec<ScopedPath>, Error> {
+        let route = self.route(path);
+        let entries = route.fs.list_dir(&route.path).await?.into_iter();
+        let Some(index) = route.target else {
+            let mut entries: Vec<_> = entries
+                .filter(|entry| {
+                    !path.is_root()
+                        || !self
+                            .mounted
+                            .iter()
+                            .any(|mounted| mounted.name == entry.file_name())
+                })
+                .collect();
+            if path.is_root() {
+                for mounted in self.mounted.iter() {
+                    // The owner might have deleted the shared folder
+                    if mounted.fs.metadata(&mounted.base).await.is_ok() {
+                        entries.push(ScopedPath::new(mounted.name.to_owned()));
+                    }
+                }
+            }
+            return Ok(entries);
+        };
+        // Map the owner's paths back into the recipient's mount
+        let mounted = &self.mounted[index];
+        Ok(entries
+            .map(|entry| {
+                let relative = entry
+                    .as_str()
+                    .strip_prefix(mounted.base.as_str())
+                    .unwrap_or_default()
+                    .trim_start_matches('/');
+                ScopedPath::new(mounted.name.to_owned()).join_segment(relative)
+            })
+            .collect())
+    }
+
+    async fn create_dir(&self, path: &ScopedPath) -> Result<(), Error> {
+        let route = self.route(path);
+        if route.is_mount_point {
+            return Err(Error::Conflict);
+        }
+        route.check_writable()?;
+        route.fs.create_dir(&route.path).await
+    }
+
+    async fn create_file(&self, path: &ScopedPath) -> Result<PendingFile, Error> {
+        let route = self.route(path);
+        if route.is_mount_point {
+            return Err(Error::Conflict);
+        }
+        route.check_writable()?;
+        route.fs.create_file(&route.path).await
+    }
+
+    async fn copy(
+        &self,
+        from: &ScopedPath,
+        to: &ScopedPath,
+        overwrite: bool,
+    ) -> Result<bool, Error> {
+        let (from, to) = (self.route(from), self.route(to));
+        if from.target != to.target {
+            return Err(Error::CrossMount);
+        }
+        if to.is_mount_point {
+            return Err(Error::Forbidden);
+        }
+        to.check_writable()?;
+        to.fs.copy(&from.path, &to.path, overwrite).await
+    }
+
+    async fn mv(&self, from: &ScopedPath, to: &ScopedPath, overwrite: bool) -> Result<bool, Error> {
+        let (from, to) = (self.route(from), self.route(to));
+        if from.target != to.target {
+            return Err(Error::CrossMount);
+        }
+        if from.is_mount_point || to.is_mount_point {
+            return Err(Error::Forbidden);
+        }
+        from.check_writable()?;
+        to.fs.mv(&from.path, &to.path, overwrite).await
+    }
+
+    async fn import_file(&self, source: &Path, to: &ScopedPath) -> Result<bool, Error> {
+        let route = self.route(to);
+        if route.is_mount_point {
+            return Err(Error::Conflict);
+        }
+        route.check_writable()?;
+        route.fs.import_file(source, &route.path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::GroupConfig,
+        db::Database,
+        filesystem::{Change, ChangeKind, SimpleFilesystemProvider},
+        groups::GroupStore,
+        shares::Grantee,
+    };
+    use chrono::Utc;
+    use std::{collections::HashMap, io::Write};
+    use tempfile::TempDir;
+
+    fn path(path: &str) -> ScopedPath {
+        ScopedPath::new(path.to_owned())
+    }
+
+    fn grant(id: &str, path: &str, grantee: Grantee, privilege: GrantPrivilege) -> Grant {
+        Grant {
+            id: id.to_owned(),
+            owner: "alice".to_owned(),
+            mount: "alice".to_owned(),
+            path: self::path(path),
+            grantee,
+            privilege,
+            name: id.to_owned(),
+            created: Utc::now(),
+        }
+    }
+
+    /// Alice shares docs read-only with bob and inbox writable with the team of bob and carol
+    fn provider() -> (TempDir, SharingFilesystemProvider<SimpleFilesystemProvider>) {
+        let root = tempfile::tempdir().unwrap();
+        for dir in ["alice/docs", "alice/inbox", "alice/private", "bob", "carol"] {
+            std::fs::create_dir_all(root.path().join(dir)).unwrap();
+        }
+        std::fs::write(root.path().join("alice/docs/a.txt"), "a").unwrap();
+        let team = GroupConfig {
+            displayname: None,
+            members: vec!["bob".to_owned(), "carol".to_owned()],
+        };
+        let groups = GroupStore::from_config(&HashMap::from([("team".to_owned(), team)]));
+        let grants = GrantStore::new(Database::open(None).unwrap(), groups);
+        let docs = grant(
+            "Docs",
+            "docs",
+            Grantee::User("bob".to_owned()),
+            GrantPrivilege::Read,
+        );
+        grants.insert(docs).unwrap();
+        let inbox = grant(
+            "Inbox",
+            "inbox",
+            Grantee::Group("team".to_owned()),
+            GrantPrivilege::Write,
+        );
+        grants.insert(inbox).unwrap();
+        let inner =
+            SimpleFilesystemProvider::new(root.path().to_owned(), ChangeNotifier::default());
+        (root, SharingFilesystemProvider::new(inner, grants))
+    }
+
+    fn received(
+        receiver: &mut tokio::sync::broadcast::Receiver<Change>,
+    ) -> Vec<(String, String, ChangeKind)> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|change| (change.mount, change.path.as_str().to_owned(), change.kind))
+            .collect()
+    }
+
+    fn change(mount: &str, path: &str, kind: ChangeKind) -> (String, String, ChangeKind) {
+        (mount.to_owned(), path.to_owned(), kind)
+    }
+
+    #[tokio::test]
+    async fn shared_folders_route_to_the_owner() {
+        let (root, provider) = provider();
+        let bob = provider.get_filesystem("bob").await.unwrap();
+        let mut names: Vec<_> = bob.list_dir(&path("")).await.unwrap().into_iter().collect();
+        names.sort();
+        assert_eq!(names, [path("Docs"), path("Inbox")]);
+        let files: Vec<_> = bob
+            .list_dir(&path("Docs"))
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(files, [path("Docs/a.txt")]);
+        assert!(
+            bob.metadata(&path("Docs/a.txt"))
+                .await
+                .unwrap()
+                .is_read_only()
+        );
+
+        let mut file = bob.create_file(&path("Inbox/b.txt")).await.unwrap();
+        file.write_all(b"b").unwrap();
+        file.commit().unwrap();
+        assert!(root.path().join("alice/inbox/b.txt").is_file());
+        // Alice doesn't share with herself
+        let alice = provider.get_filesystem("alice").await.unwrap();
+        let names: Vec<_> = alice
+            .list_dir(&path(""))
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(names.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn read_only_grants_reject_writes() {
+        let (root, provider) = provider();
+        let bob = provider.get_filesystem("bob").await.unwrap();
+        assert!(matches!(
+            bob.create_file(&path("Docs/b.txt")).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            bob.create_dir(&path("Docs/sub")).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            bob.delete_file(&path("Docs/a.txt")).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            bob.mv(&path("Docs/a.txt"), &path("Docs/b.txt"), false)
+                .await,
+            Err(Error::Forbidden)
+        ));
+        assert!(root.path().join("alice/docs/a.txt").is_file());
+    }
+
+    #[tokio::test]
+    async fn mount_points_cannot_be_replaced() {
+        let (root, provider) = provider();
+        let bob = provider.get_filesystem("bob").await.unwrap();
+        assert!(matches!(
+            bob.delete_file(&path("Inbox")).await,
+            Err(Error::Forbidden)
+        ));
+        // Renaming leaves the owner's filesystem
+        assert!(matches!(
+            bob.mv(&path("Inbox"), &path("Renamed"), false).await,
+            Err(Error::CrossMount)
+        ));
+        assert!(matches!(
+            bob.mv(&path("Inbox"), &path("Inbox"), true).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            bob.create_dir(&path("Inbox")).await,
+            Err(Error::Conflict)
+        ));
+        assert!(root.path().join("alice/inbox").is_dir());
+    }
+
+    #[test]
+    fn changes_show_up_for_every_recipient() {
+        let (_root, provider) = provider();
+        let changes = provider.changes();
+        let mut receiver = changes.subscribe();
+
+        // A change by the owner is announced in the recipients' mounts
+        changes.notify_external("alice", &path("inbox/n.txt"), ChangeKind::Created);
+        assert_eq!(
+            received(&mut receiver),
+            [
+                change("alice", "inbox/n.txt", ChangeKind::Created),
+                change("bob", "Inbox/n.txt", ChangeKind::Created),
+                change("carol", "Inbox/n.txt", ChangeKind::Created),
+            ]
+        );
+
+        // A change by a recipient is stored under the owner's mount and its echo suppressed
+        let server = changes.begin("bob", &path("Inbox/n.txt"), ChangeKind::Deleted);
+        changes.notify_external("alice", &path("inbox/n.txt"), ChangeKind::Deleted);
+        server.finish(ChangeKind::Deleted);
+        assert_eq!(
+            received(&mut receiver),
+            [
+                change("alice", "inbox/n.txt", ChangeKind::Deleted),
+                change("bob", "Inbox/n.txt", ChangeKind::Deleted),
+                change("carol", "Inbox/n.txt", ChangeKind::Deleted),
+            ]
+        );
+        assert_eq!(changes.revision("bob"), changes.revision("alice"));
+
+        // Moving out of a shared folder deletes the file for its recipients
+        let moved = ChangeKind::Moved {
+            to: path("private/a.txt"),
+        };
+        changes.notify_external("alice", &path("docs/a.txt"), moved.clone());
+        assert_eq!(
+            received(&mut receiver),
+            [
+                change("alice", "docs/a.txt", moved),
+                change("bob", "Docs/a.txt", ChangeKind::Deleted),
+            ]
+        );
+
+        // Other files stay private
+        changes.notify_external("alice", &path("private/a.txt"), ChangeKind::Modified);
+        assert_eq!(received(&mut receiver).len(), 1);
+    }
+}
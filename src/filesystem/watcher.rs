@@ -0,0 +1,235 @@
+use super::{
+    changes::{ChangeKind, ChangeNotifier},
+    pending::is_tmp_path,
+};
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher, WatcherKind,
+    event::{AccessKind, AccessMode, MetadataKind, ModifyKind, RenameMode},
+};
+use scoped_fs::ScopedPath;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The halves of a rename inside the root arrive right after each other
+const RENAME_PAIRING: Duration = Duration::from_millis(200);
+
+/// Watches the root of a SimpleFilesystemProvider for changes not made through Wolke.
+/// The returned watcher must be kept alive for as long as events should be delivered.
+pub fn watch_root(
+    root_path: PathBuf,
+    changes: ChangeNotifier,
+) -> notify::Result<RecommendedWatcher> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // Only fails once the receiving task is gone
+        let _ = tx.send(event);
+    })?;
+    watcher.watch(&root_path, RecursiveMode::Recursive)?;
+
+    tokio::spawn(async move {
+        let mut handler = EventHandler {
+            root_path,
+            changes,
+            rename_from: None,
+            last_removed: None,
+            reports_close: RecommendedWatcher::kind() == WatcherKind::Inotify,
+        };
+        loop {
+            let event = if handler.rename_from.is_some() {
+                match tokio::time::timeout(RENAME_PAIRING, rx.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        // Moved out of the root
+                        handler.flush_rename();
+                        continue;
+                    }
+                }
+            } else {
+                rx.recv().await
+            };
+            match event {
+                Some(Ok(event)) => handler.handle(event),
+                Some(Err(err)) => tracing::warn!("filesystem watcher error: {err}"),
+                None => break,
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+/// Splits an absolute path below root into mount and path inside the mount
+fn resolve(root_path: &Path, path: &Path) -> Option<(String, ScopedPath)> {
+    // Uploads in progress, they show up once renamed into place
+    if is_tmp_path(path) {
+        return None;
+    }
+    let relative = path.strip_prefix(root_path).ok()?;
+    let mut components = relative.components();
+    let mount = components.next()?.as_os_str().to_str()?.to_owned();
+    let path = ScopedPath::from_relative(components.as_path())?;
+    Some((mount, path))
+}
+
+/// inotify reports a rename as its source, its target and then both. Only the pair is
+/// reported, halves without a counterpart were moved across the root.
+struct EventHandler {
+    root_path: PathBuf,
+    changes: ChangeNotifier,
+    /// Source of a rename whose target may still follow
+    rename_from: Option<Event>,
+    /// Deleted directories are reported by themselves and by their parent
+    last_removed: Option<PathBuf>,
+    /// Writes end with a close event, every write by itself would be announced as well
+    reports_close: bool,
+}
+
+impl EventHandler {
+    fn notify(&self, path: &Path, kind: ChangeKind) {
+        if let Some((mount, path)) = resolve(&self.root_path, path) {
+            self.changes.notify_external(&mount, &path, kind);
+        }
+    }
+
+    fn flush_rename(&mut self) {
+        if let Some(from) = self.rename_from.take() {
+            for path in &from.paths {
+                self.notify(path, ChangeKind::Deleted);
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        let removed = match (&event.kind, event.paths.as_slice()) {
+            (EventKind::Remove(_), [path]) => Some(path.to_owned()),
+            _ => None,
+        };
+        if removed.is_some() && removed == self.last_removed {
+            return;
+        }
+        self.last_removed = removed;
+
+        match event.kind {
+            // A watched directory reporting its own move, its parent reports it as well
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) if event.tracker().is_none() => {}
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                self.flush_rename();
+                self.rename_from = Some(event);
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                if event.tracker().is_some()
+                    && self.rename_from.as_ref().and_then(Event::tracker) == event.tracker() =>
+            {
+                // Both halves follow as a single event
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                self.rename_from = None;
+                if let [from, to] = event.paths.as_slice() {
+                    self.handle_rename(from, to);
+                }
+            }
+            kind => {
+                self.flush_rename();
+                let kind = match kind {
+                    EventKind::Create(_) => ChangeKind::Created,
+                    EventKind::Remove(_) => ChangeKind::Deleted,
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To)) => ChangeKind::Created,
+                    EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+                        if self.reports_close =>
+                    {
+                        return;
+                    }
+                    // Reading a file doesn't change it
+                    EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime)) => return,
+                    EventKind::Modify(_) => ChangeKind::Modified,
+                    EventKind::Access(AccessKind::Close(AccessMode::Write)) => ChangeKind::Modified,
+                    EventKind::Access(_) | EventKind::Any | EventKind::Other => return,
+                };
+                for path in &event.paths {
+                    self.notify(path, kind.clone());
+                }
+            }
+        }
+    }
+
+    fn handle_rename(&self, from: &Path, to: &Path) {
+        match (resolve(&self.root_path, from), resolve(&self.root_path, to)) {
+            (Some((from_mount, from)), Some((to_mount, to))) if from_mount == to_mount => {
+                self.changes
+                    .notify_external(&from_mount, &from, ChangeKind::Moved { to });
+            }
+            // Moves across mounts look like a delete and a create
+            (from, to) => {
+                if let Some((mount, path)) = from {
+                    self.changes
+                        .notify_external(&mount, &path, ChangeKind::Deleted);
+                }
+                if let Some((mount, path)) = to {
+                    self.changes
+                        .notify_external(&mount, &path, ChangeKind::Created);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::Change;
+    use notify::event::DataChange;
+
+    fn handler(reports_close: bool) -> (EventHandler, tokio::sync::broadcast::Receiver<Change>) {
+        let changes = ChangeNotifier::default();
+        let receiver = changes.subscribe();
+        let handler = EventHandler {
+            root_path: PathBuf::from("/srv/wolke"),
+            changes,
+            rename_from: None,
+            last_removed: None,
+            reports_close,
+        };
+        (handler, receiver)
+    }
+
+    fn event(kind: EventKind) -> Event {
+        Event::new(kind).add_path(PathBuf::from("/srv/wolke/alice/notes.txt"))
+    }
+
+    #[test]
+    fn writes_are_announced_once() {
+        let (mut handler, mut receiver) = handler(true);
+        for _ in 0..3 {
+            handler.handle(event(EventKind::Modify(ModifyKind::Data(DataChange::Any))));
+        }
+        handler.handle(event(EventKind::Access(AccessKind::Close(
+            AccessMode::Write,
+        ))));
+        handler.handle(event(EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::AccessTime,
+        ))));
+
+        let change = receiver.try_recv().unwrap();
+        assert_eq!(
+            (change.mount.as_str(), change.path.as_str()),
+            ("alice", "notes.txt")
+        );
+        assert_eq!(change.kind, ChangeKind::Modified);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn writes_without_close_events() {
+        let (mut handler, mut receiver) = handler(false);
+        handler.handle(event(EventKind::Modify(ModifyKind::Data(
+            DataChange::Content,
+        ))));
+        handler.handle(event(EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::AccessTime,
+        ))));
+
+        assert_eq!(receiver.try_recv().unwrap().kind, ChangeKind::Modified);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -1,13 +1,61 @@
-use axum::Router;
-use tower_http::services::{ServeDir, ServeFile};
+use crate::{
+    auth::{Authenticator, SessionId},
+    base_url::BaseUrl,
+};
+use axum::{
+    Extension, Router,
+    extract::{OriginalUri, Request},
+    handler::HandlerWithoutStateExt,
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use http::StatusCode;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use tower_http::services::ServeDir;
 
 pub fn frontend_router() -> Router {
-    Router::new().fallback_service(
-        ServeDir::new(concat!(env!("CARGO_MANIFEST_DIR"), "/frontend/dist")).fallback(
-            ServeFile::new(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/frontend/dist/index.html"
-            )),
-        ),
-    )
+    Router::new()
+        .fallback_service(
+            // Directories fall back to the index page too instead of serving it unchanged
+            ServeDir::new(concat!(env!("CARGO_MANIFEST_DIR"), "/frontend/dist"))
+                .append_index_html_on_directories(false)
+                .fallback(index.into_service()),
+        )
+        .layer(middleware::from_fn(require_session))
+}
+
+/// The index page for every route of the app.
+/// Its base element points to where clients reach the frontend, assets and API calls resolve against it.
+async fn index(Extension(base_url): Extension<BaseUrl>) -> Response {
+    let page = concat!(env!("CARGO_MANIFEST_DIR"), "/frontend/dist/index.html");
+    let Ok(page) = tokio::fs::read_to_string(page).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // Base paths and forwarded prefixes contain no quotes or angle brackets
+    let base = format!("<head>\n  <base href=\"{}\">", base_url.path("/frontend/"));
+    Html(page.replacen("<head>", &base, 1)).into_response()
+}
+
+/// Sends browsers without a session to the login if interactive login is configured
+async fn require_session(
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(base_url): Extension<BaseUrl>,
+    SessionId(session): SessionId,
+    OriginalUri(uri): OriginalUri,
+    req: Request,
+    next: Next,
+) -> Response {
+    let logged_in = session.is_some_and(|session| authenticator.session_user(&session).is_some());
+    if !authenticator.has_session_login() || logged_in {
+        return next.run(req).await;
+    }
+    let return_to = match uri.path_and_query() {
+        Some(path) => base_url.public_path(path.as_str()),
+        None => base_url.path("/frontend"),
+    };
+    Redirect::to(&base_url.path(&format!(
+        "/auth/oidc/login?return_to={}",
+        utf8_percent_encode(&return_to, NON_ALPHANUMERIC)
+    )))
+    .into_response()
 }
@@ -0,0 +1,136 @@
+//! Groups of users, a group owns the mount `group:<id>`
+use crate::config::GroupConfig;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, RwLock},
+};
+
+/// Keeps group mounts apart from the mounts named like users,
+/// a group may be called like a user without getting access to their mount
+const MOUNT_PREFIX: &str = "group:";
+
+/// The mount owned by a group
+pub fn group_mount(group: &str) -> String {
+    format!("{MOUNT_PREFIX}{group}")
+}
+
+/// The group owning a mount, None for the mounts of users
+pub fn mount_group(mount: &str) -> Option<&str> {
+    mount.strip_prefix(MOUNT_PREFIX)
+}
+
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub id: String,
+    pub displayname: Option<String>,
+    pub members: BTreeSet<String>,
+    /// Members provisioned by an authentication provider like LDAP
+    pub external_members: BTreeSet<String>,
+}
+
+impl Group {
+    pub fn has_member(&self, user: &str) -> bool {
+        self.members.contains(user) || self.external_members.contains(user)
+    }
+
+    pub fn all_members(&self) -> BTreeSet<String> {
+        self.members
+            .union(&self.external_members)
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GroupStore(Arc<RwLock<HashMap<String, Group>>>);
+
+impl GroupStore {
+    pub fn from_config(groups: &HashMap<String, GroupConfig>) -> Self {
+        Self(Arc::new(RwLock::new(
+            groups
+                .iter()
+                .map(|(id, group)| {
+                    let group = Group {
+                        id: id.to_owned(),
+                        displayname: group.displayname.to_owned(),
+                        members: group.members.iter().cloned().collect(),
+                        external_members: BTreeSet::new(),
+                    };
+                    (id.to_owned(), group)
+                })
+                .collect(),
+        )))
+    }
+
+    /// Applies changed group configuration, externally provisioned members are kept.
+    /// Groups no longer configured remain while they have external members.
+    pub fn reload_config(&self, groups: &HashMap<String, GroupConfig>) {
+        let configured = Self::from_config(groups);
+        let mut configured = std::mem::take(&mut *configured.0.write().unwrap());
+        let mut current = self.0.write().unwrap();
+        for (id, group) in current.drain() {
+            if group.external_members.is_empty() {
+                continue;
+            }
+            configured
+                .entry(id)
+                .or_insert_with_key(|id| Group {
+                    id: id.to_owned(),
+                    displayname: None,
+                    members: BTreeSet::new(),
+                    external_members: BTreeSet::new(),
+                })
+                .external_members = group.external_members;
+        }
+        *current = configured;
+    }
+
+    pub fn get(&self, id: &str) -> Option<Group> {
+        self.0.read().unwrap().get(id).cloned()
+    }
+
+    /// Ids of the groups a user is a member of
+    pub fn memberships(&self, user: &str) -> Vec<String> {
+        let mut memberships: Vec<_> = self
+            .0
+            .read()
+            .unwrap()
+            .values()
+            .filter(|group| group.has_member(user))
+            .map(|group| group.id.to_owned())
+            .collect();
+        memberships.sort();
+        memberships
+    }
+
+    pub fn is_member(&self, user: &str, group: &str) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .get(group)
+            .is_some_and(|group| group.has_member(user))
+    }
+
+    /// Replaces the externally provisioned memberships of a user,
+    /// groups that don't exist yet are created
+    pub fn sync_external_memberships(&self, user: &str, memberships: &[String]) {
+        let mut groups = self.0.write().unwrap();
+        for group in groups.values_mut() {
+            if !memberships.contains(&group.id) {
+                group.external_members.remove(user);
+            }
+        }
+        for id in memberships {
+            groups
+                .entry(id.to_owned())
+                .or_insert_with(|| Group {
+                    id: id.to_owned(),
+                    displayname: None,
+                    members: BTreeSet::new(),
+                    external_members: BTreeSet::new(),
+                })
+                .external_members
+                .insert(user.to_owned());
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Liveness and readiness probes for orchestrators and a server overview for admins
+use crate::{api::require_admin, auth::Authenticator, dav::User, db::Database, server};
+use axum::{
+    Extension, Json, Router,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use http::StatusCode;
+use serde::Serialize;
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+struct Inner {
+    root_path: PathBuf,
+    db: Database,
+    started: Instant,
+    draining: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct Health(Arc<Inner>);
+
+#[derive(Serialize)]
+struct Check {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new<E: ToString>(name: impl Into<String>, result: Result<(), E>) -> Self {
+        let error = result.err().map(|err| err.to_string());
+        Self {
+            name: name.into(),
+            ok: error.is_none(),
+            error,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Checks {
+    ok: bool,
+    checks: Vec<Check>,
+}
+
+impl IntoResponse for Checks {
+    fn into_response(self) -> Response {
+        let status = if self.ok {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Space of the filesystem holding fs.root_path
+#[derive(Serialize)]
+struct StorageUsage {
+    total_bytes: u64,
+    used_bytes: u64,
+    available_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct Status {
+    version: &'static str,
+    uptime_seconds: u64,
+    active_connections: usize,
+    /// Whether the directory of each mount can be read
+    mounts: Vec<Check>,
+    storage: Option<StorageUsage>,
+}
+
+fn storage_usage(path: &Path) -> std::io::Result<StorageUsage> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is NUL-terminated and stat is only read after statvfs filled it
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    let fragment = stat.f_frsize as u64;
+    Ok(StorageUsage {
+        total_bytes: stat.f_blocks as u64 * fragment,
+        used_bytes: (stat.f_blocks - stat.f_bfree) as u64 * fragment,
+        available_bytes: stat.f_bavail as u64 * fragment,
+    })
+}
+
+impl Health {
+    pub fn new(root_path: PathBuf, db: Database) -> Self {
+        Self(Arc::new(Inner {
+            root_path,
+            db,
+            started: Instant::now(),
+            draining: AtomicBool::new(false),
+        }))
+    }
+
+    /// Readiness fails from now on so that load balancers stop sending new requests
+    pub fn set_draining(&self) {
+        self.0.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Directories below fs.root_path, hidden ones aren't served
+    fn mounts(&self) -> std::io::Result<Vec<String>> {
+        let mut mounts = vec![];
+        for entry in std::fs::read_dir(&self.0.root_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && !name.starts_with('.') {
+                mounts.push(name);
+            }
+        }
+        mounts.sort();
+        Ok(mounts)
+    }
+
+    /// Reads the directory of every mount, blocks
+    fn mount_checks(&self) -> std::io::Result<Vec<Check>> {
+        Ok(self
+            .mounts()?
+            .into_iter()
+            .map(|mount| {
+                let result = std::fs::read_dir(self.0.root_path.join(&mount)).map(|_| ());
+                Check::new(mount, result)
+            })
+            .collect())
+    }
+
+    /// Touches the metadata store and the directory of every mount, blocks.
+    /// Mounts are named after users, so only their combined state is reported.
+    fn check(&self) -> Checks {
+        let mut checks = vec![Check::new(
+            "database",
+            self.0.db.schema_version().map(|_| ()),
+        )];
+        let mounts = self.mount_checks();
+        checks.push(Check::new("storage", mounts.as_ref().map(|_| ())));
+        if let Ok(mounts) = mounts {
+            let failed = mounts.iter().filter(|mount| !mount.ok).count();
+            let result = match failed {
+                0 => Ok(()),
+                failed => Err(format!("{failed} of {} mounts can't be read", mounts.len())),
+            };
+            checks.push(Check::new("mounts", result));
+        }
+        Checks {
+            ok: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    async fn check_blocking(&self) -> Checks {
+        let health = self.clone();
+        tokio::task::spawn_blocking(move || health.check())
+            .await
+            .unwrap_or_else(|err| Checks {
+                ok: false,
+                checks: vec![Check::new("health", Err(err))],
+            })
+    }
+}
+
+async fn route_healthz(Extension(health): Extension<Health>) -> Checks {
+    health.check_blocking().await
+}
+
+async fn route_readyz(Extension(health): Extension<Health>) -> Checks {
+    if health.0.draining.load(Ordering::Relaxed) {
+        return Checks {
+            ok: false,
+            checks: vec![Check::new("shutdown", Err("shutting down"))],
+        };
+    }
+    health.check_blocking().await
+}
+
+async fn route_status(
+    Extension(health): Extension<Health>,
+    Extension(authenticator): Extension<Authenticator>,
+    user: User,
+) -> Result<Json<Status>, crate::api::Error> {
+    require_admin(&authenticator, &user)?;
+    let blocking = health.clone();
+    let (mounts, storage) = tokio::task::spawn_blocking(move || {
+        (
+            blocking.mount_checks(),
+            storage_usage(&blocking.0.root_path),
+        )
+    })
+    .await
+    .map_err(std::io::Error::other)?;
+    Ok(Json(Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_seconds: health.0.started.elapsed().as_secs(),
+        active_connections: server::active_connections(),
+        mounts: mounts?,
+        storage: storage
+            .inspect_err(|err| tracing::warn!("Cannot determine storage usage: {err}"))
+            .ok(),
+    }))
+}
+
+/// /healthz and /readyz don't require a login and don't name mounts, /status is limited to auth.admins
+pub fn health_router(health: Health) -> Router {
+    Router::new()
+        .route("/healthz", get(route_healthz))
+        .route("/readyz", get(route_readyz))
+        .route("/status", get(route_status))
+        .layer(Extension(health))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::GroupStore;
+    use axum::body::Body;
+    use http::Request;
+    use serde_json::Value;
+    use std::{collections::HashMap, time::Duration};
+    use tower::ServiceExt;
+
+    async fn get_json(router: &Router, uri: &str, user: &str) -> (StatusCode, Value) {
+        let mut request = Request::get(uri).body(Body::empty()).unwrap();
+        request.extensions_mut().insert(User {
+            id: user.to_owned(),
+            displayname: None,
+            memberships: vec![],
+            unrestricted: false,
+        });
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn probes_fail_without_storage_and_while_draining() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("alice")).unwrap();
+        let health = Health::new(root.clone(), Database::open(None).unwrap());
+        let router = health_router(health.clone());
+
+        let (status, body) = get_json(&router, "/healthz", "alice").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["checks"][2]["name"], "mounts");
+        assert_eq!(
+            get_json(&router, "/readyz", "alice").await.0,
+            StatusCode::OK
+        );
+
+        health.set_draining();
+        let (status, body) = get_json(&router, "/readyz", "alice").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["checks"][0]["name"], "shutdown");
+        assert_eq!(
+            get_json(&router, "/healthz", "alice").await.0,
+            StatusCode::OK
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+        let (status, body) = get_json(&router, "/healthz", "alice").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["checks"][0]["ok"], true);
+        assert_eq!(body["checks"][1]["name"], "storage");
+        assert_eq!(body["checks"][1]["ok"], false);
+    }
+
+    #[tokio::test]
+    async fn status_is_limited_to_admins() {
+        let dir = tempfile::tempdir().unwrap();
+        for mount in ["alice", "bob", ".hidden"] {
+            std::fs::create_dir(dir.path().join(mount)).unwrap();
+        }
+        let health = Health::new(dir.path().to_owned(), Database::open(None).unwrap());
+        let authenticator = Authenticator::new(
+            GroupStore::from_config(&HashMap::new()),
+            Duration::from_secs(60),
+        )
+        .with_admins(&["alice".to_owned()]);
+        let router = health_router(health).layer(Extension(authenticator));
+
+        assert_eq!(
+            get_json(&router, "/status", "bob").await.0,
+            StatusCode::FORBIDDEN
+        );
+        let (status, body) = get_json(&router, "/status", "alice").await;
+        assert_eq!(status, StatusCode::OK);
+        let mounts: Vec<&Value> = body["mounts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|mount| &mount["name"])
+            .collect();
+        assert_eq!(mounts, ["alice", "bob"]);
+        assert!(body["storage"]["total_bytes"].as_u64().unwrap() > 0);
+    }
+}
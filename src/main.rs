@@ -1,65 +1,220 @@
-use crate::dav::fs::FSPrincipalUri;
+use crate::api::api_router;
+use crate::auth::{
+    Authenticator, LocalUserStore, LoginThrottle, Oidc, ProxyAuth, RequestLimiter, oidc_router,
+    providers_from_config,
+};
+use crate::dav::fs::FSResourceService;
 use crate::frontend::frontend_router;
-use anyhow::Result;
+use crate::notifications::notifications_router;
+use crate::uploads::nextcloud::chunking_router;
+use crate::uploads::tus::tus_router;
+use anyhow::{Context, Result};
+use audit::AuditLog;
 use axum::extract::Request;
 use axum::response::Response;
-use axum::{Extension, Router, ServiceExt};
+use axum::{Extension, Router, middleware};
+use base_url::{Forwarding, resolve_base_url};
 use clap::Parser;
 use config::Config;
-use figment::Figment;
-use figment::providers::{Env, Format, Toml};
-use filesystem::SimpleFilesystemProvider;
+use db::{ChangeLog, Database};
+use filesystem::{
+    ChangeNotifier, InstrumentedFilesystemProvider, SharingFilesystemProvider,
+    SimpleFilesystemProvider, watch_root,
+};
+use groups::GroupStore;
 use headers::{HeaderMapExt, UserAgent};
+use health::{Health, health_router};
 use http::StatusCode;
-use rustical_dav::resource::ResourceService;
+use metrics::{Metrics, admin_metrics_router, metrics_router, track_requests};
+use reload::Reloader;
+use search::SearchIndex;
 use setup_tracing::setup_tracing;
+use shares::{GrantStore, ShareStore, share_router};
 use std::sync::Arc;
 use std::time::Duration;
-use tower::Layer;
 use tower_http::classify::ServerErrorsFailureClass;
-use tower_http::normalize_path::NormalizePathLayer;
 use tower_http::trace::TraceLayer;
 use tracing::Span;
 use tracing::field::display;
 
+mod api;
+mod audit;
+mod auth;
+mod base_url;
+mod check_config;
+mod cli;
 mod config;
 mod dav;
+mod db;
 mod filesystem;
 mod frontend;
+mod groups;
+mod health;
+mod metrics;
+mod notifications;
+mod reload;
+mod search;
+mod server;
 mod setup_tracing;
+mod shares;
+mod uploads;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long, env, default_value = "/etc/wolke/config.toml")]
     config_file: String,
+    /// Print machine-readable JSON from subcommands
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Option<cli::Command>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    let config: Config = Figment::new()
-        .merge(Toml::file(&args.config_file))
-        .merge(Env::prefixed("WOLKE_").split("__"))
-        .extract()?;
+    // Reports errors of the config file itself, so it runs before loading it
+    if let Some(cli::Command::CheckConfig) = args.command {
+        return check_config::run(&args.config_file, args.json);
+    }
 
-    setup_tracing(&config.tracing);
+    let config = Config::load(&args.config_file)?;
 
-    let fs_provider = Arc::new(SimpleFilesystemProvider::new(config.fs.root_path));
+    match args.command {
+        None | Some(cli::Command::Serve) => {}
+        Some(command) => return cli::run(command, config, args.json).await,
+    }
 
-    let app = Router::new()
-        .with_state(())
-        .route_service(
-            "/dav/mount/{mount}",
-            dav::fs::FSResourceService::new(fs_provider.clone()).axum_service(),
-        )
-        .route_service(
-            "/dav/mount/{mount}/{*path}",
-            dav::fs::FSResourceService::new(fs_provider).axum_service(),
+    let log_filter = setup_tracing(&config.tracing)?;
+
+    // Keeps what the running server was configured with before parts are moved out
+    let config_snapshot = serde_json::to_value(&config)?;
+    let db = Database::open(config.database.path)?;
+    let health = Health::new(config.fs.root_path.clone(), db.clone());
+    let change_log = ChangeLog::new(db.clone());
+    change_log.spawn_pruning(Duration::from_secs(
+        config.database.change_log_retention * 24 * 60 * 60,
+    ));
+    let changes = ChangeNotifier::default().with_log(change_log)?;
+    // Dropping the watcher stops it
+    let _watcher = if config.fs.watch {
+        Some(watch_root(config.fs.root_path.clone(), changes.clone())?)
+    } else {
+        None
+    };
+    let groups = GroupStore::from_config(&config.groups);
+    let grant_store = GrantStore::new(db.clone(), groups.clone());
+    let share_store = ShareStore::new(
+        db.clone(),
+        Duration::from_secs(config.shares.session_lifetime),
+    );
+    let local_users = LocalUserStore::new(db.clone());
+    let metrics = Metrics::default();
+    let fs_provider = Arc::new(SharingFilesystemProvider::new(
+        InstrumentedFilesystemProvider::new(
+            SimpleFilesystemProvider::new(config.fs.root_path, changes),
+            metrics.clone(),
+        ),
+        grant_store.clone(),
+    ));
+
+    let search_index = SearchIndex::new(config.search);
+    search_index.spawn(fs_provider.clone());
+    let mut authenticator = Authenticator::new(
+        groups.clone(),
+        Duration::from_secs(config.auth.session_lifetime),
+    )
+    .with_metrics(metrics.clone())
+    .with_providers(providers_from_config(&config.auth, &local_users))
+    .with_admins(&config.auth.admins);
+    let rate_limit = config.auth.rate_limit;
+    if let Some(requests_per_second) = rate_limit.requests_per_second.filter(|rate| *rate > 0.) {
+        authenticator = authenticator
+            .with_request_limiter(RequestLimiter::new(requests_per_second, rate_limit.burst));
+    }
+    if rate_limit.enabled {
+        authenticator = authenticator.with_throttle(LoginThrottle::new(Arc::new(rate_limit)));
+    }
+    if config
+        .http
+        .listeners()
+        .iter()
+        .filter_map(|listener| listener.tls())
+        .any(|tls| tls.client_ca_path.is_some())
+    {
+        authenticator = authenticator.with_client_cert_login();
+    }
+    if let Some(proxy) = config.auth.proxy {
+        authenticator = authenticator.with_proxy(ProxyAuth::new(proxy));
+    }
+    let oidc = config.auth.oidc.map(Oidc::new);
+    if oidc.is_some() {
+        authenticator = authenticator.with_session_login();
+    }
+    if config.auth.home_mounts {
+        authenticator = authenticator.with_home_mounts(fs_provider.clone());
+    }
+    let reloader = Reloader::new(
+        args.config_file,
+        config_snapshot,
+        groups.clone(),
+        authenticator.clone(),
+        local_users,
+        log_filter,
+    );
+    reloader.spawn_on_sighup()?;
+    let audit = AuditLog::new(&config.audit)?;
+    let resource_service = FSResourceService::new(fs_provider, groups.clone(), audit.clone());
+    uploads::spawn_cleanup(&config.uploads);
+    let upload_config = Arc::new(config.uploads);
+
+    let forwarding = Forwarding::new(&config.http)?;
+    let mut app = Router::new();
+    if let Some(oidc) = oidc {
+        app = app.nest("/auth", oidc_router(oidc));
+    }
+    let mut admin = None;
+    if config.metrics.enabled {
+        match &config.metrics.listen {
+            Some(listen) => {
+                let listener = tokio::net::TcpListener::bind(listen)
+                    .await
+                    .with_context(|| format!("Cannot listen on {listen}"))?;
+                tracing::info!("Serving metrics on {listen}");
+                admin = Some((listener, server::into_app(metrics_router(metrics.clone()))));
+            }
+            None => app = app.merge(admin_metrics_router(metrics.clone())),
+        }
+    }
+    let chunking = chunking_router(resource_service.clone(), upload_config.clone());
+    let app = app
+        .route_service("/dav/mount/{mount}", resource_service.service())
+        .route_service("/dav/mount/{mount}/{*path}", resource_service.service())
+        .nest(
+            "/notifications",
+            notifications_router(resource_service.clone()),
         )
+        .nest("/api/v1", api_router(resource_service.clone()))
+        .nest("/dav/uploads", chunking.clone())
+        .nest("/remote.php/dav/uploads", chunking)
+        .nest("/tus", tus_router(resource_service.clone(), upload_config))
+        .merge(share_router(resource_service, &config.shares))
+        .merge(health_router(health.clone()))
         .nest("/frontend", frontend_router())
-        .layer(Extension(FSPrincipalUri))
+        .layer(middleware::from_fn_with_state(
+            forwarding.clone(),
+            resolve_base_url,
+        ))
+        .layer(Extension(search_index.clone()))
+        .layer(Extension(share_store))
+        .layer(Extension(grant_store))
+        .layer(Extension(groups))
+        .layer(Extension(authenticator))
+        .layer(Extension(reloader))
+        .layer(middleware::from_fn(audit::request_context))
+        .layer(middleware::from_fn_with_state(metrics, track_requests))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request| {
@@ -95,6 +250,10 @@ async fn main() -> Result<()> {
                             StatusCode::NOT_FOUND => {
                                 tracing::warn!("client error");
                             }
+                            StatusCode::TOO_MANY_REQUESTS => {
+                                // Details are logged where the limit is enforced
+                                tracing::warn!("rate limited");
+                            }
                             _ => {
                                 tracing::error!("client error");
                             }
@@ -108,14 +267,24 @@ async fn main() -> Result<()> {
                 ),
         );
 
-    let app = ServiceExt::<Request>::into_make_service(
-        NormalizePathLayer::trim_trailing_slash().layer(app),
-    );
+    let app = match forwarding.base_path() {
+        "" => app,
+        base_path => Router::new().nest(base_path, app),
+    };
+    // The peer address is needed to check for trusted proxies
+    let app = server::into_app(app);
 
-    let listener =
-        tokio::net::TcpListener::bind(&format!("{}:{}", config.http.host, config.http.port))
-            .await?;
+    let shutdown = async move {
+        server::shutdown_signal().await;
+        health.set_draining();
+    };
+    server::serve(&config.http, app, admin, shutdown).await?;
 
-    axum::serve(listener, app).await?;
+    if let Err(err) = search_index.flush().await {
+        tracing::error!("Failed to save the search index: {err}");
+    }
+    audit.flush();
+    db.checkpoint()?;
+    tracing::info!("Shut down");
     Ok(())
 }
@@ -0,0 +1,404 @@
+//! Prometheus metrics, exposed at /metrics
+use crate::{api::require_admin, auth::Authenticator, dav::User};
+use axum::{
+    Extension, Router,
+    body::Body,
+    extract::{Request, State},
+    http::{Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use futures::StreamExt;
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+use std::{sync::Arc, time::Instant};
+
+const UPLOAD: &str = "upload";
+const DOWNLOAD: &str = "download";
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MethodLabels {
+    method: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TransferLabels {
+    mount: String,
+    direction: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DirectionLabels {
+    direction: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OperationLabels {
+    operation: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReasonLabels {
+    reason: &'static str,
+}
+
+fn latency_histogram() -> Histogram {
+    // 1ms to ~65s
+    Histogram::new(exponential_buckets(0.001, 2., 17))
+}
+
+struct Inner {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    request_duration: Family<MethodLabels, Histogram, fn() -> Histogram>,
+    transferred_bytes: Family<TransferLabels, Counter>,
+    active_transfers: Family<DirectionLabels, Gauge>,
+    fs_duration: Family<OperationLabels, Histogram, fn() -> Histogram>,
+    auth_failures: Family<ReasonLabels, Counter>,
+}
+
+#[derive(Clone)]
+pub struct Metrics(Arc<Inner>);
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut registry = Registry::with_prefix("wolke");
+        let requests = Family::<RequestLabels, Counter>::default();
+        registry.register(
+            "http_requests",
+            "HTTP requests by method and status",
+            requests.clone(),
+        );
+        let request_duration =
+            Family::<MethodLabels, Histogram, fn() -> Histogram>::new_with_constructor(
+                latency_histogram,
+            );
+        registry.register(
+            "http_request_duration_seconds",
+            "Time until the response headers were sent",
+            request_duration.clone(),
+        );
+        let transferred_bytes = Family::<TransferLabels, Counter>::default();
+        registry.register(
+            "transferred_bytes",
+            "File content uploaded to or downloaded from a mount",
+            transferred_bytes.clone(),
+        );
+        let active_transfers = Family::<DirectionLabels, Gauge>::default();
+        registry.register(
+            "active_transfers",
+            "Uploads and downloads in progress",
+            active_transfers.clone(),
+        );
+        let fs_duration =
+            Family::<OperationLabels, Histogram, fn() -> Histogram>::new_with_constructor(
+                latency_histogram,
+            );
+        registry.register(
+            "fs_operation_duration_seconds",
+            "Latency of filesystem operations",
+            fs_duration.clone(),
+        );
+        let auth_failures = Family::<ReasonLabels, Counter>::default();
+        registry.register(
+            "auth_failures",
+            "Rejected logins by reason",
+            auth_failures.clone(),
+        );
+        Self(Arc::new(Inner {
+            registry,
+            requests,
+            request_duration,
+            transferred_bytes,
+            active_transfers,
+            fs_duration,
+            auth_failures,
+        }))
+    }
+}
+
+/// Decrements the active transfers when the body is dropped
+struct TransferGuard(Gauge);
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        encode(&mut out, &self.0.registry).expect("Writing to a String cannot fail");
+        out
+    }
+
+    pub fn observe_fs(&self, operation: &'static str, start: Instant) {
+        self.0
+            .fs_duration
+            .get_or_create(&OperationLabels { operation })
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    pub fn auth_failure(&self, reason: &'static str) {
+        self.0
+            .auth_failures
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
+
+    fn transferred_bytes(&self, mount: String, direction: &'static str) -> Counter {
+        self.0
+            .transferred_bytes
+            .get_or_create(&TransferLabels { mount, direction })
+            .clone()
+    }
+
+    /// Counts the bytes of a body into `bytes` as they're streamed
+    fn count_body(&self, body: Body, bytes: Counter, direction: &'static str) -> Body {
+        let active = self
+            .0
+            .active_transfers
+            .get_or_create(&DirectionLabels { direction })
+            .clone();
+        active.inc();
+        let guard = TransferGuard(active);
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _guard = &guard;
+            if let Ok(chunk) = &chunk {
+                bytes.inc_by(chunk.len() as u64);
+            }
+            chunk
+        }))
+    }
+}
+
+/// Arbitrary methods would blow up the number of series
+fn method_label(method: &Method) -> String {
+    const KNOWN: &[&str] = &[
+        "GET",
+        "HEAD",
+        "PUT",
+        "POST",
+        "PATCH",
+        "DELETE",
+        "OPTIONS",
+        "PROPFIND",
+        "PROPPATCH",
+        "MKCOL",
+        "COPY",
+        "MOVE",
+        "LOCK",
+        "UNLOCK",
+        "SEARCH",
+        "REPORT",
+    ];
+    if KNOWN.contains(&method.as_str()) {
+        method.as_str().to_owned()
+    } else {
+        "other".to_owned()
+    }
+}
+
+/// The mount addressed by a request to one of the file endpoints
+fn request_mount(path: &str) -> Option<&str> {
+    ["/dav/mount/", "/api/v1/mounts/", "/tus/"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .and_then(|rest| rest.split('/').next())
+        .filter(|mount| !mount.is_empty())
+}
+
+pub async fn track_requests(
+    State(metrics): State<Metrics>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_owned();
+    let mount = request_mount(request.uri().path()).map(str::to_owned);
+
+    // Mounts are only known to be real once the request succeeded,
+    // so uploads are attributed to their mount afterwards
+    let uploaded = Counter::default();
+    let request = match &mount {
+        Some(_) if matches!(method, Method::PUT | Method::PATCH | Method::POST) => {
+            let (parts, body) = request.into_parts();
+            let body = metrics.count_body(body, uploaded.clone(), UPLOAD);
+            Request::from_parts(parts, body)
+        }
+        _ => request,
+    };
+
+    let response = next.run(request).await;
+
+    let label = method_label(&method);
+    metrics
+        .0
+        .request_duration
+        .get_or_create(&MethodLabels {
+            method: label.to_owned(),
+        })
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .0
+        .requests
+        .get_or_create(&RequestLabels {
+            method: label,
+            status: response.status().as_u16(),
+        })
+        .inc();
+
+    let Some(mount) = mount.filter(|_| response.status().is_success()) else {
+        return response;
+    };
+    if uploaded.get() > 0 {
+        metrics
+            .transferred_bytes(mount.to_owned(), UPLOAD)
+            .inc_by(uploaded.get());
+    }
+    if method == Method::GET {
+        let (parts, body) = response.into_parts();
+        let body = metrics.count_body(body, metrics.transferred_bytes(mount, DOWNLOAD), DOWNLOAD);
+        return Response::from_parts(parts, body);
+    }
+    response
+}
+
+async fn route_metrics(Extension(metrics): Extension<Metrics>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics.render(),
+    )
+}
+
+async fn route_admin_metrics(
+    Extension(metrics): Extension<Metrics>,
+    Extension(authenticator): Extension<Authenticator>,
+    user: User,
+) -> Result<Response, crate::api::Error> {
+    require_admin(&authenticator, &user)?;
+    Ok(route_metrics(Extension(metrics)).await.into_response())
+}
+
+/// For the separate admin listener, which is expected to be reachable by the scraper only
+pub fn metrics_router(metrics: Metrics) -> Router {
+    Router::new()
+        .route("/metrics", get(route_metrics))
+        .layer(Extension(metrics))
+}
+
+/// For the main listener, limited to auth.admins
+pub fn admin_metrics_router(metrics: Metrics) -> Router {
+    Router::new()
+        .route("/metrics", get(route_admin_metrics))
+        .layer(Extension(metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::any};
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    /// Stores nothing, uploads to the mount missing fail after reading the body
+    async fn route_file(request: Request) -> Response {
+        let missing = request.uri().path().starts_with("/dav/mount/missing/");
+        match *request.method() {
+            Method::GET => "hello".into_response(),
+            _ => {
+                axum::body::to_bytes(request.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                if missing {
+                    StatusCode::NOT_FOUND.into_response()
+                } else {
+                    StatusCode::CREATED.into_response()
+                }
+            }
+        }
+    }
+
+    async fn send(router: &Router, method: &str, uri: &str, body: &'static str) -> StatusCode {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        // Downloads are counted as the body is streamed
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        status
+    }
+
+    #[tokio::test]
+    async fn counts_requests_and_transferred_bytes() {
+        let metrics = Metrics::default();
+        let router = Router::new()
+            .route("/dav/mount/{*path}", any(route_file))
+            .layer(middleware::from_fn_with_state(
+                metrics.clone(),
+                track_requests,
+            ))
+            .merge(metrics_router(metrics.clone()));
+
+        assert_eq!(
+            send(&router, "PUT", "/dav/mount/alice/a.txt", "12345").await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            send(&router, "GET", "/dav/mount/alice/a.txt", "").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&router, "PUT", "/dav/mount/missing/a.txt", "123").await,
+            StatusCode::NOT_FOUND
+        );
+        send(&router, "BREW", "/dav/mount/alice/a.txt", "").await;
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let rendered = String::from_utf8(body.to_vec()).unwrap();
+        for line in [
+            r#"wolke_http_requests_total{method="PUT",status="201"} 1"#,
+            r#"wolke_http_requests_total{method="PUT",status="404"} 1"#,
+            r#"wolke_http_requests_total{method="other",status="201"} 1"#,
+            r#"wolke_transferred_bytes_total{mount="alice",direction="upload"} 5"#,
+            r#"wolke_transferred_bytes_total{mount="alice",direction="download"} 5"#,
+            r#"wolke_active_transfers{direction="upload"} 0"#,
+            r#"wolke_active_transfers{direction="download"} 0"#,
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "{line} in\n{rendered}"
+            );
+        }
+        // Requests to mounts that turned out not to exist don't create series
+        assert!(!rendered.contains(r#"mount="missing""#));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    dav::{
+        Error, User,
+        fs::{FSResourceService, FSResourceServicePath},
+    },
+    filesystem::{Change, ChangeKind, FilesystemProvider},
+};
+use axum::{
+    Router,
+    extract::{
+        Path, State,
+        ws::{Message, WebSocket, WebSocketUpgrade, rejection::WebSocketUpgradeRejection},
+    },
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::get,
+};
+use futures::{Stream, stream};
+use rustical_dav::privileges::UserPrivilege;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Subscribes to changes below a path in a mount.
+/// Clients get a WebSocket if they ask for an upgrade and Server-Sent Events otherwise.
+pub fn notifications_router<FSP: FilesystemProvider>(
+    resource_service: FSResourceService<FSP>,
+) -> Router {
+    Router::new()
+        .route("/{mount}", get(route_subscribe::<FSP>))
+        .route("/{mount}/{*path}", get(route_subscribe::<FSP>))
+        .with_state(resource_service)
+}
+
+async fn route_subscribe<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Path(path): Path<FSResourceServicePath>,
+    user: User,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, Error> {
+    resource_service
+        .authorize(&path, &user, UserPrivilege::Read)
+        .await?;
+
+    let receiver = resource_service.changes().subscribe();
+    Ok(match ws {
+        Ok(ws) => ws
+            .on_upgrade(move |socket| websocket_subscription(socket, receiver, path))
+            .into_response(),
+        Err(_) => Sse::new(sse_subscription(receiver, path))
+            .keep_alive(KeepAlive::default())
+            .into_response(),
+    })
+}
+
+fn is_relevant(change: &Change, subscription: &FSResourceServicePath) -> bool {
+    if change.mount != subscription.mount {
+        return false;
+    }
+    change.path.starts_with(&subscription.path)
+        || matches!(&change.kind, ChangeKind::Moved { to } if to.starts_with(&subscription.path))
+}
+
+enum Notification {
+    Change(Change),
+    /// The subscriber fell behind and missed changes, it should fetch the full state again
+    Lagged,
+}
+
+impl Notification {
+    fn to_json(&self) -> String {
+        match self {
+            Self::Change(change) => {
+                serde_json::to_string(change).expect("Change can always be serialized")
+            }
+            Self::Lagged => r#"{"type":"lagged"}"#.to_owned(),
+        }
+    }
+}
+
+async fn next_notification(
+    receiver: &mut broadcast::Receiver<Change>,
+    subscription: &FSResourceServicePath,
+) -> Option<Notification> {
+    loop {
+        match receiver.recv().await {
+            Ok(change) if is_relevant(&change, subscription) => {
+                return Some(Notification::Change(change));
+            }
+            Ok(_) => continue,
+            Err(RecvError::Lagged(_)) => return Some(Notification::Lagged),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+fn sse_subscription(
+    receiver: broadcast::Receiver<Change>,
+    subscription: FSResourceServicePath,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(
+        (receiver, subscription),
+        |(mut receiver, subscription)| async move {
+            let notification = next_notification(&mut receiver, &subscription).await?;
+            let event_name = match &notification {
+                Notification::Change(_) => "change",
+                Notification::Lagged => "lagged",
+            };
+            let event = Event::default()
+                .event(event_name)
+                .data(notification.to_json());
+            Some((Ok(event), (receiver, subscription)))
+        },
+    )
+}
+
+async fn websocket_subscription(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<Change>,
+    subscription: FSResourceServicePath,
+) {
+    loop {
+        tokio::select! {
+            message = socket.recv() => match message {
+                // We don't expect any messages from the client
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+            notification = next_notification(&mut receiver, &subscription) => {
+                let Some(notification) = notification else {
+                    return;
+                };
+                let message = Message::Text(notification.to_json().into());
+                if socket.send(message).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::Request};
+    use futures::StreamExt;
+    use http::StatusCode;
+    use scoped_fs::ScopedPath;
+    use tower::ServiceExt;
+
+    fn path(path: &str) -> ScopedPath {
+        ScopedPath::new(path.to_owned())
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_changes_below_their_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("user/docs")).unwrap();
+        let service = FSResourceService::in_dir(dir.path());
+        let changes = service.changes().clone();
+        let router = Router::new().nest("/notifications", notifications_router(service));
+
+        let request = Request::get("/notifications/user/docs")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        changes.notify_external("user", &path("notes.txt"), ChangeKind::Created);
+        changes.notify_external("other", &path("docs/a.txt"), ChangeKind::Created);
+        let moved = ChangeKind::Moved {
+            to: path("docs/notes.txt"),
+        };
+        changes.notify_external("user", &path("notes.txt"), moved);
+        changes.notify_external("user", &path("docs/notes.txt"), ChangeKind::Deleted);
+
+        let mut events = response.into_body().into_data_stream();
+        let mut next = async || String::from_utf8(events.next().await.unwrap().unwrap().to_vec());
+        let moved = next().await.unwrap();
+        assert!(moved.starts_with("event: change\n"), "{moved}");
+        assert!(moved.contains(r#""path":"notes.txt""#), "{moved}");
+        let deleted = next().await.unwrap();
+        assert!(deleted.contains(r#""path":"docs/notes.txt""#), "{deleted}");
+
+        // Missing paths can't be subscribed to
+        let request = Request::get("/notifications/user/missing")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
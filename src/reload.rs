@@ -0,0 +1,230 @@
+//! Applies configuration changes without a restart, on SIGHUP or through the admin API.
+//! Groups, login providers, admins and the log filter follow the new configuration,
+//! changes to other sections only take effect after a restart.
+use crate::{
+    auth::{Authenticator, LocalUserStore, providers_from_config},
+    config::Config,
+    groups::GroupStore,
+    setup_tracing::{LogFilter, env_filter},
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+use utoipa::ToSchema;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] Box<figment::Error>),
+    #[error("Invalid tracing filter: {0}")]
+    Filter(#[from] tracing_subscriber::filter::ParseError),
+    #[error("{0}")]
+    Rejected(&'static str),
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReloadReport {
+    /// Changed sections that only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+/// Fields that are applied on reload, by section
+const RELOADABLE: &[(&str, &[&str])] = &[
+    ("groups", &[]),
+    ("auth", &["local_users", "ldap", "admins"]),
+    ("tracing", &["filter"]),
+];
+
+/// Top-level sections whose other fields differ
+fn restart_required(old: &Value, new: &Value) -> Vec<String> {
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return vec![];
+    };
+    let mut sections: Vec<&String> = old.keys().chain(new.keys()).collect();
+    sections.sort();
+    sections.dedup();
+    sections
+        .into_iter()
+        .filter(|section| {
+            let reloadable = RELOADABLE
+                .iter()
+                .find(|(name, _)| name == section)
+                .map(|(_, fields)| *fields);
+            let strip = |value: Option<&Value>| match (value, reloadable) {
+                // The whole section is reloadable
+                (_, Some([])) => Value::Null,
+                (Some(Value::Object(fields)), Some(reloadable)) => Value::Object(
+                    fields
+                        .iter()
+                        .filter(|(field, _)| !reloadable.contains(&field.as_str()))
+                        .map(|(field, value)| (field.to_owned(), value.to_owned()))
+                        .collect(),
+                ),
+                (value, _) => value.cloned().unwrap_or_default(),
+            };
+            strip(old.get(*section)) != strip(new.get(*section))
+        })
+        .cloned()
+        .collect()
+}
+
+struct Inner {
+    config_file: String,
+    /// The configuration in effect, reloads are serialized by this lock
+    current: tokio::sync::Mutex<Value>,
+    groups: GroupStore,
+    authenticator: Authenticator,
+    local_users: LocalUserStore,
+    log_filter: LogFilter,
+}
+
+#[derive(Clone)]
+pub struct Reloader(Arc<Inner>);
+
+impl Reloader {
+    /// config is the serialized configuration the server was started with
+    pub fn new(
+        config_file: String,
+        config: Value,
+        groups: GroupStore,
+        authenticator: Authenticator,
+        local_users: LocalUserStore,
+        log_filter: LogFilter,
+    ) -> Self {
+        Self(Arc::new(Inner {
+            config_file,
+            current: tokio::sync::Mutex::new(config),
+            groups,
+            authenticator,
+            local_users,
+            log_filter,
+        }))
+    }
+
+    /// Everything is validated before anything is applied,
+    /// the old configuration stays in effect if the new one is rejected
+    pub async fn reload(&self) -> Result<ReloadReport, Error> {
+        let inner = &self.0;
+        let mut current = inner.current.lock().await;
+        let config = Config::load(&inner.config_file)?;
+
+        let filter = env_filter(&config.tracing)?;
+        let providers = providers_from_config(&config.auth, &inner.local_users);
+        // Would switch between anonymous access and requiring a login
+        if providers.is_empty() == inner.authenticator.has_providers() {
+            return Err(Error::Rejected(
+                "Enabling or disabling all login providers requires a restart",
+            ));
+        }
+
+        if let Err(err) = inner.log_filter.reload(filter) {
+            tracing::warn!("Failed to apply the log filter: {err}");
+        }
+        inner.groups.reload_config(&config.groups);
+        inner.authenticator.set_providers(providers);
+        inner.authenticator.set_admins(&config.auth.admins);
+
+        let new = serde_json::to_value(&config).unwrap_or_default();
+        let restart_required = restart_required(&current, &new);
+        *current = new;
+        if restart_required.is_empty() {
+            tracing::info!("Reloaded configuration");
+        } else {
+            tracing::warn!(
+                ?restart_required,
+                "Reloaded configuration, some changes need a restart"
+            );
+        }
+        Ok(ReloadReport { restart_required })
+    }
+
+    pub fn spawn_on_sighup(&self) -> std::io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(err) = reloader.reload().await {
+                    tracing::error!("Rejected configuration reload: {err}");
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dav::User, db::Database};
+    use std::{path::Path, time::Duration};
+    use tracing_subscriber::{EnvFilter, reload};
+
+    fn write_config(dir: &Path, root: &str, members: &str, extra: &str) -> String {
+        let file = dir.join("wolke.toml");
+        let toml = format!(
+            "[fs]\nroot_path = {:?}\n[groups.team]\nmembers = [{members:?}]\n\
+            [auth]\nadmins = [{members:?}]\n{extra}[tracing]\nfilter = \"info\"\n",
+            dir.join(root),
+        );
+        std::fs::write(&file, toml).unwrap();
+        file.to_str().unwrap().to_owned()
+    }
+
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_owned(),
+            displayname: None,
+            memberships: vec![],
+            unrestricted: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_valid_configurations() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = write_config(dir.path(), "root", "alice", "local_users = true\n");
+        let config = Config::load(&file).unwrap();
+        let groups = GroupStore::from_config(&config.groups);
+        let local_users = LocalUserStore::new(Database::open(None).unwrap());
+        let authenticator = Authenticator::new(groups.clone(), Duration::from_secs(60))
+            .with_providers(providers_from_config(&config.auth, &local_users))
+            .with_admins(&config.auth.admins);
+        // The layer has to stay alive for the handle to reload it
+        let (_layer, log_filter) =
+            reload::Layer::<_, tracing_subscriber::Registry>::new(EnvFilter::new("warn"));
+        let reloader = Reloader::new(
+            file.clone(),
+            serde_json::to_value(&config).unwrap(),
+            groups.clone(),
+            authenticator.clone(),
+            local_users,
+            log_filter.clone(),
+        );
+
+        // Only the moved root needs a restart
+        write_config(dir.path(), "other", "bob", "local_users = true\n");
+        let report = reloader.reload().await.unwrap();
+        assert_eq!(report.restart_required, ["fs"]);
+        assert!(groups.is_member("bob", "team"));
+        assert!(!groups.is_member("alice", "team"));
+        assert!(authenticator.is_admin(&user("bob")));
+        assert!(!authenticator.is_admin(&user("alice")));
+        let filter = log_filter.with_current(ToString::to_string).unwrap();
+        assert!(filter.ends_with(",info"), "{filter}");
+
+        // Rejected configurations leave everything as it was
+        write_config(dir.path(), "other", "carol", "");
+        assert!(matches!(reloader.reload().await, Err(Error::Rejected(_))));
+        std::fs::write(&file, "[fs\n").unwrap();
+        assert!(matches!(reloader.reload().await, Err(Error::Config(_))));
+        assert!(groups.is_member("bob", "team"));
+        assert!(authenticator.is_admin(&user("bob")));
+        assert!(authenticator.has_providers());
+
+        write_config(dir.path(), "other", "carol", "local_users = true\n");
+        let report = reloader.reload().await.unwrap();
+        assert!(report.restart_required.is_empty());
+        assert!(groups.is_member("carol", "team"));
+    }
+}
@@ -0,0 +1,432 @@
+use crate::{
+    config::SearchConfig,
+    filesystem::{
+        Change, ChangeKind, DavMetadata, Error, FileReader, Filesystem, FilesystemProvider,
+    },
+};
+use futures::StreamExt;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{OnceCell, broadcast::error::RecvError};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub is_dir: bool,
+    pub len: u64,
+    /// Milliseconds since the Unix epoch
+    pub modified: u64,
+    /// Milliseconds since the Unix epoch
+    pub created: u64,
+    pub content_type: Option<String>,
+    /// Words of the document if it has been indexed for full-text search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terms: Option<BTreeSet<String>>,
+}
+
+impl IndexEntry {
+    fn new(path: &ScopedPath, metadata: &impl DavMetadata) -> Self {
+        Self {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: unix_millis(metadata.modified()),
+            created: unix_millis(metadata.created()),
+            content_type: path
+                .file_extension()
+                .filter(|_| !metadata.is_dir())
+                .and_then(|ext| mime_guess::from_ext(ext).first_raw())
+                .map(str::to_owned),
+            terms: None,
+        }
+    }
+
+    /// Same format as the ETag of FSResource
+    pub fn etag(&self) -> Option<String> {
+        (!self.is_dir).then(|| format!("\"{}-{}\"", self.len, self.modified))
+    }
+
+    fn is_text(&self) -> bool {
+        let Some(content_type) = &self.content_type else {
+            return false;
+        };
+        let Ok(mime) = content_type.parse::<mime::Mime>() else {
+            return false;
+        };
+        mime.type_() == mime::TEXT
+            || matches!(mime.subtype().as_str(), "json" | "xml" | "javascript")
+            || mime
+                .suffix()
+                .is_some_and(|suffix| suffix == mime::JSON || suffix == mime::XML)
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Lowercased words for full-text search
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|char: char| !char.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[derive(Debug, Default)]
+pub struct MountIndex {
+    entries: RwLock<BTreeMap<ScopedPath, IndexEntry>>,
+    dirty: AtomicBool,
+    /// Set once the mount has been loaded or scanned for the first time
+    initialized: OnceCell<()>,
+    state: Mutex<ScanState>,
+}
+
+#[derive(Debug, Default)]
+struct ScanState {
+    /// Whether the entries can be kept up to date by applying changes to them
+    ready: bool,
+    /// Changes that arrive while the mount is scanned, replayed on top of the scan
+    queued: Option<Vec<Change>>,
+}
+
+impl MountIndex {
+    pub fn entries(&self) -> std::sync::RwLockReadGuard<'_, BTreeMap<ScopedPath, IndexEntry>> {
+        self.entries.read().unwrap()
+    }
+
+    fn replace_subtree(&self, root: &ScopedPath, subtree: BTreeMap<ScopedPath, IndexEntry>) {
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|path, _| !path.starts_with(root));
+        entries.extend(subtree);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn start_scan(&self) {
+        self.state.lock().unwrap().queued = Some(vec![]);
+    }
+
+    /// Takes the changes queued so far, ending the scan once there are none left
+    fn take_queued(&self) -> Vec<Change> {
+        let mut state = self.state.lock().unwrap();
+        let queued = state.queued.replace(vec![]).unwrap_or_default();
+        if queued.is_empty() {
+            state.queued = None;
+            state.ready = true;
+        }
+        queued
+    }
+}
+
+/// Index of the live properties (and optionally the text) of all files in a mount.
+/// Mounts are loaded from the persisted index or scanned on their first search and
+/// then kept up to date through the change stream.
+#[derive(Debug, Clone)]
+pub struct SearchIndex {
+    config: Arc<SearchConfig>,
+    mounts: Arc<Mutex<HashMap<String, Arc<MountIndex>>>>,
+}
+
+impl SearchIndex {
+    pub fn new(config: SearchConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            mounts: Default::default(),
+        }
+    }
+
+    fn index_file(&self, mount: &str) -> Option<PathBuf> {
+        let index_path = self.config.index_path.as_ref()?;
+        let name = utf8_percent_encode(mount, NON_ALPHANUMERIC).to_string();
+        Some(index_path.join(format!("{name}.json")))
+    }
+
+    pub async fn mount<FSP: FilesystemProvider>(
+        &self,
+        provider: &FSP,
+        mount: &str,
+    ) -> Result<Arc<MountIndex>, Error> {
+        let index = self
+            .mounts
+            .lock()
+            .unwrap()
+            .entry(mount.to_owned())
+            .or_default()
+            .clone();
+        index
+            .initialized
+            .get_or_try_init(|| self.initialize(provider, mount, &index))
+            .await?;
+        Ok(index)
+    }
+
+    async fn initialize<FSP: FilesystemProvider>(
+        &self,
+        provider: &FSP,
+        mount: &str,
+        index: &Arc<MountIndex>,
+    ) -> Result<(), Error> {
+        index.start_scan();
+        if let Some(previous) = self.load(mount).await {
+            // Answer from the persisted index right away, the scan in the background picks up
+            // whatever changed while we weren't running
+            *index.entries.write().unwrap() = previous;
+            index.state.lock().unwrap().ready = true;
+            let (search_index, provider) = (self.clone(), provider.clone());
+            let (mount, index) = (mount.to_owned(), index.clone());
+            tokio::spawn(async move {
+                if let Err(err) = search_index.rescan(&provider, &mount, &index).await {
+                    tracing::warn!("Failed to rescan search index of {mount}: {err}");
+                    index.state.lock().unwrap().queued = None;
+                }
+            });
+            return Ok(());
+        }
+        let result = self.rescan(provider, mount, index).await;
+        if result.is_err() {
+            index.state.lock().unwrap().queued = None;
+        }
+        result
+    }
+
+    /// Scans the whole mount and replays the changes that came in meanwhile
+    async fn rescan<FSP: FilesystemProvider>(
+        &self,
+        provider: &FSP,
+        mount: &str,
+        index: &MountIndex,
+    ) -> Result<(), Error> {
+        let fs = provider.get_filesystem(mount).await?;
+        // Reuse the words of files that didn't change instead of reading them again
+        let previous = index.entries().clone();
+        let entries = self.scan(&fs, &ScopedPath::default(), &previous).await?;
+        index.replace_subtree(&ScopedPath::default(), entries);
+        loop {
+            let queued = index.take_queued();
+            if queued.is_empty() {
+                return Ok(());
+            }
+            for change in queued {
+                if let Err(err) = self.apply_to(&fs, index, change).await {
+                    tracing::warn!("Failed to update search index: {err}");
+                }
+            }
+        }
+    }
+
+    async fn load(&self, mount: &str) -> Option<BTreeMap<ScopedPath, IndexEntry>> {
+        let index_file = self.index_file(mount)?;
+        let content = tokio::fs::read(&index_file).await.ok()?;
+        match serde_json::from_slice(&content) {
+            Ok(entries) => Some(entries),
+            Err(err) => {
+                tracing::warn!("Ignoring corrupt search index {index_file:?}: {err}");
+                None
+            }
+        }
+    }
+
+    async fn scan<FS: Filesystem>(
+        &self,
+        fs: &FS,
+        root: &ScopedPath,
+        previous: &BTreeMap<ScopedPath, IndexEntry>,
+    ) -> Result<BTreeMap<ScopedPath, IndexEntry>, Error> {
+        let mut entries = BTreeMap::new();
+        let mut queue = vec![root.to_owned()];
+        while let Some(path) = queue.pop() {
+            let metadata = match fs.metadata(&path).await {
+                Ok(metadata) => metadata,
+                // Might have been deleted while scanning
+                Err(Error::NotFound) => continue,
+                Err(err) => return Err(err),
+            };
+            let mut entry = IndexEntry::new(&path, &metadata);
+            if entry.is_dir {
+                match fs.list_dir(&path).await {
+                    Ok(children) => queue.extend(children),
+                    Err(Error::NotFound) => continue,
+                    Err(err) => return Err(err),
+                }
+            } else if self.config.full_text {
+                entry.terms = match previous.get(&path) {
+                    Some(old) if old.len == entry.len && old.modified == entry.modified => {
+                        old.terms.clone()
+                    }
+                    _ => self.read_terms(fs, &path, &entry).await,
+                };
+            }
+            entries.insert(path, entry);
+        }
+        Ok(entries)
+    }
+
+    async fn read_terms<FS: Filesystem>(
+        &self,
+        fs: &FS,
+        path: &ScopedPath,
+        entry: &IndexEntry,
+    ) -> Option<BTreeSet<String>> {
+        if !entry.is_text() || entry.len > self.config.max_text_size {
+            return None;
+        }
+        let file = fs.get_file(path).await.ok()?;
+        let mut stream = Box::pin(file.stream(entry.len, 0).await.ok()?);
+        let mut content = Vec::with_capacity(entry.len as usize);
+        while let Some(chunk) = stream.next().await {
+            content.extend(chunk.ok()?);
+        }
+        Some(tokenize(&String::from_utf8_lossy(&content)).collect())
+    }
+
+    async fn apply<FSP: FilesystemProvider>(
+        &self,
+        provider: &FSP,
+        change: Change,
+    ) -> Result<(), Error> {
+        // Mounts that weren't searched yet get scanned on their first search
+        let Some(index) = self.mounts.lock().unwrap().get(&change.mount).cloned() else {
+            return Ok(());
+        };
+        let ready = {
+            let mut state = index.state.lock().unwrap();
+            if let Some(queued) = &mut state.queued {
+                queued.push(change.clone());
+            }
+            state.ready
+        };
+        if !ready {
+            return Ok(());
+        }
+        let fs = provider.get_filesystem(&change.mount).await?;
+        self.apply_to(&fs, &index, change).await
+    }
+
+    async fn apply_to<FS: Filesystem>(
+        &self,
+        fs: &FS,
+        index: &MountIndex,
+        change: Change,
+    ) -> Result<(), Error> {
+        let previous = BTreeMap::new();
+        match change.kind {
+            ChangeKind::Created | ChangeKind::Modified => {
+                let subtree = self.scan(fs, &change.path, &previous).await?;
+                index.replace_subtree(&change.path, subtree);
+            }
+            ChangeKind::Deleted => index.replace_subtree(&change.path, previous),
+            ChangeKind::Moved { to } => {
+                index.replace_subtree(&change.path, BTreeMap::new());
+                let subtree = self.scan(fs, &to, &previous).await?;
+                index.replace_subtree(&to, subtree);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the index up to date and periodically persists it
+    pub fn spawn<FSP: FilesystemProvider>(&self, provider: Arc<FSP>) {
+        let mut receiver = provider.changes().subscribe();
+        let index = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(change) => {
+                        if let Err(err) = index.apply(provider.as_ref(), change).await {
+                            tracing::warn!("Failed to update search index: {err}");
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        // We missed changes, so everything has to be scanned again
+                        index.mounts.lock().unwrap().clear();
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+
+        if self.config.index_path.is_some() {
+            let index = self.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = index.flush().await {
+                        tracing::error!("Failed to persist search index: {err}");
+                    }
+                }
+            });
+        }
+    }
+
+    pub async fn flush(&self) -> Result<(), std::io::Error> {
+        let mounts: Vec<_> = self
+            .mounts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(mount, index)| (mount.to_owned(), index.clone()))
+            .collect();
+        for (mount, index) in mounts {
+            let Some(index_file) = self.index_file(&mount) else {
+                continue;
+            };
+            if !index.dirty.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            let content = serde_json::to_vec(&*index.entries())?;
+            if let Some(parent) = index_file.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            // Write to a temporary file first so a crash never leaves a truncated index
+            let tmp_file = index_file.with_extension("json.tmp");
+            tokio::fs::write(&tmp_file, content).await?;
+            tokio::fs::rename(&tmp_file, &index_file).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{ChangeNotifier, SimpleFilesystemProvider};
+
+    #[tokio::test]
+    async fn flush_persists_changed_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("files")).unwrap();
+        std::fs::write(root.join("files/notes.txt"), "hello").unwrap();
+        let provider = SimpleFilesystemProvider::new(root, ChangeNotifier::default());
+        let index = SearchIndex::new(SearchConfig {
+            index_path: Some(dir.path().join("index")),
+            ..Default::default()
+        });
+        index.mount(&provider, "files").await.unwrap();
+
+        // Like on shutdown, the index of the scanned mount is written out
+        index.flush().await.unwrap();
+        let index_file = dir.path().join("index/files.json");
+        let persisted: BTreeMap<ScopedPath, IndexEntry> =
+            serde_json::from_slice(&std::fs::read(&index_file).unwrap()).unwrap();
+        let notes = ScopedPath::new("notes.txt".to_owned());
+        assert_eq!(persisted[&notes].len, 5);
+
+        // Nothing changed since, so it isn't written again
+        std::fs::remove_file(&index_file).unwrap();
+        index.flush().await.unwrap();
+        assert!(!index_file.exists());
+    }
+}
@@ -0,0 +1,5 @@
+mod index;
+mod query;
+
+pub use index::{IndexEntry, SearchIndex};
+pub use query::{Depth, MAX_QUERY_SIZE, Property, Query, QueryError, Select, UnknownProperty};
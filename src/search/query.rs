@@ -0,0 +1,849 @@
+//! Parser and evaluator for the DAV:basicsearch grammar (RFC 5323)
+use super::index::{IndexEntry, tokenize};
+use quick_xml::{
+    NsReader,
+    escape::resolve_predefined_entity,
+    events::{BytesStart, Event},
+    name::{Namespace, ResolveResult},
+};
+use scoped_fs::ScopedPath;
+use std::{borrow::Cow, cmp::Ordering, collections::BTreeMap, time::SystemTime};
+
+const NS_DAV: &[u8] = b"DAV:";
+/// Largest accepted search request body
+pub const MAX_QUERY_SIZE: usize = 64 * 1024;
+/// Deepest accepted element nesting, conditions are evaluated recursively
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+    #[error("Invalid search request: {0}")]
+    Invalid(&'static str),
+    #[error("Unsupported search grammar: {0}")]
+    Unsupported(String),
+    #[error("Search request too large")]
+    TooLarge,
+}
+
+impl From<quick_xml::events::attributes::AttrError> for QueryError {
+    fn from(value: quick_xml::events::attributes::AttrError) -> Self {
+        Self::Xml(value.into())
+    }
+}
+
+impl From<quick_xml::encoding::EncodingError> for QueryError {
+    fn from(value: quick_xml::encoding::EncodingError) -> Self {
+        Self::Xml(value.into())
+    }
+}
+
+/// Minimal DOM since the basicsearch grammar is recursive
+#[derive(Debug)]
+struct Element {
+    namespace: Option<Vec<u8>>,
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn new(namespace: Option<Vec<u8>>, start: &BytesStart) -> Result<Self, QueryError> {
+        let mut attributes = vec![];
+        for attribute in start.attributes() {
+            let attribute = attribute?;
+            let key = String::from_utf8_lossy(attribute.key.local_name().as_ref()).into_owned();
+            let value = attribute.unescape_value()?.into_owned();
+            attributes.push((key, value));
+        }
+        Ok(Self {
+            namespace,
+            name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+            attributes,
+            children: vec![],
+            text: String::new(),
+        })
+    }
+
+    fn parse(input: &str) -> Result<Self, QueryError> {
+        let mut reader = NsReader::from_str(input);
+        let mut stack: Vec<Element> = vec![];
+        loop {
+            let (namespace, event) = reader.read_resolved_event()?;
+            let namespace = match namespace {
+                ResolveResult::Bound(Namespace(namespace)) => Some(namespace.to_vec()),
+                _ => None,
+            };
+            let finished = match event {
+                Event::Start(start) => {
+                    if stack.len() >= MAX_DEPTH {
+                        return Err(QueryError::Invalid("nested too deeply"));
+                    }
+                    stack.push(Element::new(namespace, &start)?);
+                    continue;
+                }
+                Event::Empty(start) => Element::new(namespace, &start)?,
+                Event::End(_) => stack.pop().ok_or(QueryError::Invalid("unbalanced tags"))?,
+                Event::Text(text) => {
+                    if let Some(element) = stack.last_mut() {
+                        element.text.push_str(&text.xml_content()?);
+                    }
+                    continue;
+                }
+                Event::CData(cdata) => {
+                    if let Some(element) = stack.last_mut() {
+                        element.text.push_str(&cdata.decode()?);
+                    }
+                    continue;
+                }
+                Event::GeneralRef(reference) => {
+                    if let Some(element) = stack.last_mut() {
+                        if let Some(char) = reference.resolve_char_ref()? {
+                            element.text.push(char);
+                        } else {
+                            let entity = reference.decode()?;
+                            let resolved = resolve_predefined_entity(&entity)
+                                .ok_or(QueryError::Invalid("unknown entity"))?;
+                            element.text.push_str(resolved);
+                        }
+                    }
+                    continue;
+                }
+                Event::Eof => return Err(QueryError::Invalid("unexpected end of document")),
+                _ => continue,
+            };
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(finished),
+                None => return Ok(finished),
+            }
+        }
+    }
+
+    fn is(&self, name: &str) -> bool {
+        self.namespace.as_deref() == Some(NS_DAV) && self.name == name
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(name))
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn caseless(&self) -> bool {
+        // The RFC leaves the default to the server, most clients expect case-insensitivity
+        self.attribute("caseless") != Some("no")
+    }
+}
+
+/// Live properties that can be queried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Property {
+    Displayname,
+    Resourcetype,
+    Getcontentlength,
+    Getlastmodified,
+    Creationdate,
+    Getcontenttype,
+    Getetag,
+}
+
+impl Property {
+    pub const ALL: &[Self] = &[
+        Self::Displayname,
+        Self::Resourcetype,
+        Self::Getcontentlength,
+        Self::Getlastmodified,
+        Self::Creationdate,
+        Self::Getcontenttype,
+        Self::Getetag,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Displayname => "displayname",
+            Self::Resourcetype => "resourcetype",
+            Self::Getcontentlength => "getcontentlength",
+            Self::Getlastmodified => "getlastmodified",
+            Self::Creationdate => "creationdate",
+            Self::Getcontenttype => "getcontenttype",
+            Self::Getetag => "getetag",
+        }
+    }
+
+    fn from_element(element: &Element) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|prop| element.is(prop.name()))
+    }
+
+    /// Parses the single property inside a DAV:prop element
+    fn from_prop(parent: &Element) -> Result<Self, QueryError> {
+        let prop = parent
+            .child("prop")
+            .and_then(|prop| prop.children.first())
+            .ok_or(QueryError::Invalid("missing property"))?;
+        Self::from_element(prop).ok_or_else(|| QueryError::Unsupported(prop.name.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownProperty {
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Select {
+    AllProp,
+    Props(Vec<Property>, Vec<UnknownProperty>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scope {
+    pub href: String,
+    pub depth: Depth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+    Compare {
+        comparison: Comparison,
+        property: Property,
+        literal: String,
+        caseless: bool,
+    },
+    Like {
+        property: Property,
+        pattern: String,
+        caseless: bool,
+    },
+    IsCollection,
+    IsDefined(Property),
+    Contains(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub property: Property,
+    pub descending: bool,
+    pub caseless: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub select: Select,
+    pub scopes: Vec<Scope>,
+    pub condition: Option<Condition>,
+    pub order: Vec<Order>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Self, QueryError> {
+        let root = Element::parse(input)?;
+        if !root.is("searchrequest") {
+            return Err(QueryError::Invalid("expected DAV:searchrequest"));
+        }
+        let Some(search) = root.child("basicsearch") else {
+            let grammar = root
+                .children
+                .first()
+                .map(|child| child.name.to_owned())
+                .unwrap_or_default();
+            return Err(QueryError::Unsupported(grammar));
+        };
+
+        let select = search
+            .child("select")
+            .ok_or(QueryError::Invalid("missing DAV:select"))?;
+        let select = if select.child("allprop").is_some() {
+            Select::AllProp
+        } else {
+            let prop = select
+                .child("prop")
+                .ok_or(QueryError::Invalid("missing DAV:prop in DAV:select"))?;
+            let mut known = vec![];
+            let mut unknown = vec![];
+            for element in &prop.children {
+                match Property::from_element(element) {
+                    Some(property) => known.push(property),
+                    None => unknown.push(UnknownProperty {
+                        namespace: element
+                            .namespace
+                            .as_deref()
+                            .map(|ns| String::from_utf8_lossy(ns).into_owned()),
+                        name: element.name.to_owned(),
+                    }),
+                }
+            }
+            Select::Props(known, unknown)
+        };
+
+        let from = search
+            .child("from")
+            .ok_or(QueryError::Invalid("missing DAV:from"))?;
+        let mut scopes = vec![];
+        for scope in from.children.iter().filter(|child| child.is("scope")) {
+            let href = scope
+                .child("href")
+                .ok_or(QueryError::Invalid("missing DAV:href in DAV:scope"))?
+                .text
+                .trim()
+                .to_owned();
+            let depth = match scope.child("depth").map(|depth| depth.text.trim()) {
+                Some("0") => Depth::Zero,
+                Some("1") => Depth::One,
+                Some("infinity") | None => Depth::Infinity,
+                Some(_) => return Err(QueryError::Invalid("invalid DAV:depth")),
+            };
+            scopes.push(Scope { href, depth });
+        }
+        if scopes.is_empty() {
+            return Err(QueryError::Invalid("missing DAV:scope"));
+        }
+
+        let condition = match search.child("where") {
+            Some(element) => match element.children.as_slice() {
+                [condition] => Some(Condition::parse(condition)?),
+                _ => return Err(QueryError::Invalid("DAV:where needs exactly one condition")),
+            },
+            None => None,
+        };
+
+        let mut order = vec![];
+        if let Some(orderby) = search.child("orderby") {
+            for element in orderby.children.iter().filter(|child| child.is("order")) {
+                order.push(Order {
+                    property: Property::from_prop(element)?,
+                    descending: element.child("descending").is_some(),
+                    caseless: element.caseless(),
+                });
+            }
+        }
+
+        let limit = match search
+            .child("limit")
+            .and_then(|limit| limit.child("nresults"))
+        {
+            Some(nresults) => Some(
+                nresults
+                    .text
+                    .trim()
+                    .parse()
+                    .map_err(|_| QueryError::Invalid("invalid DAV:nresults"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            select,
+            scopes,
+            condition,
+            order,
+            limit,
+        })
+    }
+
+    /// Runs the query against an index with the scopes already resolved to paths
+    pub fn execute(
+        &self,
+        entries: &BTreeMap<ScopedPath, IndexEntry>,
+        scopes: &[(ScopedPath, Depth)],
+    ) -> Vec<(ScopedPath, IndexEntry)> {
+        let mut results: Vec<_> = entries
+            .iter()
+            .filter(|(path, _)| {
+                scopes.iter().any(|(scope, depth)| {
+                    matches!(
+                        (depth_below(scope, path), depth),
+                        (Some(0), _) | (Some(1), Depth::One) | (Some(_), Depth::Infinity)
+                    )
+                })
+            })
+            .filter(|(path, entry)| {
+                self.condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.matches(path, entry))
+            })
+            .map(|(path, entry)| (path.to_owned(), entry.to_owned()))
+            .collect();
+
+        results.sort_by(|(a_path, a), (b_path, b)| {
+            for order in &self.order {
+                let a_value = value(order.property, a_path, a, order.caseless);
+                let b_value = value(order.property, b_path, b, order.caseless);
+                // Undefined values sort last
+                let ordering = match (a_value, b_value) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                };
+                let ordering = if order.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                };
+                if ordering.is_ne() {
+                    return ordering;
+                }
+            }
+            a_path.cmp(b_path)
+        });
+
+        if let Some(limit) = self.limit {
+            results.truncate(limit);
+        }
+        results
+    }
+}
+
+/// How many levels path is below scope
+fn depth_below(scope: &ScopedPath, path: &ScopedPath) -> Option<usize> {
+    if !path.starts_with(scope) {
+        return None;
+    }
+    let rest = path.as_str()[scope.as_str().len()..].trim_start_matches('/');
+    if rest.is_empty() {
+        Some(0)
+    } else {
+        Some(rest.split('/').count())
+    }
+}
+
+impl Condition {
+    fn parse(element: &Element) -> Result<Self, QueryError> {
+        if element.namespace.as_deref() != Some(NS_DAV) {
+            return Err(QueryError::Unsupported(element.name.to_owned()));
+        }
+        let comparison = match element.name.as_str() {
+            "and" | "or" => {
+                let operands = element
+                    .children
+                    .iter()
+                    .map(Self::parse)
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(if element.name == "and" {
+                    Self::And(operands)
+                } else {
+                    Self::Or(operands)
+                });
+            }
+            "not" => {
+                return match element.children.as_slice() {
+                    [operand] => Ok(Self::Not(Box::new(Self::parse(operand)?))),
+                    _ => Err(QueryError::Invalid("DAV:not needs exactly one operand")),
+                };
+            }
+            "is-collection" => return Ok(Self::IsCollection),
+            "is-defined" => return Ok(Self::IsDefined(Property::from_prop(element)?)),
+            "contains" => return Ok(Self::Contains(element.text.trim().to_owned())),
+            "like" => {
+                return Ok(Self::Like {
+                    property: Property::from_prop(element)?,
+                    pattern: literal(element)?,
+                    caseless: element.caseless(),
+                });
+            }
+            "eq" => Comparison::Eq,
+            "lt" => Comparison::Lt,
+            "gt" => Comparison::Gt,
+            "lte" => Comparison::Lte,
+            "gte" => Comparison::Gte,
+            other => return Err(QueryError::Unsupported(other.to_owned())),
+        };
+        Ok(Self::Compare {
+            comparison,
+            property: Property::from_prop(element)?,
+            literal: literal(element)?,
+            caseless: element.caseless(),
+        })
+    }
+
+    pub fn matches(&self, path: &ScopedPath, entry: &IndexEntry) -> bool {
+        match self {
+            Self::And(operands) => operands.iter().all(|op| op.matches(path, entry)),
+            Self::Or(operands) => operands.iter().any(|op| op.matches(path, entry)),
+            Self::Not(operand) => !operand.matches(path, entry),
+            Self::IsCollection => entry.is_dir,
+            Self::IsDefined(property) => value(*property, path, entry, false).is_some(),
+            Self::Contains(text) => entry.terms.as_ref().is_some_and(|terms| {
+                let mut words = tokenize(text).peekable();
+                words.peek().is_some() && words.all(|word| terms.contains(&word))
+            }),
+            Self::Like {
+                property,
+                pattern,
+                caseless,
+            } => match value(*property, path, entry, *caseless) {
+                Some(Value::Text(text)) => {
+                    let pattern = if *caseless {
+                        pattern.to_lowercase()
+                    } else {
+                        pattern.to_owned()
+                    };
+                    like(&pattern, &text)
+                }
+                _ => false,
+            },
+            Self::Compare {
+                comparison,
+                property,
+                literal,
+                caseless,
+            } => {
+                let Some(value) = value(*property, path, entry, *caseless) else {
+                    return false;
+                };
+                let Some(literal) = value.parse_literal(literal, *caseless) else {
+                    return false;
+                };
+                let Some(ordering) = value.partial_cmp(&literal) else {
+                    return false;
+                };
+                match comparison {
+                    Comparison::Eq => ordering.is_eq(),
+                    Comparison::Lt => ordering.is_lt(),
+                    Comparison::Gt => ordering.is_gt(),
+                    Comparison::Lte => ordering.is_le(),
+                    Comparison::Gte => ordering.is_ge(),
+                }
+            }
+        }
+    }
+}
+
+fn literal(element: &Element) -> Result<String, QueryError> {
+    element
+        .child("literal")
+        .or_else(|| element.child("typed-literal"))
+        .map(|literal| literal.text.trim().to_owned())
+        .ok_or(QueryError::Invalid("missing DAV:literal"))
+}
+
+#[derive(Debug, PartialEq, PartialOrd)]
+enum Value<'a> {
+    Number(u64),
+    /// Milliseconds since the Unix epoch
+    Date(u64),
+    Text(Cow<'a, str>),
+}
+
+impl Value<'_> {
+    fn parse_literal(&self, literal: &str, caseless: bool) -> Option<Value<'static>> {
+        Some(match self {
+            Self::Number(_) => Value::Number(literal.parse().ok()?),
+            Self::Date(_) => Value::Date(parse_date(literal)?),
+            Self::Text(_) if caseless => Value::Text(literal.to_lowercase().into()),
+            Self::Text(_) => Value::Text(literal.to_owned().into()),
+        })
+    }
+}
+
+fn parse_date(literal: &str) -> Option<u64> {
+    let time = match httpdate::parse_http_date(literal) {
+        Ok(time) => time,
+        Err(_) => chrono::DateTime::parse_from_rfc3339(literal)
+            .ok()?
+            .to_utc()
+            .into(),
+    };
+    Some(
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?
+            .as_millis() as u64,
+    )
+}
+
+fn value<'a>(
+    property: Property,
+    path: &'a ScopedPath,
+    entry: &'a IndexEntry,
+    caseless: bool,
+) -> Option<Value<'a>> {
+    let text = |text: &'a str| {
+        Value::Text(if caseless {
+            text.to_lowercase().into()
+        } else {
+            text.into()
+        })
+    };
+    Some(match property {
+        Property::Displayname => text(path.file_name()),
+        Property::Getcontenttype => text(entry.content_type.as_deref()?),
+        Property::Getetag => Value::Text(entry.etag()?.into()),
+        Property::Getcontentlength if !entry.is_dir => Value::Number(entry.len),
+        Property::Getlastmodified => Value::Date(entry.modified),
+        Property::Creationdate => Value::Date(entry.created),
+        Property::Getcontentlength | Property::Resourcetype => return None,
+    })
+}
+
+/// SQL-style LIKE with % matching any sequence, _ matching a single character
+/// and \ as escape character
+fn like(pattern: &str, text: &str) -> bool {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Token {
+        Any,
+        One,
+        Char(char),
+    }
+
+    let mut tokens = vec![];
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        tokens.push(match char {
+            '%' => Token::Any,
+            '_' => Token::One,
+            '\\' => Token::Char(chars.next().unwrap_or('\\')),
+            char => Token::Char(char),
+        });
+    }
+    let text: Vec<char> = text.chars().collect();
+
+    // Greedy matching with backtracking to the last %
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Token::Any) => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(Token::One) => {
+                t += 1;
+                p += 1;
+            }
+            Some(Token::Char(char)) if *char == text[t] => {
+                t += 1;
+                p += 1;
+            }
+            _ => match backtrack {
+                Some((any_p, any_t)) => {
+                    backtrack = Some((any_p, any_t + 1));
+                    p = any_p + 1;
+                    t = any_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[p..].iter().all(|token| *token == Token::Any)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(condition: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <d:searchrequest xmlns:d="DAV:">
+                <d:basicsearch>
+                    <d:select><d:prop><d:displayname/></d:prop></d:select>
+                    <d:from><d:scope><d:href>/</d:href><d:depth>1</d:depth></d:scope></d:from>
+                    {condition}
+                </d:basicsearch>
+            </d:searchrequest>"#
+        )
+    }
+
+    fn entry(is_dir: bool, len: u64) -> IndexEntry {
+        IndexEntry {
+            is_dir,
+            len,
+            modified: 0,
+            created: 0,
+            content_type: None,
+            terms: None,
+        }
+    }
+
+    #[test]
+    fn parses_nested_conditions() {
+        let query = Query::parse(&search(
+            r#"<d:where><d:and>
+                <d:not><d:is-collection/></d:not>
+                <d:or>
+                    <d:like caseless="no"><d:prop><d:displayname/></d:prop><d:literal>%.txt</d:literal></d:like>
+                    <d:gt><d:prop><d:getcontentlength/></d:prop><d:literal>10</d:literal></d:gt>
+                </d:or>
+            </d:and></d:where>"#,
+        ))
+        .unwrap();
+        assert_eq!(
+            query.select,
+            Select::Props(vec![Property::Displayname], vec![])
+        );
+        assert_eq!(
+            query.scopes,
+            vec![Scope {
+                href: "/".to_owned(),
+                depth: Depth::One
+            }]
+        );
+        assert_eq!(
+            query.condition,
+            Some(Condition::And(vec![
+                Condition::Not(Box::new(Condition::IsCollection)),
+                Condition::Or(vec![
+                    Condition::Like {
+                        property: Property::Displayname,
+                        pattern: "%.txt".to_owned(),
+                        caseless: false,
+                    },
+                    Condition::Compare {
+                        comparison: Comparison::Gt,
+                        property: Property::Getcontentlength,
+                        literal: "10".to_owned(),
+                        caseless: true,
+                    },
+                ]),
+            ]))
+        );
+
+        let condition = query.condition.unwrap();
+        let file = ScopedPath::new("notes.txt".to_owned());
+        let dir = ScopedPath::new("notes.txt.d".to_owned());
+        assert!(condition.matches(&file, &entry(false, 0)));
+        assert!(!condition.matches(&file, &entry(true, 0)));
+        assert!(!condition.matches(&dir, &entry(false, 5)));
+        assert!(condition.matches(&dir, &entry(false, 11)));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let invalid = |input: &str| {
+            matches!(
+                Query::parse(input),
+                Err(QueryError::Invalid(_) | QueryError::Xml(_))
+            )
+        };
+        assert!(invalid(""));
+        assert!(invalid("<d:searchrequest xmlns:d=\"DAV:\">"));
+        assert!(invalid("<searchrequest/>"));
+        assert!(invalid(
+            r#"<d:searchrequest xmlns:d="DAV:"><d:basicsearch>
+                <d:from><d:scope><d:href>/</d:href></d:scope></d:from>
+            </d:basicsearch></d:searchrequest>"#
+        ));
+        assert!(invalid(
+            r#"<d:searchrequest xmlns:d="DAV:"><d:basicsearch>
+                <d:select><d:allprop/></d:select><d:from/>
+            </d:basicsearch></d:searchrequest>"#
+        ));
+        assert!(invalid(&search(
+            "<d:where><d:is-collection/><d:is-collection/></d:where>"
+        )));
+        assert!(invalid(&search("<d:where><d:not/></d:where>")));
+        assert!(invalid(&search(
+            "<d:where><d:eq><d:prop><d:displayname/></d:prop></d:eq></d:where>"
+        )));
+        assert!(invalid(&search(
+            "<d:limit><d:nresults>many</d:nresults></d:limit>"
+        )));
+        assert!(matches!(
+            Query::parse(&search(
+                "<d:where><d:eq><d:prop><x:foo xmlns:x=\"x:\"/></d:prop><d:literal/></d:eq></d:where>"
+            )),
+            Err(QueryError::Unsupported(_))
+        ));
+        assert!(matches!(
+            Query::parse(
+                r#"<d:searchrequest xmlns:d="DAV:"><x:sql xmlns:x="x:"/></d:searchrequest>"#
+            ),
+            Err(QueryError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| {
+            search(&format!(
+                "<d:where>{}<d:is-collection/>{}</d:where>",
+                "<d:not>".repeat(depth),
+                "</d:not>".repeat(depth)
+            ))
+        };
+        assert!(Query::parse(&nested(MAX_DEPTH - 6)).is_ok());
+        assert!(matches!(
+            Query::parse(&nested(MAX_DEPTH)),
+            Err(QueryError::Invalid(_))
+        ));
+        assert!(matches!(
+            Query::parse(&nested(100_000)),
+            Err(QueryError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn like_wildcards() {
+        assert!(like("%", ""));
+        assert!(like("%", "anything"));
+        assert!(like("%.txt", "notes.txt"));
+        assert!(!like("%.txt", "notes.txt.bak"));
+        assert!(like("a%b%c", "aXXbYYc"));
+        assert!(like("a%b%c", "abbc"));
+        assert!(!like("a%b%c", "acb"));
+        assert!(like("_", "x"));
+        assert!(!like("_", ""));
+        assert!(!like("_", "xy"));
+        assert!(like("f_o", "föo"));
+        assert!(like("%_", "x"));
+        assert!(!like("%_", ""));
+        assert!(like("exact", "exact"));
+        assert!(!like("exact", "Exact"));
+    }
+
+    #[test]
+    fn like_escapes() {
+        assert!(like(r"100\%", "100%"));
+        assert!(!like(r"100\%", "1000"));
+        assert!(like(r"a\_b", "a_b"));
+        assert!(!like(r"a\_b", "axb"));
+        assert!(like(r"a\\b", r"a\b"));
+        assert!(like(r"%\%%", "50% off"));
+        assert!(!like(r"%\%%", "50 off"));
+        // A trailing escape matches a literal backslash
+        assert!(like(r"end\", r"end\"));
+    }
+
+    #[test]
+    fn like_caseless() {
+        let condition = |caseless| Condition::Like {
+            property: Property::Displayname,
+            pattern: "%.TXT".to_owned(),
+            caseless,
+        };
+        let path = ScopedPath::new("Notes.txt".to_owned());
+        assert!(condition(true).matches(&path, &entry(false, 0)));
+        assert!(!condition(false).matches(&path, &entry(false, 0)));
+    }
+}
@@ -0,0 +1,640 @@
+//! Listeners accepting connections for axum::serve
+use crate::config::{HttpConfig, ListenerConfig, TlsConfig};
+use anyhow::Context;
+use axum::{
+    Router, ServiceExt,
+    extract::{
+        Request,
+        connect_info::{ConnectInfo, Connected, IntoMakeServiceWithConnectInfo},
+    },
+    serve::{IncomingStream, Listener},
+};
+use futures::{FutureExt, future::BoxFuture};
+use http::Extensions;
+use std::{
+    future::IntoFuture,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    signal::unix::{SignalKind, signal},
+    sync::watch,
+};
+use tower::Layer;
+use tower_http::normalize_path::{NormalizePath, NormalizePathLayer};
+
+mod systemd;
+mod tls;
+use systemd::{InheritedListener, InheritedSockets};
+pub use tls::{TlsListener, load_config as load_tls_config};
+
+pub type App = IntoMakeServiceWithConnectInfo<NormalizePath<Router>, ConnectionInfo>;
+
+/// Trailing slashes are trimmed, handlers get the ConnectionInfo
+pub fn into_app(router: Router) -> App {
+    ServiceExt::<Request>::into_make_service_with_connect_info::<ConnectionInfo>(
+        NormalizePathLayer::trim_trailing_slash().layer(router),
+    )
+}
+
+/// What we know about the client of a connection
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    pub peer: Option<SocketAddr>,
+    /// User id taken from a verified TLS client certificate
+    pub client_cert_user: Option<String>,
+    /// Whether the connection uses TLS
+    pub secure: bool,
+    /// Came in on a Unix socket whose clients are trusted like configured proxies
+    pub trusted_socket: bool,
+    _active: Option<Arc<ActiveConnection>>,
+}
+
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a connection until its service and all of its requests are dropped
+#[derive(Debug)]
+struct ActiveConnection;
+
+impl ActiveConnection {
+    fn new() -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Open connections on all listeners
+pub fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+// Implemented per listener, a blanket impl for all listeners conflicts with axum's own
+impl Connected<IncomingStream<'_, TcpPlainListener>> for ConnectionInfo {
+    fn connect_info(stream: IncomingStream<'_, TcpPlainListener>) -> Self {
+        stream.remote_addr().connected()
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixPlainListener>> for ConnectionInfo {
+    fn connect_info(stream: IncomingStream<'_, UnixPlainListener>) -> Self {
+        stream.remote_addr().connected()
+    }
+}
+
+impl<L: Listener<Addr = ConnectionInfo>> Connected<IncomingStream<'_, TlsListener<L>>>
+    for ConnectionInfo
+{
+    fn connect_info(stream: IncomingStream<'_, TlsListener<L>>) -> Self {
+        stream.remote_addr().connected()
+    }
+}
+
+impl ConnectionInfo {
+    /// Called once per connection, counts it as active until it is dropped
+    fn connected(&self) -> Self {
+        Self {
+            _active: Some(Arc::new(ActiveConnection::new())),
+            ..self.clone()
+        }
+    }
+
+    pub fn from_extensions(extensions: &Extensions) -> Option<&Self> {
+        extensions
+            .get::<ConnectInfo<Self>>()
+            .map(|ConnectInfo(info)| info)
+    }
+}
+
+#[cfg(test)]
+impl ConnectionInfo {
+    pub fn unix(trusted_socket: bool) -> Self {
+        Self {
+            trusted_socket,
+            ..Default::default()
+        }
+    }
+}
+
+/// Address of the directly connected client
+pub fn peer_ip(extensions: &Extensions) -> Option<IpAddr> {
+    ConnectionInfo::from_extensions(extensions)
+        .and_then(|info| info.peer)
+        .map(|peer| peer.ip())
+}
+
+/// Plain TCP without TLS
+pub struct TcpPlainListener(pub TcpListener);
+
+impl Listener for TcpPlainListener {
+    type Io = TcpStream;
+    type Addr = ConnectionInfo;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, peer) = Listener::accept(&mut self.0).await;
+        let info = ConnectionInfo {
+            peer: Some(peer),
+            ..Default::default()
+        };
+        (stream, info)
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(ConnectionInfo {
+            peer: Some(self.0.local_addr()?),
+            ..Default::default()
+        })
+    }
+}
+
+/// Unix domain socket, there is no peer address
+pub struct UnixPlainListener {
+    pub listener: UnixListener,
+    /// Clients are trusted like configured proxies
+    pub trusted: bool,
+}
+
+impl Listener for UnixPlainListener {
+    type Io = UnixStream;
+    type Addr = ConnectionInfo;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, _) = Listener::accept(&mut self.listener).await;
+        let info = ConnectionInfo {
+            trusted_socket: self.trusted,
+            ..Default::default()
+        };
+        (stream, info)
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(ConnectionInfo {
+            trusted_socket: self.trusted,
+            ..Default::default()
+        })
+    }
+}
+
+fn bind_unix(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    // A socket left behind by a previous run would make bind fail
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Resolves on SIGTERM or SIGINT
+pub async fn shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Installing a signal handler cannot fail");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+fn serve_on<L>(
+    listener: L,
+    tls: Option<&TlsConfig>,
+    app: App,
+    shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<BoxFuture<'static, std::io::Result<()>>>
+where
+    L: Listener<Addr = ConnectionInfo>,
+    ConnectionInfo: for<'a> Connected<IncomingStream<'a, L>>,
+{
+    let shutdown = async move {
+        let mut shutdown = shutdown;
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    };
+    Ok(match tls {
+        Some(tls) => axum::serve(TlsListener::new(listener, tls.to_owned())?, app)
+            .with_graceful_shutdown(shutdown)
+            .into_future()
+            .boxed(),
+        None => axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown)
+            .into_future()
+            .boxed(),
+    })
+}
+
+/// Binds all listeners first so that configuration errors surface before serving.
+/// Once shutdown resolves no new connections are accepted
+/// and running requests get until the shutdown timeout to finish.
+/// The admin listener, e.g. for metrics, serves its own app and is drained the same way.
+pub async fn serve(
+    config: &HttpConfig,
+    app: App,
+    admin: Option<(TcpListener, App)>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut servers = vec![];
+    if let Some((listener, admin)) = admin {
+        servers.push(serve_on(
+            TcpPlainListener(listener),
+            None,
+            admin,
+            shutdown_rx.clone(),
+        )?);
+    }
+    let mut inherited = None;
+    for listener in config.listeners() {
+        let tls = listener.tls();
+        match &listener {
+            ListenerConfig::Tcp { address, .. } => {
+                let tcp = TcpListener::bind(address)
+                    .await
+                    .with_context(|| format!("Cannot listen on {address}"))?;
+                tracing::info!("Listening on {address}");
+                servers.push(serve_on(
+                    TcpPlainListener(tcp),
+                    tls,
+                    app.clone(),
+                    shutdown_rx.clone(),
+                )?);
+            }
+            ListenerConfig::Unix {
+                path,
+                mode,
+                trusted,
+                ..
+            } => {
+                let unix = bind_unix(path, *mode)
+                    .with_context(|| format!("Cannot listen on {}", path.display()))?;
+                tracing::info!("Listening on {}", path.display());
+                servers.push(serve_on(
+                    UnixPlainListener {
+                        listener: unix,
+                        trusted: *trusted,
+                    },
+                    tls,
+                    app.clone(),
+                    shutdown_rx.clone(),
+                )?);
+            }
+            ListenerConfig::Systemd { name, trusted, .. } => {
+                let sockets = inherited
+                    .get_or_insert_with(InheritedSockets::from_env)
+                    .take(name.as_deref())?;
+                if sockets.is_empty() {
+                    anyhow::bail!("systemd passed no sockets for {name:?}");
+                }
+                for socket in sockets {
+                    let server = match socket {
+                        InheritedListener::Tcp(tcp) => serve_on(
+                            TcpPlainListener(TcpListener::from_std(tcp)?),
+                            tls,
+                            app.clone(),
+                            shutdown_rx.clone(),
+                        )?,
+                        InheritedListener::Unix(unix) => serve_on(
+                            UnixPlainListener {
+                                listener: UnixListener::from_std(unix)?,
+                                trusted: *trusted,
+                            },
+                            tls,
+                            app.clone(),
+                            shutdown_rx.clone(),
+                        )?,
+                    };
+                    servers.push(server);
+                }
+                tracing::info!("Listening on sockets from systemd");
+            }
+        }
+    }
+    let mut servers = futures::future::try_join_all(servers);
+    tokio::select! {
+        result = &mut servers => {
+            result?;
+            return Ok(());
+        }
+        () = shutdown => {}
+    }
+
+    let timeout = Duration::from_secs(config.shutdown_timeout);
+    tracing::info!("Shutting down, waiting up to {timeout:?} for running requests");
+    let _ = shutdown_tx.send(true);
+    match tokio::time::timeout(timeout, servers).await {
+        Ok(result) => {
+            result?;
+        }
+        // Aborted uploads are discarded, see PendingFile
+        Err(_) => tracing::warn!("Requests still running after the shutdown timeout, aborting"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        base_url::{BaseUrl, Forwarding, client_ip, resolve_base_url},
+        config::AuditConfig,
+        dav::fs::{FSResourceService, FSResourceServicePath},
+        filesystem::{ChangeNotifier, Filesystem, FilesystemProvider, SimpleFilesystemProvider},
+        groups::GroupStore,
+    };
+    use axum::{
+        Extension,
+        body::Body,
+        extract::State,
+        routing::{get, put},
+    };
+    use futures::StreamExt;
+    use http::StatusCode;
+    use scoped_fs::ScopedPath;
+    use std::{collections::HashMap, path::PathBuf};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::{Notify, oneshot},
+        task::JoinHandle,
+    };
+
+    type ResourceService = FSResourceService<SimpleFilesystemProvider>;
+
+    /// Stores the body like a DAV PUT of /files/upload.bin
+    async fn route_put(
+        State((resource_service, started)): State<(ResourceService, Arc<Notify>)>,
+        body: Body,
+    ) -> StatusCode {
+        let body = Body::from_stream(
+            body.into_data_stream()
+                .inspect(move |_| started.notify_one()),
+        );
+        let path = FSResourceServicePath {
+            mount: "files".to_owned(),
+            path: ScopedPath::new("upload.bin".to_owned()),
+        };
+        match resource_service.write_file(&path, body).await {
+            Ok(_) => StatusCode::CREATED,
+            Err(err) => err.status_code(),
+        }
+    }
+
+    struct Server {
+        dir: tempfile::TempDir,
+        provider: Arc<SimpleFilesystemProvider>,
+        target: PathBuf,
+        started: Arc<Notify>,
+    }
+
+    impl Server {
+        /// Also returns the shutdown trigger and the task running serve
+        fn start(
+            shutdown_timeout: u64,
+        ) -> (Self, oneshot::Sender<()>, JoinHandle<anyhow::Result<()>>) {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().join("root");
+            std::fs::create_dir_all(root.join("files")).unwrap();
+            let target = root.join("files/upload.bin");
+            let provider = Arc::new(SimpleFilesystemProvider::new(
+                root,
+                ChangeNotifier::default(),
+            ));
+            let resource_service = FSResourceService::new(
+                provider.clone(),
+                GroupStore::from_config(&HashMap::new()),
+                AuditLog::new(&AuditConfig::default()).unwrap(),
+            );
+            let started = Arc::new(Notify::new());
+            let app = Router::new()
+                .route("/upload", put(route_put))
+                .with_state((resource_service, started.clone()));
+            let config = HttpConfig {
+                listeners: vec![ListenerConfig::Unix {
+                    path: dir.path().join("wolke.sock"),
+                    mode: None,
+                    trusted: false,
+                    tls: None,
+                }],
+                shutdown_timeout,
+                ..Default::default()
+            };
+            let (shutdown, signal) = oneshot::channel();
+            let handle = tokio::spawn(async move {
+                let signal = async move {
+                    let _ = signal.await;
+                };
+                serve(&config, into_app(app), None, signal).await
+            });
+            (
+                Self {
+                    dir,
+                    provider,
+                    target,
+                    started,
+                },
+                shutdown,
+                handle,
+            )
+        }
+
+        /// Sends the headers and the first half of an 8 byte upload
+        async fn begin_upload(&self) -> UnixStream {
+            let socket = self.dir.path().join("wolke.sock");
+            let mut stream = loop {
+                match UnixStream::connect(&socket).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            stream
+                .write_all(
+                    b"PUT /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 8\r\n\r\nabcd",
+                )
+                .await
+                .unwrap();
+            self.started.notified().await;
+            stream
+        }
+
+        /// Everything on disk in the mount, including leftovers of aborted uploads
+        fn files(&self) -> Vec<String> {
+            std::fs::read_dir(self.target.parent().unwrap())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect()
+        }
+
+        /// What clients see of the mount
+        async fn listed(&self) -> Vec<String> {
+            let fs = self.provider.get_filesystem("files").await.unwrap();
+            fs.list_dir(&ScopedPath::default())
+                .await
+                .unwrap()
+                .iter()
+                .map(|path| path.file_name().to_owned())
+                .collect()
+        }
+    }
+
+    /// What resolve_base_url makes of forwarded headers sent over a Unix socket
+    async fn forwarded_request(trusted: bool) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("wolke.sock");
+        let forwarding = Forwarding::new(&HttpConfig::default()).unwrap();
+        let app = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(base_url): Extension<BaseUrl>, extensions: Extensions| async move {
+                        format!("{} {:?}", base_url.url("/"), client_ip(&extensions))
+                    },
+                ),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                forwarding,
+                resolve_base_url,
+            ));
+        let listener = UnixPlainListener {
+            listener: bind_unix(&socket, None).unwrap(),
+            trusted,
+        };
+        tokio::spawn(axum::serve(listener, into_app(app)).into_future());
+
+        let mut stream = UnixStream::connect(&socket).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-Proto: https\r\n\
+                X-Forwarded-Host: files.example\r\nX-Forwarded-For: 192.0.2.1\r\n\
+                Connection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().last().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn trusts_forwarded_headers_on_trusted_unix_sockets() {
+        assert_eq!(
+            forwarded_request(true).await,
+            "https://files.example/ Some(192.0.2.1)"
+        );
+        assert_eq!(forwarded_request(false).await, "http://localhost/ None");
+    }
+
+    #[tokio::test]
+    async fn serves_on_every_listener() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("wolke.sock");
+        // A socket left behind by an earlier run
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = HttpConfig {
+            listeners: vec![
+                ListenerConfig::Tcp {
+                    address: address.to_string(),
+                    tls: None,
+                },
+                ListenerConfig::Unix {
+                    path: socket.clone(),
+                    mode: Some(0o660),
+                    trusted: false,
+                    tls: None,
+                },
+            ],
+            ..Default::default()
+        };
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let handle = tokio::spawn(async move {
+            let signal = async move {
+                let _ = signal.await;
+            };
+            serve(&config, into_app(app), None, signal).await
+        });
+
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let mut response = String::new();
+        let mut tcp = loop {
+            match TcpStream::connect(address).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        tcp.write_all(request).await.unwrap();
+        tcp.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("hello"), "{response}");
+
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        let mut unix = UnixStream::connect(&socket).await.unwrap();
+        unix.write_all(request).await.unwrap();
+        response.clear();
+        unix.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("hello"), "{response}");
+
+        shutdown.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn drains_running_uploads() {
+        let (server, shutdown, handle) = Server::start(10);
+        let mut stream = server.begin_upload().await;
+        // The upload in progress is hidden from listings
+        assert_eq!(server.files().len(), 1);
+        assert!(server.listed().await.is_empty());
+
+        shutdown.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.target.exists());
+        stream.write_all(b"efgh").await.unwrap();
+
+        let mut response = vec![0; 12];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 201");
+        drop(stream);
+        handle.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&server.target).unwrap(), b"abcdefgh");
+        assert_eq!(server.files(), ["upload.bin"]);
+        assert_eq!(server.listed().await, ["upload.bin"]);
+    }
+
+    #[tokio::test]
+    async fn discards_uploads_cut_off_by_the_timeout() {
+        let (server, shutdown, handle) = Server::start(1);
+        let stream = server.begin_upload().await;
+
+        shutdown.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+        // No truncated file at the destination, only the hidden temporary file
+        assert!(!server.target.exists());
+
+        // Once the connection is gone the temporary file is removed too
+        drop(stream);
+        for _ in 0..100 {
+            if server.files().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(server.files().is_empty());
+        assert!(!server.target.exists());
+    }
+}
@@ -0,0 +1,104 @@
+//! Socket activation as described in sd_listen_fds(3)
+use std::{
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixListener,
+    },
+};
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum InheritedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Sockets passed to us by systemd, each can only be taken once
+pub struct InheritedSockets(Vec<(String, Option<OwnedFd>)>);
+
+impl InheritedSockets {
+    pub fn from_env() -> Self {
+        // The variables might have been meant for our parent
+        let for_us = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            == Some(std::process::id());
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<RawFd>().ok())
+            .filter(|_| for_us)
+            .unwrap_or(0);
+        let names: Vec<String> = std::env::var("LISTEN_FDNAMES")
+            .map(|names| names.split(':').map(str::to_owned).collect())
+            .unwrap_or_default();
+        let sockets = (0..count)
+            .map(|i| {
+                let name = names
+                    .get(i as usize)
+                    .cloned()
+                    .unwrap_or_else(|| "unknown".to_owned());
+                // SAFETY: systemd passes the sockets starting at fd 3 and nothing else owns them
+                let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START + i) };
+                (name, Some(fd))
+            })
+            .collect();
+        Self(sockets)
+    }
+
+    /// The sockets with the given name or all remaining ones
+    pub fn take(&mut self, name: Option<&str>) -> std::io::Result<Vec<InheritedListener>> {
+        self.0
+            .iter_mut()
+            .filter(|(fd_name, _)| name.is_none_or(|name| name == fd_name))
+            .filter_map(|(_, fd)| fd.take())
+            .map(InheritedListener::try_from)
+            .collect()
+    }
+}
+
+impl TryFrom<OwnedFd> for InheritedListener {
+    type Error = std::io::Error;
+
+    fn try_from(fd: OwnedFd) -> Result<Self, Self::Error> {
+        // Only sockets of an internet address family have a TCP local address
+        let tcp = TcpListener::from(fd);
+        let listener = if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            Self::Tcp(tcp)
+        } else {
+            let unix = UnixListener::from(OwnedFd::from(tcp));
+            unix.set_nonblocking(true)?;
+            Self::Unix(unix)
+        };
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_sockets_by_name_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let unix = UnixListener::bind(dir.path().join("wolke.sock")).unwrap();
+        let mut sockets = InheritedSockets(vec![
+            ("web".to_owned(), Some(OwnedFd::from(tcp))),
+            ("admin".to_owned(), Some(OwnedFd::from(unix))),
+        ]);
+
+        let admin = sockets.take(Some("admin")).unwrap();
+        assert!(matches!(admin.as_slice(), [InheritedListener::Unix(_)]));
+        assert!(sockets.take(Some("admin")).unwrap().is_empty());
+
+        // The rest, recognized as TCP by their address
+        match sockets.take(None).unwrap().as_slice() {
+            [InheritedListener::Tcp(tcp)] => assert_eq!(tcp.local_addr().unwrap(), address),
+            _ => panic!("expected the TCP socket"),
+        }
+        assert!(sockets.take(None).unwrap().is_empty());
+    }
+}
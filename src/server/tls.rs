@@ -0,0 +1,340 @@
+use super::ConnectionInfo;
+use crate::config::{ClientCertUser, TlsConfig, TlsVersion};
+use axum::serve::Listener;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::task::JoinSet;
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+/// Clients that don't finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Certificate renewals usually touch several files in quick succession
+const RELOAD_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Cannot read {0}: {1}")]
+    Pem(String, rustls::pki_types::pem::Error),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error(transparent)]
+    Verifier(#[from] rustls::server::VerifierBuilderError),
+}
+
+pub fn load_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, Error> {
+    let pem_error = |path: &Path| {
+        let path = path.display().to_string();
+        move |err| Error::Pem(path, err)
+    };
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error(&config.cert_path))?;
+    let key =
+        PrivateKeyDer::from_pem_file(&config.key_path).map_err(pem_error(&config.key_path))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let versions: &[&rustls::SupportedProtocolVersion] = match config.min_version {
+        TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+    let builder =
+        ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(versions)?;
+    let builder = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in
+                CertificateDer::pem_file_iter(client_ca_path).map_err(pem_error(client_ca_path))?
+            {
+                roots.add(cert.map_err(pem_error(client_ca_path))?)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.client_cert_required {
+                verifier.build()?
+            } else {
+                verifier.allow_unauthenticated().build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+/// The user a verified client certificate belongs to
+fn client_cert_user(cert: &CertificateDer, field: ClientCertUser) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    match field {
+        ClientCertUser::CommonName => cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_owned),
+        ClientCertUser::Email => cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()?
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                x509_parser::extensions::GeneralName::RFC822Name(email) => {
+                    Some((*email).to_owned())
+                }
+                _ => None,
+            }),
+    }
+}
+
+/// TLS on top of another listener, the certificate is reloaded when its files change
+pub struct TlsListener<L: Listener> {
+    inner: L,
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+    client_cert_user: ClientCertUser,
+    handshakes: JoinSet<Option<(TlsStream<L::Io>, ConnectionInfo)>>,
+    // Dropping the watcher stops reloading
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl<L: Listener<Addr = ConnectionInfo>> TlsListener<L> {
+    pub fn new(inner: L, config: TlsConfig) -> anyhow::Result<Self> {
+        let server_config = Arc::new(RwLock::new(load_config(&config)?));
+        let watcher = if config.reload {
+            Some(watch_certificate(config.clone(), server_config.clone())?)
+        } else {
+            None
+        };
+        Ok(Self {
+            inner,
+            server_config,
+            client_cert_user: config.client_cert_user,
+            handshakes: JoinSet::new(),
+            _watcher: watcher,
+        })
+    }
+}
+
+fn watch_certificate(
+    config: TlsConfig,
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+) -> notify::Result<RecommendedWatcher> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // Only fails once the receiving task is gone
+        let _ = tx.send(event);
+    })?;
+    // Watch the directories since renewals tend to replace files or symlinks
+    let mut paths = vec![config.cert_path.as_path(), config.key_path.as_path()];
+    paths.extend(config.client_ca_path.as_deref());
+    for path in paths {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Err(err) = event {
+                tracing::warn!("certificate watcher error: {err}");
+                continue;
+            }
+            // Wait for the renewal to finish and swallow the events caused by it
+            tokio::time::sleep(RELOAD_DELAY).await;
+            while rx.try_recv().is_ok() {}
+            match load_config(&config) {
+                Ok(new_config) => {
+                    *server_config.write().unwrap() = new_config;
+                    tracing::info!("Reloaded TLS certificate");
+                }
+                // Keep serving the old certificate
+                Err(err) => tracing::error!("Failed to reload TLS certificate: {err}"),
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+impl<L: Listener<Addr = ConnectionInfo>> Listener for TlsListener<L> {
+    type Io = TlsStream<L::Io>;
+    type Addr = ConnectionInfo;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                (stream, info) = self.inner.accept() => {
+                    let acceptor = TlsAcceptor::from(self.server_config.read().unwrap().clone());
+                    let field = self.client_cert_user;
+                    self.handshakes.spawn(async move {
+                        let stream = match tokio::time::timeout(
+                            HANDSHAKE_TIMEOUT,
+                            acceptor.accept(stream),
+                        )
+                        .await
+                        {
+                            Ok(Ok(stream)) => stream,
+                            Ok(Err(err)) => {
+                                tracing::debug!(peer = ?info.peer, "TLS handshake failed: {err}");
+                                return None;
+                            }
+                            Err(_) => {
+                                tracing::debug!(peer = ?info.peer, "TLS handshake timed out");
+                                return None;
+                            }
+                        };
+                        let client_cert_user = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .and_then(|cert| client_cert_user(cert, field));
+                        Some((
+                            stream,
+                            ConnectionInfo {
+                                client_cert_user,
+                                secure: true,
+                                ..info
+                            },
+                        ))
+                    });
+                }
+                Some(handshake) = self.handshakes.join_next() => {
+                    if let Ok(Some(connection)) = handshake {
+                        return connection;
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::TcpPlainListener;
+    use rustls::{ClientConfig, pki_types::ServerName};
+    use std::path::PathBuf;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+    use tokio_rustls::{TlsConnector, client};
+
+    /// A CA, a certificate for localhost and one for alice@example.org signed by it
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/server/testdata")
+            .join(name)
+    }
+
+    fn config(client_cert_required: bool) -> TlsConfig {
+        TlsConfig {
+            cert_path: testdata("server.pem"),
+            key_path: testdata("server.key"),
+            min_version: TlsVersion::Tls13,
+            reload: false,
+            client_ca_path: Some(testdata("ca.pem")),
+            client_cert_required,
+            client_cert_user: ClientCertUser::Email,
+        }
+    }
+
+    /// Accepts connections in the background, handshakes that failed never arrive
+    async fn listen(
+        config: TlsConfig,
+    ) -> (
+        std::net::SocketAddr,
+        mpsc::UnboundedReceiver<(TlsStream<TcpStream>, ConnectionInfo)>,
+    ) {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let mut listener = TlsListener::new(TcpPlainListener(tcp), config).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                if tx.send(listener.accept().await).is_err() {
+                    return;
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    async fn connect(addr: std::net::SocketAddr, with_cert: bool) -> client::TlsStream<TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(testdata("ca.pem")).unwrap())
+            .unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = if with_cert {
+            let cert = CertificateDer::from_pem_file(testdata("client.pem")).unwrap();
+            let key = PrivateKeyDer::from_pem_file(testdata("client.key")).unwrap();
+            builder.with_client_auth_cert(vec![cert], key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_certificates_name_the_user() {
+        let (addr, mut connections) = listen(config(false)).await;
+
+        let _client = connect(addr, true).await;
+        let (_, info) = connections.recv().await.unwrap();
+        assert!(info.secure);
+        assert_eq!(info.client_cert_user.as_deref(), Some("alice@example.org"));
+
+        let _client = connect(addr, false).await;
+        let (_, info) = connections.recv().await.unwrap();
+        assert!(info.secure);
+        assert_eq!(info.client_cert_user, None);
+
+        let cert = CertificateDer::from_pem_file(testdata("client.pem")).unwrap();
+        let user = client_cert_user(&cert, ClientCertUser::CommonName);
+        assert_eq!(user.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn required_client_certificates() {
+        let (addr, mut connections) = listen(config(true)).await;
+
+        // TLS 1.3 clients only learn about the rejection when reading
+        let mut rejected = connect(addr, false).await;
+        assert!(rejected.read(&mut [0; 16]).await.is_err());
+
+        let _client = connect(addr, true).await;
+        let (_, info) = connections.recv().await.unwrap();
+        assert_eq!(info.client_cert_user.as_deref(), Some("alice@example.org"));
+    }
+
+    #[test]
+    fn unreadable_certificates_name_the_file() {
+        let mut config = config(false);
+        config.key_path = testdata("missing.key");
+        let err = load_config(&config).unwrap_err();
+        assert!(err.to_string().contains("missing.key"), "{err}");
+    }
+}
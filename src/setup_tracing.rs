@@ -17,9 +17,13 @@ use tracing::level_filters::LevelFilter;
 use tracing::warn;
 #[cfg(feature = "opentelemetry")]
 use tracing_opentelemetry::OpenTelemetryLayer;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::ParseError;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+/// Swaps the log filter of the running subscriber
+pub type LogFilter = reload::Handle<EnvFilter, Registry>;
 
 #[cfg(feature = "opentelemetry")]
 pub fn init_otel() -> Tracer {
@@ -48,14 +52,21 @@ pub fn init_otel() -> Tracer {
     tracer_provider.tracer("rustical")
 }
 
-pub fn setup_tracing(config: &TracingConfig) {
-    let fmt_layer = tracing_subscriber::fmt::layer();
-    let filter_layer = EnvFilter::builder()
-        .with_default_directive(LevelFilter::WARN.into())
-        .from_env_lossy()
+pub fn env_filter(config: &TracingConfig) -> Result<EnvFilter, ParseError> {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::WARN.into());
+    let filter = match &config.filter {
+        Some(filter) => builder.parse(filter)?,
+        None => builder.from_env_lossy(),
+    };
+    Ok(filter
         .add_directive("h2=warn".parse().unwrap())
         .add_directive("hyper_util=warn".parse().unwrap())
-        .add_directive("tower=warn".parse().unwrap());
+        .add_directive("tower=warn".parse().unwrap()))
+}
+
+pub fn setup_tracing(config: &TracingConfig) -> Result<LogFilter, ParseError> {
+    let fmt_layer = tracing_subscriber::fmt::layer();
+    let (filter_layer, log_filter) = reload::Layer::new(env_filter(config)?);
 
     let registry = tracing_subscriber::registry()
         .with(filter_layer)
@@ -77,4 +88,5 @@ pub fn setup_tracing(config: &TracingConfig) {
     } else {
         registry.init();
     }
+    Ok(log_filter)
 }
@@ -0,0 +1,76 @@
+use axum::response::{IntoResponse, Response};
+use http::StatusCode;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Not Found")]
+    NotFound,
+
+    /// The share expired or its download limit was reached
+    #[error("Gone")]
+    Gone,
+
+    #[error("Password required")]
+    Locked,
+
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error(transparent)]
+    Dav(#[from] crate::dav::Error),
+
+    #[error(transparent)]
+    Render(#[from] askama::Error),
+
+    /// Too many wrong passwords
+    #[error(transparent)]
+    Auth(#[from] crate::auth::Error),
+}
+
+impl From<crate::db::Error> for Error {
+    fn from(value: crate::db::Error) -> Self {
+        Self::Dav(value.into())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Dav(value.into())
+    }
+}
+
+impl From<crate::filesystem::Error> for Error {
+    fn from(value: crate::filesystem::Error) -> Self {
+        Self::Dav(value.into())
+    }
+}
+
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Gone => StatusCode::GONE,
+            Self::Locked => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::Dav(err) => err.status_code(),
+            Self::Render(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Auth(err) => err.status_code(),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        if let Self::Auth(err) = self {
+            return err.into_response();
+        }
+        let mut response = (self.status_code(), self.to_string()).into_response();
+        if let Self::Locked = self {
+            response.headers_mut().insert(
+                http::header::WWW_AUTHENTICATE,
+                http::HeaderValue::from_static(r#"Basic realm="Wolke share""#),
+            );
+        }
+        response
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    db::{Database, Error, json_column, to_json},
+    groups::GroupStore,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{Params, params};
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum Grantee {
+    User(String),
+    Group(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantPrivilege {
+    Read,
+    /// Read, write and delete
+    Write,
+}
+
+/// A folder shared with other users.
+/// It appears as a collection called `name` in the root of each recipient's mount.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub id: String,
+    pub owner: String,
+    pub mount: String,
+    pub path: ScopedPath,
+    pub grantee: Grantee,
+    pub privilege: GrantPrivilege,
+    pub name: String,
+    pub created: DateTime<Utc>,
+}
+
+/// Grants, persisted in the database
+#[derive(Debug, Clone)]
+pub struct GrantStore {
+    db: Database,
+    groups: GroupStore,
+    /// All grants, every request looks up the ones its user received.
+    /// Filled on first use and dropped whenever a grant changes.
+    cache: Arc<RwLock<Option<Arc<[Grant]>>>>,
+}
+
+impl GrantStore {
+    pub fn new(db: Database, groups: GroupStore) -> Self {
+        Self {
+            db,
+            groups,
+            cache: Default::default(),
+        }
+    }
+
+    fn cached(&self) -> Result<Arc<[Grant]>, Error> {
+        if let Some(grants) = self.cache.read().unwrap().as_ref() {
+            return Ok(grants.clone());
+        }
+        let mut cache = self.cache.write().unwrap();
+        let grants: Arc<[Grant]> = self.list()?.into();
+        *cache = Some(grants.clone());
+        Ok(grants)
+    }
+
+    fn invalidate(&self) {
+        *self.cache.write().unwrap() = None;
+    }
+
+    fn query(&self, sql: &str, params: impl Params) -> Result<Vec<Grant>, Error> {
+        self.db.with(|conn| {
+            let mut stmt = conn.prepare(sql)?;
+            let mut grants = stmt
+                .query_map(params, |row| json_column::<Grant>(row, 0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            grants.sort_by_key(|grant| grant.created);
+            Ok(grants)
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Grant>, Error> {
+        Ok(self
+            .query("SELECT data FROM grants WHERE id = ?1", [id])?
+            .pop())
+    }
+
+    pub fn list(&self) -> Result<Vec<Grant>, Error> {
+        self.query("SELECT data FROM grants", [])
+    }
+
+    pub fn list_by_owner(&self, owner: &str) -> Result<Vec<Grant>, Error> {
+        self.query("SELECT data FROM grants WHERE owner = ?1", [owner])
+    }
+
+    /// Grants that show up in the mount of a user.
+    /// Group memberships are checked on every call as they change without the grants.
+    pub fn received_by(&self, user: &str) -> Result<Vec<Grant>, Error> {
+        Ok(self
+            .cached()?
+            .iter()
+            .filter(|grant| match &grant.grantee {
+                Grantee::User(id) => id == user,
+                Grantee::Group(id) => self.groups.is_member(user, id),
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Grants mounted in the mount of a user, one per collection name.
+    /// Sharing with oneself would only shadow the original folder.
+    pub fn mounted_in(&self, user: &str) -> Result<Vec<Grant>, Error> {
+        let mut mounted: Vec<Grant> = vec![];
+        for grant in self.received_by(user)? {
+            if grant.mount != user && !mounted.iter().any(|other| other.name == grant.name) {
+                mounted.push(grant);
+            }
+        }
+        Ok(mounted)
+    }
+
+    /// Grants of folders in a mount
+    pub fn shared_from(&self, mount: &str) -> Result<Vec<Grant>, Error> {
+        Ok(self
+            .cached()?
+            .iter()
+            .filter(|grant| grant.mount == mount)
+            .cloned()
+            .collect())
+    }
+
+    /// Users currently receiving a grant
+    pub fn recipients(&self, grant: &Grant) -> Vec<String> {
+        match &grant.grantee {
+            Grantee::User(id) => vec![id.to_owned()],
+            Grantee::Group(id) => self
+                .groups
+                .get(id)
+                .map(|group| group.all_members().into_iter().collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Inserts or replaces a grant
+    pub fn insert(&self, grant: Grant) -> Result<(), Error> {
+        let data = to_json(&grant)?;
+        self.db.with(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO grants (id, owner, data) VALUES (?1, ?2, ?3)",
+                params![grant.id, grant.owner, data],
+            )?;
+            Ok(())
+        })?;
+        self.invalidate();
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<Grant>, Error> {
+        let grant = self.get(id)?;
+        if grant.is_some() {
+            self.db
+                .with(|conn| Ok(conn.execute("DELETE FROM grants WHERE id = ?1", [id])?))?;
+            self.invalidate();
+        }
+        Ok(grant)
+    }
+}
@@ -0,0 +1,88 @@
+//! Public share links exposing a single path of a mount through a random token
+//! and folders shared between users
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use chrono::{DateTime, Utc};
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+mod error;
+mod grants;
+mod public;
+mod store;
+pub use error::Error;
+pub use grants::{Grant, GrantPrivilege, GrantStore, Grantee};
+pub use public::share_router;
+pub use store::ShareStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareKind {
+    /// Download only
+    Read,
+    /// Upload only, visitors can add new files to a folder but not see its contents
+    FileDrop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Share {
+    pub token: String,
+    pub owner: String,
+    pub mount: String,
+    pub path: ScopedPath,
+    pub is_dir: bool,
+    pub kind: ShareKind,
+    /// Argon2 PHC string
+    pub password_hash: Option<String>,
+    pub expires: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u64>,
+    pub downloads: u64,
+    pub created: DateTime<Utc>,
+}
+
+impl Share {
+    pub fn new_token() -> String {
+        uuid::Uuid::new_v4().simple().to_string()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires < Utc::now())
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max_downloads| self.downloads >= max_downloads)
+    }
+
+    /// Expired or exhausted shares stay around until their owner revokes them
+    pub fn is_active(&self) -> bool {
+        !self.is_expired() && !self.is_exhausted()
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        let Some(password_hash) = &self.password_hash else {
+            return true;
+        };
+        verify_password(password_hash, password)
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 with default parameters cannot fail")
+        .to_string()
+}
+
+/// Checks a password against an Argon2 PHC string
+pub fn verify_password(password_hash: &str, password: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
@@ -0,0 +1,733 @@
+use super::{Error, Share, ShareKind, ShareStore};
+use crate::{
+    auth::Authenticator,
+    base_url::BaseUrl,
+    config::ShareConfig,
+    dav::{
+        PATH_SEGMENT, User,
+        fs::{FSResource, FSResourceService, FSResourceServicePath, route_get, route_head},
+    },
+    filesystem::{DavMetadata, FilesystemProvider},
+};
+use askama::Template;
+use axum::{
+    Extension, Form, Router,
+    body::Body,
+    extract::{Path, Request, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{any, get},
+};
+use axum_extra::TypedHeader;
+use headers::{Authorization, Cookie, HeaderMapExt, Range, authorization::Basic};
+use http::{Extensions, HeaderMap, HeaderValue, StatusCode, header};
+use httpdate::HttpDate;
+use percent_encoding::utf8_percent_encode;
+use quick_xml::escape::escape;
+use rustical_dav::{
+    privileges::UserPrivilege,
+    resource::{Resource, ResourceService},
+};
+use scoped_fs::ScopedPath;
+use serde::Deserialize;
+use std::{fmt::Write, net::IpAddr, ops::Bound};
+
+const SESSION_COOKIE: &str = "wolke_share";
+
+/// Share links for visitors without an account,
+/// as web pages below /s/{token} and as WebDAV below /dav/share/{token}
+pub fn share_router<FSP: FilesystemProvider>(
+    resource_service: FSResourceService<FSP>,
+    config: &ShareConfig,
+) -> Router {
+    Router::new()
+        .route("/s/{token}", get(route_page::<FSP>).post(route_unlock))
+        .route(
+            "/s/{token}/{*path}",
+            get(route_page::<FSP>).put(route_drop::<FSP>),
+        )
+        .route("/dav/share/{token}", any(route_dav::<FSP>))
+        .route("/dav/share/{token}/{*path}", any(route_dav::<FSP>))
+        .layer(Extension(DropLimit(config.max_drop_size)))
+        .with_state(resource_service)
+}
+
+/// Maximum size of a dropped file
+#[derive(Debug, Clone, Copy)]
+struct DropLimit(u64);
+
+#[derive(Debug, Deserialize)]
+struct SharePath {
+    token: String,
+    /// Path relative to the shared path
+    #[serde(default)]
+    path: String,
+}
+
+/// Wrong passwords are throttled like failed logins, per IP address and share
+fn check_password(
+    authenticator: &Authenticator,
+    client: Option<IpAddr>,
+    share: &Share,
+    password: &str,
+) -> Result<bool, Error> {
+    let account = format!("share:{}", share.token);
+    let throttle = authenticator.throttle();
+    if let Some(throttle) = throttle
+        && let Err(retry_after) = throttle.check(client, &account)
+    {
+        tracing::info!(?client, ?retry_after, "share password throttled");
+        return Err(crate::auth::Error::TooManyRequests(retry_after).into());
+    }
+    let valid = share.verify_password(password);
+    if let Some(throttle) = throttle {
+        if valid {
+            throttle.record_success(&account);
+        } else {
+            throttle.record_failure(client, &account);
+        }
+    }
+    Ok(valid)
+}
+
+/// Looks up an active share the request may access,
+/// a password is accepted as session cookie or as HTTP Basic auth
+fn open_share(
+    store: &ShareStore,
+    authenticator: &Authenticator,
+    token: &str,
+    headers: &HeaderMap,
+    extensions: &Extensions,
+) -> Result<Share, Error> {
+    let share = store.get(token)?.ok_or(Error::NotFound)?;
+    crate::audit::set_principal(format!("share:{token}"));
+    if !share.is_active() {
+        return Err(Error::Gone);
+    }
+    if share.password_hash.is_none() {
+        return Ok(share);
+    }
+    let session = headers
+        .typed_get::<Cookie>()
+        .and_then(|cookie| cookie.get(SESSION_COOKIE).map(str::to_owned));
+    if session.is_some_and(|session| store.is_unlocked(token, &session)) {
+        return Ok(share);
+    }
+    if let Some(Authorization(basic)) = headers.typed_get::<Authorization<Basic>>()
+        && check_password(
+            authenticator,
+            crate::base_url::client_ip(extensions),
+            &share,
+            basic.password(),
+        )?
+    {
+        return Ok(share);
+    }
+    Err(Error::Locked)
+}
+
+/// Maps a path relative to the share into its mount, never leaving the shared path
+fn resolve(share: &Share, relative: &str) -> Result<FSResourceServicePath, Error> {
+    let relative = ScopedPath::from_relative(std::path::Path::new(relative.trim_matches('/')))
+        .ok_or(Error::NotFound)?;
+    if relative.is_root() {
+        return Ok(FSResourceServicePath {
+            mount: share.mount.to_owned(),
+            path: share.path.to_owned(),
+        });
+    }
+    if !share.is_dir {
+        return Err(Error::NotFound);
+    }
+    Ok(FSResourceServicePath {
+        mount: share.mount.to_owned(),
+        path: share.path.join_segment(relative.as_str()),
+    })
+}
+
+/// Visitors act with the owner's privileges, a share stops working once the owner lost access
+fn owner<FSP: FilesystemProvider>(
+    resource_service: &FSResourceService<FSP>,
+    authenticator: &Authenticator,
+    share: &Share,
+) -> User {
+    User {
+        id: share.owner.to_owned(),
+        displayname: None,
+        memberships: resource_service.groups.memberships(&share.owner),
+        unrestricted: !authenticator.is_enabled(),
+    }
+}
+
+/// Path of a resource relative to the share
+fn relative_path<'a>(share: &Share, path: &'a ScopedPath) -> &'a str {
+    path.as_str()
+        .strip_prefix(share.path.as_str())
+        .unwrap_or_default()
+        .trim_start_matches('/')
+}
+
+/// The mount root has no name of its own
+fn share_title(share: &Share, path: &ScopedPath) -> String {
+    match path.file_name() {
+        "" => share.mount.to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+struct ListingEntry {
+    name: String,
+    href: String,
+    is_dir: bool,
+    size: String,
+    modified: String,
+}
+
+#[derive(Template)]
+#[template(path = "share_listing.html")]
+struct ListingTemplate {
+    title: String,
+    parent_href: Option<String>,
+    entries: Vec<ListingEntry>,
+}
+
+#[derive(Template)]
+#[template(path = "share_password.html")]
+struct PasswordTemplate {
+    share_href: String,
+    wrong_password: bool,
+}
+
+#[derive(Template)]
+#[template(path = "share_drop.html")]
+struct DropTemplate {
+    share_href: String,
+    title: String,
+}
+
+fn render(template: impl Template, status: StatusCode) -> Result<Response, Error> {
+    Ok((status, Html(template.render()?)).into_response())
+}
+
+/// Whether a GET of a file of len bytes counts towards the download limit.
+/// Only ranges reaching the end of the file count, so a download fetched in chunks
+/// counts once however it is split.
+fn is_download(headers: &HeaderMap, len: u64) -> bool {
+    let Some(range) = headers.typed_get::<Range>() else {
+        return true;
+    };
+    let mut ranges = range.satisfiable_ranges(len).peekable();
+    // Without a satisfiable range the whole file is sent
+    ranges.peek().is_none()
+        || ranges.any(|(_, end)| match end {
+            Bound::Included(end) => end + 1 >= len,
+            Bound::Excluded(end) => end >= len,
+            Bound::Unbounded => true,
+        })
+}
+
+async fn serve_read<FSP: FilesystemProvider>(
+    resource_service: FSResourceService<FSP>,
+    store: &ShareStore,
+    base_url: &BaseUrl,
+    share: &Share,
+    user: User,
+    relative: &str,
+    req: Request,
+) -> Result<Response, Error> {
+    let target = resolve(share, relative)?;
+    let resource = resource_service
+        .authorize(&target, &user, UserPrivilege::Read)
+        .await?;
+    if !resource.metadata.is_dir() {
+        if is_download(req.headers(), resource.metadata.len())
+            && !store.record_download(&share.token)?
+        {
+            return Err(Error::Gone);
+        }
+        let range = req.headers().typed_get::<Range>().map(TypedHeader);
+        return Ok(route_get(State(resource_service), Path(target), user, range, req).await?);
+    }
+
+    let base_href = base_url.path(&format!("/s/{}", share.token));
+    let mut members = resource_service.get_members(&target).await?;
+    members.sort_by(|a, b| {
+        (!a.metadata.is_dir(), a.path.file_name()).cmp(&(!b.metadata.is_dir(), b.path.file_name()))
+    });
+    let entries = members
+        .iter()
+        .map(|member| ListingEntry {
+            name: member.path.file_name().to_owned(),
+            href: format!(
+                "{base_href}/{}",
+                encode_path(relative_path(share, &member.path))
+            ),
+            is_dir: member.metadata.is_dir(),
+            size: format_size(member.metadata.len()),
+            modified: HttpDate::from(member.metadata.modified()).to_string(),
+        })
+        .collect();
+    let relative = relative_path(share, &resource.path);
+    let parent_href = (!relative.is_empty()).then(|| {
+        let parent = ScopedPath::new(relative.to_owned()).parent();
+        format!("{base_href}/{}", encode_path(parent.as_str()))
+    });
+    render(
+        ListingTemplate {
+            title: share_title(share, &resource.path),
+            parent_href,
+            entries,
+        },
+        StatusCode::OK,
+    )
+}
+
+/// Adds a new file to a file drop, existing files are never overwritten
+async fn drop_file<FSP: FilesystemProvider>(
+    resource_service: FSResourceService<FSP>,
+    DropLimit(max_size): DropLimit,
+    share: &Share,
+    user: &User,
+    relative: &str,
+    body: Body,
+) -> Result<Response, Error> {
+    if share.kind != ShareKind::FileDrop {
+        return Err(Error::Forbidden);
+    }
+    let name = relative.trim_matches('/');
+    if name.is_empty() || name.contains('/') {
+        return Err(Error::Forbidden);
+    }
+    let target = resolve(share, name)?;
+    resource_service.authorize_create(&target, user).await?;
+    resource_service
+        .create_file(&target, body, max_size)
+        .await?;
+    Ok(StatusCode::CREATED.into_response())
+}
+
+async fn route_page<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Extension(store): Extension<ShareStore>,
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(base_url): Extension<BaseUrl>,
+    Path(SharePath { token, path }): Path<SharePath>,
+    req: Request,
+) -> Result<Response, Error> {
+    let share = match open_share(
+        &store,
+        &authenticator,
+        &token,
+        req.headers(),
+        req.extensions(),
+    ) {
+        Ok(share) => share,
+        Err(Error::Locked) => {
+            return render(
+                PasswordTemplate {
+                    share_href: base_url.path(&format!("/s/{token}")),
+                    wrong_password: false,
+                },
+                StatusCode::UNAUTHORIZED,
+            );
+        }
+        Err(err) => return Err(err),
+    };
+    let user = owner(&resource_service, &authenticator, &share);
+    match share.kind {
+        ShareKind::Read => {
+            serve_read(
+                resource_service,
+                &store,
+                &base_url,
+                &share,
+                user,
+                &path,
+                req,
+            )
+            .await
+        }
+        ShareKind::FileDrop if path.is_empty() => render(
+            DropTemplate {
+                title: share_title(&share, &share.path),
+                share_href: base_url.path(&format!("/s/{token}")),
+            },
+            StatusCode::OK,
+        ),
+        ShareKind::FileDrop => Err(Error::NotFound),
+    }
+}
+
+#[derive(Deserialize)]
+struct UnlockForm {
+    password: String,
+}
+
+async fn route_unlock(
+    Extension(store): Extension<ShareStore>,
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(base_url): Extension<BaseUrl>,
+    Path(SharePath { token, .. }): Path<SharePath>,
+    extensions: Extensions,
+    Form(UnlockForm { password }): Form<UnlockForm>,
+) -> Result<Response, Error> {
+    let share = store.get(&token)?.ok_or(Error::NotFound)?;
+    if !share.is_active() {
+        return Err(Error::Gone);
+    }
+    let share_href = base_url.path(&format!("/s/{token}"));
+    let client = crate::base_url::client_ip(&extensions);
+    if !check_password(&authenticator, client, &share, &password)? {
+        return render(
+            PasswordTemplate {
+                share_href,
+                wrong_password: true,
+            },
+            StatusCode::FORBIDDEN,
+        );
+    }
+    let session = store.unlock(&token);
+    let mut response = Redirect::to(&share_href).into_response();
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        HeaderValue::try_from(format!(
+            "{SESSION_COOKIE}={session}; Path={share_href}; Max-Age={}; HttpOnly; SameSite=Lax",
+            store.session_lifetime().as_secs()
+        ))
+        .unwrap(),
+    );
+    Ok(response)
+}
+
+async fn route_drop<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Extension(store): Extension<ShareStore>,
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(limit): Extension<DropLimit>,
+    Path(SharePath { token, path }): Path<SharePath>,
+    req: Request,
+) -> Result<Response, Error> {
+    let share = open_share(
+        &store,
+        &authenticator,
+        &token,
+        req.headers(),
+        req.extensions(),
+    )?;
+    let user = owner(&resource_service, &authenticator, &share);
+    drop_file(
+        resource_service,
+        limit,
+        &share,
+        &user,
+        &path,
+        req.into_body(),
+    )
+    .await
+}
+
+async fn route_dav<FSP: FilesystemProvider>(
+    State(resource_service): State<FSResourceService<FSP>>,
+    Extension(store): Extension<ShareStore>,
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(base_url): Extension<BaseUrl>,
+    Extension(limit): Extension<DropLimit>,
+    Path(SharePath { token, path }): Path<SharePath>,
+    req: Request,
+) -> Result<Response, Error> {
+    let share = open_share(
+        &store,
+        &authenticator,
+        &token,
+        req.headers(),
+        req.extensions(),
+    )?;
+    let user = owner(&resource_service, &authenticator, &share);
+    let method = req.method().clone();
+    match (method.as_str(), share.kind) {
+        ("OPTIONS", kind) => {
+            let allow = match kind {
+                ShareKind::Read => "OPTIONS, GET, HEAD, PROPFIND",
+                ShareKind::FileDrop => "OPTIONS, PROPFIND, PUT",
+            };
+            let mut response = StatusCode::OK.into_response();
+            let headers = response.headers_mut();
+            headers.insert(header::ALLOW, HeaderValue::from_static(allow));
+            headers.insert("DAV", HeaderValue::from_static("1"));
+            Ok(response)
+        }
+        ("PROPFIND", kind) => {
+            let base_href = base_url.path(&format!("/dav/share/{token}"));
+            propfind(
+                resource_service,
+                &base_href,
+                &share,
+                &user,
+                kind,
+                &path,
+                req.headers(),
+            )
+            .await
+        }
+        ("GET", ShareKind::Read) => {
+            serve_read(
+                resource_service,
+                &store,
+                &base_url,
+                &share,
+                user,
+                &path,
+                req,
+            )
+            .await
+        }
+        ("HEAD", ShareKind::Read) => {
+            let target = resolve(&share, &path)?;
+            Ok(route_head(State(resource_service), Path(target), user).await?)
+        }
+        ("PUT", _) => {
+            drop_file(
+                resource_service,
+                limit,
+                &share,
+                &user,
+                &path,
+                req.into_body(),
+            )
+            .await
+        }
+        _ => Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+    }
+}
+
+async fn propfind<FSP: FilesystemProvider>(
+    resource_service: FSResourceService<FSP>,
+    base_href: &str,
+    share: &Share,
+    user: &User,
+    kind: ShareKind,
+    relative: &str,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    let mut multistatus =
+        String::from(r#"<?xml version="1.0" encoding="utf-8"?><multistatus xmlns="DAV:">"#);
+    match kind {
+        // Visitors of a file drop only see an empty folder
+        ShareKind::FileDrop if relative.trim_matches('/').is_empty() => {
+            write!(
+                multistatus,
+                "<response><href>{}/</href><propstat><prop><resourcetype><collection/></resourcetype></prop><status>HTTP/1.1 200 OK</status></propstat></response>",
+                escape(base_href)
+            )
+            .unwrap();
+        }
+        ShareKind::FileDrop => return Err(Error::NotFound),
+        ShareKind::Read => {
+            let target = resolve(share, relative)?;
+            let resource = resource_service
+                .authorize(&target, user, UserPrivilege::Read)
+                .await?;
+            write_response(&mut multistatus, base_href, share, &resource);
+            let depth_zero = headers.get("Depth").is_some_and(|depth| depth == "0");
+            if resource.metadata.is_dir() && !depth_zero {
+                for member in resource_service.get_members(&target).await? {
+                    write_response(&mut multistatus, base_href, share, &member);
+                }
+            }
+        }
+    }
+    multistatus.push_str("</multistatus>");
+    Ok((
+        StatusCode::MULTI_STATUS,
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/xml; charset=utf-8"),
+        )],
+        multistatus,
+    )
+        .into_response())
+}
+
+fn write_response<FSP: FilesystemProvider>(
+    out: &mut String,
+    base_href: &str,
+    share: &Share,
+    resource: &FSResource<FSP>,
+) {
+    let relative = relative_path(share, &resource.path);
+    let mut href = format!("{base_href}/{}", encode_path(relative));
+    let is_dir = resource.metadata.is_dir();
+    if is_dir && !href.ends_with('/') {
+        href.push('/');
+    }
+    write!(
+        out,
+        "<response><href>{}</href><propstat><prop><displayname>{}</displayname>",
+        escape(&href),
+        escape(resource.path.file_name())
+    )
+    .unwrap();
+    if is_dir {
+        out.push_str("<resourcetype><collection/></resourcetype>");
+    } else {
+        write!(
+            out,
+            "<resourcetype/><getcontentlength>{}</getcontentlength>",
+            resource.metadata.len()
+        )
+        .unwrap();
+        if let Some(content_type) = resource.get_content_type() {
+            write!(
+                out,
+                "<getcontenttype>{}</getcontenttype>",
+                escape(content_type)
+            )
+            .unwrap();
+        }
+    }
+    write!(
+        out,
+        "<getlastmodified>{}</getlastmodified>",
+        HttpDate::from(resource.metadata.modified())
+    )
+    .unwrap();
+    if let Some(etag) = resource.get_etag() {
+        write!(out, "<getetag>{}</getetag>", escape(&etag)).unwrap();
+    }
+    out.push_str("</prop><status>HTTP/1.1 200 OK</status></propstat></response>");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use chrono::Utc;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn share(max_downloads: Option<u64>) -> Share {
+        Share {
+            token: "token".to_owned(),
+            owner: "alice".to_owned(),
+            mount: "alice".to_owned(),
+            path: ScopedPath::new("file.bin".to_owned()),
+            is_dir: false,
+            kind: ShareKind::Read,
+            password_hash: None,
+            expires: None,
+            max_downloads,
+            downloads: 0,
+            created: Utc::now(),
+        }
+    }
+
+    /// Router serving a 100 byte file of alice through share
+    fn router(dir: &std::path::Path, share: Share) -> (Router, ShareStore) {
+        std::fs::create_dir_all(dir.join("alice")).unwrap();
+        std::fs::write(dir.join("alice/file.bin"), [0; 100]).unwrap();
+        let resource_service = FSResourceService::in_dir(dir);
+        let store = ShareStore::new(Database::open(None).unwrap(), Duration::from_secs(60));
+        store.insert(share).unwrap();
+        let router = share_router(resource_service.clone(), &ShareConfig::default())
+            .layer(Extension(store.clone()))
+            .layer(Extension(Authenticator::new(
+                resource_service.groups.clone(),
+                Duration::from_secs(60),
+            )))
+            .layer(Extension(BaseUrl::root()));
+        (router, store)
+    }
+
+    async fn get(router: &Router, range: Option<&str>) -> StatusCode {
+        let mut request = Request::get("/s/token");
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+        let response = router
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    fn downloads(store: &ShareStore) -> u64 {
+        store.get("token").unwrap().unwrap().downloads
+    }
+
+    #[test]
+    fn ranges_reaching_the_end_are_downloads() {
+        let headers = |range: &str| HeaderMap::from_iter([(header::RANGE, range.parse().unwrap())]);
+        assert!(is_download(&HeaderMap::new(), 100));
+        assert!(is_download(&headers("bytes=0-"), 100));
+        assert!(is_download(&headers("bytes=50-99"), 100));
+        assert!(is_download(&headers("bytes=-10"), 100));
+        assert!(is_download(&headers("bytes=0-9, 90-"), 100));
+        assert!(!is_download(&headers("bytes=0-49"), 100));
+        // Unsatisfiable ranges are answered with the whole file
+        assert!(is_download(&headers("bytes=200-"), 100));
+    }
+
+    #[tokio::test]
+    async fn download_limit_counts_chunked_and_suffix_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let (router, store) = router(dir.path(), share(Some(2)));
+
+        // A download split into chunks counts once, with the chunk reaching the end
+        assert_eq!(
+            get(&router, Some("bytes=0-49")).await,
+            StatusCode::PARTIAL_CONTENT
+        );
+        assert_eq!(downloads(&store), 0);
+        assert_eq!(
+            get(&router, Some("bytes=50-99")).await,
+            StatusCode::PARTIAL_CONTENT
+        );
+        assert_eq!(downloads(&store), 1);
+
+        // Suffix ranges are no way around the limit
+        assert_eq!(
+            get(&router, Some("bytes=-10")).await,
+            StatusCode::PARTIAL_CONTENT
+        );
+        assert_eq!(downloads(&store), 2);
+        assert_eq!(get(&router, Some("bytes=0-49")).await, StatusCode::GONE);
+        assert_eq!(get(&router, None).await, StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn unlimited_shares_serve_whole_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (router, store) = router(dir.path(), share(None));
+        for _ in 0..3 {
+            assert_eq!(get(&router, None).await, StatusCode::OK);
+        }
+        assert_eq!(downloads(&store), 3);
+        assert_eq!(
+            get(&router, Some("bytes=0-9")).await,
+            StatusCode::PARTIAL_CONTENT
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use super::Share;
+use crate::db::{Database, Error, json_column, to_json};
+use rusqlite::{OptionalExtension, Params, TransactionBehavior, params};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Shares, persisted in the database
+#[derive(Clone)]
+pub struct ShareStore {
+    db: Database,
+    session_lifetime: Duration,
+    /// Browser sessions that entered the password of a share, as (token, session) with expiry
+    unlocked: Arc<Mutex<HashMap<(String, String), Instant>>>,
+}
+
+impl ShareStore {
+    pub fn new(db: Database, session_lifetime: Duration) -> Self {
+        Self {
+            db,
+            session_lifetime,
+            unlocked: Default::default(),
+        }
+    }
+
+    pub fn session_lifetime(&self) -> Duration {
+        self.session_lifetime
+    }
+
+    fn query(&self, sql: &str, params: impl Params) -> Result<Vec<Share>, Error> {
+        self.db.with(|conn| {
+            let mut stmt = conn.prepare(sql)?;
+            let shares = stmt
+                .query_map(params, |row| json_column::<Share>(row, 0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(shares)
+        })
+    }
+
+    pub fn get(&self, token: &str) -> Result<Option<Share>, Error> {
+        Ok(self
+            .query("SELECT data FROM shares WHERE token = ?1", [token])?
+            .pop())
+    }
+
+    pub fn list(&self) -> Result<Vec<Share>, Error> {
+        let mut shares = self.query("SELECT data FROM shares", [])?;
+        shares.sort_by_key(|share| share.created);
+        Ok(shares)
+    }
+
+    pub fn list_by_owner(&self, owner: &str) -> Result<Vec<Share>, Error> {
+        let mut shares = self.query("SELECT data FROM shares WHERE owner = ?1", [owner])?;
+        shares.sort_by_key(|share| share.created);
+        Ok(shares)
+    }
+
+    /// Inserts or replaces a share
+    pub fn insert(&self, share: Share) -> Result<(), Error> {
+        let data = to_json(&share)?;
+        self.db.with(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO shares (token, owner, data) VALUES (?1, ?2, ?3)",
+                params![share.token, share.owner, data],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn remove(&self, token: &str) -> Result<Option<Share>, Error> {
+        let share = self.get(token)?;
+        if share.is_some() {
+            self.db
+                .with(|conn| Ok(conn.execute("DELETE FROM shares WHERE token = ?1", [token])?))?;
+            self.unlocked
+                .lock()
+                .unwrap()
+                .retain(|(unlocked_token, _), _| unlocked_token != token);
+        }
+        Ok(share)
+    }
+
+    /// Counts a download, returns false if the download limit was already reached
+    pub fn record_download(&self, token: &str) -> Result<bool, Error> {
+        self.db.with(|conn| {
+            // The transaction keeps concurrent downloads from exceeding the limit
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let share = tx
+                .query_row("SELECT data FROM shares WHERE token = ?1", [token], |row| {
+                    json_column::<Share>(row, 0)
+                })
+                .optional()?;
+            let Some(mut share) = share else {
+                return Ok(false);
+            };
+            if share.is_exhausted() {
+                return Ok(false);
+            }
+            share.downloads += 1;
+            tx.execute(
+                "UPDATE shares SET data = ?2 WHERE token = ?1",
+                params![token, to_json(&share)?],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+    }
+
+    /// Creates a session for a browser that entered the correct password
+    pub fn unlock(&self, token: &str) -> String {
+        let session = Share::new_token();
+        let now = Instant::now();
+        let mut unlocked = self.unlocked.lock().unwrap();
+        unlocked.retain(|_, expires| *expires > now);
+        unlocked.insert(
+            (token.to_owned(), session.to_owned()),
+            now + self.session_lifetime,
+        );
+        session
+    }
+
+    pub fn is_unlocked(&self, token: &str, session: &str) -> bool {
+        self.unlocked
+            .lock()
+            .unwrap()
+            .get(&(token.to_owned(), session.to_owned()))
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+}
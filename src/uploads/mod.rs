@@ -0,0 +1,48 @@
+use crate::config::UploadConfig;
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+pub mod nextcloud;
+pub mod tus;
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Periodically removes staged uploads that have expired
+pub fn spawn_cleanup(config: &UploadConfig) {
+    let tus_path = tus::staging_path(config);
+    let nextcloud_path = nextcloud::staging_path(config);
+    let expiration = Duration::from_secs(config.expiration);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let (tus_path, nextcloud_path) = (tus_path.clone(), nextcloud_path.clone());
+            let cleanup = tokio::task::spawn_blocking(move || {
+                if let Err(err) = tus::remove_expired(&tus_path) {
+                    tracing::warn!("Failed to clean up expired tus uploads: {err}");
+                }
+                if let Err(err) = nextcloud::remove_stale(&nextcloud_path, expiration) {
+                    tracing::warn!("Failed to clean up abandoned chunked uploads: {err}");
+                }
+            });
+            let _ = cleanup.await;
+        }
+    });
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether a staging entry hasn't been modified within the expiration time
+fn is_stale(path: &Path, expiration: Duration) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed > expiration)
+}
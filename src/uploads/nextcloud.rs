@@ -0,0 +1,601 @@
+//! Nextcloud chunked upload v2
+//!
+//! Clients create a transfer with MKCOL /dav/uploads/{user}/{transfer-id},
+//! PUT numbered chunks into it and finally MOVE the virtual `.file` member to the destination.
+//! The staging area is also reachable at /remote.php/dav/uploads where Nextcloud clients expect it.
+use super::is_stale;
+use crate::{
+    base_url::BaseUrl,
+    config::UploadConfig,
+    dav::{
+        Error, User,
+        fs::{FSResourceService, FSResourceServicePath},
+    },
+    filesystem::FilesystemProvider,
+};
+use axum::{
+    Extension, Router,
+    body::Body,
+    extract::{OriginalUri, Path, State},
+    response::{IntoResponse, Response},
+    routing::any,
+};
+use futures::StreamExt;
+use http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header};
+use percent_encoding::percent_decode_str;
+use quick_xml::escape::escape;
+use rustical_dav::{
+    Principal,
+    resource::{Resource, ResourceService},
+};
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::File,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, sync::OwnedMutexGuard};
+
+/// Name of the virtual member that gets moved to the destination
+const ASSEMBLED_FILE: &str = ".file";
+const TRANSFER_INFO: &str = ".transfer.json";
+/// Nextcloud numbers chunks from 1 to 10000
+const MAX_CHUNKS: u64 = 10000;
+/// Where a Destination header can point to, Nextcloud clients address mounts by user
+const MOUNT_PREFIXES: [&str; 2] = ["/dav/mount/", "/remote.php/dav/files/"];
+
+pub fn staging_path(config: &UploadConfig) -> PathBuf {
+    config.staging_path.join("nextcloud")
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TransferInfo {
+    /// OC-Total-Length announced when the transfer was created
+    total_length: Option<u64>,
+}
+
+pub struct ChunkingState<FSP: FilesystemProvider> {
+    resource_service: FSResourceService<FSP>,
+    config: Arc<UploadConfig>,
+    /// One request at a time changes a transfer, the size limit is checked against its chunks
+    transfers: Arc<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl<FSP: FilesystemProvider> Clone for ChunkingState<FSP> {
+    fn clone(&self) -> Self {
+        Self {
+            resource_service: self.resource_service.clone(),
+            config: self.config.clone(),
+            transfers: self.transfers.clone(),
+        }
+    }
+}
+
+impl<FSP: FilesystemProvider> ChunkingState<FSP> {
+    fn transfer_path(&self, user: &str, transfer: &str) -> PathBuf {
+        staging_path(&self.config).join(user).join(transfer)
+    }
+
+    /// Waits for the requests to a transfer that came first
+    async fn lock(&self, transfer_path: &std::path::Path) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut transfers = self.transfers.lock().unwrap();
+            // Nobody holds or waits for locks only the map refers to
+            transfers.retain(|_, lock| Arc::strong_count(lock) > 1);
+            transfers
+                .entry(transfer_path.to_owned())
+                .or_default()
+                .clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+/// Staging area for chunked uploads, nested at /dav/uploads
+pub fn chunking_router<FSP: FilesystemProvider>(
+    resource_service: FSResourceService<FSP>,
+    config: Arc<UploadConfig>,
+) -> Router {
+    Router::new()
+        .route("/{user}/{transfer}", any(route_transfer::<FSP>))
+        .route("/{user}/{transfer}/{chunk}", any(route_chunk::<FSP>))
+        .with_state(ChunkingState {
+            resource_service,
+            config,
+            transfers: Default::default(),
+        })
+}
+
+/// Removes transfers that haven't been touched within the expiration time
+pub fn remove_stale(path: &std::path::Path, expiration: Duration) -> Result<(), std::io::Error> {
+    let users = match std::fs::read_dir(path) {
+        Ok(users) => users,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for user in users {
+        for transfer in std::fs::read_dir(user?.path())? {
+            let transfer = transfer?.path();
+            if is_stale(&transfer, expiration) {
+                tracing::info!("Removing abandoned transfer {transfer:?}");
+                std::fs::remove_dir_all(&transfer)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct TransferPath {
+    user: String,
+    transfer: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkPath {
+    user: String,
+    transfer: String,
+    chunk: String,
+}
+
+/// Transfer ids are chosen by the client, make sure they stay a single directory
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\'])
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Maps a Destination header to a path inside a mount
+fn parse_destination(base_url: &BaseUrl, headers: &HeaderMap) -> Option<FSResourceServicePath> {
+    let destination = headers.get("Destination")?.to_str().ok()?;
+    let destination = match destination.parse::<Uri>() {
+        Ok(uri) if uri.scheme().is_some() => uri.path().to_owned(),
+        _ => destination.to_owned(),
+    };
+    let destination = percent_decode_str(&destination).decode_utf8().ok()?;
+    let rest = MOUNT_PREFIXES
+        .iter()
+        .find_map(|prefix| destination.strip_prefix(&base_url.local_path(prefix)))?;
+    let (mount, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if !is_valid_name(mount) {
+        return None;
+    }
+    Some(FSResourceServicePath {
+        mount: mount.to_owned(),
+        // The header is decoded, so %2E%2E arrives as ..
+        path: ScopedPath::parse(path)?,
+    })
+}
+
+/// Chunks of a transfer ordered by their number
+async fn list_chunks(
+    transfer_path: &std::path::Path,
+) -> Result<Vec<(u64, PathBuf, u64)>, std::io::Error> {
+    let mut chunks = vec![];
+    let mut entries = tokio::fs::read_dir(transfer_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(number) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        chunks.push((number, entry.path(), entry.metadata().await?.len()));
+    }
+    chunks.sort_by_key(|(number, ..)| *number);
+    Ok(chunks)
+}
+
+async fn route_transfer<FSP: FilesystemProvider>(
+    State(state): State<ChunkingState<FSP>>,
+    Path(TransferPath {
+        user: owner,
+        transfer,
+    }): Path<TransferPath>,
+    Extension(base_url): Extension<BaseUrl>,
+    OriginalUri(uri): OriginalUri,
+    method: Method,
+    user: User,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    if owner != user.get_id() {
+        return Err(Error::Forbidden);
+    }
+    if !is_valid_name(&transfer) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let transfer_path = state.transfer_path(&owner, &transfer);
+
+    match method.as_str() {
+        "MKCOL" => {
+            if tokio::fs::try_exists(&transfer_path).await? {
+                return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response());
+            }
+            let total_length = header_u64(&headers, "OC-Total-Length");
+            if let (Some(max_size), Some(total_length)) = (state.config.max_size, total_length)
+                && total_length > max_size
+            {
+                return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+            }
+            // Fail early if the client already tells us where the file will end up
+            if let Some(destination) = parse_destination(&base_url, &headers) {
+                state
+                    .resource_service
+                    .authorize_create(&destination, &user)
+                    .await?;
+            }
+            tokio::fs::create_dir_all(&transfer_path).await?;
+            tokio::fs::write(
+                transfer_path.join(TRANSFER_INFO),
+                serde_json::to_vec(&TransferInfo { total_length }).map_err(std::io::Error::from)?,
+            )
+            .await?;
+            Ok(StatusCode::CREATED.into_response())
+        }
+        "DELETE" => {
+            let _lock = state.lock(&transfer_path).await;
+            if !tokio::fs::try_exists(&transfer_path).await? {
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            }
+            tokio::fs::remove_dir_all(&transfer_path).await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        "PROPFIND" => {
+            if !tokio::fs::try_exists(&transfer_path).await? {
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            }
+            // Clients list the chunks to resume an interrupted transfer, under the path they used
+            let href = format!(
+                "{}/",
+                base_url.public_path(uri.path().trim_end_matches('/'))
+            );
+            let mut multistatus =
+                String::from(r#"<?xml version="1.0" encoding="utf-8"?><multistatus xmlns="DAV:">"#);
+            write!(
+                multistatus,
+                "<response><href>{}</href><propstat><prop><resourcetype><collection/></resourcetype></prop><status>HTTP/1.1 200 OK</status></propstat></response>",
+                escape(&href)
+            )
+            .unwrap();
+            if headers.get("Depth").is_none_or(|depth| depth != "0") {
+                for (number, path, len) in list_chunks(&transfer_path).await? {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    write!(
+                        multistatus,
+                        "<response><href>{}{}</href><propstat><prop><displayname>{number}</displayname><resourcetype/><getcontentlength>{len}</getcontentlength></prop><status>HTTP/1.1 200 OK</status></propstat></response>",
+                        escape(&href),
+                        escape(name)
+                    )
+                    .unwrap();
+                }
+            }
+            multistatus.push_str("</multistatus>");
+            Ok((
+                StatusCode::MULTI_STATUS,
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/xml; charset=utf-8"),
+                )],
+                multistatus,
+            )
+                .into_response())
+        }
+        _ => Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+    }
+}
+
+async fn route_chunk<FSP: FilesystemProvider>(
+    State(state): State<ChunkingState<FSP>>,
+    Path(ChunkPath {
+        user: owner,
+        transfer,
+        chunk,
+    }): Path<ChunkPath>,
+    Extension(base_url): Extension<BaseUrl>,
+    method: Method,
+    user: User,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Error> {
+    if owner != user.get_id() {
+        return Err(Error::Forbidden);
+    }
+    if !is_valid_name(&transfer) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let transfer_path = state.transfer_path(&owner, &transfer);
+    let _lock = state.lock(&transfer_path).await;
+    if !tokio::fs::metadata(&transfer_path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    match (method.as_str(), chunk.as_str()) {
+        ("PUT", chunk) => {
+            let Some(number) = chunk
+                .parse::<u64>()
+                .ok()
+                .filter(|number| (1..=MAX_CHUNKS).contains(number))
+            else {
+                return Ok(StatusCode::BAD_REQUEST.into_response());
+            };
+            put_chunk(&state, &transfer_path, number, &headers, body).await
+        }
+        ("MOVE", ASSEMBLED_FILE) => {
+            let Some(destination) = parse_destination(&base_url, &headers) else {
+                return Ok(StatusCode::BAD_REQUEST.into_response());
+            };
+            assemble(&state, &transfer_path, &destination, &user, &headers).await
+        }
+        ("DELETE", chunk) => {
+            let Ok(number) = chunk.parse::<u64>() else {
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            };
+            let chunk_path = transfer_path.join(number.to_string());
+            if !tokio::fs::metadata(&chunk_path)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            }
+            tokio::fs::remove_file(chunk_path).await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        _ => Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+    }
+}
+
+async fn transfer_info(transfer_path: &std::path::Path) -> TransferInfo {
+    tokio::fs::read(transfer_path.join(TRANSFER_INFO))
+        .await
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+async fn put_chunk<FSP: FilesystemProvider>(
+    state: &ChunkingState<FSP>,
+    transfer_path: &std::path::Path,
+    number: u64,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, Error> {
+    let limit = [
+        transfer_info(transfer_path).await.total_length,
+        header_u64(headers, "OC-Total-Length"),
+        state.config.max_size,
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+    let received: u64 = list_chunks(transfer_path)
+        .await?
+        .iter()
+        .filter(|(existing, ..)| *existing != number)
+        .map(|(.., len)| len)
+        .sum();
+
+    // Write to a temporary name so a broken connection never leaves a truncated chunk behind
+    let chunk_path = transfer_path.join(number.to_string());
+    let tmp_path = transfer_path.join(format!(".{number}.part"));
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    let mut written = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let result = match chunk.map_err(Error::from) {
+            Ok(chunk) => {
+                written += chunk.len() as u64;
+                if limit.is_some_and(|limit| received + written > limit) {
+                    Err(Error::UploadTooLarge)
+                } else {
+                    file.write_all(&chunk).await.map_err(Error::from)
+                }
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            drop(file);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(err);
+        }
+    }
+    file.sync_data().await?;
+    let existed = tokio::fs::try_exists(&chunk_path).await?;
+    tokio::fs::rename(&tmp_path, &chunk_path).await?;
+    Ok(if existed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    }
+    .into_response())
+}
+
+async fn assemble<FSP: FilesystemProvider>(
+    state: &ChunkingState<FSP>,
+    transfer_path: &std::path::Path,
+    destination: &FSResourceServicePath,
+    user: &User,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    state
+        .resource_service
+        .authorize_create(destination, user)
+        .await?;
+    let exists = state
+        .resource_service
+        .get_resource(destination, false)
+        .await
+        .is_ok();
+    if exists && headers.get("Overwrite").is_some_and(|value| value == "F") {
+        return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+    }
+
+    let chunks = list_chunks(transfer_path).await?;
+    let total: u64 = chunks.iter().map(|(.., len)| len).sum();
+    let expected =
+        header_u64(headers, "OC-Total-Length").or(transfer_info(transfer_path).await.total_length);
+    if expected.is_some_and(|expected| expected != total) {
+        tracing::warn!("Transfer {transfer_path:?} has {total} bytes, expected {expected:?}");
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let assembled_path = transfer_path.join(ASSEMBLED_FILE);
+    // Up to MAX_CHUNKS copies, keep them off the async workers
+    let concatenate = {
+        let assembled_path = assembled_path.clone();
+        move || -> Result<(), std::io::Error> {
+            let mut assembled = File::create(&assembled_path)?;
+            for (_, chunk_path, _) in &chunks {
+                std::io::copy(&mut File::open(chunk_path)?, &mut assembled)?;
+            }
+            assembled.sync_data()
+        }
+    };
+    tokio::task::spawn_blocking(concatenate)
+        .await
+        .map_err(std::io::Error::other)??;
+
+    state
+        .resource_service
+        .import_file(destination, &assembled_path)
+        .await?;
+    tokio::fs::remove_dir_all(transfer_path).await?;
+
+    let resource = state
+        .resource_service
+        .get_resource(destination, false)
+        .await?;
+    let mut response = if exists {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    }
+    .into_response();
+    if let Some(etag) = resource
+        .get_etag()
+        .and_then(|etag| HeaderValue::try_from(etag).ok())
+    {
+        response.headers_mut().insert("OC-ETag", etag.clone());
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::Request;
+    use tower::ServiceExt;
+
+    /// Staging area at the Nextcloud path for the mount of the anonymous user, up to 8 bytes
+    fn router(dir: &std::path::Path) -> Router {
+        std::fs::create_dir_all(dir.join("root/user")).unwrap();
+        let config = UploadConfig {
+            staging_path: dir.join("staging"),
+            max_size: Some(8),
+            ..Default::default()
+        };
+        let chunking = chunking_router(
+            FSResourceService::in_dir(&dir.join("root")),
+            Arc::new(config),
+        );
+        Router::new()
+            .nest("/remote.php/dav/uploads", chunking)
+            .layer(Extension(BaseUrl::root()))
+    }
+
+    async fn send(router: &Router, method: &str, uri: &str, body: &'static str) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Destination", "/remote.php/dav/files/user/notes.txt")
+            .body(Body::from(body))
+            .unwrap();
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    #[test]
+    fn destinations_in_both_dav_roots() {
+        let destination = |value: &str| {
+            let headers = HeaderMap::from_iter([(
+                http::HeaderName::from_static("destination"),
+                HeaderValue::from_str(value).unwrap(),
+            )]);
+            parse_destination(&BaseUrl::root(), &headers)
+                .map(|destination| format!("{}:{}", destination.mount, destination.path.as_str()))
+        };
+        assert_eq!(
+            destination("/dav/mount/alice/a%20b.txt").as_deref(),
+            Some("alice:a b.txt")
+        );
+        assert_eq!(
+            destination("https://files.example/remote.php/dav/files/alice/docs/c.txt").as_deref(),
+            Some("alice:docs/c.txt")
+        );
+        assert_eq!(
+            destination("/remote.php/dav/files/alice/%2E%2E/bob/c.txt"),
+            None
+        );
+        assert_eq!(destination("/remote.php/dav/uploads/alice/c.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn uploads_through_the_nextcloud_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path());
+        let transfer = "/remote.php/dav/uploads/user/transfer";
+        assert_eq!(
+            send(&router, "MKCOL", transfer, "").await.status(),
+            StatusCode::CREATED
+        );
+        for (chunk, content) in [("00001", "note"), ("00002", "s")] {
+            let response = send(&router, "PUT", &format!("{transfer}/{chunk}"), content).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        let response = send(&router, "PROPFIND", transfer, "").await;
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<href>/remote.php/dav/uploads/user/transfer/1</href>"));
+
+        let response = send(&router, "MOVE", &format!("{transfer}/.file"), "").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let uploaded = std::fs::read_to_string(dir.path().join("root/user/notes.txt")).unwrap();
+        assert_eq!(uploaded, "notes");
+    }
+
+    #[tokio::test]
+    async fn parallel_chunks_share_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path());
+        let transfer = "/remote.php/dav/uploads/user/transfer";
+        assert_eq!(
+            send(&router, "MKCOL", transfer, "").await.status(),
+            StatusCode::CREATED
+        );
+
+        let (first, second) = (format!("{transfer}/1"), format!("{transfer}/2"));
+        let (first, second) = tokio::join!(
+            send(&router, "PUT", &first, "hello"),
+            send(&router, "PUT", &second, "world"),
+        );
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            [StatusCode::CREATED, StatusCode::PAYLOAD_TOO_LARGE]
+        );
+    }
+}
@@ -0,0 +1,633 @@
+//! tus 1.0 resumable uploads with the creation, termination and expiration extensions
+use super::unix_secs;
+use crate::{
+    base_url::BaseUrl,
+    config::UploadConfig,
+    dav::{
+        Error, PATH_SEGMENT, User,
+        fs::{FSResourceService, FSResourceServicePath},
+    },
+    filesystem::FilesystemProvider,
+};
+use axum::{
+    Extension, Router,
+    body::Body,
+    extract::{Path, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{options, post},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures::StreamExt;
+use http::{HeaderMap, HeaderValue, StatusCode, header};
+use httpdate::HttpDate;
+use percent_encoding::utf8_percent_encode;
+use rustical_dav::Principal;
+use scoped_fs::ScopedPath;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+
+pub fn staging_path(config: &UploadConfig) -> PathBuf {
+    config.staging_path.join("tus")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TusUpload {
+    owner: String,
+    mount: String,
+    path: ScopedPath,
+    length: u64,
+    offset: u64,
+    /// Upload-Metadata as sent by the client
+    metadata: Option<String>,
+    /// Unix timestamp
+    expires: u64,
+}
+
+impl TusUpload {
+    fn expires(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.expires)
+    }
+}
+
+pub struct TusState<FSP: FilesystemProvider> {
+    resource_service: FSResourceService<FSP>,
+    config: Arc<UploadConfig>,
+    /// Uploads currently receiving a PATCH
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<FSP: FilesystemProvider> Clone for TusState<FSP> {
+    fn clone(&self) -> Self {
+        Self {
+            resource_service: self.resource_service.clone(),
+            config: self.config.clone(),
+            busy: self.busy.clone(),
+        }
+    }
+}
+
+impl<FSP: FilesystemProvider> TusState<FSP> {
+    fn info_path(&self, id: &str) -> PathBuf {
+        staging_path(&self.config).join(format!("{id}.json"))
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        staging_path(&self.config).join(format!("{id}.bin"))
+    }
+
+    async fn load(&self, id: &str) -> Option<TusUpload> {
+        // Ids are only ever generated by us
+        if !id.chars().all(|char| char.is_ascii_hexdigit()) {
+            return None;
+        }
+        let content = tokio::fs::read(self.info_path(id)).await.ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    async fn save(&self, id: &str, upload: &TusUpload) -> Result<(), std::io::Error> {
+        let tmp_path = self.info_path(id).with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(upload)?).await?;
+        tokio::fs::rename(&tmp_path, self.info_path(id)).await
+    }
+
+    async fn remove(&self, id: &str) {
+        let _ = tokio::fs::remove_file(self.data_path(id)).await;
+        let _ = tokio::fs::remove_file(self.info_path(id)).await;
+    }
+
+    fn next_expiry(&self) -> u64 {
+        unix_secs(SystemTime::now()) + self.config.expiration
+    }
+}
+
+/// Marks an upload as busy for the lifetime of the guard
+struct BusyGuard(Arc<Mutex<HashSet<String>>>, String);
+
+impl BusyGuard {
+    fn acquire(busy: &Arc<Mutex<HashSet<String>>>, id: &str) -> Option<Self> {
+        busy.lock()
+            .unwrap()
+            .insert(id.to_owned())
+            .then(|| Self(busy.clone(), id.to_owned()))
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().remove(&self.1);
+    }
+}
+
+/// Removes uploads past their expiry date
+pub fn remove_expired(tus_path: &std::path::Path) -> Result<(), std::io::Error> {
+    let now = unix_secs(SystemTime::now());
+    let entries = match std::fs::read_dir(tus_path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let expired = std::fs::read(&path)
+            .ok()
+            .and_then(|content| serde_json::from_slice::<TusUpload>(&content).ok())
+            .is_none_or(|upload| upload.expires < now);
+        if expired {
+            tracing::info!("Removing expired upload {path:?}");
+            let _ = std::fs::remove_file(path.with_extension("bin"));
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// tus endpoint, nested at /tus.
+/// Uploads are created at /tus/{mount} and live at /tus/{mount}/{id}
+pub fn tus_router<FSP: FilesystemProvider>(
+    resource_service: FSResourceService<FSP>,
+    config: Arc<UploadConfig>,
+) -> Router {
+    Router::new()
+        .route(
+            "/{mount}",
+            post(route_create::<FSP>).options(route_options::<FSP>),
+        )
+        .route(
+            "/{mount}/{id}",
+            options(route_options::<FSP>)
+                .head(route_head::<FSP>)
+                .patch(route_patch::<FSP>)
+                .delete(route_delete::<FSP>),
+        )
+        .layer(middleware::from_fn(tus_resumable))
+        .with_state(TusState {
+            resource_service,
+            config,
+            busy: Default::default(),
+        })
+}
+
+/// Checks the protocol version and adds Tus-Resumable to all responses
+async fn tus_resumable(req: Request, next: Next) -> Response {
+    let version = req.headers().get("Tus-Resumable");
+    let mut response = if req.method() != http::Method::OPTIONS
+        && version.is_none_or(|version| version != TUS_VERSION)
+    {
+        let mut response = StatusCode::PRECONDITION_FAILED.into_response();
+        response
+            .headers_mut()
+            .insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+        response
+    } else {
+        next.run(req).await
+    };
+    response
+        .headers_mut()
+        .insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Decodes a value from Upload-Metadata
+fn metadata_value(metadata: &str, key: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let (pair_key, value) = pair.trim().split_once(' ').unwrap_or((pair.trim(), ""));
+        if pair_key != key {
+            return None;
+        }
+        String::from_utf8(STANDARD.decode(value).ok()?).ok()
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadPath {
+    mount: String,
+    id: String,
+}
+
+async fn route_options<FSP: FilesystemProvider>(State(state): State<TusState<FSP>>) -> Response {
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    let headers = response.headers_mut();
+    headers.insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("Tus-Extension", HeaderValue::from_static(TUS_EXTENSIONS));
+    if let Some(max_size) = state.config.max_size {
+        headers.insert("Tus-Max-Size", max_size.into());
+    }
+    response
+}
+
+async fn route_create<FSP: FilesystemProvider>(
+    State(state): State<TusState<FSP>>,
+    Extension(base_url): Extension<BaseUrl>,
+    Path(mount): Path<String>,
+    user: User,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let Some(length) = header_u64(&headers, "Upload-Length") else {
+        // We don't support the creation-defer-length extension
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    if state.config.max_size.is_some_and(|max| length > max) {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    let metadata = headers
+        .get("Upload-Metadata")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    // The target is given as path inside the mount or just as filename for the mount root
+    let Some(target) = metadata.as_deref().and_then(|metadata| {
+        metadata_value(metadata, "path").or_else(|| metadata_value(metadata, "filename"))
+    }) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    // .. could reach into other mounts
+    let Some(target) = ScopedPath::parse(&target) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    let path = FSResourceServicePath {
+        mount: mount.to_owned(),
+        path: target,
+    };
+    state
+        .resource_service
+        .authorize_create(&path, &user)
+        .await?;
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let upload = TusUpload {
+        owner: user.get_id().to_owned(),
+        mount: path.mount,
+        path: path.path,
+        length,
+        offset: 0,
+        metadata,
+        expires: state.next_expiry(),
+    };
+    tokio::fs::create_dir_all(staging_path(&state.config)).await?;
+    tokio::fs::File::create(state.data_path(&id)).await?;
+    state.save(&id, &upload).await?;
+
+    let mut response = StatusCode::CREATED.into_response();
+    let headers = response.headers_mut();
+    let location = base_url.url(&format!(
+        "/tus/{}/{id}",
+        utf8_percent_encode(&mount, PATH_SEGMENT)
+    ));
+    headers.insert(header::LOCATION, HeaderValue::try_from(location).unwrap());
+    headers.insert(
+        "Upload-Expires",
+        HeaderValue::try_from(HttpDate::from(upload.expires()).to_string()).unwrap(),
+    );
+    Ok(response)
+}
+
+/// Loads an upload that belongs to user
+async fn load_owned<FSP: FilesystemProvider>(
+    state: &TusState<FSP>,
+    UploadPath { mount, id }: &UploadPath,
+    user: &User,
+) -> Result<TusUpload, StatusCode> {
+    let upload = state.load(id).await.ok_or(StatusCode::NOT_FOUND)?;
+    if &upload.mount != mount || upload.owner != user.get_id() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if upload.expires < unix_secs(SystemTime::now()) {
+        state.remove(id).await;
+        return Err(StatusCode::GONE);
+    }
+    Ok(upload)
+}
+
+fn offset_response(status: StatusCode, upload: &TusUpload) -> Response {
+    let mut response = status.into_response();
+    let headers = response.headers_mut();
+    headers.insert("Upload-Offset", upload.offset.into());
+    headers.insert(
+        "Upload-Expires",
+        HeaderValue::try_from(HttpDate::from(upload.expires()).to_string()).unwrap(),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+async fn route_head<FSP: FilesystemProvider>(
+    State(state): State<TusState<FSP>>,
+    Path(path): Path<UploadPath>,
+    user: User,
+) -> Response {
+    let upload = match load_owned(&state, &path, &user).await {
+        Ok(upload) => upload,
+        Err(status) => return status.into_response(),
+    };
+    let mut response = offset_response(StatusCode::OK, &upload);
+    let headers = response.headers_mut();
+    headers.insert("Upload-Length", upload.length.into());
+    if let Some(metadata) = upload
+        .metadata
+        .as_deref()
+        .and_then(|metadata| HeaderValue::try_from(metadata).ok())
+    {
+        headers.insert("Upload-Metadata", metadata);
+    }
+    response
+}
+
+async fn route_patch<FSP: FilesystemProvider>(
+    State(state): State<TusState<FSP>>,
+    Path(path): Path<UploadPath>,
+    user: User,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Error> {
+    if headers
+        .get(header::CONTENT_TYPE)
+        .is_none_or(|content_type| content_type != "application/offset+octet-stream")
+    {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+    // Loaded while holding the guard, a concurrent PATCH could otherwise move the offset
+    let Some(_guard) = BusyGuard::acquire(&state.busy, &path.id) else {
+        return Ok(StatusCode::LOCKED.into_response());
+    };
+    let mut upload = match load_owned(&state, &path, &user).await {
+        Ok(upload) => upload,
+        Err(status) => return Ok(status.into_response()),
+    };
+    if header_u64(&headers, "Upload-Offset") != Some(upload.offset) {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(state.data_path(&path.id))
+        .await?;
+    let mut stream = body.into_data_stream();
+    let mut result = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // The client went away, keep what we've got so it can resume
+                result = Err(Error::from(err));
+                break;
+            }
+        };
+        if upload.offset + chunk.len() as u64 > upload.length {
+            result = Err(Error::UploadTooLarge);
+            break;
+        }
+        if let Err(err) = file.write_all(&chunk).await {
+            result = Err(err.into());
+            break;
+        }
+        upload.offset += chunk.len() as u64;
+    }
+    file.sync_data().await?;
+    upload.expires = state.next_expiry();
+    state.save(&path.id, &upload).await?;
+    result?;
+
+    if upload.offset == upload.length {
+        let target = FSResourceServicePath {
+            mount: upload.mount.to_owned(),
+            path: upload.path.to_owned(),
+        };
+        state
+            .resource_service
+            .import_file(&target, &state.data_path(&path.id))
+            .await?;
+        state.remove(&path.id).await;
+    }
+    Ok(offset_response(StatusCode::NO_CONTENT, &upload))
+}
+
+async fn route_delete<FSP: FilesystemProvider>(
+    State(state): State<TusState<FSP>>,
+    Path(path): Path<UploadPath>,
+    user: User,
+) -> Response {
+    let Some(_guard) = BusyGuard::acquire(&state.busy, &path.id) else {
+        return StatusCode::LOCKED.into_response();
+    };
+    if let Err(status) = load_owned(&state, &path, &user).await {
+        return status.into_response();
+    }
+    state.remove(&path.id).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base_url::{Forwarding, resolve_base_url},
+        config::HttpConfig,
+    };
+    use http::Method;
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    /// tus endpoint for the mount of the anonymous user, uploads up to 16 bytes
+    fn router(dir: &std::path::Path) -> Router {
+        std::fs::create_dir_all(dir.join("root/user")).unwrap();
+        let config = UploadConfig {
+            staging_path: dir.join("staging"),
+            max_size: Some(16),
+            ..Default::default()
+        };
+        Router::new()
+            .nest(
+                "/tus",
+                tus_router(
+                    FSResourceService::in_dir(&dir.join("root")),
+                    Arc::new(config),
+                ),
+            )
+            .layer(middleware::from_fn_with_state(
+                Forwarding::new(&HttpConfig::default()).unwrap(),
+                resolve_base_url,
+            ))
+    }
+
+    fn tus(method: Method, uri: &str) -> http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::HOST, "files.example")
+            .header("Tus-Resumable", TUS_VERSION)
+    }
+
+    fn patch(uri: &str, offset: u64, body: impl Into<Body>) -> Request {
+        tus(Method::PATCH, uri)
+            .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+            .header("Upload-Offset", offset)
+            .body(body.into())
+            .unwrap()
+    }
+
+    async fn send(router: &Router, request: Request) -> Response {
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    /// Creates an upload of length bytes to name, returns its path
+    async fn create(router: &Router, name: &str, length: u64) -> String {
+        let response = send(
+            router,
+            tus(Method::POST, "/tus/user")
+                .header("Upload-Length", length)
+                .header(
+                    "Upload-Metadata",
+                    format!("filename {}", STANDARD.encode(name)),
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        // Clients resolve the upload URL against nothing but the server
+        location
+            .strip_prefix("http://files.example")
+            .unwrap()
+            .to_owned()
+    }
+
+    fn offset(response: &Response) -> &str {
+        response.headers()["Upload-Offset"].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_in_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path());
+        let upload = create(&router, "notes.txt", 8).await;
+        assert!(upload.starts_with("/tus/user/"));
+
+        let response = send(&router, patch(&upload, 0, "abcd")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(offset(&response), "4");
+        let response = send(
+            &router,
+            tus(Method::HEAD, &upload).body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(offset(&response), "4");
+        assert_eq!(response.headers()["Upload-Length"], "8");
+        assert!(!dir.path().join("root/user/notes.txt").exists());
+
+        // Resuming from an outdated offset would corrupt the file
+        let response = send(&router, patch(&upload, 0, "abcd")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = send(&router, patch(&upload, 4, "efgh")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(offset(&response), "8");
+        assert_eq!(
+            std::fs::read(dir.path().join("root/user/notes.txt")).unwrap(),
+            b"abcdefgh"
+        );
+        let response = send(
+            &router,
+            tus(Method::HEAD, &upload).body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn patches_wait_for_the_running_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path());
+        let upload = create(&router, "notes.txt", 8).await;
+
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let read = Arc::new(Notify::new());
+        let body = Body::from_stream(receiver.inspect({
+            let read = read.clone();
+            move |_| read.notify_one()
+        }));
+        sender
+            .unbounded_send(Ok::<_, std::io::Error>("abcd"))
+            .unwrap();
+        let running = tokio::spawn(router.clone().oneshot(patch(&upload, 0, body)));
+        read.notified().await;
+
+        let response = send(&router, patch(&upload, 0, "wxyz")).await;
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        drop(sender);
+        let response = running.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(offset(&response), "4");
+
+        // The offset is checked against the upload as the running PATCH left it
+        let response = send(&router, patch(&upload, 0, "wxyz")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = send(&router, patch(&upload, 4, "efgh")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            std::fs::read(dir.path().join("root/user/notes.txt")).unwrap(),
+            b"abcdefgh"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path());
+        let response = send(
+            &router,
+            tus(Method::POST, "/tus/user")
+                .header("Upload-Length", 17)
+                .header("Upload-Metadata", "filename YS50eHQ=")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = send(
+            &router,
+            tus(Method::POST, "/tus/user")
+                .header("Upload-Length", 4)
+                .header(
+                    "Upload-Metadata",
+                    format!("path {}", STANDARD.encode("../x")),
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Chunks beyond the declared length
+        let upload = create(&router, "a.txt", 2).await;
+        let response = send(&router, patch(&upload, 0, "abc")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = send(
+            &router,
+            Request::post("/tus/user")
+                .header("Upload-Length", 4)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    }
+}